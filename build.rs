@@ -5,6 +5,43 @@ use std::fs;
 use std::path::PathBuf;
 use std::process::Command;
 
+/// Max number of HARTs if `HIKAMI_MAX_HART_NUM` is not set.
+const DEFAULT_MAX_HART_NUM: usize = 8;
+
+/// Number of repeats regarded as a guest panic loop if `HIKAMI_WATCHDOG_THRESHOLD` is not set.
+const DEFAULT_WATCHDOG_THRESHOLD: usize = 8;
+
+/// Time window (ms) of the guest panic loop watchdog if `HIKAMI_WATCHDOG_WINDOW_MS` is not set.
+const DEFAULT_WATCHDOG_WINDOW_MS: u64 = 100;
+
+/// Max size of hotplugged guest memory if `HIKAMI_MAX_HOTPLUG_SIZE` is not set. (64 MiB)
+const DEFAULT_MAX_HOTPLUG_SIZE: usize = 0x400_0000;
+
+/// Return `LENGTH` of the memory region `name` in the linker script.
+///
+/// Only decimal lengths with optional `K` or `M` suffix are supported.
+fn memory_region_length(linker_script: &str, name: &str) -> usize {
+    let length = linker_script
+        .lines()
+        .filter(|line| line.trim_start().starts_with(name))
+        .find_map(|line| line.split("LENGTH").nth(1))
+        .and_then(|length| length.trim_start().strip_prefix('='))
+        .map_or_else(
+            || panic!("LENGTH of {name} is not found in memory.x"),
+            str::trim,
+        );
+    let (number, unit) = match length.strip_suffix('M') {
+        Some(number) => (number, 1024 * 1024),
+        None => length
+            .strip_suffix('K')
+            .map_or((length, 1), |number| (number, 1024)),
+    };
+    number
+        .parse::<usize>()
+        .unwrap_or_else(|_| panic!("unsupported LENGTH of {name} in memory.x: {length}"))
+        * unit
+}
+
 /// Build script for cargo project
 #[allow(clippy::similar_names)]
 fn main() {
@@ -20,6 +57,94 @@ fn main() {
 
     assert!(status.success(), "dtc failed with exit status: {status}");
 
+    // Max number of HARTs can be configured by `HIKAMI_MAX_HART_NUM` at build time.
+    let max_hart_num = env::var("HIKAMI_MAX_HART_NUM").map_or(DEFAULT_MAX_HART_NUM, |num| {
+        num.parse()
+            .expect("HIKAMI_MAX_HART_NUM must be a positive number")
+    });
+    assert!(
+        max_hart_num > 0,
+        "HIKAMI_MAX_HART_NUM must be a positive number"
+    );
+    fs::write(out_dir.join("max_hart_num.rs"), max_hart_num.to_string()).unwrap();
+    println!("cargo:rerun-if-env-changed=HIKAMI_MAX_HART_NUM");
+
+    // Initial offset (ns) of guest RTC can be configured by `HIKAMI_RTC_OFFSET_NS` at build time.
+    let rtc_offset: i64 = env::var("HIKAMI_RTC_OFFSET_NS").map_or(0, |offset| {
+        offset
+            .parse()
+            .expect("HIKAMI_RTC_OFFSET_NS must be a number")
+    });
+    fs::write(out_dir.join("rtc_offset.rs"), format!("{rtc_offset}_i64")).unwrap();
+    println!("cargo:rerun-if-env-changed=HIKAMI_RTC_OFFSET_NS");
+
+    // Load offset of flat binary guest kernel from guest dram base can be configured by
+    // `HIKAMI_FLAT_BINARY_OFFSET` at build time. (decimal or `0x` prefixed hex)
+    let flat_binary_offset = env::var("HIKAMI_FLAT_BINARY_OFFSET").map_or(0, |offset| {
+        offset
+            .strip_prefix("0x")
+            .map_or_else(|| offset.parse(), |hex| usize::from_str_radix(hex, 16))
+            .expect("HIKAMI_FLAT_BINARY_OFFSET must be a number")
+    });
+    fs::write(
+        out_dir.join("flat_binary_offset.rs"),
+        flat_binary_offset.to_string(),
+    )
+    .unwrap();
+    println!("cargo:rerun-if-env-changed=HIKAMI_FLAT_BINARY_OFFSET");
+
+    // Max size of memory that a guest can add at runtime (hikami memory hotplug extension)
+    // can be configured by `HIKAMI_MAX_HOTPLUG_SIZE` at build time. (decimal or `0x` prefixed hex)
+    let max_hotplug_size =
+        env::var("HIKAMI_MAX_HOTPLUG_SIZE").map_or(DEFAULT_MAX_HOTPLUG_SIZE, |size| {
+            size.strip_prefix("0x")
+                .map_or_else(|| size.parse(), |hex| usize::from_str_radix(hex, 16))
+                .expect("HIKAMI_MAX_HOTPLUG_SIZE must be a number")
+        });
+    fs::write(
+        out_dir.join("max_hotplug_size.rs"),
+        max_hotplug_size.to_string(),
+    )
+    .unwrap();
+    println!("cargo:rerun-if-env-changed=HIKAMI_MAX_HOTPLUG_SIZE");
+
+    // Guest panic loop watchdog can be configured by `HIKAMI_WATCHDOG_THRESHOLD` (number of repeats)
+    // and `HIKAMI_WATCHDOG_WINDOW_MS` (time window) at build time.
+    let watchdog_threshold: usize =
+        env::var("HIKAMI_WATCHDOG_THRESHOLD").map_or(DEFAULT_WATCHDOG_THRESHOLD, |num| {
+            num.parse()
+                .expect("HIKAMI_WATCHDOG_THRESHOLD must be a positive number")
+        });
+    assert!(
+        watchdog_threshold > 0,
+        "HIKAMI_WATCHDOG_THRESHOLD must be a positive number"
+    );
+    fs::write(
+        out_dir.join("watchdog_threshold.rs"),
+        watchdog_threshold.to_string(),
+    )
+    .unwrap();
+    println!("cargo:rerun-if-env-changed=HIKAMI_WATCHDOG_THRESHOLD");
+    let watchdog_window_ms: u64 =
+        env::var("HIKAMI_WATCHDOG_WINDOW_MS").map_or(DEFAULT_WATCHDOG_WINDOW_MS, |ms| {
+            ms.parse()
+                .expect("HIKAMI_WATCHDOG_WINDOW_MS must be a number")
+        });
+    fs::write(
+        out_dir.join("watchdog_window_ms.rs"),
+        watchdog_window_ms.to_string(),
+    )
+    .unwrap();
+    println!("cargo:rerun-if-env-changed=HIKAMI_WATCHDOG_WINDOW_MS");
+
+    // Size of the stack region (`L2_LIM`) is passed to check that stacks of all HARTs fit in it.
+    let stack_region_size = memory_region_length(include_str!("memory.x"), "L2_LIM");
+    fs::write(
+        out_dir.join("stack_region_size.rs"),
+        stack_region_size.to_string(),
+    )
+    .unwrap();
+
     // Put the linker script somewhere the linker can find it.
     fs::write(out_dir.join("memory.x"), include_bytes!("memory.x")).unwrap();
     println!("cargo:rustc-link-search={}", out_dir.display());
@@ -0,0 +1,186 @@
+//! Ring buffers of debug messages for post-mortem analysis.
+//!
+//! `debug!` and `debugln!` write to this buffer instead of the console when `debug_ring` feature is enabled.
+//! Each HART has its own ring, so writers never block. (single producer per HART)
+//! The oldest records are overwritten.
+//! The buffers are flushed to the console on panic or on request through the SBI stats extension.
+
+use crate::memmap::constant::MAX_HART_NUM;
+use crate::{current_hart_id, print, println};
+
+use core::cell::UnsafeCell;
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use riscv::register::cycle;
+
+/// Size of a record in bytes. (longer messages are truncated)
+const RECORD_SIZE: usize = 128;
+/// Number of records per HART.
+const RECORD_NUM: usize = 256;
+/// Sequence number of a record that is being written.
+const SEQ_WRITING: usize = usize::MAX;
+
+/// A debug message.
+struct Record {
+    /// Sequence number of the message in this record.
+    seq: AtomicUsize,
+    /// Cycle count when the message is written.
+    cycle: AtomicU64,
+    /// Length of the message.
+    len: AtomicUsize,
+    /// Message. (UTF-8)
+    data: UnsafeCell<[u8; RECORD_SIZE]>,
+}
+
+// Data is only written by the owner HART and readers validate it by `seq`.
+unsafe impl Sync for Record {}
+
+impl Record {
+    /// Constructor for `Record`.
+    const fn new() -> Self {
+        Record {
+            seq: AtomicUsize::new(SEQ_WRITING),
+            cycle: AtomicU64::new(0),
+            len: AtomicUsize::new(0),
+            data: UnsafeCell::new([0; RECORD_SIZE]),
+        }
+    }
+}
+
+/// Ring buffer of a HART.
+struct HartRing {
+    /// Records of debug messages.
+    records: [Record; RECORD_NUM],
+    /// Sequence number of the next record.
+    head: AtomicUsize,
+}
+
+impl HartRing {
+    /// Constructor for `HartRing`.
+    ///
+    /// It is only evaluated at compile time to initialize `RINGS`.
+    #[allow(clippy::large_stack_arrays)]
+    const fn new() -> Self {
+        HartRing {
+            records: [const { Record::new() }; RECORD_NUM],
+            head: AtomicUsize::new(0),
+        }
+    }
+
+    /// Return the oldest sequence number that may be still in the ring.
+    fn tail(&self) -> usize {
+        self.head.load(Ordering::Acquire).saturating_sub(RECORD_NUM)
+    }
+
+    /// Return cycle of the record `seq`. (`None` if the record has been overwritten)
+    fn cycle(&self, seq: usize) -> Option<u64> {
+        let record = &self.records[seq % RECORD_NUM];
+        let cycle = record.cycle.load(Ordering::Relaxed);
+        (record.seq.load(Ordering::Acquire) == seq).then_some(cycle)
+    }
+
+    /// Copy the message of record `seq` to `buf` and return its length.
+    ///
+    /// Return `None` if the record is being written or has been overwritten.
+    fn read(&self, seq: usize, buf: &mut [u8; RECORD_SIZE]) -> Option<usize> {
+        let record = &self.records[seq % RECORD_NUM];
+        if record.seq.load(Ordering::Acquire) != seq {
+            return None;
+        }
+
+        let len = record.len.load(Ordering::Relaxed);
+        buf.copy_from_slice(unsafe { &*record.data.get() });
+
+        // the record was overwritten while copying.
+        (record.seq.load(Ordering::Acquire) == seq).then_some(len)
+    }
+}
+
+/// Ring buffers of each HART.
+static RINGS: [HartRing; MAX_HART_NUM] = [const { HartRing::new() }; MAX_HART_NUM];
+
+/// Fixed size buffer for formatting a message.
+struct RecordWriter {
+    /// Formatted message.
+    buf: [u8; RECORD_SIZE],
+    /// Length of the message.
+    len: usize,
+}
+
+impl Write for RecordWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let free = RECORD_SIZE - self.len;
+        // truncate at a char boundary.
+        let mut len = s.len().min(free);
+        while !s.is_char_boundary(len) {
+            len -= 1;
+        }
+        self.buf[self.len..self.len + len].copy_from_slice(&s.as_bytes()[..len]);
+        self.len += len;
+        Ok(())
+    }
+}
+
+/// Write function calling from debug macro
+pub fn write_for_macro(args: fmt::Arguments) {
+    let mut writer = RecordWriter {
+        buf: [0; RECORD_SIZE],
+        len: 0,
+    };
+    writer.write_fmt(args).unwrap();
+
+    let ring = &RINGS[current_hart_id()];
+    let seq = ring.head.load(Ordering::Relaxed);
+    let record = &ring.records[seq % RECORD_NUM];
+    record.seq.store(SEQ_WRITING, Ordering::Release);
+    unsafe {
+        (*record.data.get()).copy_from_slice(&writer.buf);
+    }
+    record.len.store(writer.len, Ordering::Relaxed);
+    record.cycle.store(cycle::read() as u64, Ordering::Relaxed);
+    record.seq.store(seq, Ordering::Release);
+    ring.head.store(seq + 1, Ordering::Release);
+}
+
+/// Print buffered messages of all HARTs in cycle order.
+///
+/// Each message is prefixed with HART id and cycle count.
+pub fn flush() {
+    println!("==================== debug records ====================");
+
+    let mut cursors: [usize; MAX_HART_NUM] = core::array::from_fn(|hart_id| RINGS[hart_id].tail());
+    let mut buf = [0u8; RECORD_SIZE];
+    loop {
+        // pick the oldest record among HARTs.
+        let mut oldest: Option<(usize, u64)> = None;
+        for (hart_id, ring) in RINGS.iter().enumerate() {
+            let head = ring.head.load(Ordering::Acquire);
+            // skip records that have been overwritten.
+            cursors[hart_id] = cursors[hart_id].max(ring.tail());
+            while cursors[hart_id] < head {
+                if let Some(cycle) = ring.cycle(cursors[hart_id]) {
+                    if oldest.is_none_or(|(_, oldest_cycle)| cycle < oldest_cycle) {
+                        oldest = Some((hart_id, cycle));
+                    }
+                    break;
+                }
+                cursors[hart_id] += 1;
+            }
+        }
+
+        let Some((hart_id, cycle)) = oldest else {
+            break;
+        };
+        if let Some(len) = RINGS[hart_id].read(cursors[hart_id], &mut buf) {
+            print!(
+                "[hart {} @{}] {}",
+                hart_id,
+                cycle,
+                core::str::from_utf8(&buf[..len]).unwrap_or("<invalid utf-8>\n")
+            );
+        }
+        cursors[hart_id] += 1;
+    }
+
+    println!("=======================================================");
+}
@@ -1,16 +1,24 @@
 //! Devices data
 
-mod axi_sdc;
+pub mod axi_sdc;
 pub mod clint;
-mod initrd;
+pub mod initrd;
 pub mod pci;
 pub mod plic;
-mod rtc;
+pub mod rtc;
+#[cfg(feature = "boot_selftest")]
+pub mod selftest;
 pub mod uart;
 mod virtio;
 
-use crate::memmap::page_table::{constants::PAGE_SIZE, g_stage_trans_addr, PteFlag};
-use crate::memmap::{page_table, GuestPhysicalAddress, HostPhysicalAddress, MemoryMap};
+use crate::hart_local;
+use crate::memmap::page_table::{
+    constants::PAGE_SIZE, g_stage_trans_addr, PteFlag, TransAddrError,
+};
+use crate::memmap::{
+    self, page_table, GuestPhysicalAddress, HostPhysicalAddress, MemoryMap, MemoryMapBuilder,
+    MemoryMapError,
+};
 use alloc::vec::Vec;
 use fdt::Fdt;
 
@@ -24,15 +32,90 @@ const PTE_FLAGS_FOR_DEVICE: [PteFlag; 6] = [
     PteFlag::Valid,
 ];
 
+/// Create identity memory map of device registers.
+///
+/// G-stage page table can not map smaller than a page, so the size is rounded up to page boundary.
+/// (e.g. 0x100 bytes of UART registers)
+/// Unaligned base address is rejected by `MemoryMapBuilder::build`.
+fn device_memmap(paddr: HostPhysicalAddress, size: usize) -> Result<MemoryMap, MemoryMapError> {
+    let size = size.next_multiple_of(PAGE_SIZE);
+    let vaddr = GuestPhysicalAddress(paddr.raw());
+    MemoryMapBuilder::new()
+        .virt_range(vaddr..vaddr + size)
+        .phys_range(paddr..paddr + size)
+        .flags(&PTE_FLAGS_FOR_DEVICE)
+        .build()
+}
+
 /// Device emulation error.
+#[derive(Debug, PartialEq)]
 #[allow(clippy::module_name_repetitions)]
 pub enum DeviceEmulateError {
-    /// Invalid plic address.
+    /// Address is not belong to the device.
     InvalidAddress,
     /// Context ID is out of range.
     InvalidContextId,
     /// Accessed register is reserved.
     ReservedRegister,
+    /// Address is belong to the device but emulation of the register is not implemented.
+    Unimplemented(HostPhysicalAddress),
+    /// No more interrupts can be injected to the context.
+    InterruptQueueFull,
+}
+
+impl core::fmt::Display for DeviceEmulateError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            DeviceEmulateError::InvalidAddress => write!(f, "address is not belong to the device"),
+            DeviceEmulateError::InvalidContextId => write!(f, "context id is out of range"),
+            DeviceEmulateError::ReservedRegister => write!(f, "accessed register is reserved"),
+            DeviceEmulateError::Unimplemented(addr) => {
+                write!(f, "emulation of register {addr:#x} is not implemented")
+            }
+            DeviceEmulateError::InterruptQueueFull => {
+                write!(f, "interrupt queue of the context is full")
+            }
+        }
+    }
+}
+
+/// Validate the accessed register before passing through.
+///
+/// Access to holes in register map may lock up the bus on real hardware. (e.g. FPGA)
+/// Every register is regarded as implemented with `mmio_full_pass_through` feature.
+pub fn validate_register(is_implemented: bool) -> Result<(), DeviceEmulateError> {
+    if is_implemented || cfg!(feature = "mmio_full_pass_through") {
+        Ok(())
+    } else {
+        Err(DeviceEmulateError::ReservedRegister)
+    }
+}
+
+/// Is `gpa..gpa + size` in the memory region of the current guest?
+///
+/// Devices must not be pointed at addresses outside of guest memory. (e.g. identity mapped devices)
+fn in_guest_memory(gpa: GuestPhysicalAddress, size: usize) -> bool {
+    let guest_memory = hart_local()
+        .lock()
+        .get()
+        .unwrap()
+        .guest()
+        .memory_region()
+        .clone();
+    gpa >= guest_memory.start
+        && gpa
+            .raw()
+            .checked_add(size)
+            .is_some_and(|end| end <= guest_memory.end.raw())
+}
+
+/// Width of trapped load/store.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessWidth {
+    /// 32 bit access. (also used for narrower access)
+    Word,
+    /// 64 bit access.
+    DoubleWord,
 }
 
 /// Device Emulation functions.
@@ -119,54 +202,54 @@ impl DmaHostBuffer {
         self.buf.as_ptr() as usize
     }
 
+    /// Call `f` with each chunk of the guest buffer that fits in one guest page.
+    ///
+    /// `f` receives offset from start of buffer, translated host physical address and chunk size.
+    /// The first chunk may be shorter than `PAGE_SIZE` if `guest_buf_addr` is not page-aligned.
+    /// It stops at the first chunk that fails to translate.
+    fn for_each_guest_page(
+        &self,
+        guest_buf_addr: GuestPhysicalAddress,
+        mut f: impl FnMut(usize, HostPhysicalAddress, usize),
+    ) -> Result<(), (TransAddrError, &'static str)> {
+        let mut offset = 0;
+        while offset < self.used_len {
+            let gpa = guest_buf_addr + offset;
+            let hpa = g_stage_trans_addr(gpa)?;
+            let chunk_size = core::cmp::min(PAGE_SIZE - gpa % PAGE_SIZE, self.used_len - offset);
+            debug_assert!(hpa % PAGE_SIZE + chunk_size <= PAGE_SIZE);
+
+            f(offset, hpa, chunk_size);
+            offset += chunk_size;
+        }
+
+        Ok(())
+    }
+
     /// Copy guest buffer data to host buffer.
     ///
     /// It is used in emulating write command.
-    #[allow(clippy::similar_names)]
-    fn guest_to_host(&mut self, guest_buf_addr: GuestPhysicalAddress) {
-        let buf_ptr = self.buf.as_ptr().cast_mut();
-        for offset in (0..self.used_len).step_by(PAGE_SIZE) {
-            let src_gpa = guest_buf_addr + offset;
-            let src_hpa =
-                g_stage_trans_addr(src_gpa).expect("failed translation of data base address");
-
-            unsafe {
-                core::ptr::copy(
-                    src_hpa.raw() as *const u8,
-                    buf_ptr.add(offset),
-                    if offset + PAGE_SIZE < self.used_len {
-                        PAGE_SIZE
-                    } else {
-                        self.used_len - offset
-                    },
-                );
-            }
-        }
+    fn guest_to_host(
+        &mut self,
+        guest_buf_addr: GuestPhysicalAddress,
+    ) -> Result<(), (TransAddrError, &'static str)> {
+        let buf_ptr = self.buf.as_mut_ptr();
+        self.for_each_guest_page(guest_buf_addr, |offset, src_hpa, chunk_size| unsafe {
+            core::ptr::copy(src_hpa.raw() as *const u8, buf_ptr.add(offset), chunk_size);
+        })
     }
 
     /// Copy guest buffer data to host buffer.
     ///
     /// It is used in emulating read command.
-    #[allow(clippy::similar_names)]
-    fn host_to_guest(&mut self, guest_buf_addr: GuestPhysicalAddress) {
-        let buf_ptr = self.buf.as_ptr().cast_mut();
-        for offset in (0..self.used_len).step_by(PAGE_SIZE) {
-            let dst_gpa = guest_buf_addr + offset;
-            let dst_hpa =
-                g_stage_trans_addr(dst_gpa).expect("failed translation of data base address");
-
-            unsafe {
-                core::ptr::copy(
-                    buf_ptr.add(offset),
-                    dst_hpa.raw() as *mut u8,
-                    if offset + PAGE_SIZE < self.used_len {
-                        PAGE_SIZE
-                    } else {
-                        self.used_len - offset
-                    },
-                );
-            }
-        }
+    fn host_to_guest(
+        &mut self,
+        guest_buf_addr: GuestPhysicalAddress,
+    ) -> Result<(), (TransAddrError, &'static str)> {
+        let buf_ptr = self.buf.as_ptr();
+        self.for_each_guest_page(guest_buf_addr, |offset, dst_hpa, chunk_size| unsafe {
+            core::ptr::copy(buf_ptr.add(offset), dst_hpa.raw() as *mut u8, chunk_size);
+        })
     }
 }
 
@@ -186,7 +269,51 @@ pub trait MmioDevice {
     /// Return address of physical memory
     fn paddr(&self) -> HostPhysicalAddress;
     /// Return memory map between physical to physical (identity map) for crate page table.
-    fn memmap(&self) -> MemoryMap;
+    ///
+    /// # Errors
+    /// It returns `MemoryMapError` if the region taken from device tree can not be mapped.
+    fn memmap(&self) -> Result<MemoryMap, MemoryMapError>;
+    /// Return interrupt id in PLIC. (`None` if the device has no interrupt line)
+    fn irq(&self) -> Option<u32> {
+        None
+    }
+}
+
+/// Device that raises an interrupt.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqSource {
+    /// Virt IO (index of `virtio_list`)
+    VirtIo(usize),
+    /// MMC
+    Mmc,
+}
+
+/// Map from interrupt id in PLIC to the device that raises it.
+#[derive(Debug)]
+pub struct IrqMap(Vec<(u32, IrqSource)>);
+
+impl IrqMap {
+    /// Collect interrupt ids of devices.
+    fn new(virtio_list: &virtio::VirtIoList, mmc: Option<&axi_sdc::Mmc>) -> Self {
+        let mut map: Vec<(u32, IrqSource)> = virtio_list
+            .iter()
+            .enumerate()
+            .filter_map(|(index, virtio)| Some((virtio.irq()?, IrqSource::VirtIo(index))))
+            .collect();
+        if let Some(irq) = mmc.and_then(MmioDevice::irq) {
+            map.push((irq, IrqSource::Mmc));
+        }
+
+        IrqMap(map)
+    }
+
+    /// Return the device that raises interrupt `irq`.
+    pub fn get(&self, irq: u32) -> Option<IrqSource> {
+        self.0
+            .iter()
+            .find(|(device_irq, _)| *device_irq == irq)
+            .map(|(_, source)| *source)
+    }
 }
 
 /// Manage devices sush as uart, plic, etc...
@@ -212,45 +339,92 @@ pub struct Devices {
     pub clint: clint::Clint,
 
     /// RTC: Real Time Clock.
-    pub rtc: Option<rtc::Rtc>,
+    pub rtc: Option<rtc::RtcEmulation>,
 
     /// PCI: Peripheral Component Interconnect
     pub pci: Option<pci::Pci>,
 
     /// MMC:
     pub mmc: Option<axi_sdc::Mmc>,
+
+    /// Map from interrupt id to device.
+    pub irq_map: IrqMap,
 }
 
 impl Devices {
     /// Constructor for `Devices`.
     pub fn new(device_tree: Fdt) -> Self {
+        let uart_compatibles = uart::compatibles(&device_tree);
+        let virtio_list = virtio::VirtIoList::new(&device_tree, "/soc/virtio_mmio");
+        let mut plic =
+            plic::Plic::try_new(&device_tree, &["riscv,plic0"]).expect("plic is not found in fdt");
+        // guest must not enable interrupts from hypervisor owned devices.
+        for irq in virtio_list.hypervisor_owned_irqs() {
+            plic.mask_irq(irq);
+        }
+        let mmc = axi_sdc::Mmc::try_new(&device_tree, &["riscv,axi-sd-card-1.0"]);
+        let irq_map = IrqMap::new(&virtio_list, mmc.as_ref());
+
         Devices {
-            uart: uart::Uart::try_new(&device_tree, &["ns16550a", "riscv,axi-uart-1.0"])
+            uart: uart::Uart::try_new(&device_tree, &uart_compatibles)
                 .expect("uart is not found in fdt"),
-            virtio_list: virtio::VirtIoList::new(&device_tree, "/soc/virtio_mmio"),
+            virtio_list,
             initrd: initrd::Initrd::try_new_from_node_path(&device_tree, "/chosen"),
-            plic: plic::Plic::try_new(&device_tree, &["riscv,plic0"])
-                .expect("plic is not found in fdt"),
+            plic,
             clint: clint::Clint::try_new(&device_tree, &["sifive,clint0", "riscv,clint0"])
                 .expect("clint is not found in fdt"),
-            rtc: rtc::Rtc::try_new(&device_tree, &["google,goldfish-rtc"]),
+            rtc: rtc::Rtc::try_new(&device_tree, &["google,goldfish-rtc"])
+                .map(rtc::RtcEmulation::new),
             pci: pci::Pci::try_new(&device_tree, &["pci-host-ecam-generic"]),
-            mmc: axi_sdc::Mmc::try_new(&device_tree, &["riscv,axi-sd-card-1.0"]),
+            mmc,
+            irq_map,
+        }
+    }
+
+    /// Initialize devices after G-stage page table is generated.
+    ///
+    /// - IOMMU (if PCI exists)
+    /// - MMC (if exists)
+    pub fn init_devices(&mut self) {
+        if let Some(pci) = &self.pci {
+            pci.init_iommu();
+        }
+        if let Some(mmc) = &mut self.mmc {
+            mmc.init_mmc();
         }
     }
 
+    /// Is the address in a device emulated by the hypervisor?
+    ///
+    /// They are not mapped to G-stage page table, so accessing them causes guest page fault.
+    pub fn is_emulated_address(&self, addr: HostPhysicalAddress) -> bool {
+        (self.plic.paddr()..self.plic.paddr() + self.plic.size()).contains(&addr)
+            || self.virtio_list.contains(addr)
+            || self.rtc.as_ref().is_some_and(|rtc| rtc.contains(addr))
+            || self
+                .mmc
+                .as_ref()
+                .is_some_and(|mmc| (mmc.paddr()..mmc.paddr() + mmc.size()).contains(&addr))
+            || self
+                .pci
+                .as_ref()
+                .is_some_and(|pci| pci.pci_devices.contains(addr))
+    }
+
     /// Identity map for devices.
     pub fn device_mapping_g_stage(&self, page_table_start: HostPhysicalAddress) {
         let memory_map = self.create_device_map();
-        page_table::sv39x4::generate_page_table(page_table_start, &memory_map);
+        page_table::g_stage_generate_page_table(page_table_start, &memory_map);
     }
 
     /// Return devices range to crate identity map.  
-    /// It does not return `Plic` address to emulate it.
+    /// It does not return `Plic` and `Rtc` address to emulate it.
+    /// Hypervisor owned Virt IO devices are also excluded to hide them from guest.
     fn create_device_map(&self) -> Vec<MemoryMap> {
-        let mut device_mapping: Vec<MemoryMap> = self
+        let mut device_mapping: Vec<Result<MemoryMap, MemoryMapError>> = self
             .virtio_list
             .iter()
+            .filter(|virt| !virt.is_hypervisor_owned())
             .flat_map(|virt| [virt.memmap()])
             .collect();
 
@@ -262,15 +436,49 @@ impl Devices {
 
         if let Some(pci) = &self.pci {
             device_mapping.push(pci.memmap());
-            device_mapping.extend_from_slice(pci.pci_memory_maps());
-        }
-        if let Some(rtc) = &self.rtc {
-            device_mapping.push(rtc.memmap());
+            device_mapping.extend(pci.pci_memory_maps().iter().cloned().map(Ok));
         }
         if let Some(initrd) = &self.initrd {
             device_mapping.push(initrd.memmap());
         }
 
-        device_mapping
+        // overlapped mapping overwrites the earlier one in G-stage page table.
+        let mut checked_mapping: Vec<MemoryMap> = Vec::with_capacity(device_mapping.len());
+        for memmap in device_mapping {
+            let memmap = match memmap {
+                Ok(memmap) => memmap,
+                Err(err) => {
+                    #[cfg(debug_assertions)]
+                    panic!("invalid device region: {err:?}");
+                    #[cfg(not(debug_assertions))]
+                    {
+                        crate::println!(
+                            "[warning] device region is skipped since it is invalid: {:?}",
+                            err
+                        );
+                        continue;
+                    }
+                }
+            };
+            if let Some(conflict) = checked_mapping
+                .iter()
+                .find(|checked| memmap::overlaps(checked, &memmap))
+            {
+                #[cfg(debug_assertions)]
+                panic!("device regions overlap: {conflict:#x?} and {memmap:#x?}");
+                #[cfg(not(debug_assertions))]
+                {
+                    crate::println!(
+                        "[warning] device region is skipped since it overlaps: {:#x?} and {:#x?}",
+                        conflict,
+                        memmap
+                    );
+                    continue;
+                }
+            }
+            checked_mapping.push(memmap);
+        }
+
+        checked_mapping
     }
 }
@@ -4,10 +4,16 @@
 
 mod register;
 
-use super::{DeviceEmulateError, DmaHostBuffer, EmulateDevice, MmioDevice, PTE_FLAGS_FOR_DEVICE};
+use super::plic::{ContextId, Plic};
+use super::{
+    device_memmap, in_guest_memory, validate_register, DeviceEmulateError, DmaHostBuffer,
+    EmulateDevice, MmioDevice,
+};
 use crate::memmap::page_table::{constants::PAGE_SIZE, g_stage_trans_addr};
-use crate::memmap::{GuestPhysicalAddress, HostPhysicalAddress, MemoryMap};
-use register::SdcRegisters;
+use crate::memmap::{GuestPhysicalAddress, HostPhysicalAddress, MemoryMap, MemoryMapError};
+use register::{
+    SdcRegisters, CMD_INT_STATUS_CC, CMD_INT_STATUS_EI, DAT_INT_STATUS_ERR, DAT_INT_STATUS_TRS,
+};
 
 use fdt::Fdt;
 
@@ -19,19 +25,154 @@ pub struct Mmc {
     base_addr: HostPhysicalAddress,
     /// Memory map size.
     size: usize,
+    /// Interrupt id in PLIC.
+    irq: Option<u32>,
     /// DMA address.
     dma_addr: GuestPhysicalAddress,
     /// DMA alternative buffer
     dma_alt_buffer: DmaHostBuffer,
     /// Is the mmc command being executed now.
     is_transferring: bool,
+    /// Is the last command aborted by hypervisor due to untranslatable DMA address.
+    ///
+    /// Error bits are shown in interrupt status registers until data interrupt status is cleared.
+    dma_error: bool,
+    /// Is the interrupt of the aborted command not injected yet? (device never raises it)
+    abort_interrupt: bool,
+}
+
+impl Mmc {
+    /// Reset DMA emulation state.
+    ///
+    /// DMA address left by firmware is cleared not to show host physical address to guest.
+    pub fn init_mmc(&mut self) {
+        let registers_ptr = self.base_addr.raw() as *mut SdcRegisters;
+        unsafe {
+            (*registers_ptr).dma_addres = 0;
+        }
+        self.dma_addr = GuestPhysicalAddress(0);
+        self.dma_alt_buffer.clear_used_len();
+        self.is_transferring = false;
+        self.dma_error = false;
+        self.abort_interrupt = false;
+    }
+
+    /// Inject transfer interrupt claimed in PLIC to guest as VS-level external interrupt.
+    pub fn inject_interrupt(&self, plic: &mut Plic, context_id: &ContextId) {
+        crate::debugln!(
+            "[mmc] interrupt (transferring: {}, dma error: {})",
+            self.is_transferring,
+            self.dma_error
+        );
+        plic.inject_claimed(context_id, self.irq.unwrap())
+            .expect("failed to inject mmc interrupt");
+    }
+
+    /// Inject interrupt of the command aborted by hypervisor to guest.
+    ///
+    /// It is emulated interrupt, so completion of it is not forwarded to PLIC.
+    pub fn inject_abort_interrupt(&mut self, plic: &mut Plic, context_id: &ContextId) {
+        if !core::mem::take(&mut self.abort_interrupt) {
+            return;
+        }
+        if let Some(irq) = self.irq {
+            crate::debugln!("[mmc] interrupt (aborted)");
+            plic.inject(context_id, irq)
+                .expect("failed to inject mmc interrupt");
+        }
+    }
+
+    /// Abort the command without starting it.
+    fn abort_transfer(&mut self, registers_ptr: *mut SdcRegisters) {
+        unsafe {
+            (*registers_ptr).dma_addres = self.dma_addr.raw() as u64;
+        }
+        self.dma_alt_buffer.clear_used_len();
+        self.is_transferring = false;
+        self.dma_error = true;
+        self.abort_interrupt = true;
+    }
+}
+
+/// Read blocks from SD card by hypervisor itself. (e.g. loading guest image)
+///
+/// The card is assumed to be initialized by firmware and addressed in blocks. (SDHC/SDXC)
+#[cfg(feature = "load_from_mmc")]
+impl crate::fat::BlockDevice for Mmc {
+    #[allow(clippy::cast_possible_truncation)]
+    fn read_blocks(&self, lba: usize, buf: &mut [u8]) {
+        use crate::fat::SECTOR_SIZE;
+        use register::{COMMAND_DATA_READ, COMMAND_READ_SINGLE_BLOCK, COMMAND_RESPONSE_R1};
+
+        let registers_ptr = self.base_addr.raw() as *mut SdcRegisters;
+        for (index, block) in buf.chunks_exact_mut(SECTOR_SIZE).enumerate() {
+            unsafe {
+                core::ptr::addr_of_mut!((*registers_ptr).cmd_int_status).write_volatile(0);
+                core::ptr::addr_of_mut!((*registers_ptr).dat_int_status).write_volatile(0);
+                core::ptr::addr_of_mut!((*registers_ptr).block_size)
+                    .write_volatile(SECTOR_SIZE as u32 - 1);
+                core::ptr::addr_of_mut!((*registers_ptr).block_count).write_volatile(0);
+                core::ptr::addr_of_mut!((*registers_ptr).dma_addres)
+                    .write_volatile(block.as_mut_ptr() as u64);
+                core::ptr::addr_of_mut!((*registers_ptr).command).write_volatile(
+                    COMMAND_READ_SINGLE_BLOCK | COMMAND_RESPONSE_R1 | COMMAND_DATA_READ,
+                );
+                // writing argument starts the command.
+                core::ptr::addr_of_mut!((*registers_ptr).argument)
+                    .write_volatile((lba + index) as u32);
+
+                let cmd_status = loop {
+                    let status =
+                        core::ptr::addr_of!((*registers_ptr).cmd_int_status).read_volatile();
+                    if status != 0 {
+                        break status;
+                    }
+                };
+                assert!(
+                    cmd_status & CMD_INT_STATUS_EI == 0,
+                    "[mmc] failed to read block {:#x}: command error {:#x}",
+                    lba + index,
+                    cmd_status
+                );
+
+                let dat_status = loop {
+                    let status =
+                        core::ptr::addr_of!((*registers_ptr).dat_int_status).read_volatile();
+                    if status != 0 {
+                        break status;
+                    }
+                };
+                assert!(
+                    dat_status & DAT_INT_STATUS_ERR == 0,
+                    "[mmc] failed to read block {:#x}: data error {:#x}",
+                    lba + index,
+                    dat_status
+                );
+
+                core::ptr::addr_of_mut!((*registers_ptr).cmd_int_status).write_volatile(0);
+                core::ptr::addr_of_mut!((*registers_ptr).dat_int_status).write_volatile(0);
+            }
+        }
+    }
 }
 
 impl EmulateDevice for Mmc {
     /// Emulate loading port registers.
     #[allow(clippy::cast_possible_truncation)]
     fn emulate_loading(&self, dst_addr: HostPhysicalAddress) -> Result<u32, DeviceEmulateError> {
-        Ok(Self::pass_through_loading(dst_addr))
+        if !(self.base_addr..self.base_addr + self.size).contains(&dst_addr) {
+            return Err(DeviceEmulateError::InvalidAddress);
+        }
+
+        let offset = dst_addr.raw() - self.base_addr.raw();
+        validate_register(register::is_implemented(offset))?;
+        match offset {
+            // Command interrupt status
+            52 if self.dma_error => Ok(CMD_INT_STATUS_CC | CMD_INT_STATUS_EI),
+            // Data interrupt status
+            60 if self.dma_error => Ok(DAT_INT_STATUS_TRS | DAT_INT_STATUS_ERR),
+            _ => Ok(Self::pass_through_loading(dst_addr)),
+        }
     }
 
     /// Emulate storing port registers.
@@ -41,7 +182,12 @@ impl EmulateDevice for Mmc {
         dst_addr: HostPhysicalAddress,
         value: u32,
     ) -> Result<(), DeviceEmulateError> {
+        if !(self.base_addr..self.base_addr + self.size).contains(&dst_addr) {
+            return Err(DeviceEmulateError::InvalidAddress);
+        }
+
         let offset = dst_addr.raw() - self.base_addr.raw();
+        validate_register(register::is_implemented(offset))?;
         match offset {
             // Argument
             //
@@ -61,10 +207,24 @@ impl EmulateDevice for Mmc {
                         let dma_buffer_size = dma_block_size * dma_block_count;
                         self.dma_addr = dma_gpa;
 
-                        if dma_buffer_size <= PAGE_SIZE {
+                        if !in_guest_memory(dma_gpa, dma_buffer_size) {
+                            crate::debugln!(
+                                "[mmc] DMA buffer is out of guest memory: {:#x}",
+                                dma_gpa
+                            );
+                            // the command is not started.
+                            self.abort_transfer(registers_ptr);
+                            return Ok(());
+                        }
+
+                        // the buffer is contiguous in host memory only if it is in a page.
+                        if dma_gpa % PAGE_SIZE + dma_buffer_size <= PAGE_SIZE {
                             // only translation
-                            let dma_hpa = g_stage_trans_addr(dma_gpa)
-                                .expect("failed to translate dma address");
+                            let Ok(dma_hpa) = g_stage_trans_addr(dma_gpa) else {
+                                // the command is not started.
+                                self.abort_transfer(registers_ptr);
+                                return Ok(());
+                            };
                             (*registers_ptr).dma_addres = dma_hpa.raw() as u64;
                         } else {
                             // pass new buffer
@@ -72,8 +232,12 @@ impl EmulateDevice for Mmc {
                             (*registers_ptr).dma_addres = self.dma_alt_buffer.addr() as u64;
 
                             // write data to allocated memory if command is `write`
-                            if ((command >> 6) & 1) == 1 {
-                                self.dma_alt_buffer.guest_to_host(dma_gpa);
+                            if ((command >> 6) & 1) == 1
+                                && self.dma_alt_buffer.guest_to_host(dma_gpa).is_err()
+                            {
+                                // the command is not started.
+                                self.abort_transfer(registers_ptr);
+                                return Ok(());
                             }
                         }
                     }
@@ -84,6 +248,10 @@ impl EmulateDevice for Mmc {
             //
             // End transfer if write zero to it
             60 => {
+                if value == 0 {
+                    self.dma_error = false;
+                }
+
                 // end transfer
                 if value == 0 && self.is_transferring {
                     let registers_ptr = self.base_addr.raw() as *mut SdcRegisters;
@@ -95,8 +263,10 @@ impl EmulateDevice for Mmc {
                     // write back data to guest memory if command is `read`
                     if self.dma_alt_buffer.is_used() {
                         unsafe {
-                            if (((*registers_ptr).command >> 5) & 0x1) == 1 {
-                                self.dma_alt_buffer.host_to_guest(self.dma_addr);
+                            if (((*registers_ptr).command >> 5) & 0x1) == 1
+                                && self.dma_alt_buffer.host_to_guest(self.dma_addr).is_err()
+                            {
+                                crate::debugln!("[mmc] failed to write back read data");
                             }
 
                             self.dma_alt_buffer.clear_used_len();
@@ -117,18 +287,22 @@ impl EmulateDevice for Mmc {
 
 impl MmioDevice for Mmc {
     fn try_new(device_tree: &Fdt, compatibles: &[&str]) -> Option<Self> {
-        let region = device_tree
-            .find_compatible(compatibles)?
-            .reg()
-            .unwrap()
-            .next()?;
+        let node = device_tree.find_compatible(compatibles)?;
+        let region = node.reg().unwrap().next()?;
+        let irq = node
+            .property("interrupts")
+            .and_then(fdt::node::NodeProperty::as_usize)
+            .map(|irq| u32::try_from(irq).unwrap());
 
         Some(Mmc {
             base_addr: HostPhysicalAddress(region.starting_address as usize),
             size: region.size.unwrap(),
+            irq,
             dma_addr: GuestPhysicalAddress(0),
             dma_alt_buffer: DmaHostBuffer::new(PAGE_SIZE),
             is_transferring: false,
+            dma_error: false,
+            abort_interrupt: false,
         })
     }
 
@@ -140,12 +314,11 @@ impl MmioDevice for Mmc {
         self.base_addr
     }
 
-    fn memmap(&self) -> MemoryMap {
-        let vaddr = GuestPhysicalAddress(self.paddr().raw());
-        MemoryMap::new(
-            vaddr..vaddr + self.size(),
-            self.paddr()..self.paddr() + self.size(),
-            &PTE_FLAGS_FOR_DEVICE,
-        )
+    fn memmap(&self) -> Result<MemoryMap, MemoryMapError> {
+        device_memmap(self.paddr(), self.size())
+    }
+
+    fn irq(&self) -> Option<u32> {
+        self.irq
     }
 }
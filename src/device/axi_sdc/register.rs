@@ -1,5 +1,33 @@
 //! AXI SD Card Registers
 
+/// Command complete bit in command interrupt status.
+pub const CMD_INT_STATUS_CC: u32 = 0x0001;
+/// Any error bit in command interrupt status.
+pub const CMD_INT_STATUS_EI: u32 = 0x0002;
+/// Transfer complete bit in data interrupt status.
+pub const DAT_INT_STATUS_TRS: u32 = 0x0001;
+/// Any error bit in data interrupt status.
+pub const DAT_INT_STATUS_ERR: u32 = 0x0002;
+
+/// Command index of `READ_SINGLE_BLOCK` (CMD17) in command register.
+#[cfg(feature = "load_from_mmc")]
+pub const COMMAND_READ_SINGLE_BLOCK: u32 = 17 << 8;
+/// Response type R1 (short response with CRC and index check) in command register.
+#[cfg(feature = "load_from_mmc")]
+pub const COMMAND_RESPONSE_R1: u32 = 0x0001 | 0x0008 | 0x0010;
+/// Data read bit in command register.
+#[cfg(feature = "load_from_mmc")]
+pub const COMMAND_DATA_READ: u32 = 0x0020;
+
+/// Is the register at `offset` implemented?
+pub fn is_implemented(offset: usize) -> bool {
+    let reserved_start = core::mem::offset_of!(SdcRegisters, _reserved);
+    let reserved_end = core::mem::offset_of!(SdcRegisters, dma_addres);
+    offset % 4 == 0
+        && offset < core::mem::size_of::<SdcRegisters>()
+        && !(reserved_start..reserved_end).contains(&offset)
+}
+
 /// Register definition of AXI SD Card
 ///
 /// Ref: [https://github.com/eugene-tarassov/vivado-risc-v/blob/d72a439f786b455cc321e2e615d7954a75f9ebde/patches/fpga-axi-sdc.c#L67](https://github.com/eugene-tarassov/vivado-risc-v/blob/d72a439f786b455cc321e2e615d7954a75f9ebde/patches/fpga-axi-sdc.c#L67)
@@ -8,7 +36,7 @@ pub struct SdcRegisters {
     /// Command arguments
     ///
     /// If it is written, command starts.
-    _argument: u32,
+    pub argument: u32,
     /// Command
     pub command: u32,
     /// Response 1
@@ -38,7 +66,7 @@ pub struct SdcRegisters {
     /// Command interrupt enable
     _cmd_int_enable: u32,
     /// Data interrupt status
-    _dat_int_status: u32,
+    pub dat_int_status: u32,
     /// Data interrupt enable
     _dat_int_enable: u32,
     /// DMA block size
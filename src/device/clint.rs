@@ -1,9 +1,17 @@
 //! CLINT: *C*ore *L*ocal *Int*errupt
 
-use super::{MmioDevice, PTE_FLAGS_FOR_DEVICE};
-use crate::memmap::{GuestPhysicalAddress, HostPhysicalAddress, MemoryMap};
+use super::{device_memmap, MmioDevice};
+use crate::memmap::{HostPhysicalAddress, MemoryMap, MemoryMapError};
 use fdt::Fdt;
 
+mod register {
+    //! Ref: `SiFive` FU540-C000 Manual, Chapter 9 Core Local Interruptor (CLINT)
+    #![allow(dead_code)]
+
+    /// mtime register offset.
+    pub const MTIME_OFFSET: usize = 0xbff8;
+}
+
 #[allow(clippy::doc_markdown)]
 /// CLINT: Core Local INTerrupt
 /// Local interrupt controller
@@ -15,6 +23,14 @@ pub struct Clint {
     size: usize,
 }
 
+impl Clint {
+    /// Read `mtime` register.
+    pub fn read_mtime(&self) -> u64 {
+        let mtime_addr = self.base_addr + register::MTIME_OFFSET;
+        unsafe { (mtime_addr.raw() as *const u64).read_volatile() }
+    }
+}
+
 impl MmioDevice for Clint {
     fn try_new(device_tree: &Fdt, compatibles: &[&str]) -> Option<Self> {
         let region = device_tree
@@ -38,12 +54,7 @@ impl MmioDevice for Clint {
         self.base_addr
     }
 
-    fn memmap(&self) -> MemoryMap {
-        let vaddr = GuestPhysicalAddress(self.paddr().raw());
-        MemoryMap::new(
-            vaddr..vaddr + self.size(),
-            self.paddr()..self.paddr() + self.size(),
-            &PTE_FLAGS_FOR_DEVICE,
-        )
+    fn memmap(&self) -> Result<MemoryMap, MemoryMapError> {
+        device_memmap(self.paddr(), self.size())
     }
 }
@@ -1,8 +1,8 @@
 //! initrd: INITial RamDisk
 #![allow(clippy::doc_markdown)]
 
-use super::{MmioDevice, PTE_FLAGS_FOR_DEVICE};
-use crate::memmap::{GuestPhysicalAddress, HostPhysicalAddress, MemoryMap};
+use super::{device_memmap, MmioDevice};
+use crate::memmap::{HostPhysicalAddress, MemoryMap, MemoryMapError};
 use fdt::Fdt;
 
 /// A scheme for loading a temporary root file system into memory,
@@ -54,12 +54,7 @@ impl MmioDevice for Initrd {
         self.base_addr
     }
 
-    fn memmap(&self) -> MemoryMap {
-        let vaddr = GuestPhysicalAddress(self.paddr().raw());
-        MemoryMap::new(
-            vaddr..vaddr + self.size(),
-            self.paddr()..self.paddr() + self.size(),
-            &PTE_FLAGS_FOR_DEVICE,
-        )
+    fn memmap(&self) -> Result<MemoryMap, MemoryMapError> {
+        device_memmap(self.paddr(), self.size())
     }
 }
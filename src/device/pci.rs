@@ -2,20 +2,23 @@
 
 // PCI devices
 pub mod iommu;
-mod sata;
+mod msi;
+pub mod sata;
+mod unknown;
 
 pub mod config_register;
 
-use super::{MmioDevice, PTE_FLAGS_FOR_DEVICE};
-use crate::memmap::{GuestPhysicalAddress, HostPhysicalAddress, MemoryMap};
-use config_register::{read_config_register, ConfigSpaceHeaderField};
+use super::{device_memmap, MmioDevice};
+use crate::h_extension::csrs::hgatp;
+use crate::memmap::{GuestPhysicalAddress, HostPhysicalAddress, MemoryMap, MemoryMapError};
+use config_register::{read_config_register, Bar, ConfigSpaceHeaderField};
 
 use alloc::vec::Vec;
 use core::ops::Range;
 use fdt::Fdt;
 
 /// Bus - Device - Function
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub struct Bdf {
     /// PCI Bus number
     bus: u32,
@@ -38,6 +41,11 @@ impl Bdf {
         }
     }
 
+    /// Return `device_id` that is used by IOMMU to identify the device.
+    pub fn device_id(&self) -> u32 {
+        (self.bus << 8) | (self.device << 3) | self.function
+    }
+
     /// Calculate offset of config space header
     pub fn calc_config_space_header_offset(&self) -> usize {
         ((self.bus & 0b1111_1111) << 20) as usize
@@ -52,6 +60,8 @@ impl Bdf {
 #[allow(clippy::module_name_repetitions)]
 pub trait PciDevice {
     /// Create self instance.
+    ///
+    /// Return `None` if the device cannot be used. (e.g. unsupported BAR)
     fn new(
         bdf: Bdf,
         vendor_id: u32,
@@ -59,7 +69,9 @@ pub trait PciDevice {
         pci_config_space_base_addr: HostPhysicalAddress,
         pci_addr_space: &PciAddressSpace,
         memory_maps: &mut Vec<MemoryMap>,
-    ) -> Self;
+    ) -> Option<Self>
+    where
+        Self: Sized;
 
     /// Initialize pci device.
     /// * `pci`: struct `Pci`
@@ -73,6 +85,10 @@ pub struct PciDevices {
     iommu: Option<iommu::IoMmu>,
     /// SATA: Serial ATA
     pub sata: Option<sata::Sata>,
+    /// Devices that are not emulated. (passed through)
+    pub unknown: Vec<unknown::UnknownPciDevice>,
+    /// Devices that perform DMA through IOMMU. (all endpoints except IOMMU)
+    dma_devices: Vec<Bdf>,
 }
 
 impl PciDevices {
@@ -90,8 +106,12 @@ impl PciDevices {
         const PCI_MAX_DEVICE: u8 = 31;
         /// Max PCI function size.
         const PCI_MAX_FUNCTION: u8 = 7;
+        /// Header layout of endpoints. (bridges are type 1)
+        const ENDPOINT_HEADER_LAYOUT: u8 = 0;
 
         let mut sata = None;
+        let mut unknown = Vec::new();
+        let mut dma_devices = Vec::new();
         for bus in 0..=PCI_MAX_BUS {
             for device in 0..=PCI_MAX_DEVICE {
                 for function in 0..=PCI_MAX_FUNCTION {
@@ -131,15 +151,40 @@ impl PciDevices {
                         class_code & 0xff,
                     );
 
-                    if let (1, 6, 1) = (base_class, sub_class, interface) {
-                        sata = Some(sata::Sata::new(
+                    // every endpoint except IOMMU may perform DMA to guest memory.
+                    if header_type & 0x7f == ENDPOINT_HEADER_LAYOUT
+                        && (base_class, sub_class) != (8, 6)
+                    {
+                        dma_devices.push(bdf);
+                    }
+
+                    // interrupts of devices except IOMMU are delivered to the guest by INTx.
+                    if (base_class, sub_class) != (8, 6)
+                        && msi::fall_back_to_intx(config_space_header_addr)
+                    {
+                        crate::println!("MSI of {:?} is disabled to use INTx", bdf);
+                    }
+
+                    match (base_class, sub_class, interface) {
+                        (1, 6, 1) => {
+                            sata = sata::Sata::new(
+                                bdf,
+                                vendor_id.into(),
+                                device_id.into(),
+                                pci_config_space_base_addr,
+                                pci_addr_space,
+                                memory_maps,
+                            );
+                        }
+                        // IOMMU is owned by hypervisor.
+                        (8, 6, _) => (),
+                        _ => unknown.extend(unknown::UnknownPciDevice::new_for_each_bar(
                             bdf,
-                            vendor_id.into(),
-                            device_id.into(),
                             pci_config_space_base_addr,
+                            header_type,
                             pci_addr_space,
                             memory_maps,
-                        ));
+                        )),
                     }
 
                     // skip remain function id if it's not multi function device.
@@ -156,10 +201,30 @@ impl PciDevices {
                 &["riscv,pci-iommu"],
                 pci_config_space_base_addr,
                 pci_addr_space,
+                &dma_devices,
             ),
             sata,
+            unknown,
+            dma_devices,
         }
     }
+
+    /// Is the address in a BAR of the emulated or passed through devices?
+    pub fn contains(&self, addr: HostPhysicalAddress) -> bool {
+        self.sata.as_ref().is_some_and(|sata| sata.contains(addr))
+            || self.unknown.iter().any(|device| device.contains(addr))
+    }
+}
+
+/// Memory space window of PCI host bridge. (an entry of `ranges` property)
+#[derive(Debug)]
+struct MemoryWindow {
+    /// Address range
+    range: Range<HostPhysicalAddress>,
+    /// 64-bit memory space or not
+    is_64bit: bool,
+    /// Prefetchable memory or not
+    prefetchable: bool,
 }
 
 /// PCI address space
@@ -167,10 +232,8 @@ impl PciDevices {
 /// Ref: [https://elinux.org/Device_Tree_Usage#PCI_Address_Translation](https://elinux.org/Device_Tree_Usage#PCI_Address_Translation)
 #[derive(Debug)]
 pub struct PciAddressSpace {
-    /// Address range of a 32-bit memory space
-    bit32_memory_space: Range<HostPhysicalAddress>,
-    /// Address range of a 64-bit memory space
-    bit64_memory_space: Range<HostPhysicalAddress>,
+    /// 32-bit and 64-bit memory space windows in the order of `ranges`.
+    memory_windows: Vec<MemoryWindow>,
 }
 
 impl PciAddressSpace {
@@ -200,45 +263,69 @@ impl PciAddressSpace {
                 | u32::from(range[index + 3])
         };
 
-        let mut bit32_memory_space: Range<HostPhysicalAddress> = Range::default();
-        let mut bit64_memory_space: Range<HostPhysicalAddress> = Range::default();
+        let mut memory_windows = Vec::new();
         for range in ranges.chunks(RANGE_NUM * BYTES_U32) {
             let bus_address = get_u32(range, 0);
 
             // ignore I/O space map
             // https://elinux.org/Device_Tree_Usage#PCI_Address_Translation
+            let is_64bit = match (bus_address >> 24) & 0b11 {
+                0b10 => false,
+                0b11 => true,
+                _ => continue,
+            };
             let base_addr = HostPhysicalAddress(
                 ((get_u32(range, 3) as usize) << 32) | get_u32(range, 4) as usize,
             );
             let size = ((get_u32(range, 5) as usize) << 32) | get_u32(range, 6) as usize;
-            if (bus_address >> 24) & 0b11 == 0b10 {
-                bit32_memory_space = Range {
-                    start: HostPhysicalAddress(base_addr.raw()),
-                    end: HostPhysicalAddress(base_addr.raw() + size),
-                }
-            }
-            if (bus_address >> 24) & 0b11 == 0b11 {
-                bit64_memory_space = Range {
-                    start: HostPhysicalAddress(base_addr.raw()),
-                    end: HostPhysicalAddress(base_addr.raw() + size),
-                }
-            }
+            memory_windows.push(MemoryWindow {
+                range: base_addr..base_addr + size,
+                is_64bit,
+                prefetchable: (bus_address >> 30) & 0b1 == 1,
+            });
         }
 
-        PciAddressSpace {
-            bit32_memory_space,
-            bit64_memory_space,
-        }
+        PciAddressSpace { memory_windows }
     }
 
-    /// Return base address of 32-bit memory space.
-    pub fn base_addr_32bit_memory_space(&self) -> HostPhysicalAddress {
-        self.bit32_memory_space.start
+    /// Is the range in 32-bit or 64-bit memory space?
+    pub fn contains(&self, range: &Range<HostPhysicalAddress>) -> bool {
+        self.memory_windows
+            .iter()
+            .any(|window| window.range.start <= range.start && range.end <= window.range.end)
     }
 
-    /// Return base address of 64-bit memory space.
-    pub fn base_addr_64bit_memory_space(&self) -> HostPhysicalAddress {
-        self.bit64_memory_space.start
+    /// Return address ranges of all memory space windows.
+    pub fn memory_windows(&self) -> impl Iterator<Item = &Range<HostPhysicalAddress>> {
+        self.memory_windows.iter().map(|window| &window.range)
+    }
+
+    /// Return the memory space window that the BAR should be assigned to.
+    ///
+    /// - 32-bit BAR is assigned only to 32-bit window.
+    /// - Non-prefetchable BAR is not assigned to prefetchable window.
+    ///
+    /// The window of the same type as the BAR is preferred.
+    /// Return `None` for I/O BAR or if there is no suitable window.
+    pub fn window_for(&self, bar: &Bar) -> Option<&Range<HostPhysicalAddress>> {
+        let (is_64bit, prefetchable) = match *bar {
+            Bar::Memory32 { prefetchable, .. } => (false, prefetchable),
+            Bar::Memory64 { prefetchable, .. } => (true, prefetchable),
+            Bar::Io { .. } => return None,
+        };
+
+        self.memory_windows
+            .iter()
+            .filter(|window| {
+                (is_64bit || !window.is_64bit) && (prefetchable || !window.prefetchable)
+            })
+            .min_by_key(|window| {
+                (
+                    window.is_64bit != is_64bit,
+                    window.prefetchable != prefetchable,
+                )
+            })
+            .map(|window| &window.range)
     }
 }
 
@@ -267,12 +354,32 @@ impl Pci {
         &self.memory_maps
     }
 
-    /// Initialize PCI devices.
-    pub fn init_pci_devices(&self) {
+    /// Are there devices that perform DMA to guest memory?
+    #[cfg(feature = "multi_guest")]
+    pub fn has_dma_devices(&self) -> bool {
+        !self.pci_devices.dma_devices.is_empty()
+    }
+
+    /// Initialize IOMMU.
+    ///
+    /// DMA capable devices are attached to current G-stage page table.
+    pub fn init_iommu(&self) {
         if let Some(iommu) = &self.pci_devices.iommu {
+            for bdf in &self.pci_devices.dma_devices {
+                iommu.attach_device(bdf, hgatp::read().bits());
+            }
             iommu.init(self.base_addr);
         }
     }
+
+    /// Invalidate IOMMU translation cache for the GPA range.
+    ///
+    /// It must be called when G-stage mapping is changed after `init_iommu`.
+    pub fn flush_iommu_gpa_range(&self, gpa: GuestPhysicalAddress, len: usize) {
+        if let Some(iommu) = &self.pci_devices.iommu {
+            iommu.flush_gpa_range(gpa, len);
+        }
+    }
 }
 
 impl MmioDevice for Pci {
@@ -290,21 +397,17 @@ impl MmioDevice for Pci {
         let pci_devices =
             PciDevices::new(device_tree, base_address, &pci_addr_space, &mut memory_maps);
 
-        // 32 bit memory map
-        memory_maps.push(MemoryMap::new(
-            GuestPhysicalAddress(pci_addr_space.bit32_memory_space.start.raw())
-                ..GuestPhysicalAddress(pci_addr_space.bit32_memory_space.end.raw()),
-            pci_addr_space.bit32_memory_space.clone(),
-            &PTE_FLAGS_FOR_DEVICE,
-        ));
-
-        // 64 bit memory map
-        memory_maps.push(MemoryMap::new(
-            GuestPhysicalAddress(pci_addr_space.bit64_memory_space.start.raw())
-                ..GuestPhysicalAddress(pci_addr_space.bit64_memory_space.end.raw()),
-            pci_addr_space.bit64_memory_space.clone(),
-            &PTE_FLAGS_FOR_DEVICE,
-        ));
+        // 32 bit and 64 bit memory maps
+        for window in pci_addr_space.memory_windows() {
+            match device_memmap(window.start, window.end.raw() - window.start.raw()) {
+                Ok(memmap) => memory_maps.push(memmap),
+                Err(err) => crate::println!(
+                    "[warning] PCI memory window {:#x?} is not mapped: {:?}",
+                    window,
+                    err
+                ),
+            }
+        }
 
         Some(Pci {
             base_addr: HostPhysicalAddress(region.starting_address as usize),
@@ -323,12 +426,7 @@ impl MmioDevice for Pci {
         self.base_addr
     }
 
-    fn memmap(&self) -> MemoryMap {
-        let vaddr = GuestPhysicalAddress(self.paddr().raw());
-        MemoryMap::new(
-            vaddr..vaddr + self.size(),
-            self.paddr()..self.paddr() + self.size(),
-            &PTE_FLAGS_FOR_DEVICE,
-        )
+    fn memmap(&self) -> Result<MemoryMap, MemoryMapError> {
+        device_memmap(self.paddr(), self.size())
     }
 }
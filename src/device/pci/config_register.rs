@@ -2,6 +2,8 @@
 //!
 //! Ref: [https://www.macnica.co.jp/business/semiconductor/articles/microchip/140352/](https://www.macnica.co.jp/business/semiconductor/articles/microchip/140352/)
 
+use crate::memmap::HostPhysicalAddress;
+
 /// Field size of Config Space Header
 enum FieldSize {
     /// 1 byte
@@ -46,9 +48,24 @@ pub enum ConfigSpaceHeaderField {
     BaseAddressRegister4 = 0x20,
     /// Base Address Register 5
     BaseAddressRegister5 = 0x24,
+    /// Capabilities Pointer
+    CapabilitiesPointer = 0x34,
 }
 
 impl ConfigSpaceHeaderField {
+    /// Return Base Address Register of `index`. (0 - 5)
+    pub fn base_address_register(index: usize) -> Self {
+        match index {
+            0 => ConfigSpaceHeaderField::BaseAddressRegister0,
+            1 => ConfigSpaceHeaderField::BaseAddressRegister1,
+            2 => ConfigSpaceHeaderField::BaseAddressRegister2,
+            3 => ConfigSpaceHeaderField::BaseAddressRegister3,
+            4 => ConfigSpaceHeaderField::BaseAddressRegister4,
+            5 => ConfigSpaceHeaderField::BaseAddressRegister5,
+            _ => panic!("invalid BAR index: {index}"),
+        }
+    }
+
     /// Field size [byte]
     fn field_size(self) -> FieldSize {
         match self {
@@ -57,7 +74,9 @@ impl ConfigSpaceHeaderField {
             | ConfigSpaceHeaderField::Command
             | ConfigSpaceHeaderField::Status => FieldSize::Byte2,
             ConfigSpaceHeaderField::ClassCode => FieldSize::Byte3,
-            ConfigSpaceHeaderField::HeaderType => FieldSize::Byte1,
+            ConfigSpaceHeaderField::HeaderType | ConfigSpaceHeaderField::CapabilitiesPointer => {
+                FieldSize::Byte1
+            }
             ConfigSpaceHeaderField::BaseAddressRegister0
             | ConfigSpaceHeaderField::BaseAddressRegister1
             | ConfigSpaceHeaderField::BaseAddressRegister2
@@ -68,29 +87,135 @@ impl ConfigSpaceHeaderField {
     }
 }
 
-/// Get size of BAR.
-#[allow(clippy::cast_possible_truncation)]
-pub fn get_bar_size(config_reg_base_addr: usize, reg: ConfigSpaceHeaderField) -> u32 {
-    let config_reg_addr = config_reg_base_addr + reg as usize;
-    match reg {
-        ConfigSpaceHeaderField::BaseAddressRegister0
-        | ConfigSpaceHeaderField::BaseAddressRegister1
-        | ConfigSpaceHeaderField::BaseAddressRegister2
-        | ConfigSpaceHeaderField::BaseAddressRegister3
-        | ConfigSpaceHeaderField::BaseAddressRegister4
-        | ConfigSpaceHeaderField::BaseAddressRegister5 => unsafe {
-            let original_value = core::ptr::read_volatile(config_reg_addr as *const u32);
-            core::ptr::write_volatile(config_reg_addr as *mut u32, 0xffff_ffff);
-            let size_bit_mask =
-                core::ptr::read_volatile(config_reg_addr as *const u32) & 0xffff_fff0;
-            core::ptr::write_volatile(config_reg_addr as *mut u32, original_value);
-
-            1 << size_bit_mask.trailing_zeros()
-        },
-        _ => unreachable!("please specify BAR"),
+/// Decoded Base Address Register.
+#[derive(Debug, Clone, Copy)]
+#[allow(dead_code)]
+pub enum Bar {
+    /// 32-bit memory space.
+    Memory32 {
+        /// Base address.
+        addr: HostPhysicalAddress,
+        /// Size. (0 if the BAR is not implemented)
+        size: usize,
+        /// Prefetchable memory or not.
+        prefetchable: bool,
+    },
+    /// 64-bit memory space. (it occupies the next BAR as upper 32 bit)
+    Memory64 {
+        /// Base address.
+        addr: HostPhysicalAddress,
+        /// Size. (0 if the BAR is not implemented)
+        size: usize,
+        /// Prefetchable memory or not.
+        prefetchable: bool,
+    },
+    /// I/O space.
+    Io {
+        /// Base address.
+        addr: u32,
+        /// Size. (0 if the BAR is not implemented)
+        size: u32,
+    },
+}
+
+/// Write all ones to the 32-bit register, read it back and restore the original value.
+fn probe_register(reg_addr: usize) -> u32 {
+    unsafe {
+        let original_value = core::ptr::read_volatile(reg_addr as *const u32);
+        core::ptr::write_volatile(reg_addr as *mut u32, 0xffff_ffff);
+        let size_bit_mask = core::ptr::read_volatile(reg_addr as *const u32);
+        core::ptr::write_volatile(reg_addr as *mut u32, original_value);
+        size_bit_mask
     }
 }
 
+/// Parse Base Address Register of `index` (0 - 5).
+///
+/// The size is probed by writing all ones to the BAR (both halves of 64-bit BAR) while decoding is disabled.
+/// Original values of the BAR and command register are restored.
+/// Unimplemented BARs (including reserved type and 64-bit BAR without upper half) have size 0.
+#[allow(clippy::cast_possible_truncation)]
+pub fn parse_bar(config_reg_base_addr: usize, index: usize) -> Bar {
+    /// I/O space and memory space enable bits of command register.
+    const DECODE_ENABLE: u32 = 0b11;
+
+    let reg = ConfigSpaceHeaderField::base_address_register(index);
+    let reg_addr = config_reg_base_addr + reg as usize;
+
+    // disable decoding while the BAR holds all ones.
+    let command = read_config_register(config_reg_base_addr, ConfigSpaceHeaderField::Command);
+    write_config_register(
+        config_reg_base_addr,
+        ConfigSpaceHeaderField::Command,
+        command & !DECODE_ENABLE,
+    );
+
+    let value = read_config_register(config_reg_base_addr, reg);
+    let prefetchable = value & 0b1000 != 0;
+    let bar = if value & 0b1 == 1 {
+        // upper 16 bit of I/O BAR may be hardwired to zero.
+        let size_bit_mask = probe_register(reg_addr) & 0xffff_fffc;
+        let size = match size_bit_mask {
+            0 => 0,
+            0x1..=0xffff => (!size_bit_mask & 0xffff) + 1,
+            _ => (!size_bit_mask).wrapping_add(1),
+        };
+        Bar::Io {
+            addr: value & 0xffff_fffc,
+            size,
+        }
+    } else {
+        match (value >> 1) & 0b11 {
+            0b10 if index < 5 => {
+                let upper_reg_addr = reg_addr + 4;
+                let upper_value = unsafe { core::ptr::read_volatile(upper_reg_addr as *const u32) };
+                let size_bit_mask = (u64::from(probe_register(upper_reg_addr)) << 32)
+                    | u64::from(probe_register(reg_addr) & 0xffff_fff0);
+                Bar::Memory64 {
+                    addr: HostPhysicalAddress(
+                        ((upper_value as usize) << 32) | (value & 0xffff_fff0) as usize,
+                    ),
+                    size: match size_bit_mask {
+                        0 => 0,
+                        _ => (!size_bit_mask).wrapping_add(1) as usize,
+                    },
+                    prefetchable,
+                }
+            }
+            0b10 => Bar::Memory64 {
+                addr: HostPhysicalAddress((value & 0xffff_fff0) as usize),
+                size: 0,
+                prefetchable,
+            },
+            0b00 => {
+                let size_bit_mask = probe_register(reg_addr) & 0xffff_fff0;
+                Bar::Memory32 {
+                    addr: HostPhysicalAddress((value & 0xffff_fff0) as usize),
+                    size: match size_bit_mask {
+                        0 => 0,
+                        _ => (!size_bit_mask).wrapping_add(1) as usize,
+                    },
+                    prefetchable,
+                }
+            }
+            // reserved
+            _ => Bar::Memory32 {
+                addr: HostPhysicalAddress(0),
+                size: 0,
+                prefetchable: false,
+            },
+        }
+    };
+
+    write_config_register(
+        config_reg_base_addr,
+        ConfigSpaceHeaderField::Command,
+        command,
+    );
+
+    bar
+}
+
 /// Read config data from "PCI Configuration Space".
 #[allow(clippy::cast_possible_truncation)]
 pub fn read_config_register(config_reg_base_addr: usize, reg: ConfigSpaceHeaderField) -> u32 {
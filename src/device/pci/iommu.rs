@@ -1,21 +1,27 @@
 //! IOMMU: I/O memory management unit.
 //! Ref: [https://github.com/riscv-non-isa/riscv-iommu/releases/download/v1.0.0/riscv-iommu.pdf](https://github.com/riscv-non-isa/riscv-iommu/releases/download/v1.0.0/riscv-iommu.pdf)
 
+mod command;
 mod register_map;
 
-use super::config_register::{
-    get_bar_size, read_config_register, write_config_register, ConfigSpaceHeaderField,
-};
+use super::config_register::{parse_bar, write_config_register, Bar, ConfigSpaceHeaderField};
 use super::{Bdf, PciAddressSpace, PciDevice};
-use crate::h_extension::csrs::hgatp;
-use crate::memmap::{page_table::constants::PAGE_SIZE, HostPhysicalAddress, MemoryMap};
-use crate::PageBlock;
+use crate::memmap::{
+    page_allocator::PAGE_ALLOCATOR, page_table::constants::PAGE_SIZE, GuestPhysicalAddress,
+    HostPhysicalAddress, MemoryMap,
+};
+use command::{Command, CommandQueue};
 use register_map::{IoMmuMode, IoMmuRegisters};
 
 use alloc::vec::Vec;
 use core::ops::Range;
 use fdt::Fdt;
 
+/// Size of leaf ddt entry [byte]. (extended format)
+const LEAF_DDT_ENTRY_SIZE: usize = 64; // 512 / 8 = 64 [byte]
+/// Size of non-leaf ddt entry [byte].
+const NON_LEAF_DDT_ENTRY_SIZE: usize = 8;
+
 /// IOMMU: I/O memory management unit.
 #[derive(Debug)]
 pub struct IoMmu {
@@ -23,6 +29,12 @@ pub struct IoMmu {
     _ident: Bdf,
     /// IOMMU memory mapped register
     reg_space: Range<HostPhysicalAddress>,
+    /// Root device-directory-table address
+    ddt_addr: HostPhysicalAddress,
+    /// Device-directory-table mode
+    ddt_mode: IoMmuMode,
+    /// Command queue
+    command_queue: CommandQueue,
     /// PCI Vender ID
     _vender_id: u32,
     /// PCI Device ID
@@ -33,12 +45,14 @@ impl IoMmu {
     /// Create self instance from device tree.
     /// * `device_tree`: struct Fdt
     /// * `node_path`: node path in fdt
+    /// * `dma_devices`: devices that will be attached (decide levels of device-directory-table)
     #[allow(clippy::cast_possible_truncation)]
     pub fn new_from_dtb(
         device_tree: &Fdt,
         compatibles: &[&str],
         pci_config_space_base_addr: HostPhysicalAddress,
         pci_addr_space: &PciAddressSpace,
+        dma_devices: &[Bdf],
     ) -> Option<Self> {
         let pci_reg = device_tree
             .find_compatible(compatibles)?
@@ -57,46 +71,55 @@ impl IoMmu {
 
         let config_space_header_addr =
             pci_config_space_base_addr.0 | ident.calc_config_space_header_offset();
-        let memory_space_kind = (read_config_register(
-            config_space_header_addr,
-            ConfigSpaceHeaderField::BaseAddressRegister0,
-        ) >> 1)
-            & 0b11;
-        let iommu_reg_addr = match memory_space_kind {
-            0b00 => pci_addr_space.base_addr_32bit_memory_space(),
-            0b10 => pci_addr_space.base_addr_64bit_memory_space(),
-            0b01 | 0b11 => panic!("[pci BAR] reserved field"),
-            _ => unreachable!(),
+        let bar = parse_bar(config_space_header_addr, 0);
+        let bar_size = match bar {
+            Bar::Memory32 { size, .. } | Bar::Memory64 { size, .. } => size,
+            Bar::Io { .. } => panic!("[pci BAR] IOMMU registers are in I/O space"),
         };
-
-        let bar_size = get_bar_size(
-            config_space_header_addr,
-            ConfigSpaceHeaderField::BaseAddressRegister0,
-        );
+        let iommu_reg_addr = pci_addr_space
+            .window_for(&bar)
+            .expect("[pci BAR] no PCI memory window for IOMMU registers")
+            .start;
         // set iommu reg space
         write_config_register(
             config_space_header_addr,
             ConfigSpaceHeaderField::BaseAddressRegister0,
             iommu_reg_addr.raw() as u32,
         );
-        write_config_register(
-            config_space_header_addr,
-            ConfigSpaceHeaderField::BaseAddressRegister1,
-            (iommu_reg_addr.raw() >> 32) as u32,
-        );
+        if let Bar::Memory64 { .. } = bar {
+            write_config_register(
+                config_space_header_addr,
+                ConfigSpaceHeaderField::BaseAddressRegister1,
+                (iommu_reg_addr.raw() >> 32) as u32,
+            );
+        }
         write_config_register(
             config_space_header_addr,
             ConfigSpaceHeaderField::Command,
             0b10, // enable memory space
         );
 
+        // device_id width of extended format: Lv1 = 6 bit, Lv2 = 15 bit, Lv3 = 24 bit
+        let max_device_id = dma_devices.iter().map(Bdf::device_id).max().unwrap_or(0);
+        let ddt_mode = match max_device_id {
+            0..=0x3f => IoMmuMode::Lv1,
+            0x40..=0x7fff => IoMmuMode::Lv2,
+            _ => IoMmuMode::Lv3,
+        };
+
+        // zero filled entries are invalid. (cause IOMMU fault)
+        let ddt_addr = PAGE_ALLOCATOR.lock().alloc_zeroed();
+
         // https://www.kernel.org/doc/Documentation/devicetree/bindings/pci/pci.txt
         Some(IoMmu {
             _ident: ident,
             reg_space: Range {
                 start: iommu_reg_addr,
-                end: iommu_reg_addr + bar_size as usize,
+                end: iommu_reg_addr + bar_size,
             },
+            ddt_addr,
+            ddt_mode,
+            command_queue: CommandQueue::new(),
             // TODO: obtain from pci register.
             // source of these values: https://www.qemu.org/docs/master/specs/riscv-iommu.html
             _vender_id: 0x1efd,
@@ -104,24 +127,93 @@ impl IoMmu {
         })
     }
 
-    /// Set page table in IOMMU.
-    fn init_page_table(ddt_addr: HostPhysicalAddress) {
+    /// Invalidate G-stage translation cache in IOMMU for the GPA range.
+    ///
+    /// All entries are invalidated if the range is too large.
+    pub fn flush_gpa_range(&self, gpa: GuestPhysicalAddress, len: usize) {
+        /// Max number of pages invalidated one by one.
+        const MAX_FLUSH_PAGES: usize = 64;
+        /// GSCID of guest. (same as VMID in hgatp)
+        const GSCID: u16 = 0;
+
+        let registers = self.reg_space.start.raw() as *mut IoMmuRegisters;
+        let registers = unsafe { &mut *registers };
+
+        let start = gpa.raw() & !(PAGE_SIZE - 1);
+        let end = gpa.raw() + len;
+        if (end - start).div_ceil(PAGE_SIZE) > MAX_FLUSH_PAGES {
+            self.command_queue
+                .push(registers, Command::iotinval_gvma(GSCID, None));
+        } else {
+            for page_addr in (start..end).step_by(PAGE_SIZE) {
+                self.command_queue.push(
+                    registers,
+                    Command::iotinval_gvma(GSCID, Some(GuestPhysicalAddress(page_addr))),
+                );
+            }
+        }
+
+        self.command_queue.sync(registers);
+    }
+
+    /// Make DDT entry of the device valid and set G-stage page table to it.
+    ///
+    /// Non-leaf tables are allocated if they are not exist.
+    /// IOMMU must be invalidated (`IODIR.INVAL_DDT`) if this is called after `init`.
+    #[allow(clippy::cast_possible_truncation)]
+    pub fn attach_device(&self, bdf: &Bdf, hgatp_value: usize) {
         /// Offset of `iohgatp` register [byte].
         const OFFSET_IOHGATP: usize = 8;
-        /// Size of leaf ddt entry [byte].
-        const LEAF_DDT_ENTRY_SIZE: usize = 64; // 512 / 8 = 64 [byte]
         /// V field in TC regsiter.
         const TC_V: u64 = 1;
+        /// V field in non-leaf DDT entry.
+        const NON_LEAF_V: u64 = 1;
+        /// Field `ppn` of non-leaf DDT entry. (44 bit)
+        const FIELD_NON_LEAF_PPN: usize = 10;
 
-        // set all ddt entry
-        for offset in (0..PAGE_SIZE).step_by(LEAF_DDT_ENTRY_SIZE) {
-            let tc_addr = ddt_addr + offset;
-            let iohgatp_addr = ddt_addr + offset + OFFSET_IOHGATP;
+        let device_id = bdf.device_id() as usize;
+        let levels = self.ddt_mode.ddt_levels();
+        // DDI[0] = 6 bit, DDI[1] = 9 bit, DDI[2] = 9 bit
+        let ddi = [
+            device_id & 0x3f,
+            (device_id >> 6) & 0x1ff,
+            (device_id >> 15) & 0x1ff,
+        ];
+        assert!(
+            device_id >> [6, 15, 24][levels - 1] == 0,
+            "device_id {device_id:#x} is out of range of {:?}",
+            self.ddt_mode
+        );
 
-            unsafe {
-                core::ptr::write_volatile(tc_addr.0 as *mut u64, TC_V);
-                core::ptr::write_volatile(iohgatp_addr.0 as *mut u64, hgatp::read().bits() as u64);
-            }
+        // walk non-leaf tables
+        let mut table_addr = self.ddt_addr;
+        for level in (1..levels).rev() {
+            let entry_ptr = (table_addr + ddi[level] * NON_LEAF_DDT_ENTRY_SIZE).0 as *mut u64;
+            let entry = unsafe { core::ptr::read_volatile(entry_ptr) };
+            table_addr = if entry & NON_LEAF_V == 0 {
+                let next_table_addr = PAGE_ALLOCATOR.lock().alloc_zeroed();
+                unsafe {
+                    core::ptr::write_volatile(
+                        entry_ptr,
+                        ((next_table_addr.0 as u64 >> 12) << FIELD_NON_LEAF_PPN) | NON_LEAF_V,
+                    );
+                }
+                next_table_addr
+            } else {
+                HostPhysicalAddress(
+                    (((entry >> FIELD_NON_LEAF_PPN) & 0xfff_ffff_ffff) << 12) as usize,
+                )
+            };
+        }
+
+        // set leaf entry (set iohgatp before tc.V)
+        let leaf_addr = table_addr + ddi[0] * LEAF_DDT_ENTRY_SIZE;
+        unsafe {
+            core::ptr::write_volatile(
+                (leaf_addr + OFFSET_IOHGATP).0 as *mut u64,
+                hgatp_value as u64,
+            );
+            core::ptr::write_volatile(leaf_addr.0 as *mut u64, TC_V);
         }
     }
 }
@@ -134,7 +226,7 @@ impl PciDevice for IoMmu {
         _pci_config_space_base_addr: HostPhysicalAddress,
         _pci_addr_space: &PciAddressSpace,
         _memory_maps: &mut Vec<MemoryMap>,
-    ) -> Self {
+    ) -> Option<Self> {
         unreachable!("use `IoMmu::new_from_dtb` instead.");
     }
 
@@ -163,12 +255,9 @@ impl PciDevice for IoMmu {
         // Allocate a N x 16-bytes sized memory buffer that is naturally aligned to the greater of 4-KiB or N x 16-bytes.
         // Let k=log2(N) and B be the physical page number (PPN) of the allocated memory buffer.
         // CQB.PPN = B, CQB.LOG2SZ-1 = k - 1
-        let command_queue = PageBlock::alloc();
-        let command_queue_ptr = command_queue.0 as *mut u8;
-        unsafe {
-            core::ptr::write_bytes(command_queue_ptr, 0u8, PAGE_SIZE);
-        }
-        registers.cqb.set(command_queue, 4096);
+        registers
+            .cqb
+            .set(self.command_queue.base_addr(), CommandQueue::LEN);
         // cqt = 0
         registers.cqt.write(0);
         // cqcsr.cqen = 1
@@ -181,11 +270,7 @@ impl PciDevice for IoMmu {
         // Allocate a N x 32-bytes sized memory buffer that is naturally aligned to the greater of 4-KiB or N x 32-bytes.
         // Let k=log2(N) and B be the PPN of the allocated memory buffer.
         // FQB.PPN = B, FQB.LOG2SZ-1 = k - 1
-        let fault_queue = PageBlock::alloc();
-        let fault_queue_ptr = fault_queue.0 as *mut u8;
-        unsafe {
-            core::ptr::write_bytes(fault_queue_ptr, 0u8, PAGE_SIZE);
-        }
+        let fault_queue = PAGE_ALLOCATOR.lock().alloc_zeroed();
         registers.fqb.set(fault_queue, 4096);
         // fqt = 0
         registers.fqt.write(0);
@@ -199,11 +284,7 @@ impl PciDevice for IoMmu {
         // Allocate a N x 16-bytes sized buffer that is naturally aligned to the greater of 4-KiB or N x 16-bytes.
         // Let k=log2(N) and B be the PPN of the allocated memory buffer.
         // PQB.PPN = B, PQB.LOG2SZ-1 = k - 1
-        let page_request_queue = PageBlock::alloc();
-        let page_request_queue_ptr = page_request_queue.0 as *mut u8;
-        unsafe {
-            core::ptr::write_bytes(page_request_queue_ptr, 0u8, PAGE_SIZE);
-        }
+        let page_request_queue = PAGE_ALLOCATOR.lock().alloc_zeroed();
         registers.pqb.set(page_request_queue, 4096);
         // pqt = 0
         registers.pqt.write(0);
@@ -213,12 +294,7 @@ impl PciDevice for IoMmu {
         while !registers.pqcsr.pqon() {}
 
         // 15. To program the DDT pointer, first determine the supported device_id width Dw and the format of the device-context data structure.
-        let ddt_addr = PageBlock::alloc();
-        let ddt_ptr = ddt_addr.0 as *mut u8;
-        unsafe {
-            core::ptr::write_bytes(ddt_ptr, 0u8, PAGE_SIZE);
-        }
-        Self::init_page_table(ddt_addr);
-        registers.ddtp.set(IoMmuMode::Lv1, ddt_addr);
+        // DDT entries are set by `attach_device`.
+        registers.ddtp.set(self.ddt_mode, self.ddt_addr);
     }
 }
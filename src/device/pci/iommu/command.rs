@@ -0,0 +1,124 @@
+//! Command queue of IOMMU.
+//!
+//! Ref: [https://github.com/riscv-non-isa/riscv-iommu/releases/download/v1.0.0/riscv-iommu.pdf](https://github.com/riscv-non-isa/riscv-iommu/releases/download/v1.0.0/riscv-iommu.pdf) p.44
+
+use super::register_map::IoMmuRegisters;
+use crate::memmap::{
+    page_allocator::PAGE_ALLOCATOR, page_table::constants::PAGE_SIZE, GuestPhysicalAddress,
+    HostPhysicalAddress,
+};
+
+/// Size of a command [byte].
+const COMMAND_SIZE: usize = 16;
+
+/// IOMMU command. (128 bit)
+#[derive(Debug, Clone, Copy)]
+pub struct Command([u64; 2]);
+
+impl Command {
+    /// `IOTINVAL.GVMA`: invalidate G-stage translation cache.
+    ///
+    /// Invalidate all entries of `gscid` if `gpa` is `None`.
+    pub fn iotinval_gvma(gscid: u16, gpa: Option<GuestPhysicalAddress>) -> Self {
+        /// Opcode of `IOTINVAL`.
+        const OPCODE_IOTINVAL: u64 = 1;
+        /// func3 of `IOTINVAL.GVMA`.
+        const FUNC3_GVMA: u64 = 1;
+        /// Field `AV` (address valid).
+        const FIELD_AV: usize = 10;
+        /// Field `GV` (GSCID valid).
+        const FIELD_GV: usize = 33;
+        /// Field `GSCID`.
+        const FIELD_GSCID: usize = 44;
+        /// Field `ADDR[63:12]` of second double word.
+        const FIELD_ADDR: usize = 10;
+
+        let av = u64::from(gpa.is_some());
+        let addr = gpa.map_or(0, |gpa| gpa.raw() as u64 >> 12);
+        Command([
+            OPCODE_IOTINVAL
+                | (FUNC3_GVMA << 7)
+                | (av << FIELD_AV)
+                | (1 << FIELD_GV)
+                | (u64::from(gscid) << FIELD_GSCID),
+            addr << FIELD_ADDR,
+        ])
+    }
+
+    /// `IOFENCE.C`: ensure all previous commands are completed.
+    pub fn iofence_c() -> Self {
+        /// Opcode of `IOFENCE`.
+        const OPCODE_IOFENCE: u64 = 2;
+        /// func3 of `IOFENCE.C`.
+        const FUNC3_C: u64 = 0;
+
+        Command([OPCODE_IOFENCE | (FUNC3_C << 7), 0])
+    }
+}
+
+/// Command queue ring.
+#[derive(Debug)]
+pub struct CommandQueue {
+    /// Base address of the queue.
+    base_addr: HostPhysicalAddress,
+}
+
+impl CommandQueue {
+    /// Number of entries in the queue. (one page)
+    pub const LEN: usize = PAGE_SIZE / COMMAND_SIZE;
+
+    /// Allocate new command queue.
+    pub fn new() -> Self {
+        let base_addr = PAGE_ALLOCATOR.lock().alloc_zeroed();
+
+        CommandQueue { base_addr }
+    }
+
+    /// Return base address of the queue.
+    pub fn base_addr(&self) -> HostPhysicalAddress {
+        self.base_addr
+    }
+
+    /// Push the command to the queue and advance `cqt`.
+    ///
+    /// Wait for the IOMMU to consume commands if the queue is full.
+    pub fn push(&self, registers: &mut IoMmuRegisters, command: Command) {
+        let tail = registers.cqt.read() as usize;
+        let next_tail = (tail + 1) % Self::LEN;
+        while registers.cqh.read() as usize == next_tail {
+            self.check_errors(registers);
+        }
+
+        let entry_ptr = (self.base_addr + tail * COMMAND_SIZE).raw() as *mut [u64; 2];
+        unsafe {
+            entry_ptr.write_volatile(command.0);
+        }
+        // make the command visible to IOMMU before updating the tail.
+        unsafe {
+            core::arch::asm!("fence ow, ow");
+        }
+        registers.cqt.write(u32::try_from(next_tail).unwrap());
+    }
+
+    /// Push `IOFENCE.C` and wait until all commands are completed.
+    pub fn sync(&self, registers: &mut IoMmuRegisters) {
+        self.push(registers, Command::iofence_c());
+        while registers.cqh.read() != registers.cqt.read() {
+            self.check_errors(registers);
+        }
+    }
+
+    /// Panic if the command queue reports an error.
+    fn check_errors(&self, registers: &IoMmuRegisters) {
+        let errors = registers.cqcsr.errors();
+        if errors != 0 {
+            let head = registers.cqh.read() as usize;
+            let entry_ptr = (self.base_addr + head * COMMAND_SIZE).raw() as *const [u64; 2];
+            let command = unsafe { entry_ptr.read_volatile() };
+            panic!(
+                "IOMMU command queue error (cqcsr: {errors:#x}): command {:#x} {:#x}",
+                command[0], command[1]
+            );
+        }
+    }
+}
@@ -17,7 +17,7 @@ pub struct IoMmuRegisters {
     /// Command-queue base
     pub cqb: Cqb,
     /// Command-queue head
-    _cqh: u32,
+    pub cqh: Cqh,
     /// Command-queue tail
     pub cqt: Cqt,
 
@@ -82,12 +82,26 @@ impl Cqb {
     }
 }
 
+/// Command-queue head
+pub struct Cqh(u32);
+impl Cqh {
+    /// Read a value.
+    pub fn read(&self) -> u32 {
+        unsafe { core::ptr::addr_of!(self.0).read_volatile() }
+    }
+}
+
 /// Command-queue tail
 pub struct Cqt(u32);
 impl Cqt {
+    /// Read a value.
+    pub fn read(&self) -> u32 {
+        unsafe { core::ptr::addr_of!(self.0).read_volatile() }
+    }
+
     /// Write a value.
     pub fn write(&mut self, value: u32) {
-        self.0 = value;
+        unsafe { core::ptr::addr_of_mut!(self.0).write_volatile(value) }
     }
 }
 
@@ -107,6 +121,15 @@ impl CqCsr {
         let cqcsr = self.0;
         (cqcsr >> FIELD_CQCSR_CQON) & 0x1 == 1
     }
+
+    /// Return error bits. (`cqmf`: 8, `cmd_to`: 9, `cmd_ill`: 10)
+    pub fn errors(&self) -> u32 {
+        /// Mask of `cqmf`, `cmd_to` and `cmd_ill` field.
+        const CQCSR_ERRORS_MASK: u32 = 0b111 << 8;
+
+        let cqcsr = unsafe { core::ptr::addr_of!(self.0).read_volatile() };
+        cqcsr & CQCSR_ERRORS_MASK
+    }
 }
 
 /// Fault-queue base
@@ -190,6 +213,7 @@ impl PqCsr {
 
 /// For `ddtp.iommu_mode`.
 #[allow(dead_code)]
+#[derive(Debug, Clone, Copy)]
 pub enum IoMmuMode {
     /// No inbound memory transactions are allowed by the IOMMU.
     Off,
@@ -203,6 +227,18 @@ pub enum IoMmuMode {
     Lv3,
 }
 
+impl IoMmuMode {
+    /// Number of device-directory-table levels.
+    pub fn ddt_levels(self) -> usize {
+        match self {
+            IoMmuMode::Off | IoMmuMode::Bare => 0,
+            IoMmuMode::Lv1 => 1,
+            IoMmuMode::Lv2 => 2,
+            IoMmuMode::Lv3 => 3,
+        }
+    }
+}
+
 /// Device-directory-table pointer
 pub struct Ddtp(u64);
 impl Ddtp {
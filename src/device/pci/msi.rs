@@ -0,0 +1,128 @@
+//! MSI and MSI-X capabilities.
+//!
+//! The guest device tree has no MSI controller (e.g. IMSIC), and message writes of devices are DMA
+//! that the hypervisor cannot intercept as guest stores.
+//! So devices passed through to the guest are kept in `INTx` mode,
+//! whose interrupts are claimed from physical PLIC and injected through emulated PLIC.
+//!
+//! Ref: [https://astralvx.com/storage/2020/11/PCI_Express_Base_4.0_Rev0.3_February19-2014.pdf](https://astralvx.com/storage/2020/11/PCI_Express_Base_4.0_Rev0.3_February19-2014.pdf) p. 614
+
+use super::config_register::{read_config_register, write_config_register, ConfigSpaceHeaderField};
+
+/// `Capabilities List` bit of status register.
+const STATUS_CAPABILITIES_LIST: u32 = 1 << 4;
+/// `Interrupt Disable` bit of command register. (disable `INTx`)
+const COMMAND_INTERRUPT_DISABLE: u32 = 1 << 10;
+/// Capability ID of MSI.
+const CAP_ID_MSI: u32 = 0x05;
+/// Capability ID of MSI-X.
+const CAP_ID_MSIX: u32 = 0x11;
+/// `MSI Enable` bit of MSI message control.
+const MSI_ENABLE: u32 = 1 << 0;
+/// `MSI-X Enable` bit of MSI-X message control.
+const MSIX_ENABLE: u32 = 1 << 15;
+/// Max number of capabilities in config space header. (to stop at broken list)
+const MAX_CAPABILITY_NUM: usize = 48;
+
+/// Kind of message signaled interrupt capability.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MsiKind {
+    /// MSI (capability ID `0x05`)
+    Msi,
+    /// MSI-X (capability ID `0x11`)
+    MsiX,
+}
+
+/// MSI or MSI-X capability structure in config space.
+#[derive(Debug, Clone, Copy)]
+pub struct MsiCapability {
+    /// Kind of the capability.
+    kind: MsiKind,
+    /// Offset of the capability from config space header.
+    offset: usize,
+}
+
+impl MsiCapability {
+    /// Return MSI and MSI-X capabilities of the device by walking its capability list.
+    pub fn find_all(config_reg_base_addr: usize) -> impl Iterator<Item = Self> {
+        let has_capabilities =
+            read_config_register(config_reg_base_addr, ConfigSpaceHeaderField::Status)
+                & STATUS_CAPABILITIES_LIST
+                != 0;
+        // the lower two bits of capability pointers are reserved.
+        let first = if has_capabilities {
+            read_config_register(
+                config_reg_base_addr,
+                ConfigSpaceHeaderField::CapabilitiesPointer,
+            ) as usize
+                & 0xfc
+        } else {
+            0
+        };
+
+        core::iter::successors(Some(first), move |&offset| {
+            Some((read_capability_dword(config_reg_base_addr, offset) >> 8) as usize & 0xfc)
+        })
+        .take_while(|&offset| offset != 0)
+        .take(MAX_CAPABILITY_NUM)
+        .filter_map(move |offset| {
+            let kind = match read_capability_dword(config_reg_base_addr, offset) & 0xff {
+                CAP_ID_MSI => MsiKind::Msi,
+                CAP_ID_MSIX => MsiKind::MsiX,
+                _ => return None,
+            };
+            Some(MsiCapability { kind, offset })
+        })
+    }
+
+    /// Return enable bit in the first dword of the capability. (message control is upper 16 bits)
+    fn enable_bit(&self) -> u32 {
+        match self.kind {
+            MsiKind::Msi => MSI_ENABLE << 16,
+            MsiKind::MsiX => MSIX_ENABLE << 16,
+        }
+    }
+
+    /// Is message signaled interrupt enabled?
+    pub fn is_enabled(&self, config_reg_base_addr: usize) -> bool {
+        read_capability_dword(config_reg_base_addr, self.offset) & self.enable_bit() != 0
+    }
+
+    /// Disable message signaled interrupt.
+    pub fn disable(&self, config_reg_base_addr: usize) {
+        let dword = read_capability_dword(config_reg_base_addr, self.offset);
+        unsafe {
+            core::ptr::write_volatile(
+                (config_reg_base_addr + self.offset) as *mut u32,
+                dword & !self.enable_bit(),
+            );
+        }
+    }
+}
+
+/// Read 32-bit register at `offset` in config space.
+fn read_capability_dword(config_reg_base_addr: usize, offset: usize) -> u32 {
+    unsafe { core::ptr::read_volatile((config_reg_base_addr + offset) as *const u32) }
+}
+
+/// Disable MSI and MSI-X of the device and enable `INTx` instead.
+///
+/// Return `true` if MSI or MSI-X was enabled. (e.g. left by firmware)
+pub fn fall_back_to_intx(config_reg_base_addr: usize) -> bool {
+    let mut was_enabled = false;
+    for capability in MsiCapability::find_all(config_reg_base_addr) {
+        if capability.is_enabled(config_reg_base_addr) {
+            capability.disable(config_reg_base_addr);
+            was_enabled = true;
+        }
+    }
+
+    let command = read_config_register(config_reg_base_addr, ConfigSpaceHeaderField::Command);
+    write_config_register(
+        config_reg_base_addr,
+        ConfigSpaceHeaderField::Command,
+        command & !COMMAND_INTERRUPT_DISABLE,
+    );
+
+    was_enabled
+}
@@ -3,11 +3,13 @@
 //! Ref: [https://osdev.jp/wiki/AHCI-Memo](https://osdev.jp/wiki/AHCI-Memo)
 
 mod command;
+#[cfg(feature = "boot_selftest")]
+pub mod selftest;
 
-use super::config_register::{get_bar_size, read_config_register, ConfigSpaceHeaderField};
+use super::config_register::{parse_bar, Bar};
 use super::{Bdf, PciAddressSpace, PciDevice};
-use crate::device::DeviceEmulateError;
-use crate::memmap::page_table::g_stage_trans_addr;
+use crate::device::{in_guest_memory, validate_register, AccessWidth, DeviceEmulateError};
+use crate::memmap::page_table::{g_stage_trans_addr, TransAddrError};
 use crate::memmap::{GuestPhysicalAddress, HostPhysicalAddress, MemoryMap};
 use command::{
     CommandHeader, CommandTable, CommandTableGpaStorage, TransferDirection, COMMAND_HEADER_SIZE,
@@ -24,6 +26,18 @@ const SATA_PORT_NUM: usize = 32;
 const PORT_CONTROL_REGS_OFFSET: usize = 0x100;
 /// Size of port control registers.
 const PORT_CONTROL_REGS_SIZE: usize = 0x80;
+/// Size of command list. (32 command headers)
+const CMD_LIST_SIZE: usize = 0x400;
+/// Size of received FIS.
+const RECEIVED_FIS_SIZE: usize = 0x100;
+/// Host Bus Fatal Error Status in `Port x Interrupt Status`.
+const PXIS_HBFS: u32 = 1 << 29;
+/// Task File Error Status in `Port x Interrupt Status`.
+const PXIS_TFES: u32 = 1 << 30;
+/// Error bit of status field in `Port x Task File Data`.
+const PXTFD_STS_ERR: u32 = 1 << 0;
+/// Command aborted bit of error field in `Port x Task File Data`.
+const PXTFD_ERR_ABRT: u32 = 1 << 10;
 
 /// HBA(Host Bus Adapter) Port
 #[derive(Debug, Clone)]
@@ -36,6 +50,14 @@ struct HbaPort {
     ///
     /// It is copy of `Port x Command Issue`(0x38) at the time of writing.
     commands_status: u32,
+    /// Interrupt status set by hypervisor.
+    ///
+    /// It is merged to `Port x Interrupt Status`(0x10) to notify commands aborted by hypervisor.
+    emulated_interrupt_status: u32,
+    /// Is `CLB` or `FB` out of guest memory?
+    ///
+    /// Commands are aborted while it is set.
+    invalid_base_addr: bool,
     /// Addresses of `CommandTable` and its each CTBA.
     cmd_table_gpa_storage: [CommandTableGpaStorage; COMMAND_HEADER_SIZE],
 }
@@ -47,6 +69,8 @@ impl HbaPort {
             cmd_list_gpa: GuestPhysicalAddress(0), // init by 0.
             fis_gpa: GuestPhysicalAddress(0),      // init by 0.
             commands_status: 0,
+            emulated_interrupt_status: 0,
+            invalid_base_addr: false,
             cmd_table_gpa_storage: [const { CommandTableGpaStorage::new() }; COMMAND_HEADER_SIZE],
         }
     }
@@ -75,11 +99,47 @@ impl HbaPort {
             0x8 => (self.fis_gpa.raw() & 0xffff_ffff) as u32,
             // 0x0c: FIS base address upper 32 bits
             0xc => ((self.fis_gpa.raw() >> 32) & 0xffff_ffff) as u32,
+            // 0x10: interrupt status
+            0x10 => Self::pass_through_loading(dst_addr) | self.emulated_interrupt_status,
+            // 0x20: task file data
+            0x20 => {
+                if self.emulated_interrupt_status & PXIS_TFES == 0 {
+                    Self::pass_through_loading(dst_addr)
+                } else {
+                    Self::pass_through_loading(dst_addr) | PXTFD_STS_ERR | PXTFD_ERR_ABRT
+                }
+            }
             // other registers
             _ => Self::pass_through_loading(dst_addr),
         }
     }
 
+    /// Emulate 64 bit loading port registers.
+    ///
+    /// `CLB` and `FB` are returned from GPA shadow at once.
+    /// Other registers are loaded as a pair of 32 bit loads.
+    #[allow(clippy::cast_possible_truncation)]
+    pub fn emulate_loading_u64(
+        &self,
+        base_addr: HostPhysicalAddress,
+        dst_addr: HostPhysicalAddress,
+    ) -> u64 {
+        let offset = dst_addr.raw() - base_addr.raw();
+        let port_offset = offset % PORT_CONTROL_REGS_SIZE;
+        match port_offset {
+            // 0x00: command list base address
+            0x0 => self.cmd_list_gpa.raw() as u64,
+            // 0x08: FIS base address
+            0x8 => self.fis_gpa.raw() as u64,
+            // other registers
+            _ => {
+                let lower = self.emulate_loading(base_addr, dst_addr);
+                let upper = self.emulate_loading(base_addr, dst_addr + 4);
+                (u64::from(upper) << 32) | u64::from(lower)
+            }
+        }
+    }
+
     /// Emulate storing base address to `CLB` of `FB`
     #[allow(clippy::cast_possible_truncation)]
     fn storing_base_addr(
@@ -102,49 +162,88 @@ impl HbaPort {
             GuestPhysicalAddress(((value as usize) << 32) | lower_addr)
         };
 
+        self.set_base_addr(hba_base_addr, offset, port_offset, base_gpa);
+    }
+
+    /// Store base guest physical address of `CLB` or `FB` and write translated address to the register.
+    ///
+    /// If the address is out of guest memory, it is not passed to the device and `PxIS.HBFS` is set.
+    #[allow(clippy::cast_possible_truncation, clippy::similar_names)]
+    fn set_base_addr(
+        &mut self,
+        hba_base_addr: HostPhysicalAddress,
+        offset: usize,
+        port_offset: usize,
+        base_gpa: GuestPhysicalAddress,
+    ) {
         // store base guest physical addr
-        if port_offset == 0x0 || port_offset == 0x4 {
+        let is_cmd_list = port_offset == 0x0 || port_offset == 0x4;
+        let region_size = if is_cmd_list {
             self.cmd_list_gpa = base_gpa;
+            CMD_LIST_SIZE
         } else {
             self.fis_gpa = base_gpa;
-        }
+            RECEIVED_FIS_SIZE
+        };
+        let base_hpa = if base_gpa == GuestPhysicalAddress(0) {
+            // cleared while the port is stopped.
+            Some(HostPhysicalAddress(0))
+        } else if in_guest_memory(base_gpa, region_size) {
+            g_stage_trans_addr(base_gpa).ok()
+        } else {
+            None
+        };
 
-        if (0x9000_0000..0xa000_0000).contains(&base_gpa.raw()) {
-            if let Ok(base_hpa) = g_stage_trans_addr(base_gpa) {
-                if port_offset == 0x0 || port_offset == 0x4 {
-                    crate::debugln!(
-                        "[translate] P{}CLB: {:#x}(GPA) -> {:#x}(HPA)",
-                        (offset - PORT_CONTROL_REGS_OFFSET) / PORT_CONTROL_REGS_SIZE,
-                        base_gpa.raw(),
-                        base_hpa.raw()
-                    );
-                } else {
-                    crate::debugln!(
-                        "[translate] P{}FB: {:#x}(GPA) -> {:#x}(HPA)",
-                        (offset - PORT_CONTROL_REGS_OFFSET) / PORT_CONTROL_REGS_SIZE,
-                        base_gpa.raw(),
-                        base_hpa.raw()
-                    );
-                }
+        let lower_offset = offset & !0b111;
+        let write_base_hpa = |base_hpa: HostPhysicalAddress| unsafe {
+            core::ptr::write_volatile(
+                (hba_base_addr.raw() + lower_offset) as *mut u32,
+                (base_hpa.raw() & 0xffff_ffff) as u32,
+            );
+            core::ptr::write_volatile(
+                (hba_base_addr.raw() + lower_offset + 4) as *mut u32,
+                ((base_hpa.raw() >> 32) & 0xffff_ffff) as u32,
+            );
+        };
 
-                let lower_offset = offset & !0b111;
-                unsafe {
-                    core::ptr::write_volatile(
-                        (hba_base_addr.raw() + lower_offset) as *mut u32,
-                        (base_hpa.raw() & 0xffff_ffff) as u32,
-                    );
-                    core::ptr::write_volatile(
-                        (hba_base_addr.raw() + lower_offset + 4) as *mut u32,
-                        ((base_hpa.raw() >> 32) & 0xffff_ffff) as u32,
-                    );
-                }
-            }
+        #[cfg_attr(not(feature = "debug_log"), allow(unused_variables))]
+        let port_num = (offset - PORT_CONTROL_REGS_OFFSET) / PORT_CONTROL_REGS_SIZE;
+        #[cfg_attr(not(feature = "debug_log"), allow(unused_variables))]
+        let reg_name = if is_cmd_list { "CLB" } else { "FB" };
+        if let Some(base_hpa) = base_hpa {
+            crate::debugln!(
+                "[translate] P{}{}: {:#x}(GPA) -> {:#x}(HPA)",
+                port_num,
+                reg_name,
+                base_gpa,
+                base_hpa
+            );
+            write_base_hpa(base_hpa);
+            self.invalid_base_addr = false;
+        } else {
+            // the address is not passed to the device.
+            crate::debugln!(
+                "[port error] P{}{}: {:#x}(GPA) is out of guest memory",
+                port_num,
+                reg_name,
+                base_gpa
+            );
+            write_base_hpa(HostPhysicalAddress(0));
+            self.invalid_base_addr = true;
+            self.emulated_interrupt_status |= PXIS_HBFS;
         }
     }
 
     /// Rewrite address in command list and command table to host physical address.
+    ///
+    /// Addresses are left as GPA if any of them fails to translate.
     #[allow(clippy::cast_possible_truncation, clippy::similar_names)]
-    fn rewrite_cmd_addr(&mut self, base_addr: HostPhysicalAddress, port_num: usize, cmd_num: u32) {
+    fn rewrite_cmd_addr(
+        &mut self,
+        base_addr: HostPhysicalAddress,
+        port_num: usize,
+        cmd_num: u32,
+    ) -> Result<(), (TransAddrError, &'static str)> {
         let cmd_list_reg_addr =
             base_addr + PORT_CONTROL_REGS_OFFSET + PORT_CONTROL_REGS_SIZE * port_num;
         let cmd_list_hpa = unsafe {
@@ -165,8 +264,7 @@ impl HbaPort {
                 (((*cmd_header_ptr).ctba_u as usize) << 32) | (*cmd_header_ptr).ctba as usize,
             )
         };
-        let cmd_table_hpa =
-            g_stage_trans_addr(cmd_table_gpa).expect("command_header.ctba is not GPA");
+        let cmd_table_hpa = g_stage_trans_addr(cmd_table_gpa)?;
 
         // store gpa
         self.cmd_table_gpa_storage[cmd_num as usize].cmd_table_gpa = cmd_table_gpa;
@@ -178,13 +276,32 @@ impl HbaPort {
         }
 
         let cmd_table_ptr = cmd_table_hpa.raw() as *mut CommandTable;
-        unsafe {
+        let result = unsafe {
             (*cmd_table_ptr).translate_all_data_base_addresses(
                 prdtl,
                 &mut self.cmd_table_gpa_storage[cmd_num as usize].ctba_list,
                 &transfer_dir,
-            );
+            )
+        };
+
+        if result.is_err() {
+            // restore translated addresses.
+            // `HostToDevice` is passed to prevent writing back the buffer that is not transferred.
+            unsafe {
+                (*cmd_header_ptr).ctba_u = ((cmd_table_gpa.raw() >> 32) & 0xffff_ffff) as u32;
+                (*cmd_header_ptr).ctba = (cmd_table_gpa.raw() & 0xffff_ffff) as u32;
+                (*cmd_table_ptr).restore_all_data_base_addresses(
+                    &mut self.cmd_table_gpa_storage[cmd_num as usize].ctba_list,
+                    &TransferDirection::HostToDevice,
+                );
+            }
+            self.cmd_table_gpa_storage[cmd_num as usize]
+                .ctba_list
+                .clear();
+            self.cmd_table_gpa_storage[cmd_num as usize].cmd_table_gpa = GuestPhysicalAddress(0);
         }
+
+        result
     }
 
     /// Restore address in command list and command table to `GuestPhysicalAddress` physical address.
@@ -233,6 +350,34 @@ impl HbaPort {
         self.cmd_table_gpa_storage[cmd_num as usize].cmd_table_gpa = GuestPhysicalAddress(0);
     }
 
+    /// Restore addresses of commands that completed since they were issued.
+    ///
+    /// Completed commands are the issued ones whose bit is cleared in `Port x Command Issue`.
+    /// Multiple commands may be completed at once. (e.g. NCQ)
+    fn restore_completed_cmds(
+        &mut self,
+        base_addr: HostPhysicalAddress,
+        port_num: usize,
+        cmd_issue_addr: HostPhysicalAddress,
+    ) {
+        if self.commands_status == 0 {
+            return;
+        }
+
+        let mut completed_cmd_bitmap =
+            self.commands_status & !Self::pass_through_loading(cmd_issue_addr);
+        while completed_cmd_bitmap != 0 {
+            let completed_cmd_num = completed_cmd_bitmap.trailing_zeros();
+            crate::debugln!("[command completed] {}", completed_cmd_num);
+
+            // restore translated address.
+            self.restore_cmd_addr(base_addr, port_num, completed_cmd_num);
+
+            completed_cmd_bitmap &= !(1 << completed_cmd_num);
+            self.commands_status &= !(1 << completed_cmd_num);
+        }
+    }
+
     /// Pass through storing memory
     fn pass_through_storing(dst_addr: HostPhysicalAddress, value: u32) {
         let dst_ptr = dst_addr.raw() as *mut u32;
@@ -241,6 +386,44 @@ impl HbaPort {
         }
     }
 
+    /// Emulate 64 bit storing port registers.
+    ///
+    /// Both halves of `CLB` and `FB` are written at once.
+    /// Other registers are stored as a pair of 32 bit stores.
+    #[allow(clippy::cast_possible_truncation)]
+    pub fn emulate_storing_u64(
+        &mut self,
+        base_addr: HostPhysicalAddress,
+        dst_addr: HostPhysicalAddress,
+        value: u64,
+    ) {
+        let offset = dst_addr.raw() - base_addr.raw();
+        let port_offset = offset % PORT_CONTROL_REGS_SIZE;
+        match port_offset {
+            // 0x00: command list base address
+            // 0x08: FIS base address
+            port_offset @ (0x00 | 0x08) => {
+                crate::debugln!(
+                    "[port{} write] {:#x} <- {:#x}",
+                    (offset - PORT_CONTROL_REGS_OFFSET) / PORT_CONTROL_REGS_SIZE,
+                    port_offset,
+                    value
+                );
+                self.set_base_addr(
+                    base_addr,
+                    offset,
+                    port_offset,
+                    GuestPhysicalAddress(value as usize),
+                );
+            }
+            // other registers
+            _ => {
+                self.emulate_storing(base_addr, dst_addr, value as u32);
+                self.emulate_storing(base_addr, dst_addr + 4, (value >> 32) as u32);
+            }
+        }
+    }
+
     /// Emulate storing port registers.
     pub fn emulate_storing(
         &mut self,
@@ -267,30 +450,50 @@ impl HbaPort {
             // interrupt status
             // Ref: https://osdev.jp/wiki/AHCI-Memo, Offset 10h: PxIS - Port Interrupt Status
             0x10 => {
-                // command has already issued
-                if self.commands_status != 0 {
-                    // get completed command number
-                    let current_cmd_status = Self::pass_through_loading(dst_addr + 0x28); // current command isssue value
-                    let completed_cmd_num =
-                        (self.commands_status & !current_cmd_status).trailing_zeros();
-                    crate::debugln!("[command completed] {}", completed_cmd_num);
-
-                    // restore translated address.
-                    let port_num = (offset - PORT_CONTROL_REGS_OFFSET) / PORT_CONTROL_REGS_SIZE;
-                    self.restore_cmd_addr(base_addr, port_num, completed_cmd_num);
-                }
+                // write 1 to clear
+                self.emulated_interrupt_status &= !value;
+
+                // `Port x Command Issue` is at 0x38.
+                let port_num = (offset - PORT_CONTROL_REGS_OFFSET) / PORT_CONTROL_REGS_SIZE;
+                self.restore_completed_cmds(base_addr, port_num, dst_addr + 0x28);
 
                 Self::pass_through_storing(dst_addr, value);
             }
             // command issue
             0x38 => {
-                let cmd_num = value.trailing_zeros();
                 let port_num = (offset - PORT_CONTROL_REGS_OFFSET) / PORT_CONTROL_REGS_SIZE;
-                crate::debugln!("[command issue] {}", cmd_num);
-                self.rewrite_cmd_addr(base_addr, port_num, cmd_num);
-                self.commands_status = Self::pass_through_loading(dst_addr) | value;
+                // a slot completed but not processed yet may be issued again.
+                self.restore_completed_cmds(base_addr, port_num, dst_addr);
+
+                // writing 1 to the bit of a command that is already issued has no effect.
+                let mut issued_cmds = value & !self.commands_status;
+                if self.invalid_base_addr {
+                    crate::debugln!("[command aborted] {:#x}: invalid CLB or FB", issued_cmds);
+                    self.emulated_interrupt_status |= PXIS_TFES;
+                    return;
+                }
 
-                Self::pass_through_storing(dst_addr, value);
+                // translate addresses of each newly issued command.
+                let mut new_cmds = issued_cmds;
+                while new_cmds != 0 {
+                    let cmd_num = new_cmds.trailing_zeros();
+                    new_cmds &= !(1 << cmd_num);
+                    crate::debugln!("[command issue] {}", cmd_num);
+
+                    #[cfg_attr(not(feature = "debug_log"), allow(unused_variables))]
+                    if let Err((err, msg)) = self.rewrite_cmd_addr(base_addr, port_num, cmd_num) {
+                        // abort the command without issuing it to the device.
+                        crate::debugln!("[command aborted] {}: {}: {}", cmd_num, msg, err);
+                        issued_cmds &= !(1 << cmd_num);
+                        self.emulated_interrupt_status |= PXIS_TFES;
+                    }
+                }
+                if issued_cmds == 0 {
+                    return;
+                }
+                self.commands_status = Self::pass_through_loading(dst_addr) | issued_cmds;
+
+                Self::pass_through_storing(dst_addr, issued_cmds);
             }
             // other registers
             _ => Self::pass_through_storing(dst_addr, value),
@@ -314,6 +517,54 @@ pub struct Sata {
 }
 
 impl Sata {
+    /// Is the address in ABAR?
+    pub fn contains(&self, addr: HostPhysicalAddress) -> bool {
+        self.abar.contains(&addr)
+    }
+
+    /// Is the HBA register at `offset` implemented?
+    ///
+    /// Ports that are not set in `PI` (Ports Implemented) are regarded as holes.
+    fn is_implemented_register(&self, offset: usize) -> bool {
+        /// Offset of `PI` (Ports Implemented).
+        const PORTS_IMPLEMENTED: usize = 0x0c;
+        /// Reserved register in port control registers.
+        const PORT_RESERVED: usize = 0x1c;
+        /// End of port control registers. (`PxDEVSLP`)
+        const PORT_REGS_END: usize = 0x44;
+
+        if offset % 4 != 0 {
+            return false;
+        }
+
+        match offset {
+            // 0x00 - 0x2b: Generic Host Control
+            0x0..=0x2b => true,
+            // Port control registers
+            0x100..=0x10ff => {
+                let port_num = (offset - PORT_CONTROL_REGS_OFFSET) / PORT_CONTROL_REGS_SIZE;
+                let port_offset = offset % PORT_CONTROL_REGS_SIZE;
+                let ports_implemented = unsafe {
+                    ((self.abar.start.raw() + PORTS_IMPLEMENTED) as *const u32).read_volatile()
+                };
+                (ports_implemented >> port_num) & 1 == 1
+                    && port_offset <= PORT_REGS_END
+                    && port_offset != PORT_RESERVED
+            }
+            // reserved, vendor specific registers and out of HBA registers.
+            _ => false,
+        }
+    }
+
+    /// Validate registers accessed with `width`.
+    fn validate_access(&self, offset: usize, width: AccessWidth) -> Result<(), DeviceEmulateError> {
+        validate_register(self.is_implemented_register(offset))?;
+        if width == AccessWidth::DoubleWord {
+            validate_register(self.is_implemented_register(offset + 4))?;
+        }
+        Ok(())
+    }
+
     /// Pass through loading memory
     fn pass_through_loading(dst_addr: HostPhysicalAddress) -> u32 {
         let dst_ptr = dst_addr.raw() as *const u32;
@@ -324,25 +575,25 @@ impl Sata {
     }
 
     /// Emulate loading HBA Memory Registers.
+    ///
+    /// 64 bit loads are accepted since some drivers read `PxCLB`/`PxFB` at once.
     pub fn emulate_loading(
         &self,
         dst_addr: HostPhysicalAddress,
-    ) -> Result<u32, DeviceEmulateError> {
-        if !self.abar.contains(&dst_addr) {
+        width: AccessWidth,
+    ) -> Result<u64, DeviceEmulateError> {
+        if !self.contains(dst_addr) {
             return Err(DeviceEmulateError::InvalidAddress);
         }
 
         let base_addr = self.abar.start;
         let offset = dst_addr.raw() - base_addr.raw();
+        self.validate_access(offset, width)?;
 
         #[allow(clippy::match_same_arms)]
-        match offset {
-            // 0x00 - 0x2b: Generic Host Control
-            // 0x2c - 0x9f: Reserved
-            // 0xa0 - 0xff: Vendor specific registers
-            0x0..=0xff => Ok(Self::pass_through_loading(dst_addr)),
+        match (offset, width) {
             // Port control registers
-            0x100..=0x10ff => {
+            (0x100..=0x10ff, AccessWidth::Word) => {
                 let port_num = (offset - PORT_CONTROL_REGS_OFFSET) / PORT_CONTROL_REGS_SIZE;
                 let loaded_data = self.ports[port_num].emulate_loading(base_addr, dst_addr);
                 crate::debugln!(
@@ -351,10 +602,29 @@ impl Sata {
                     offset % PORT_CONTROL_REGS_SIZE,
                     loaded_data
                 );
+                Ok(u64::from(loaded_data))
+            }
+            (0x100..=0x10ff, AccessWidth::DoubleWord) => {
+                let port_num = (offset - PORT_CONTROL_REGS_OFFSET) / PORT_CONTROL_REGS_SIZE;
+                let loaded_data = self.ports[port_num].emulate_loading_u64(base_addr, dst_addr);
+                crate::debugln!(
+                    "[port{}  read] {:#x} -> {:#x}",
+                    port_num,
+                    offset % PORT_CONTROL_REGS_SIZE,
+                    loaded_data
+                );
                 Ok(loaded_data)
             }
-            // out of range but it may be used by others.
-            _ => Ok(Self::pass_through_loading(dst_addr)),
+            // 0x00 - 0x2b: Generic Host Control
+            // 0x2c - 0x9f: Reserved
+            // 0xa0 - 0xff: Vendor specific registers
+            // and out of range but it may be used by others.
+            (_, AccessWidth::Word) => Ok(u64::from(Self::pass_through_loading(dst_addr))),
+            (_, AccessWidth::DoubleWord) => {
+                let lower = Self::pass_through_loading(dst_addr);
+                let upper = Self::pass_through_loading(dst_addr + 4);
+                Ok((u64::from(upper) << 32) | u64::from(lower))
+            }
         }
     }
 
@@ -368,31 +638,43 @@ impl Sata {
     }
 
     /// Emulate storing HBA Memory Registers.
+    ///
+    /// 64 bit stores are accepted since some drivers write `PxCLB`/`PxFB` at once (e.g. `writeq`).
+    #[allow(clippy::cast_possible_truncation)]
     pub fn emulate_storing(
         &mut self,
         dst_addr: HostPhysicalAddress,
-        value: u32,
+        value: u64,
+        width: AccessWidth,
     ) -> Result<(), DeviceEmulateError> {
-        if !self.abar.contains(&dst_addr) {
+        if !self.contains(dst_addr) {
             return Err(DeviceEmulateError::InvalidAddress);
         }
 
         let base_addr = self.abar.start;
         let offset = dst_addr.raw() - base_addr.raw();
+        self.validate_access(offset, width)?;
 
         #[allow(clippy::match_same_arms)]
-        match offset {
+        match (offset, width) {
+            // Port control registers
+            (0x100..=0x10ff, AccessWidth::Word) => {
+                let port_num = (offset - PORT_CONTROL_REGS_OFFSET) / PORT_CONTROL_REGS_SIZE;
+                self.ports[port_num].emulate_storing(base_addr, dst_addr, value as u32);
+            }
+            (0x100..=0x10ff, AccessWidth::DoubleWord) => {
+                let port_num = (offset - PORT_CONTROL_REGS_OFFSET) / PORT_CONTROL_REGS_SIZE;
+                self.ports[port_num].emulate_storing_u64(base_addr, dst_addr, value);
+            }
             // 0x00 - 0x2b: Generic Host Control
             // 0x2c - 0x9f: Reserved
             // 0xa0 - 0xff: Vendor specific registers
-            0x0..=0xff => Self::pass_through_storing(dst_addr, value),
-            // Port control registers
-            0x100..=0x10ff => {
-                let port_num = (offset - PORT_CONTROL_REGS_OFFSET) / PORT_CONTROL_REGS_SIZE;
-                self.ports[port_num].emulate_storing(base_addr, dst_addr, value);
+            // and out of range but it may be used by others.
+            (_, AccessWidth::Word) => Self::pass_through_storing(dst_addr, value as u32),
+            (_, AccessWidth::DoubleWord) => {
+                Self::pass_through_storing(dst_addr, value as u32);
+                Self::pass_through_storing(dst_addr + 4, (value >> 32) as u32);
             }
-            // out of range but it may be used by others.
-            _ => Self::pass_through_storing(dst_addr, value),
         }
 
         Ok(())
@@ -407,38 +689,59 @@ impl PciDevice for Sata {
         pci_config_space_base_addr: HostPhysicalAddress,
         pci_addr_space: &PciAddressSpace,
         _memory_maps: &mut Vec<MemoryMap>,
-    ) -> Self {
+    ) -> Option<Self> {
         let config_space_header_addr =
             pci_config_space_base_addr.0 | bdf.calc_config_space_header_offset();
 
-        let bar_value = read_config_register(
-            config_space_header_addr,
-            ConfigSpaceHeaderField::BaseAddressRegister5,
-        );
+        // ABAR: AHCI base address (BAR5)
+        let bar = parse_bar(config_space_header_addr, 5);
+        let (start_address, size) = match bar {
+            Bar::Memory32 { addr, size, .. } if size != 0 => (addr, size),
+            // the guest only sees 32-bit memory space.
+            Bar::Memory64 { addr, size, .. } if size != 0 && addr.raw() + size <= 0x1_0000_0000 => {
+                (addr, size)
+            }
+            bar => {
+                crate::println!("[sata] unsupported ABAR: {:x?}", bar);
+                return None;
+            }
+        };
 
         // memory map
-        assert_eq!(bar_value & 0x1, 0x0);
-        let start_address = if bar_value == 0 {
-            pci_addr_space.base_addr_32bit_memory_space()
+        let start_address = if start_address.raw() == 0 {
+            // not assigned yet: place it at the 32-bit window since the guest only sees 32-bit memory space.
+            let bar_32bit_view = match bar {
+                Bar::Memory64 {
+                    addr,
+                    size,
+                    prefetchable,
+                } => Bar::Memory32 {
+                    addr,
+                    size,
+                    prefetchable,
+                },
+                bar => bar,
+            };
+            let Some(window) = pci_addr_space.window_for(&bar_32bit_view) else {
+                crate::println!("[sata] no PCI memory window for ABAR: {:x?}", bar);
+                return None;
+            };
+            window.start
         } else {
-            HostPhysicalAddress((bar_value & 0xffff_fff0) as usize)
+            start_address
         };
-        let size = get_bar_size(
-            config_space_header_addr,
-            ConfigSpaceHeaderField::BaseAddressRegister5,
-        );
         let abar = Range {
             start: start_address,
-            end: start_address + size as usize,
+            end: start_address + size,
         };
 
-        Sata {
+        Some(Sata {
             _ident: bdf,
             abar,
             ports: vec![HbaPort::new(); SATA_PORT_NUM].into_boxed_slice(),
             _vender_id: vender_id,
             _device_id: device_id,
-        }
+        })
     }
 
     fn init(&self, _: HostPhysicalAddress) {
@@ -1,7 +1,7 @@
 //! Utility for HBA (= ATA) command.
 
 use crate::device::DmaHostBuffer;
-use crate::memmap::page_table::{constants::PAGE_SIZE, g_stage_trans_addr};
+use crate::memmap::page_table::{constants::PAGE_SIZE, g_stage_trans_addr, TransAddrError};
 use crate::memmap::GuestPhysicalAddress;
 
 use alloc::vec::Vec;
@@ -102,12 +102,13 @@ impl PhysicalRegionDescriptor {
         &mut self,
         ctba_list: &mut Vec<CommandTableAddressData>,
         dir: &TransferDirection,
-    ) {
+    ) -> Result<(), (TransAddrError, &'static str)> {
         let db_gpa = GuestPhysicalAddress(((self.dbau as usize) << 32) | self.dba as usize);
 
         let data_base_size = self.dbc as usize + 1;
-        if data_base_size <= PAGE_SIZE {
-            let db_hpa = g_stage_trans_addr(db_gpa).expect("data base address translation failed");
+        // the buffer is contiguous in host memory only if it is in a page.
+        if db_gpa % PAGE_SIZE + data_base_size <= PAGE_SIZE {
+            let db_hpa = g_stage_trans_addr(db_gpa)?;
             ctba_list.push(CommandTableAddressData::TranslatedAddress(db_gpa));
             self.dbau = ((db_hpa.raw() >> 32) & 0xffff_ffff) as u32;
             self.dba = (db_hpa.raw() & 0xffff_ffff) as u32;
@@ -120,10 +121,17 @@ impl PhysicalRegionDescriptor {
 
             // write data to allocated memory if command is `write`
             if *dir == TransferDirection::HostToDevice {
-                host_buf.guest_to_host(db_gpa);
+                if let Err(err) = host_buf.guest_to_host(db_gpa) {
+                    // restore address since it is not pushed to `ctba_list`.
+                    self.dbau = ((db_gpa.raw() >> 32) & 0xffff_ffff) as u32;
+                    self.dba = (db_gpa.raw() & 0xffff_ffff) as u32;
+                    return Err(err);
+                }
             }
             ctba_list.push(CommandTableAddressData::AllocatedAddress(db_gpa, host_buf));
         }
+
+        Ok(())
     }
 
     /// Restore all dba to host physical address.
@@ -138,13 +146,16 @@ impl PhysicalRegionDescriptor {
                 self.dbau = ((db_gpa.raw() >> 32) & 0xffff_ffff) as u32;
                 self.dba = (db_gpa.raw() & 0xffff_ffff) as u32;
             }
+            #[cfg_attr(not(feature = "debug_log"), allow(unused_variables))]
             CommandTableAddressData::AllocatedAddress(db_gpa, host_buf) => {
                 self.dbau = ((db_gpa.raw() >> 32) & 0xffff_ffff) as u32;
                 self.dba = (db_gpa.raw() & 0xffff_ffff) as u32;
 
                 // write back data to guest memory if command is `read`
                 if *dir == TransferDirection::DeviceToHost {
-                    host_buf.host_to_guest(*db_gpa);
+                    if let Err((err, msg)) = host_buf.host_to_guest(*db_gpa) {
+                        crate::debugln!("[dba] failed to write back read data: {}: {}", msg, err);
+                    }
                 }
             }
         }
@@ -170,19 +181,24 @@ pub struct CommandTable {
 
 impl CommandTable {
     /// Translate all dba to host physical address.
+    ///
+    /// It stops at the first dba that fails to translate.
+    /// Already translated dba are recorded in `ctba_list` to be restored.
     pub fn translate_all_data_base_addresses(
         &mut self,
         prdtl: u32,
         ctba_list: &mut Vec<CommandTableAddressData>,
         dir: &TransferDirection,
-    ) {
+    ) -> Result<(), (TransAddrError, &'static str)> {
         let prd_base_ptr = self.prdt.as_mut_ptr().cast::<PhysicalRegionDescriptor>();
         for index in 0..prdtl {
             unsafe {
                 let prd_ptr = prd_base_ptr.add(index as usize);
-                (*prd_ptr).translate_data_base_address(ctba_list, dir);
+                (*prd_ptr).translate_data_base_address(ctba_list, dir)?;
             }
         }
+
+        Ok(())
     }
 
     /// Restore all dba
@@ -0,0 +1,102 @@
+//! Boot-time self test of SATA command tracking.
+//!
+//! Two commands are issued at once on port 0 of a fake HBA placed in host memory.
+//! Both must be translated on the `PxCI` write and restored on the `PxIS` write after they complete.
+//! Command tables are placed in the memory of the running guest and translated by current hgatp.
+//! (PRDT is empty, so guest memory is not written)
+
+use super::command::CommandHeader;
+use super::{HbaPort, PORT_CONTROL_REGS_OFFSET};
+use crate::hart_local;
+use crate::memmap::page_allocator::PAGE_ALLOCATOR;
+use crate::memmap::page_table::{constants::PAGE_SIZE, g_stage_trans_addr};
+use crate::memmap::GuestPhysicalAddress;
+
+/// Offset of `Port x Interrupt Status` in port control registers.
+const PXIS: usize = 0x10;
+/// Offset of `Port x Command Issue` in port control registers.
+const PXCI: usize = 0x38;
+/// Command slots issued at once.
+const ISSUED_SLOTS: [usize; 2] = [0, 2];
+
+/// Return command table base address in the command header.
+fn ctba(header: *const CommandHeader) -> usize {
+    unsafe { ((*header).ctba_u as usize) << 32 | (*header).ctba as usize }
+}
+
+/// Issue two commands, complete them at once and check that both addresses are restored.
+///
+/// # Panics
+/// It panics with the offending slot if the command table address is wrong.
+#[allow(clippy::cast_possible_truncation)]
+pub fn two_outstanding_commands() {
+    let abar = PAGE_ALLOCATOR.lock().alloc_zeroed();
+    let cmd_list = PAGE_ALLOCATOR.lock().alloc_zeroed();
+    let port_regs = abar + PORT_CONTROL_REGS_OFFSET;
+    let pxci_addr = port_regs + PXCI;
+
+    // `PxCLB` holds the command list address that the device sees. (already translated)
+    unsafe {
+        (port_regs.raw() as *mut u32).write_volatile(cmd_list.raw() as u32);
+    }
+
+    let guest_memory_start = hart_local()
+        .lock()
+        .get()
+        .unwrap()
+        .guest()
+        .memory_region()
+        .start;
+    let headers = cmd_list.raw() as *mut CommandHeader;
+    let table_gpa = |slot: usize| guest_memory_start + slot * PAGE_SIZE;
+    let mut issued = 0;
+    for slot in ISSUED_SLOTS {
+        unsafe {
+            (*headers.add(slot)).ctba = table_gpa(slot).raw() as u32;
+            (*headers.add(slot)).ctba_u = (table_gpa(slot).raw() >> 32) as u32;
+        }
+        issued |= 1 << slot;
+    }
+
+    let mut port = HbaPort::new();
+    port.emulate_storing(abar, pxci_addr, issued);
+    for slot in ISSUED_SLOTS {
+        let expected = g_stage_trans_addr(table_gpa(slot));
+        let translated = ctba(unsafe { headers.add(slot) });
+        assert!(
+            expected.is_ok_and(|hpa| hpa.raw() == translated),
+            "[selftest] SATA slot {slot} is not translated: {translated:#x}"
+        );
+    }
+    assert_eq!(
+        port.commands_status, issued,
+        "[selftest] SATA issued commands are not tracked"
+    );
+
+    // both commands complete before the guest clears the interrupt status.
+    unsafe {
+        (pxci_addr.raw() as *mut u32).write_volatile(0);
+    }
+    port.emulate_storing(abar, port_regs + PXIS, u32::MAX);
+    for slot in ISSUED_SLOTS {
+        let restored = ctba(unsafe { headers.add(slot) });
+        assert!(
+            restored == table_gpa(slot).raw(),
+            "[selftest] SATA slot {slot} is not restored: {restored:#x}"
+        );
+        assert!(
+            port.cmd_table_gpa_storage[slot].cmd_table_gpa == GuestPhysicalAddress(0),
+            "[selftest] SATA slot {slot} is left in storage"
+        );
+    }
+    assert_eq!(
+        port.commands_status, 0,
+        "[selftest] SATA completed commands are left"
+    );
+
+    unsafe {
+        let mut allocator = PAGE_ALLOCATOR.lock();
+        allocator.free(cmd_list);
+        allocator.free(abar);
+    }
+}
@@ -0,0 +1,131 @@
+//! PCI devices that hypervisor does not know.
+//!
+//! Memory BARs of them are passed through to the guest without address translation.
+
+use super::config_register::{parse_bar, Bar};
+use super::{Bdf, PciAddressSpace};
+use crate::device::{device_memmap, AccessWidth, DeviceEmulateError};
+use crate::memmap::{HostPhysicalAddress, MemoryMap};
+
+use alloc::vec::Vec;
+
+/// Memory BAR of a PCI device that is not emulated.
+#[derive(Debug)]
+pub struct UnknownPciDevice {
+    /// Bus - device - function
+    _bdf: Bdf,
+    /// Base address of the BAR.
+    base_addr: HostPhysicalAddress,
+    /// Size of the BAR.
+    size: usize,
+}
+
+impl UnknownPciDevice {
+    /// Create instances for each assigned memory BAR of the device.
+    ///
+    /// BARs out of PCI memory spaces are added to `memory_maps` since the spaces are already mapped.
+    /// (type 0 header has 6 BARs and type 1 header (PCI-to-PCI bridge) has 2 BARs)
+    pub fn new_for_each_bar(
+        bdf: Bdf,
+        pci_config_space_base_addr: HostPhysicalAddress,
+        header_type: u8,
+        pci_addr_space: &PciAddressSpace,
+        memory_maps: &mut Vec<MemoryMap>,
+    ) -> Vec<Self> {
+        let config_space_header_addr =
+            pci_config_space_base_addr.0 | bdf.calc_config_space_header_offset();
+        let bar_num = match header_type & 0x7f {
+            0 => 6,
+            1 => 2,
+            _ => 0,
+        };
+
+        let mut devices = Vec::new();
+        let mut index = 0;
+        while index < bar_num {
+            let bar = parse_bar(config_space_header_addr, index);
+            index += 1;
+            let (base_addr, size) = match bar {
+                Bar::Memory32 { addr, size, .. } => (addr, size),
+                Bar::Memory64 { addr, size, .. } => {
+                    // skip upper half
+                    index += 1;
+                    (addr, size)
+                }
+                Bar::Io { .. } => continue,
+            };
+            // not implemented or not assigned yet.
+            if size == 0 || base_addr.raw() == 0 {
+                continue;
+            }
+
+            let bar_range = base_addr..base_addr + size;
+            if !pci_addr_space.contains(&bar_range) {
+                match device_memmap(base_addr, size) {
+                    Ok(memmap) => memory_maps.push(memmap),
+                    Err(err) => crate::println!(
+                        "[warning] BAR {:#x?} of {:?} is not mapped: {:?}",
+                        bar_range,
+                        bdf,
+                        err
+                    ),
+                }
+            }
+            devices.push(UnknownPciDevice {
+                _bdf: bdf,
+                base_addr,
+                size,
+            });
+        }
+
+        devices
+    }
+
+    /// Is the address in the BAR?
+    pub fn contains(&self, addr: HostPhysicalAddress) -> bool {
+        (self.base_addr..self.base_addr + self.size).contains(&addr)
+    }
+
+    /// Pass through loading a register with the access width of the guest.
+    pub fn emulate_loading(
+        &self,
+        dst_addr: HostPhysicalAddress,
+        width: AccessWidth,
+    ) -> Result<u64, DeviceEmulateError> {
+        if !self.contains(dst_addr) {
+            return Err(DeviceEmulateError::InvalidAddress);
+        }
+
+        match width {
+            AccessWidth::Word => Ok(u64::from(unsafe {
+                (dst_addr.raw() as *const u32).read_volatile()
+            })),
+            AccessWidth::DoubleWord => {
+                Ok(unsafe { (dst_addr.raw() as *const u64).read_volatile() })
+            }
+        }
+    }
+
+    /// Pass through storing a register with the access width of the guest.
+    #[allow(clippy::cast_possible_truncation)]
+    pub fn emulate_storing(
+        &self,
+        dst_addr: HostPhysicalAddress,
+        value: u64,
+        width: AccessWidth,
+    ) -> Result<(), DeviceEmulateError> {
+        if !self.contains(dst_addr) {
+            return Err(DeviceEmulateError::InvalidAddress);
+        }
+
+        match width {
+            AccessWidth::Word => unsafe {
+                (dst_addr.raw() as *mut u32).write_volatile(value as u32);
+            },
+            AccessWidth::DoubleWord => unsafe {
+                (dst_addr.raw() as *mut u64).write_volatile(value);
+            },
+        }
+        Ok(())
+    }
+}
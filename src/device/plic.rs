@@ -1,25 +1,66 @@
 //! PLIC: Platform-Level Interrupt Controller  
 //! ref: [https://github.com/riscv/riscv-plic-spec/releases/download/1.0.0/riscv-plic-1.0.0.pdf](https://github.com/riscv/riscv-plic-spec/releases/download/1.0.0/riscv-plic-1.0.0.pdf)
 
-use super::{DeviceEmulateError, MmioDevice, PTE_FLAGS_FOR_DEVICE};
+#[cfg(feature = "boot_selftest")]
+pub mod selftest;
+
+use super::{device_memmap, DeviceEmulateError, MmioDevice};
+use crate::current_hart_id;
 use crate::h_extension::csrs::{hvip, VsInterruptKind};
 use crate::memmap::constant::MAX_HART_NUM;
-use crate::memmap::{GuestPhysicalAddress, HostPhysicalAddress, MemoryMap};
+use crate::memmap::{HostPhysicalAddress, MemoryMap, MemoryMapError};
 
-use fdt::Fdt;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
+use fdt::{node::FdtNode, Fdt};
 use riscv::register::sie;
 
 /// Max number of PLIC context.
 pub const MAX_CONTEXT_NUM: usize = MAX_HART_NUM * 2;
 
+/// Max number of interrupt sources.
+const MAX_IRQ_NUM: usize = 1024;
+/// Base offset of interrupt priorities.
+const PRIORITY_BASE: usize = 0x0;
+/// Base offset of pending bits.
+const PENDING_BASE: usize = 0x1000;
+/// End of pending bits.
+const PENDING_END: usize = PENDING_BASE + MAX_IRQ_NUM / 8;
+/// Base offset of enable bits.
+const ENABLE_BASE: usize = 0x2000;
+/// Enable bits region size per context.
+const ENABLE_PER_CONTEXT: usize = 0x80;
 /// Base offset of context.
 const CONTEXT_BASE: usize = 0x20_0000;
 /// Context registers region size.
 const CONTEXT_REGS_SIZE: usize = 0x1000;
-/// Claim/complete register offset from `CONTEXT_BASE` + `CONTEXT_REGS_SIZE` * `CONTEXT_REGS_SIZE`.
+/// Threshold register offset from `CONTEXT_BASE` + `CONTEXT_REGS_SIZE` * context id.
+const CONTEXT_THRESHOLD: usize = 0x0;
+/// Claim/complete register offset from `CONTEXT_BASE` + `CONTEXT_REGS_SIZE` * context id.
 const CONTEXT_CLAIM: usize = 0x4;
-/// End of context registers region.
-const CONTEXT_END: usize = CONTEXT_BASE * CONTEXT_REGS_SIZE * MAX_CONTEXT_NUM;
+/// Interrupt id of supervisor external interrupt in `interrupts-extended`.
+const SUPERVISOR_EXTERNAL_IRQ: u32 = 9;
+/// Interrupt id of machine external interrupt in `interrupts-extended`.
+const MACHINE_EXTERNAL_IRQ: u32 = 11;
+/// End of context registers region. (exclusive)
+const CONTEXT_END: usize = CONTEXT_BASE + CONTEXT_REGS_SIZE * MAX_CONTEXT_NUM;
+/// Number of interrupts that can be injected to a context at once.
+pub const IRQ_QUEUE_LEN: usize = 4;
+
+/// Is VS-level external interrupt injected to each HART by another HART?
+///
+/// hvip of other HARTs cannot be written, so it is asserted by `assert_deferred_irq`.
+static DEFERRED_EXTERNAL_IRQ: [AtomicBool; MAX_HART_NUM] =
+    [const { AtomicBool::new(false) }; MAX_HART_NUM];
+
+/// Raise VS-level external interrupt injected to current HART by another HART.
+///
+/// It is called on every trap exit, so the interrupt waits for the next trap of current HART.
+pub fn assert_deferred_irq() {
+    if DEFERRED_EXTERNAL_IRQ[current_hart_id()].swap(false, Ordering::Relaxed) {
+        hvip::set(VsInterruptKind::External);
+    }
+}
 
 /// PLIC context ID.
 pub struct ContextId(usize);
@@ -36,6 +77,198 @@ impl ContextId {
     pub fn raw(&self) -> usize {
         self.0
     }
+
+    /// Return hart id of the context.
+    pub fn hart_id(&self) -> usize {
+        self.0 / 2
+    }
+}
+
+/// Interrupt injected to a guest context.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InjectedIrq {
+    /// Interrupt id.
+    irq: u32,
+    /// Is it claimed in physical PLIC? (completion is forwarded)
+    claimed_in_hardware: bool,
+    /// Is it claimed by the guest? (waiting for completion)
+    claimed_by_guest: bool,
+}
+
+/// Queue of interrupts injected to a guest context and not completed yet.
+///
+/// The guest claims them in injected order, and they are removed on completion.
+#[derive(Debug, Clone, Copy)]
+pub struct IrqQueue<const N: usize> {
+    /// Injected interrupts. (`irqs[..len]` are valid)
+    irqs: [Option<InjectedIrq>; N],
+    /// Number of injected interrupts.
+    len: usize,
+}
+
+impl<const N: usize> IrqQueue<N> {
+    /// Bit width of each interrupt in `to_bits`. (10 bits of irq and flags)
+    const ENTRY_BITS: usize = 16;
+    /// Flag of `claimed_in_hardware` in `to_bits`.
+    const HARDWARE_FLAG: u64 = 1 << 15;
+    /// Flag of `claimed_by_guest` in `to_bits`.
+    const GUEST_FLAG: u64 = 1 << 14;
+
+    /// Create empty queue.
+    pub const fn new() -> Self {
+        IrqQueue {
+            irqs: [None; N],
+            len: 0,
+        }
+    }
+
+    /// Is there an interrupt that the guest has not claimed yet?
+    fn has_unclaimed(&self) -> bool {
+        self.irqs[..self.len]
+            .iter()
+            .flatten()
+            .any(|injected| !injected.claimed_by_guest)
+    }
+
+    /// Mark the first interrupt that the guest has not claimed yet as claimed and return it.
+    fn claim(&mut self) -> Option<InjectedIrq> {
+        let injected = self.irqs[..self.len]
+            .iter_mut()
+            .flatten()
+            .find(|injected| !injected.claimed_by_guest)?;
+        injected.claimed_by_guest = true;
+        Some(*injected)
+    }
+
+    /// Append an interrupt.
+    ///
+    /// The interrupt that is already in the queue is not appended twice.
+    fn push(&mut self, injected: InjectedIrq) -> Result<(), DeviceEmulateError> {
+        if self.irqs[..self.len]
+            .iter()
+            .flatten()
+            .any(|queued| queued.irq == injected.irq)
+        {
+            return Ok(());
+        }
+        if self.len == N {
+            return Err(DeviceEmulateError::InterruptQueueFull);
+        }
+
+        self.irqs[self.len] = Some(injected);
+        self.len += 1;
+        Ok(())
+    }
+
+    /// Remove the interrupt `irq` claimed by the guest and return it.
+    fn remove(&mut self, irq: u32) -> Option<InjectedIrq> {
+        let index = self.irqs[..self.len].iter().position(|injected| {
+            injected.is_some_and(|injected| injected.irq == irq && injected.claimed_by_guest)
+        })?;
+        let removed = self.irqs[index];
+        self.irqs.copy_within(index + 1..self.len, index);
+        self.len -= 1;
+        self.irqs[self.len] = None;
+        removed
+    }
+
+    /// Return bitmap of queued interrupts in pending bits word `word`.
+    fn pending_bits(&self, word: usize) -> u32 {
+        self.irqs[..self.len]
+            .iter()
+            .flatten()
+            .filter(|injected| injected.irq as usize / 32 == word)
+            .fold(0, |bits, injected| bits | 1 << (injected.irq % 32))
+    }
+
+    /// Pack into `u64`. (for snapshot)
+    pub fn to_bits(self) -> u64 {
+        const { assert!(N * Self::ENTRY_BITS <= 64) };
+        self.irqs[..self.len]
+            .iter()
+            .flatten()
+            .enumerate()
+            .fold(0, |bits, (index, injected)| {
+                let hardware_flag = if injected.claimed_in_hardware {
+                    Self::HARDWARE_FLAG
+                } else {
+                    0
+                };
+                let guest_flag = if injected.claimed_by_guest {
+                    Self::GUEST_FLAG
+                } else {
+                    0
+                };
+                bits | (u64::from(injected.irq) | hardware_flag | guest_flag)
+                    << (index * Self::ENTRY_BITS)
+            })
+    }
+
+    /// Unpack from `u64` packed by `to_bits`.
+    #[allow(clippy::cast_possible_truncation)]
+    pub fn from_bits(bits: u64) -> Self {
+        let mut queue = Self::new();
+        for index in 0..N {
+            let entry = (bits >> (index * Self::ENTRY_BITS)) & 0xffff;
+            if entry == 0 {
+                break;
+            }
+            queue.irqs[index] = Some(InjectedIrq {
+                irq: (entry & !(Self::HARDWARE_FLAG | Self::GUEST_FLAG)) as u32,
+                claimed_in_hardware: entry & Self::HARDWARE_FLAG != 0,
+                claimed_by_guest: entry & Self::GUEST_FLAG != 0,
+            });
+            queue.len += 1;
+        }
+        queue
+    }
+}
+
+/// PLIC register decoded from offset.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PlicRegister {
+    /// Priority of interrupt source. (irq)
+    Priority(usize),
+    /// Pending bits. (word index)
+    Pending(usize),
+    /// Enable bits. (context id, word index)
+    Enable(usize, usize),
+    /// Priority threshold. (context id)
+    Threshold(usize),
+    /// Claim/complete. (context id)
+    ClaimComplete(usize),
+}
+
+impl TryFrom<usize> for PlicRegister {
+    type Error = DeviceEmulateError;
+    fn try_from(offset: usize) -> Result<Self, Self::Error> {
+        match offset {
+            // interrupt source 0 does not exist.
+            PRIORITY_BASE..0x4 => Err(DeviceEmulateError::ReservedRegister),
+            0x4..PENDING_BASE => Ok(PlicRegister::Priority((offset - PRIORITY_BASE) / 4)),
+            PENDING_BASE..PENDING_END => Ok(PlicRegister::Pending((offset - PENDING_BASE) / 4)),
+            PENDING_END..ENABLE_BASE => Err(DeviceEmulateError::ReservedRegister),
+            ENABLE_BASE..CONTEXT_BASE => {
+                let context_id = (offset - ENABLE_BASE) / ENABLE_PER_CONTEXT;
+                if context_id >= MAX_CONTEXT_NUM {
+                    return Err(DeviceEmulateError::InvalidContextId);
+                }
+                Ok(PlicRegister::Enable(
+                    context_id,
+                    (offset % ENABLE_PER_CONTEXT) / 4,
+                ))
+            }
+            CONTEXT_BASE..CONTEXT_END => {
+                let context_id = (offset - CONTEXT_BASE) / CONTEXT_REGS_SIZE;
+                match offset % CONTEXT_REGS_SIZE {
+                    CONTEXT_THRESHOLD => Ok(PlicRegister::Threshold(context_id)),
+                    CONTEXT_CLAIM => Ok(PlicRegister::ClaimComplete(context_id)),
+                    _ => Err(DeviceEmulateError::ReservedRegister),
+                }
+            }
+            _ => Err(DeviceEmulateError::InvalidContextId),
+        }
+    }
 }
 
 /// PLIC: Platform-Level Interrupt Controller  
@@ -46,127 +279,317 @@ pub struct Plic {
     base_addr: HostPhysicalAddress,
     /// Memory map size.
     size: usize,
-    /// Claim complete flags for external interrupts emulation.
+    /// Interrupts injected to each guest context.
     ///
-    /// Each bit indicates whether interrupts are claimed in context.
-    claim_complete: [u32; MAX_CONTEXT_NUM],
+    /// The guest reads the front of the queue from claim/complete register.
+    pending_irqs: [IrqQueue<IRQ_QUEUE_LEN>; MAX_CONTEXT_NUM],
+    /// Physical context id corresponding to each guest context id.
+    context_map: [Option<usize>; MAX_CONTEXT_NUM],
+    /// Shadow of threshold register written by guest for each guest context.
+    threshold: [u32; MAX_CONTEXT_NUM],
+    /// Interrupt sources that guest cannot enable. (each bit corresponds to an interrupt source)
+    masked_irqs: [u32; MAX_IRQ_NUM / 32],
 }
 
 impl Plic {
-    /// Read plic claim/update register and reflect to `claim_complete`.
-    pub fn update_claim_complete(&mut self, context_id: &ContextId) {
-        let claim_complete_addr =
-            self.base_addr + CONTEXT_BASE + CONTEXT_REGS_SIZE * context_id.raw() + CONTEXT_CLAIM;
-        let irq = unsafe { core::ptr::read_volatile(claim_complete_addr.raw() as *const u32) };
-        self.claim_complete[context_id.raw()] = irq;
-    }
-
-    /// Emulate reading plic context register
-    fn context_load(&self, offset: usize) -> Result<u32, DeviceEmulateError> {
-        let context_id = (offset - CONTEXT_BASE) / CONTEXT_REGS_SIZE;
-        let offset_per_context = offset % CONTEXT_REGS_SIZE;
-        match offset_per_context {
-            // threshold
-            0 => unreachable!("[may be unreachable] plic threshold read"),
-            // claim/complete
-            4 => {
-                if context_id > MAX_CONTEXT_NUM {
-                    Err(DeviceEmulateError::InvalidContextId)
-                } else {
-                    Ok(self.claim_complete[context_id])
-                }
-            }
-            _ => Err(DeviceEmulateError::InvalidAddress),
+    /// Translate guest context id to physical context id.
+    fn physical_context(&self, context_id: usize) -> Result<usize, DeviceEmulateError> {
+        self.context_map
+            .get(context_id)
+            .copied()
+            .flatten()
+            .ok_or(DeviceEmulateError::InvalidContextId)
+    }
+
+    /// Return address of register in physical context.
+    fn physical_context_reg(
+        &self,
+        context_id: usize,
+        offset_per_context: usize,
+    ) -> Result<HostPhysicalAddress, DeviceEmulateError> {
+        let phys_context_id = self.physical_context(context_id)?;
+        Ok(
+            self.base_addr
+                + CONTEXT_BASE
+                + CONTEXT_REGS_SIZE * phys_context_id
+                + offset_per_context,
+        )
+    }
+
+    /// Prevent guest from enabling interrupt source `irq`.
+    pub fn mask_irq(&mut self, irq: u32) {
+        let irq = irq as usize;
+        self.masked_irqs[irq / 32] |= 1 << (irq % 32);
+    }
+
+    /// Return address of enable bits word in physical context.
+    fn physical_enable_reg(
+        &self,
+        context_id: usize,
+        word: usize,
+    ) -> Result<HostPhysicalAddress, DeviceEmulateError> {
+        let phys_context_id = self.physical_context(context_id)?;
+        Ok(self.base_addr + ENABLE_BASE + ENABLE_PER_CONTEXT * phys_context_id + word * 4)
+    }
+
+    /// Read plic claim/update register and return claimed interrupt id.
+    pub fn claim(&self, context_id: &ContextId) -> u32 {
+        let Ok(claim_complete_addr) = self.physical_context_reg(context_id.raw(), CONTEXT_CLAIM)
+        else {
+            panic!("context {} is not found in plic", context_id.raw());
+        };
+        unsafe { core::ptr::read_volatile(claim_complete_addr.raw() as *const u32) }
+    }
+
+    /// Return interrupts injected to the guest context.
+    pub fn pending_irqs(&self, context_id: &ContextId) -> IrqQueue<IRQ_QUEUE_LEN> {
+        self.pending_irqs[context_id.raw()]
+    }
+
+    /// Replace interrupts injected to the guest context. (e.g. on guest switch)
+    pub fn set_pending_irqs(&mut self, context_id: &ContextId, queue: IrqQueue<IRQ_QUEUE_LEN>) {
+        self.pending_irqs[context_id.raw()] = queue;
+    }
+
+    /// Append an interrupt to the queue of the guest context and raise VS-level external interrupt.
+    ///
+    /// The interrupt to a context of another HART is deferred to the next trap of the HART.
+    fn push_irq(
+        &mut self,
+        context_id: &ContextId,
+        injected: InjectedIrq,
+    ) -> Result<(), DeviceEmulateError> {
+        self.pending_irqs
+            .get_mut(context_id.raw())
+            .ok_or(DeviceEmulateError::InvalidContextId)?
+            .push(injected)?;
+        if context_id.hart_id() == current_hart_id() {
+            hvip::set(VsInterruptKind::External);
+        } else {
+            DEFERRED_EXTERNAL_IRQ[context_id.hart_id()].store(true, Ordering::Relaxed);
         }
+        Ok(())
     }
 
-    /// Emulate reading plic register.
-    pub fn emulate_loading(
+    /// Inject interrupt `irq` of an emulated device to the guest context.
+    ///
+    /// Completion of it is not forwarded to physical PLIC.
+    pub fn inject(&mut self, context_id: &ContextId, irq: u32) -> Result<(), DeviceEmulateError> {
+        self.push_irq(
+            context_id,
+            InjectedIrq {
+                irq,
+                claimed_in_hardware: false,
+                claimed_by_guest: false,
+            },
+        )
+    }
+
+    /// Inject interrupt `irq` claimed in physical PLIC to the guest context.
+    ///
+    /// Completion of it is forwarded to physical PLIC.
+    pub fn inject_claimed(
+        &mut self,
+        context_id: &ContextId,
+        irq: u32,
+    ) -> Result<(), DeviceEmulateError> {
+        self.push_irq(
+            context_id,
+            InjectedIrq {
+                irq,
+                claimed_in_hardware: true,
+                claimed_by_guest: false,
+            },
+        )
+    }
+
+    /// Read plic claim/update register and inject the claimed interrupt to the guest context.
+    ///
+    /// Nothing is injected if no interrupt is pending in physical PLIC.
+    pub fn claim_and_inject(&mut self, context_id: &ContextId) -> Result<(), DeviceEmulateError> {
+        match self.claim(context_id) {
+            0 => Ok(()),
+            irq => self.inject_claimed(context_id, irq),
+        }
+    }
+
+    /// Return register at `dst_addr`.
+    fn decode_register(
         &self,
         dst_addr: HostPhysicalAddress,
-    ) -> Result<u32, DeviceEmulateError> {
+    ) -> Result<PlicRegister, DeviceEmulateError> {
         if !(self.base_addr..self.base_addr + self.size).contains(&dst_addr) {
             return Err(DeviceEmulateError::InvalidAddress);
         }
 
-        let offset = dst_addr.raw() - self.base_addr.raw();
-        match offset {
-            CONTEXT_BASE..=CONTEXT_END => self.context_load(offset),
-            _ => Err(DeviceEmulateError::InvalidAddress),
+        PlicRegister::try_from(dst_addr.raw() - self.base_addr.raw())
+    }
+
+    /// Emulate reading plic register.
+    pub fn emulate_loading(
+        &mut self,
+        dst_addr: HostPhysicalAddress,
+    ) -> Result<u32, DeviceEmulateError> {
+        match self.decode_register(dst_addr)? {
+            PlicRegister::Enable(context_id, word) => {
+                let enable_addr = self.physical_enable_reg(context_id, word)?;
+                Ok(unsafe { (enable_addr.raw() as *const u32).read_volatile() })
+            }
+            PlicRegister::Threshold(context_id) => {
+                self.physical_context(context_id)?;
+                Ok(self.threshold[context_id])
+            }
+            // VS-level external interrupt is deasserted if no interrupt is left to be claimed.
+            PlicRegister::ClaimComplete(context_id) => {
+                self.physical_context(context_id)?;
+                let queue = &mut self.pending_irqs[context_id];
+                let claimed = queue.claim().map_or(0, |injected| injected.irq);
+                if !queue.has_unclaimed() {
+                    hvip::clear(VsInterruptKind::External);
+                }
+                Ok(claimed)
+            }
+            // interrupts already injected to a guest context are not pending anymore,
+            // and interrupts of hypervisor owned devices are hidden.
+            PlicRegister::Pending(word) => {
+                let injected = self
+                    .pending_irqs
+                    .iter()
+                    .fold(0, |bits, queue| bits | queue.pending_bits(word));
+                let pending = unsafe { (dst_addr.raw() as *const u32).read_volatile() };
+                Ok(pending & !injected & !self.masked_irqs[word])
+            }
+            // priority is shared among contexts and needs no translation.
+            PlicRegister::Priority(_) => {
+                Ok(unsafe { (dst_addr.raw() as *const u32).read_volatile() })
+            }
         }
     }
 
-    /// Emulate storing plic context register.
-    fn context_storing(
+    /// Emulate storing plic register.
+    pub fn emulate_storing(
         &mut self,
         dst_addr: HostPhysicalAddress,
         value: u32,
     ) -> Result<(), DeviceEmulateError> {
-        let offset = dst_addr.raw() - self.base_addr.raw();
-        let context_id = (offset - CONTEXT_BASE) / CONTEXT_REGS_SIZE;
-        let offset_per_context = offset % CONTEXT_REGS_SIZE;
-        match offset_per_context {
-            // threshold
-            0 => {
-                let dst_ptr = dst_addr.raw() as *mut u32;
+        match self.decode_register(dst_addr)? {
+            PlicRegister::Enable(context_id, word) => {
+                let enable_addr = self.physical_enable_reg(context_id, word)?;
+                let mask = self.masked_irqs[word];
                 unsafe {
-                    dst_ptr.write_volatile(value);
+                    (enable_addr.raw() as *mut u32).write_volatile(value & !mask);
                 }
 
                 Ok(())
             }
-            // claim/complete
-            4 => {
-                let dst_ptr = dst_addr.raw() as *mut u32;
+            PlicRegister::Threshold(context_id) => {
+                let dst_ptr = self
+                    .physical_context_reg(context_id, CONTEXT_THRESHOLD)?
+                    .raw() as *mut u32;
+                self.threshold[context_id] = value;
                 unsafe {
-                    if self.claim_complete[context_id] == value {
-                        self.claim_complete[context_id] = 0;
-                        dst_ptr.write_volatile(value);
+                    dst_ptr.write_volatile(value);
+                }
 
+                Ok(())
+            }
+            PlicRegister::ClaimComplete(context_id) => {
+                let dst_ptr =
+                    self.physical_context_reg(context_id, CONTEXT_CLAIM)?.raw() as *mut u32;
+                let queue = &mut self.pending_irqs[context_id];
+                if let Some(completed) = queue.remove(value) {
+                    if completed.claimed_in_hardware {
+                        unsafe {
+                            dst_ptr.write_volatile(value);
+                            sie::set_sext();
+                        }
+                    }
+                    // the other injected interrupts are still pending.
+                    if !queue.has_unclaimed() {
                         hvip::clear(VsInterruptKind::External);
-                        sie::set_sext();
                     }
                 }
 
                 Ok(())
             }
-            8 => Err(DeviceEmulateError::ReservedRegister),
-            _ => Err(DeviceEmulateError::InvalidAddress),
+            PlicRegister::Priority(irq) => {
+                // interrupts masked for the hypervisor stay at the priority it set.
+                if self.masked_irqs[irq / 32] & (1 << (irq % 32)) == 0 {
+                    unsafe {
+                        (dst_addr.raw() as *mut u32).write_volatile(value);
+                    }
+                }
+
+                Ok(())
+            }
+            PlicRegister::Pending(_) => Err(DeviceEmulateError::Unimplemented(dst_addr)),
         }
     }
+}
 
-    /// Emulate storing plic register.
-    pub fn emulate_storing(
-        &mut self,
-        dst_addr: HostPhysicalAddress,
-        value: u32,
-    ) -> Result<(), DeviceEmulateError> {
-        if !(self.base_addr..self.base_addr + self.size).contains(&dst_addr) {
-            return Err(DeviceEmulateError::InvalidAddress);
-        }
+/// Build guest context id to physical context id table from `interrupts-extended`.
+///
+/// `interrupts-extended` consists of (phandle of hart interrupt controller, irq) pairs.
+/// The index of each pair is the physical context id.
+/// Guest context ids are used as physical ones as is if the node has no `interrupts-extended`.
+fn parse_context_map(device_tree: &Fdt, plic_node: &FdtNode) -> [Option<usize>; MAX_CONTEXT_NUM] {
+    let Some(interrupts_extended) = plic_node.property("interrupts-extended") else {
+        return core::array::from_fn(Some);
+    };
 
-        let offset = dst_addr.raw() - self.base_addr.raw();
-        match offset {
-            CONTEXT_BASE..=CONTEXT_END => self.context_storing(dst_addr, value),
-            _ => Err(DeviceEmulateError::InvalidAddress),
+    let mut context_map = [None; MAX_CONTEXT_NUM];
+
+    let cells = interrupts_extended
+        .value
+        .chunks_exact(4)
+        .map(|cell| u32::from_be_bytes([cell[0], cell[1], cell[2], cell[3]]))
+        .collect::<Vec<_>>();
+    for (phys_context_id, pair) in cells.chunks_exact(2).enumerate() {
+        let (phandle, irq) = (pair[0], pair[1]);
+        let is_supervisor = match irq {
+            SUPERVISOR_EXTERNAL_IRQ => true,
+            MACHINE_EXTERNAL_IRQ => false,
+            _ => continue,
+        };
+
+        let hart_id = device_tree.find_all_nodes("/cpus/cpu").find_map(|cpu| {
+            cpu.children()
+                .any(|intc| {
+                    intc.property("phandle")
+                        .and_then(fdt::node::NodeProperty::as_usize)
+                        == Some(phandle as usize)
+                })
+                .then(|| {
+                    cpu.property("reg")
+                        .and_then(fdt::node::NodeProperty::as_usize)
+                })
+                .flatten()
+        });
+
+        if let Some(hart_id) = hart_id {
+            let context_id = ContextId::new(hart_id, is_supervisor);
+            if let Some(entry) = context_map.get_mut(context_id.raw()) {
+                *entry = Some(phys_context_id);
+            }
         }
     }
+
+    context_map
 }
 
 impl MmioDevice for Plic {
     #[allow(clippy::cast_ptr_alignment)]
     fn try_new(device_tree: &Fdt, compatibles: &[&str]) -> Option<Self> {
-        let region = device_tree
-            .find_compatible(compatibles)?
-            .reg()
-            .unwrap()
-            .next()
-            .unwrap();
+        let plic_node = device_tree.find_compatible(compatibles)?;
+        let region = plic_node.reg().unwrap().next().unwrap();
 
         Some(Plic {
             base_addr: HostPhysicalAddress(region.starting_address as usize),
             size: region.size.unwrap(),
-            claim_complete: [0u32; MAX_CONTEXT_NUM],
+            pending_irqs: [IrqQueue::new(); MAX_CONTEXT_NUM],
+            context_map: parse_context_map(device_tree, &plic_node),
+            threshold: [0u32; MAX_CONTEXT_NUM],
+            masked_irqs: [0u32; MAX_IRQ_NUM / 32],
         })
     }
 
@@ -178,14 +601,9 @@ impl MmioDevice for Plic {
         self.base_addr
     }
 
-    fn memmap(&self) -> MemoryMap {
-        // Pass through 0x0 - 0x20_0000.
-        // Disallow 0x20_0000 - for emulation.
-        let vaddr = GuestPhysicalAddress(self.paddr().raw());
-        MemoryMap::new(
-            vaddr..vaddr + CONTEXT_BASE,
-            self.paddr()..self.paddr() + CONTEXT_BASE,
-            &PTE_FLAGS_FOR_DEVICE,
-        )
+    fn memmap(&self) -> Result<MemoryMap, MemoryMapError> {
+        // Pass through 0x0 - 0x2000. (priority and pending bits)
+        // Disallow 0x2000 - for emulation. (enable bits and context registers)
+        device_memmap(self.paddr(), ENABLE_BASE)
     }
 }
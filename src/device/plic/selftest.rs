@@ -0,0 +1,74 @@
+//! Boot-time self test of PLIC register decoding at region boundaries. (see `PlicRegister`)
+
+use super::{
+    PlicRegister, CONTEXT_BASE, CONTEXT_CLAIM, CONTEXT_END, CONTEXT_REGS_SIZE, CONTEXT_THRESHOLD,
+    ENABLE_BASE, ENABLE_PER_CONTEXT, MAX_CONTEXT_NUM, MAX_IRQ_NUM, PENDING_BASE, PENDING_END,
+    PRIORITY_BASE,
+};
+use crate::device::DeviceEmulateError;
+
+/// End of enable bits of existing contexts.
+const ENABLE_END: usize = ENABLE_BASE + ENABLE_PER_CONTEXT * MAX_CONTEXT_NUM;
+/// Base offset of the last context.
+const LAST_CONTEXT_BASE: usize = CONTEXT_END - CONTEXT_REGS_SIZE;
+
+/// Offsets at region boundaries and their expected decoding.
+const CASES: [(usize, Result<PlicRegister, DeviceEmulateError>); 15] = [
+    // priority: interrupt source 0 does not exist.
+    (PRIORITY_BASE, Err(DeviceEmulateError::ReservedRegister)),
+    (0x4, Ok(PlicRegister::Priority(1))),
+    (
+        PENDING_BASE - 4,
+        Ok(PlicRegister::Priority(MAX_IRQ_NUM - 1)),
+    ),
+    // pending
+    (PENDING_BASE, Ok(PlicRegister::Pending(0))),
+    (
+        PENDING_END - 4,
+        Ok(PlicRegister::Pending(MAX_IRQ_NUM / 32 - 1)),
+    ),
+    (PENDING_END, Err(DeviceEmulateError::ReservedRegister)),
+    // enable
+    (ENABLE_BASE, Ok(PlicRegister::Enable(0, 0))),
+    (
+        ENABLE_BASE + ENABLE_PER_CONTEXT,
+        Ok(PlicRegister::Enable(1, 0)),
+    ),
+    (
+        ENABLE_END - 4,
+        Ok(PlicRegister::Enable(
+            MAX_CONTEXT_NUM - 1,
+            MAX_IRQ_NUM / 32 - 1,
+        )),
+    ),
+    (ENABLE_END, Err(DeviceEmulateError::InvalidContextId)),
+    // context
+    (
+        CONTEXT_BASE + CONTEXT_THRESHOLD,
+        Ok(PlicRegister::Threshold(0)),
+    ),
+    (
+        CONTEXT_BASE + CONTEXT_CLAIM,
+        Ok(PlicRegister::ClaimComplete(0)),
+    ),
+    (
+        LAST_CONTEXT_BASE + CONTEXT_CLAIM,
+        Ok(PlicRegister::ClaimComplete(MAX_CONTEXT_NUM - 1)),
+    ),
+    (CONTEXT_END - 4, Err(DeviceEmulateError::ReservedRegister)),
+    (CONTEXT_END, Err(DeviceEmulateError::InvalidContextId)),
+];
+
+/// Check decoding of PLIC register offsets.
+///
+/// # Panics
+/// It panics with the offending offset if the decoded register is wrong.
+pub fn register_decoding() {
+    for (offset, expected) in CASES {
+        let result = PlicRegister::try_from(offset);
+        assert!(
+            result == expected,
+            "[selftest] PLIC offset {offset:#x} is decoded to {result:x?}, expected {expected:x?}"
+        );
+    }
+}
@@ -1,9 +1,27 @@
-//! RTC: Real Time Clock.
+//! RTC: Real Time Clock.  
+//! ref: [https://github.com/qemu/qemu/blob/master/hw/rtc/goldfish_rtc.c](https://github.com/qemu/qemu/blob/master/hw/rtc/goldfish_rtc.c)
 
-use super::{MmioDevice, PTE_FLAGS_FOR_DEVICE};
-use crate::memmap::{GuestPhysicalAddress, HostPhysicalAddress, MemoryMap};
+use super::{device_memmap, DeviceEmulateError, MmioDevice};
+use crate::current_hart_id;
+use crate::memmap::constant::MAX_HART_NUM;
+use crate::memmap::{HostPhysicalAddress, MemoryMap, MemoryMapError};
 use fdt::Fdt;
 
+/// Lower 32 bits of current time (ns).
+///
+/// Reading it latches upper 32 bits to `TIME_HIGH`.
+const TIME_LOW: usize = 0x00;
+/// Upper 32 bits of current time (ns).
+const TIME_HIGH: usize = 0x04;
+/// Lower 32 bits of alarm time (ns).
+///
+/// Writing it arms the alarm with upper 32 bits written to `ALARM_HIGH`.
+const ALARM_LOW: usize = 0x08;
+/// Upper 32 bits of alarm time (ns).
+const ALARM_HIGH: usize = 0x0c;
+/// Writing it disarms the alarm.
+const CLEAR_ALARM: usize = 0x14;
+
 /// RTC: Real Time Clock.
 /// An electronic device that measures the passage of time.
 #[derive(Debug)]
@@ -14,6 +32,31 @@ pub struct Rtc {
     size: usize,
 }
 
+impl Rtc {
+    /// Read host time (ns).
+    fn host_time(&self) -> u64 {
+        // reading `TIME_LOW` latches `TIME_HIGH`.
+        unsafe {
+            let low = core::ptr::read_volatile((self.base_addr + TIME_LOW).raw() as *const u32);
+            let high = core::ptr::read_volatile((self.base_addr + TIME_HIGH).raw() as *const u32);
+            (u64::from(high) << 32) | u64::from(low)
+        }
+    }
+
+    /// Arm host alarm (ns).
+    #[allow(clippy::cast_possible_truncation)]
+    fn set_host_alarm(&self, alarm: u64) {
+        // writing `ALARM_LOW` arms the alarm.
+        unsafe {
+            core::ptr::write_volatile(
+                (self.base_addr + ALARM_HIGH).raw() as *mut u32,
+                (alarm >> 32) as u32,
+            );
+            core::ptr::write_volatile((self.base_addr + ALARM_LOW).raw() as *mut u32, alarm as u32);
+        }
+    }
+}
+
 impl MmioDevice for Rtc {
     fn try_new(device_tree: &Fdt, compatibles: &[&str]) -> Option<Self> {
         let region = device_tree
@@ -37,12 +80,135 @@ impl MmioDevice for Rtc {
         self.base_addr
     }
 
-    fn memmap(&self) -> MemoryMap {
-        let vaddr = GuestPhysicalAddress(self.paddr().raw());
-        MemoryMap::new(
-            vaddr..vaddr + self.size(),
-            self.paddr()..self.paddr() + self.size(),
-            &PTE_FLAGS_FOR_DEVICE,
-        )
+    fn memmap(&self) -> Result<MemoryMap, MemoryMapError> {
+        device_memmap(self.paddr(), self.size())
+    }
+}
+
+/// RTC emulation that isolates guest clock from host clock.
+///
+/// Guest time is calculated by host time + per-guest time offset (stored in `Guest`).
+/// Alarm is shadowed in guest time and host alarm is armed at the corresponding host time,
+/// so the alarm interrupt is delivered to the guest through PLIC emulation as usual.
+/// Other registers (e.g. `IRQ_ENABLED`, `ALARM_STATUS`) are passed through.
+#[derive(Debug)]
+pub struct RtcEmulation {
+    /// Host RTC.
+    rtc: Rtc,
+    /// Upper 32 bits of guest time latched by reading `TIME_LOW` for each HART.
+    latched_time_high: [u32; MAX_HART_NUM],
+    /// Upper 32 bits of guest time written to `TIME_HIGH` for each HART.
+    pending_time_high: [u32; MAX_HART_NUM],
+    /// Upper 32 bits of guest alarm written to `ALARM_HIGH` for each HART.
+    pending_alarm_high: [u32; MAX_HART_NUM],
+    /// Guest alarm time (ns) for each HART. (`None` if it is not armed)
+    guest_alarm: [Option<u64>; MAX_HART_NUM],
+}
+
+impl RtcEmulation {
+    /// Wrap host RTC to emulate it.
+    pub fn new(rtc: Rtc) -> Self {
+        RtcEmulation {
+            rtc,
+            latched_time_high: [0; MAX_HART_NUM],
+            pending_time_high: [0; MAX_HART_NUM],
+            pending_alarm_high: [0; MAX_HART_NUM],
+            guest_alarm: [None; MAX_HART_NUM],
+        }
+    }
+
+    /// Return host RTC time (ns).
+    pub fn host_time(&self) -> u64 {
+        self.rtc.host_time()
+    }
+
+    /// Is the address in RTC region?
+    pub fn contains(&self, addr: HostPhysicalAddress) -> bool {
+        self.offset(addr).is_ok()
+    }
+
+    /// Return emulated RTC offset if `dst_addr` is in RTC region.
+    fn offset(&self, dst_addr: HostPhysicalAddress) -> Result<usize, DeviceEmulateError> {
+        if (self.rtc.paddr()..self.rtc.paddr() + self.rtc.size()).contains(&dst_addr) {
+            Ok(dst_addr.raw() - self.rtc.paddr().raw())
+        } else {
+            Err(DeviceEmulateError::InvalidAddress)
+        }
+    }
+
+    /// Emulate reading RTC register.
+    ///
+    /// `time_offset` is a time offset (ns) of current guest.
+    #[allow(clippy::cast_possible_truncation)]
+    pub fn emulate_loading(
+        &mut self,
+        dst_addr: HostPhysicalAddress,
+        time_offset: i64,
+    ) -> Result<u32, DeviceEmulateError> {
+        match self.offset(dst_addr)? {
+            TIME_LOW => {
+                let guest_time = self.rtc.host_time().wrapping_add_signed(time_offset);
+                self.latched_time_high[current_hart_id()] = (guest_time >> 32) as u32;
+                Ok(guest_time as u32)
+            }
+            TIME_HIGH => Ok(self.latched_time_high[current_hart_id()]),
+            ALARM_LOW => Ok(self.guest_alarm[current_hart_id()].unwrap_or(0) as u32),
+            ALARM_HIGH => Ok((self.guest_alarm[current_hart_id()].unwrap_or(0) >> 32) as u32),
+            _ => {
+                let dst_ptr = dst_addr.raw() as *const u32;
+                Ok(unsafe { dst_ptr.read_volatile() })
+            }
+        }
+    }
+
+    /// Emulate storing RTC register.
+    ///
+    /// Writing time updates `time_offset` instead of host clock.
+    /// Writing alarm arms host alarm at the host time corresponding to the guest alarm.
+    #[allow(clippy::cast_possible_wrap, clippy::cast_sign_loss)]
+    pub fn emulate_storing(
+        &mut self,
+        dst_addr: HostPhysicalAddress,
+        value: u32,
+        time_offset: &mut i64,
+    ) -> Result<(), DeviceEmulateError> {
+        match self.offset(dst_addr)? {
+            // `TIME_HIGH` is written before `TIME_LOW`.
+            TIME_HIGH => self.pending_time_high[current_hart_id()] = value,
+            TIME_LOW => {
+                let new_time =
+                    (u64::from(self.pending_time_high[current_hart_id()]) << 32) | u64::from(value);
+                *time_offset = new_time.wrapping_sub(self.rtc.host_time()) as i64;
+                // re-arm the alarm for new offset.
+                if let Some(alarm) = self.guest_alarm[current_hart_id()] {
+                    self.rtc
+                        .set_host_alarm(alarm.wrapping_sub(*time_offset as u64));
+                }
+            }
+            // `ALARM_HIGH` is written before `ALARM_LOW`.
+            ALARM_HIGH => self.pending_alarm_high[current_hart_id()] = value,
+            ALARM_LOW => {
+                let alarm = (u64::from(self.pending_alarm_high[current_hart_id()]) << 32)
+                    | u64::from(value);
+                self.guest_alarm[current_hart_id()] = Some(alarm);
+                self.rtc
+                    .set_host_alarm(alarm.wrapping_sub(*time_offset as u64));
+            }
+            CLEAR_ALARM => {
+                self.guest_alarm[current_hart_id()] = None;
+                let dst_ptr = dst_addr.raw() as *mut u32;
+                unsafe {
+                    dst_ptr.write_volatile(value);
+                }
+            }
+            _ => {
+                let dst_ptr = dst_addr.raw() as *mut u32;
+                unsafe {
+                    dst_ptr.write_volatile(value);
+                }
+            }
+        }
+
+        Ok(())
     }
 }
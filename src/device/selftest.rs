@@ -0,0 +1,73 @@
+//! Boot-time self test of DMA buffer copies.
+//!
+//! A guest buffer that starts in the middle of a page is copied through `DmaHostBuffer`,
+//! and each chunk must stay in one guest page and be translated by current hgatp.
+//! The buffer is placed in the memory of the running guest and written back with the same data.
+
+use super::DmaHostBuffer;
+use crate::hart_local;
+use crate::memmap::page_table::{constants::PAGE_SIZE, g_stage_trans_addr};
+
+use alloc::vec::Vec;
+
+/// Offset of the buffer from the start of guest memory. (not page-aligned)
+const BUFFER_OFFSET: usize = PAGE_SIZE + 0x234;
+/// Size of the buffer. (it spans three guest pages)
+const BUFFER_SIZE: usize = 2 * PAGE_SIZE + 0x100;
+/// Expected chunk sizes split at guest page boundaries.
+const EXPECTED_CHUNKS: [usize; 3] = [PAGE_SIZE - 0x234, PAGE_SIZE, 0x334];
+
+/// Copy a misaligned guest buffer to host and back, and check the chunks.
+///
+/// # Panics
+/// It panics with the offending chunk if it crosses a page or the copied data is wrong.
+pub fn misaligned_buffer() {
+    let guest_buf_addr = hart_local()
+        .lock()
+        .get()
+        .unwrap()
+        .guest()
+        .memory_region()
+        .start
+        + BUFFER_OFFSET;
+
+    let mut buffer = DmaHostBuffer::new(BUFFER_SIZE);
+    buffer.set_used_len(BUFFER_SIZE);
+
+    let mut chunks = Vec::new();
+    buffer
+        .for_each_guest_page(guest_buf_addr, |offset, hpa, chunk_size| {
+            chunks.push((offset, hpa, chunk_size));
+        })
+        .expect("[selftest] guest buffer is not mapped");
+    assert!(
+        chunks.iter().map(|&(_, _, size)| size).eq(EXPECTED_CHUNKS),
+        "[selftest] DMA buffer chunks are not split at guest page boundaries: {chunks:x?}"
+    );
+    for &(offset, hpa, chunk_size) in &chunks {
+        assert!(
+            g_stage_trans_addr(guest_buf_addr + offset).is_ok_and(|expected| expected == hpa)
+                && hpa % PAGE_SIZE + chunk_size <= PAGE_SIZE,
+            "[selftest] DMA buffer chunk at {offset:#x} is wrong: {hpa:#x} ({chunk_size:#x} bytes)"
+        );
+    }
+
+    buffer
+        .guest_to_host(guest_buf_addr)
+        .expect("[selftest] copying guest buffer failed");
+    for &(offset, hpa, chunk_size) in &chunks {
+        let guest_data = unsafe { core::slice::from_raw_parts(hpa.raw() as *const u8, chunk_size) };
+        let host_data = unsafe {
+            core::slice::from_raw_parts((buffer.addr() + offset) as *const u8, chunk_size)
+        };
+        assert!(
+            guest_data == host_data,
+            "[selftest] DMA buffer chunk at {offset:#x} is not copied"
+        );
+    }
+
+    // the guest memory is not changed since the same data is written back.
+    buffer
+        .host_to_guest(guest_buf_addr)
+        .expect("[selftest] copying host buffer failed");
+}
@@ -1,17 +1,68 @@
 //! UART: Universal Asynchronous Receiver-Transmitter
 
-use super::{MmioDevice, PTE_FLAGS_FOR_DEVICE};
-use crate::memmap::{GuestPhysicalAddress, HostPhysicalAddress, MemoryMap};
+use super::{device_memmap, MmioDevice};
+use crate::memmap::{HostPhysicalAddress, MemoryMap, MemoryMapError};
 
+use alloc::vec::Vec;
 use core::cell::OnceCell;
 use fdt::Fdt;
 use spin::Mutex;
 
-mod register {
-    //! Ref: [http://byterunner.com/16550.html](http://byterunner.com/16550.html)
+/// Property in `/chosen` to override compatible list of UART.
+const UART_COMPATIBLES_PROPERTY: &str = "hikami,uart-compatibles";
 
-    /// LSR register offset.
-    pub const LSR_OFFSET: usize = 3;
+/// Kind of UART distinguished by compatible.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UartCompat {
+    /// NS16550A compatible UART.
+    Ns16550a,
+    /// AXI UART (16550 compatible register layout).
+    AxiUart,
+    /// `SiFive` UART.
+    SifiveUart0,
+    /// Xilinx Zynq UART.
+    XilinxUartPs,
+}
+
+impl UartCompat {
+    /// Default compatible list to search UART.
+    const DEFAULT: [UartCompat; 4] = [
+        UartCompat::Ns16550a,
+        UartCompat::AxiUart,
+        UartCompat::SifiveUart0,
+        UartCompat::XilinxUartPs,
+    ];
+
+    /// Return compatible string in device tree.
+    pub fn compatible(self) -> &'static str {
+        match self {
+            UartCompat::Ns16550a => "ns16550a",
+            UartCompat::AxiUart => "riscv,axi-uart-1.0",
+            UartCompat::SifiveUart0 => "sifive,uart0",
+            UartCompat::XilinxUartPs => "xlnx,xuartps",
+        }
+    }
+}
+
+/// Return compatible list to search UART.
+///
+/// `hikami,uart-compatibles` in `/chosen` is used if exists, otherwise `UartCompat::DEFAULT` is used.
+pub fn compatibles<'a>(device_tree: &'a Fdt) -> Vec<&'a str> {
+    let Some(prop) = device_tree
+        .find_node("/chosen")
+        .and_then(|chosen| chosen.property(UART_COMPATIBLES_PROPERTY))
+    else {
+        return UartCompat::DEFAULT
+            .into_iter()
+            .map(UartCompat::compatible)
+            .collect();
+    };
+
+    prop.value
+        .split(|&c| c == 0)
+        .filter(|compatible| !compatible.is_empty())
+        .filter_map(|compatible| core::str::from_utf8(compatible).ok())
+        .collect()
 }
 
 /// Uart address for `UartWriter`.
@@ -26,13 +77,6 @@ pub struct Uart {
     size: usize,
 }
 
-impl Uart {
-    /// Return address of LSR register.
-    pub fn lsr_addr(&self) -> HostPhysicalAddress {
-        self.base_addr + register::LSR_OFFSET
-    }
-}
-
 impl MmioDevice for Uart {
     fn try_new(device_tree: &Fdt, compatibles: &[&str]) -> Option<Self> {
         let region = device_tree
@@ -60,12 +104,7 @@ impl MmioDevice for Uart {
         self.base_addr
     }
 
-    fn memmap(&self) -> MemoryMap {
-        let vaddr = GuestPhysicalAddress(self.paddr().raw());
-        MemoryMap::new(
-            vaddr..vaddr + self.size(),
-            self.paddr()..self.paddr() + self.size(),
-            &PTE_FLAGS_FOR_DEVICE,
-        )
+    fn memmap(&self) -> Result<MemoryMap, MemoryMapError> {
+        device_memmap(self.paddr(), self.size())
     }
 }
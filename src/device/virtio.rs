@@ -1,10 +1,29 @@
 //! A virtualization standard for network and disk device drivers.
 
-use super::{MmioDevice, PTE_FLAGS_FOR_DEVICE};
-use crate::memmap::{GuestPhysicalAddress, HostPhysicalAddress, MemoryMap};
+use super::{device_memmap, DeviceEmulateError, EmulateDevice, MmioDevice};
+use crate::memmap::{HostPhysicalAddress, MemoryMap, MemoryMapError};
 use alloc::vec::Vec;
 use core::slice::Iter;
-use fdt::Fdt;
+use fdt::{node::FdtNode, Fdt};
+
+/// Property that marks the device as owned by hypervisor.
+///
+/// Owned devices are hidden from guest.
+const HYPERVISOR_OWNED: &str = "hikami,hypervisor-owned";
+
+/// Offset of `MagicValue` register.
+const MAGIC_VALUE: usize = 0x000;
+/// Offset of `Version` register.
+const VERSION: usize = 0x004;
+/// Offset of `DeviceID` register.
+const DEVICE_ID: usize = 0x008;
+
+/// "virt" in little endian.
+const MAGIC: u32 = 0x7472_6976;
+/// Version of virtio-mmio. (non-legacy)
+const MMIO_VERSION: u32 = 2;
+/// Device id that means "no device".
+const NO_DEVICE: u32 = 0;
 
 /// A virtualization standard for network and disk device drivers.
 /// Since more than one may be found, we will temporarily use the first one.
@@ -17,15 +36,7 @@ impl VirtIoList {
         VirtIoList(
             device_tree
                 .find_all_nodes(node_path)
-                .map(|node| {
-                    let region = node.reg().unwrap().next().unwrap();
-                    let irq = node.property("interrupts").unwrap().value[0];
-                    VirtIo {
-                        base_addr: HostPhysicalAddress(region.starting_address as usize),
-                        size: region.size.unwrap(),
-                        irq,
-                    }
-                })
+                .map(|node| VirtIo::from_node(&node))
                 .collect(),
         )
     }
@@ -34,6 +45,43 @@ impl VirtIoList {
     pub fn iter(&self) -> Iter<'_, VirtIo> {
         self.0.iter()
     }
+
+    /// Return IRQs of hypervisor owned devices.
+    pub fn hypervisor_owned_irqs(&self) -> impl Iterator<Item = u32> + '_ {
+        self.iter()
+            .filter(|virtio| virtio.is_hypervisor_owned())
+            .filter_map(MmioDevice::irq)
+    }
+
+    /// Is the address in a hypervisor owned device?
+    pub fn contains(&self, addr: HostPhysicalAddress) -> bool {
+        self.iter()
+            .any(|virtio| virtio.is_hypervisor_owned() && virtio.contains(addr))
+    }
+
+    /// Emulate loading registers of hypervisor owned devices.
+    pub fn emulate_loading(
+        &self,
+        dst_addr: HostPhysicalAddress,
+    ) -> Result<u32, DeviceEmulateError> {
+        self.iter()
+            .find(|virtio| virtio.is_hypervisor_owned() && virtio.contains(dst_addr))
+            .ok_or(DeviceEmulateError::InvalidAddress)?
+            .emulate_loading(dst_addr)
+    }
+
+    /// Emulate storing registers of hypervisor owned devices.
+    pub fn emulate_storing(
+        &mut self,
+        dst_addr: HostPhysicalAddress,
+        value: u32,
+    ) -> Result<(), DeviceEmulateError> {
+        self.0
+            .iter_mut()
+            .find(|virtio| virtio.is_hypervisor_owned() && virtio.contains(dst_addr))
+            .ok_or(DeviceEmulateError::InvalidAddress)?
+            .emulate_storing(dst_addr, value)
+    }
 }
 
 /// Virtualization standard for IO device.
@@ -44,27 +92,74 @@ pub struct VirtIo {
     /// Memory map size.
     size: usize,
     /// Interrupt Reqeust bit.
-    irq: u8,
+    irq: u32,
+    /// Is it owned by hypervisor? (hidden from guest)
+    hypervisor_owned: bool,
 }
 
 impl VirtIo {
-    /// Return `irq`.
-    pub fn irq(&self) -> u8 {
-        self.irq
+    /// Create Virt IO data from the device tree node.
+    fn from_node(node: &FdtNode) -> Self {
+        let region = node.reg().unwrap().next().unwrap();
+        let irq = node
+            .property("interrupts")
+            .and_then(fdt::node::NodeProperty::as_usize)
+            .unwrap();
+
+        VirtIo {
+            base_addr: HostPhysicalAddress(region.starting_address as usize),
+            size: region.size.unwrap(),
+            irq: u32::try_from(irq).unwrap(),
+            hypervisor_owned: node.property(HYPERVISOR_OWNED).is_some(),
+        }
+    }
+
+    /// Is it owned by hypervisor?
+    pub fn is_hypervisor_owned(&self) -> bool {
+        self.hypervisor_owned
+    }
+
+    /// Is the address belong to the device?
+    fn contains(&self, addr: HostPhysicalAddress) -> bool {
+        (self.base_addr..self.base_addr + self.size).contains(&addr)
+    }
+}
+
+/// Hypervisor owned device looks like an empty slot from guest.
+impl EmulateDevice for VirtIo {
+    /// Emulate loading registers.
+    fn emulate_loading(&self, dst_addr: HostPhysicalAddress) -> Result<u32, DeviceEmulateError> {
+        if !self.contains(dst_addr) {
+            return Err(DeviceEmulateError::InvalidAddress);
+        }
+
+        let offset = dst_addr.raw() - self.base_addr.raw();
+        match offset {
+            MAGIC_VALUE => Ok(MAGIC),
+            VERSION => Ok(MMIO_VERSION),
+            DEVICE_ID => Ok(NO_DEVICE),
+            _ => Ok(0),
+        }
+    }
+
+    /// Ignore storing registers.
+    fn emulate_storing(
+        &mut self,
+        dst_addr: HostPhysicalAddress,
+        _value: u32,
+    ) -> Result<(), DeviceEmulateError> {
+        if !self.contains(dst_addr) {
+            return Err(DeviceEmulateError::InvalidAddress);
+        }
+
+        Ok(())
     }
 }
 
 impl MmioDevice for VirtIo {
     fn try_new(device_tree: &Fdt, compatibles: &[&str]) -> Option<Self> {
         let node = device_tree.find_compatible(compatibles)?;
-        let region = node.reg().unwrap().next().unwrap();
-        let irq = node.property("interrupts").unwrap().value[0];
-
-        Some(VirtIo {
-            base_addr: HostPhysicalAddress(region.starting_address as usize),
-            size: region.size.unwrap(),
-            irq,
-        })
+        Some(VirtIo::from_node(&node))
     }
 
     fn size(&self) -> usize {
@@ -75,12 +170,11 @@ impl MmioDevice for VirtIo {
         self.base_addr
     }
 
-    fn memmap(&self) -> MemoryMap {
-        let vaddr = GuestPhysicalAddress(self.paddr().raw());
-        MemoryMap::new(
-            vaddr..vaddr + self.size(),
-            self.paddr()..self.paddr() + self.size(),
-            &PTE_FLAGS_FOR_DEVICE,
-        )
+    fn memmap(&self) -> Result<MemoryMap, MemoryMapError> {
+        device_memmap(self.paddr(), self.size())
+    }
+
+    fn irq(&self) -> Option<u32> {
+        Some(self.irq)
     }
 }
@@ -1,26 +1,124 @@
 //! Extension emulation
 
+#[cfg(feature = "csr_log")]
+pub mod csr_access_log;
+pub mod zba;
+pub mod zicfilp;
 pub mod zicfiss;
+pub mod zicntr;
+pub mod zicond;
 
+use crate::guest::watchdog;
 use crate::h_extension::csrs::vstvec;
 use crate::trap::hstrap_exit;
-use crate::HYPERVISOR_DATA;
+use crate::{hart_local, stats};
 
+use alloc::vec::Vec;
 use core::arch::asm;
 use raki::Instruction;
 use riscv::register::sstatus;
 
 /// Trait for extention emulation.
-pub trait EmulateExtension {
+///
+/// `I` is decoded instruction. (extensions that `raki` does not support decode it by themselves)
+pub trait EmulateExtension<I = Instruction> {
     /// Emulate instruction
-    fn instruction(&mut self, inst: &Instruction);
+    fn instruction(&mut self, inst: &I) -> EmulateResult;
     /// Emulate CSR
-    fn csr(&mut self, inst: &Instruction);
+    fn csr(&mut self, inst: &I) -> EmulateResult;
     /// Emulate CSR field that already exists.
-    fn csr_field(&mut self, inst: &Instruction, write_to_csr_value: u64, read_csr_value: &mut u64);
+    fn csr_field(&mut self, inst: &I, write_to_csr_value: u64, read_csr_value: &mut u64);
+}
+
+/// Result of extension emulation.
+pub type EmulateResult = Result<(), VsException>;
+
+/// Exception that extension emulation raises to the guest.
+///
+/// It is returned instead of raised in place, so that locks held by the emulator are released
+/// before `raise` takes `HART_DATA`.
+#[derive(Debug, Clone, Copy)]
+pub struct VsException {
+    /// Exception number. (stored to vscause)
+    cause: usize,
+    /// Trap value. (stored to vstval)
+    tval: usize,
+}
+
+impl VsException {
+    /// Constructor for `VsException`.
+    pub fn new(cause: usize, tval: usize) -> Self {
+        VsException { cause, tval }
+    }
+
+    /// Raise the exception to the guest.
+    ///
+    /// No lock for emulation must be held.
+    pub fn raise(self) -> ! {
+        pseudo_vs_exception(self.cause, self.tval)
+    }
+}
+
+/// Extension emulation module that decodes instructions by itself. (`raki` does not support it)
+///
+/// Modules registered in `EXTENSION_MODULES` are initialized by `initialize` and
+/// tried by `dispatch_extensions`, so adding one needs no edit of trap handlers.
+pub trait ExtensionModule {
+    /// Names of the extension. (appended to ISA string of the guest)
+    fn names() -> &'static [&'static str];
+    /// Initialize singleton of the extension.
+    fn init();
+    /// Emulate the instruction if it belongs to the extension.
+    ///
+    /// Return `false` if `inst_value` is not an instruction of the extension.
+    /// sepc is advanced by `dispatch_extensions`.
+    fn try_handle(inst_value: usize) -> bool;
+}
+
+/// Entry of `EXTENSION_MODULES`.
+struct ModuleEntry {
+    /// `ExtensionModule::names`
+    names: fn() -> &'static [&'static str],
+    /// `ExtensionModule::init`
+    init: fn(),
+    /// `ExtensionModule::try_handle`
+    try_handle: fn(usize) -> bool,
+}
+
+/// Create `ModuleEntry` array from types that implement `ExtensionModule`.
+macro_rules! extension_modules {
+    ($($module:ty),* $(,)?) => {
+        [$(ModuleEntry {
+            names: <$module as ExtensionModule>::names,
+            init: <$module as ExtensionModule>::init,
+            try_handle: <$module as ExtensionModule>::try_handle,
+        }),*]
+    };
+}
+
+/// Extension modules that decode instructions by themselves. (tried in this order)
+static EXTENSION_MODULES: &[ModuleEntry] =
+    &extension_modules![zicond::Zicond, zba::Zba, zicntr::Zicntr];
+
+/// Emulate the instruction by extension modules and advance sepc.
+///
+/// Return `false` if no module handles `inst_value`.
+pub fn dispatch_extensions(inst_value: usize) -> bool {
+    if !EXTENSION_MODULES
+        .iter()
+        .any(|module| (module.try_handle)(inst_value))
+    {
+        return false;
+    }
+
+    let mut hart_data = hart_local().lock();
+    let context = hart_data.get_mut().unwrap().guest_mut().context();
+    context.set_sepc(context.sepc() + 4);
+    true
 }
 
 /// Holding a CSR value for CSRs emulation.
+#[derive(Debug, Default)]
 pub struct EmulatedCsr(u64);
 
 impl EmulatedCsr {
@@ -49,10 +147,22 @@ impl EmulatedCsr {
 }
 
 /// Initialize singletons for extension emulation.
-/// TODO: Remove it when `OnceCell` is replaced to `LazyCell`.
 pub fn initialize() {
-    use zicfiss::{Zicfiss, ZICFISS_DATA};
-    unsafe { ZICFISS_DATA.lock() }.get_or_init(Zicfiss::new);
+    EXTENSION_MODULES.iter().for_each(|module| (module.init)());
+}
+
+/// Return names of extensions that the hypervisor emulates.
+///
+/// Zicfilp is not listed because landing pads cannot be enforced. (see `zicfilp`)
+pub fn emulated_extensions() -> Vec<&'static str> {
+    let mut extensions = Vec::new();
+    // the state of Zicfiss is held by each guest context.
+    extensions.push("zicfiss");
+    for module in EXTENSION_MODULES {
+        extensions.extend((module.names)());
+    }
+    extensions.sort_unstable();
+    extensions
 }
 
 /// Throw an VS-level exception.
@@ -60,8 +170,11 @@ pub fn initialize() {
 /// * `trap_value`: Trap value. (stored to vstval)
 pub fn pseudo_vs_exception(exception_num: usize, trap_value: usize) -> ! {
     unsafe {
-        let hypervisor_data = HYPERVISOR_DATA.lock();
-        let mut context = hypervisor_data.get().unwrap().guest().context;
+        let mut hart_data = hart_local().lock();
+        let hart = hart_data.get_mut().unwrap();
+        watchdog::check_forwarded_exception(hart, exception_num, trap_value);
+        stats::record_forwarded_exception();
+        let context = hart.guest_mut().context();
         asm!(
             "csrw vsepc, {sepc}",
             "csrw vscause, {cause}",
@@ -89,7 +202,7 @@ pub fn pseudo_vs_exception(exception_num: usize, trap_value: usize) -> ! {
 
         context.set_sepc(vstvec::read().bits());
 
-        drop(hypervisor_data);
+        drop(hart_data);
 
         hstrap_exit();
     }
@@ -0,0 +1,127 @@
+//! Log of guest accesses to CSRs that the hypervisor does not emulate.
+//!
+//! Unsupported CSR accesses are recorded and illegal instruction exception is raised to the guest
+//! instead of panicking in hypervisor.
+//! The log is dumped to the console when it is full or on panic.
+
+use super::pseudo_vs_exception;
+use crate::{current_hart_id, hart_local, println};
+
+use raki::{Instruction, OpcodeKind, ZicsrOpcode};
+use riscv::register::stval;
+use spin::Mutex;
+
+/// Number of records in the log.
+const LOG_SIZE: usize = 64;
+/// Illegal instruction exception.
+const ILLEGAL_INSTRUCTION: usize = 2;
+
+/// Log of CSR accesses shared among all HARTs.
+static CSR_ACCESS_LOG: Mutex<CsrAccessLog> = Mutex::new(CsrAccessLog::new());
+
+/// Kind of CSR access.
+#[derive(Debug, Clone, Copy)]
+enum CsrAccessKind {
+    /// CSRRW, CSRRWI
+    Write,
+    /// CSRRS, CSRRSI
+    Set,
+    /// CSRRC, CSRRCI
+    Clear,
+}
+
+impl CsrAccessKind {
+    /// Return access kind of Zicsr instruction.
+    fn from_inst(inst: &Instruction) -> Option<Self> {
+        match inst.opc {
+            OpcodeKind::Zicsr(ZicsrOpcode::CSRRW | ZicsrOpcode::CSRRWI) => Some(Self::Write),
+            OpcodeKind::Zicsr(ZicsrOpcode::CSRRS | ZicsrOpcode::CSRRSI) => Some(Self::Set),
+            OpcodeKind::Zicsr(ZicsrOpcode::CSRRC | ZicsrOpcode::CSRRCI) => Some(Self::Clear),
+            _ => None,
+        }
+    }
+}
+
+/// A CSR access.
+#[derive(Debug, Clone, Copy)]
+struct CsrAccess {
+    /// CSR number.
+    csr_num: usize,
+    /// Access kind.
+    kind: CsrAccessKind,
+    /// HART id.
+    hart_id: usize,
+    /// Address of the instruction.
+    sepc: usize,
+}
+
+/// Fixed size log of CSR accesses.
+pub struct CsrAccessLog {
+    /// Records.
+    records: [Option<CsrAccess>; LOG_SIZE],
+    /// Number of records.
+    len: usize,
+}
+
+impl CsrAccessLog {
+    /// Constructor for `CsrAccessLog`.
+    const fn new() -> Self {
+        CsrAccessLog {
+            records: [None; LOG_SIZE],
+            len: 0,
+        }
+    }
+
+    /// Add a record and return true if the log becomes full.
+    fn push(&mut self, access: CsrAccess) -> bool {
+        self.records[self.len] = Some(access);
+        self.len += 1;
+        self.len == LOG_SIZE
+    }
+
+    /// Print all records and clear them.
+    fn dump(&mut self) {
+        println!("==================== CSR access log ====================");
+        for access in self.records[..self.len].iter().flatten() {
+            println!(
+                "[hart {}] {:#x}: {:?} csr {:#x}",
+                access.hart_id, access.sepc, access.kind, access.csr_num
+            );
+        }
+        println!("========================================================");
+        self.len = 0;
+    }
+}
+
+/// Record access to unsupported CSR and raise illegal instruction exception to the guest.
+pub fn unsupported_csr(inst: &Instruction) -> ! {
+    let access = CsrAccess {
+        csr_num: inst.rs2.unwrap(),
+        kind: CsrAccessKind::from_inst(inst).unwrap(),
+        hart_id: current_hart_id(),
+        sepc: hart_local()
+            .lock()
+            .get_mut()
+            .unwrap()
+            .guest_mut()
+            .context()
+            .sepc(),
+    };
+
+    let mut log = CSR_ACCESS_LOG.lock();
+    if log.push(access) {
+        log.dump();
+    }
+    drop(log);
+
+    pseudo_vs_exception(ILLEGAL_INSTRUCTION, stval::read());
+}
+
+/// Print logged CSR accesses. (e.g. from panic handler)
+///
+/// Nothing is printed if the log is locked.
+pub fn dump() {
+    if let Some(mut log) = CSR_ACCESS_LOG.try_lock() {
+        log.dump();
+    }
+}
@@ -0,0 +1,170 @@
+//! Emulation Zba (Address Generation)
+//! Ref: [https://github.com/riscv/riscv-isa-manual/releases/download/20240411/unpriv-isa-asciidoc.pdf](https://github.com/riscv/riscv-isa-manual/releases/download/20240411/unpriv-isa-asciidoc.pdf)
+
+use super::{EmulateExtension, EmulateResult, ExtensionModule};
+use crate::hart_local;
+
+use core::cell::OnceCell;
+use spin::Mutex;
+
+/// Singleton for Zba.
+/// TODO: change `OnceCell` to `LazyCell` when stable `LazyCell::force_mut`.
+pub static ZBA_DATA: Mutex<OnceCell<Zba>> = Mutex::new(OnceCell::new());
+
+/// Opcode of `SHxADD`. (OP)
+const OPCODE_OP: usize = 0b011_0011;
+/// Opcode of `SHxADD.UW` and `ADD.UW`. (OP-32)
+const OPCODE_OP_32: usize = 0b011_1011;
+/// Opcode of `SLLI.UW`. (OP-IMM-32)
+const OPCODE_OP_IMM_32: usize = 0b001_1011;
+/// funct7 of `SHxADD` and `SHxADD.UW`.
+const FUNCT7_SHADD: usize = 0b001_0000;
+/// funct7 of `ADD.UW`.
+const FUNCT7_ADD_UW: usize = 0b000_0100;
+/// funct6 of `SLLI.UW`.
+const FUNCT6_SLLI_UW: usize = 0b00_0010;
+
+/// Zba instructions.
+#[allow(non_camel_case_types)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ZbaOpcode {
+    /// rd = rs2 + (rs1 << 1)
+    SH1ADD,
+    /// rd = rs2 + (rs1 << 2)
+    SH2ADD,
+    /// rd = rs2 + (rs1 << 3)
+    SH3ADD,
+    /// rd = rs2 + (`zext.w(rs1)` << 1)
+    SH1ADD_UW,
+    /// rd = rs2 + (`zext.w(rs1)` << 2)
+    SH2ADD_UW,
+    /// rd = rs2 + (`zext.w(rs1)` << 3)
+    SH3ADD_UW,
+    /// rd = rs2 + `zext.w(rs1)`
+    ADD_UW,
+    /// rd = `zext.w(rs1)` << shamt
+    SLLI_UW,
+}
+
+/// Decoded Zba instruction.
+///
+/// `raki` does not support Zba, so it is decoded here.
+#[derive(Debug)]
+pub struct ZbaInstruction {
+    /// Opcode
+    opc: ZbaOpcode,
+    /// Destination register
+    rd: usize,
+    /// Source register 1
+    rs1: usize,
+    /// Source register 2 (shift amount for `SLLI.UW`)
+    rs2: usize,
+}
+
+impl ZbaInstruction {
+    /// Decode Zba instruction.
+    ///
+    /// Return `None` if `inst_value` is not a Zba instruction.
+    pub fn decode(inst_value: usize) -> Option<Self> {
+        let funct3 = (inst_value >> 12) & 0x7;
+        let funct7 = (inst_value >> 25) & 0x7f;
+        let (opc, rs2) = match (inst_value & 0x7f, funct7, funct3) {
+            (OPCODE_OP, FUNCT7_SHADD, 0b010) => (ZbaOpcode::SH1ADD, (inst_value >> 20) & 0x1f),
+            (OPCODE_OP, FUNCT7_SHADD, 0b100) => (ZbaOpcode::SH2ADD, (inst_value >> 20) & 0x1f),
+            (OPCODE_OP, FUNCT7_SHADD, 0b110) => (ZbaOpcode::SH3ADD, (inst_value >> 20) & 0x1f),
+            (OPCODE_OP_32, FUNCT7_SHADD, 0b010) => {
+                (ZbaOpcode::SH1ADD_UW, (inst_value >> 20) & 0x1f)
+            }
+            (OPCODE_OP_32, FUNCT7_SHADD, 0b100) => {
+                (ZbaOpcode::SH2ADD_UW, (inst_value >> 20) & 0x1f)
+            }
+            (OPCODE_OP_32, FUNCT7_SHADD, 0b110) => {
+                (ZbaOpcode::SH3ADD_UW, (inst_value >> 20) & 0x1f)
+            }
+            (OPCODE_OP_32, FUNCT7_ADD_UW, 0b000) => (ZbaOpcode::ADD_UW, (inst_value >> 20) & 0x1f),
+            // shamt is 6 bits.
+            (OPCODE_OP_IMM_32, _, 0b001) if funct7 >> 1 == FUNCT6_SLLI_UW => {
+                (ZbaOpcode::SLLI_UW, (inst_value >> 20) & 0x3f)
+            }
+            _ => return None,
+        };
+
+        Some(ZbaInstruction {
+            opc,
+            rd: (inst_value >> 7) & 0x1f,
+            rs1: (inst_value >> 15) & 0x1f,
+            rs2,
+        })
+    }
+}
+
+/// Singleton for Zba extension
+pub struct Zba;
+
+impl Zba {
+    /// Constructor for `Zba`.
+    pub fn new() -> Self {
+        Zba
+    }
+}
+
+impl EmulateExtension<ZbaInstruction> for Zba {
+    /// Emulate Zba instruction.
+    fn instruction(&mut self, inst: &ZbaInstruction) -> EmulateResult {
+        let mut hart_data = hart_local().lock();
+        let context = hart_data.get_mut().unwrap().guest_mut().context();
+        let rs1 = context.xreg(inst.rs1);
+        let zext_rs1 = rs1 & 0xffff_ffff;
+        let result = match inst.opc {
+            ZbaOpcode::SH1ADD => context.xreg(inst.rs2).wrapping_add(rs1 << 1),
+            ZbaOpcode::SH2ADD => context.xreg(inst.rs2).wrapping_add(rs1 << 2),
+            ZbaOpcode::SH3ADD => context.xreg(inst.rs2).wrapping_add(rs1 << 3),
+            ZbaOpcode::SH1ADD_UW => context.xreg(inst.rs2).wrapping_add(zext_rs1 << 1),
+            ZbaOpcode::SH2ADD_UW => context.xreg(inst.rs2).wrapping_add(zext_rs1 << 2),
+            ZbaOpcode::SH3ADD_UW => context.xreg(inst.rs2).wrapping_add(zext_rs1 << 3),
+            ZbaOpcode::ADD_UW => context.xreg(inst.rs2).wrapping_add(zext_rs1),
+            ZbaOpcode::SLLI_UW => zext_rs1 << inst.rs2,
+        };
+
+        if inst.rd != 0 {
+            context.set_xreg(inst.rd, result);
+        }
+        Ok(())
+    }
+
+    /// Zba has no CSRs.
+    fn csr(&mut self, _inst: &ZbaInstruction) -> EmulateResult {
+        unreachable!("Zba has no CSRs");
+    }
+
+    /// Zba has no CSRs.
+    fn csr_field(
+        &mut self,
+        _inst: &ZbaInstruction,
+        _write_to_csr_value: u64,
+        _read_csr_value: &mut u64,
+    ) {
+        unreachable!("Zba has no CSRs");
+    }
+}
+
+impl ExtensionModule for Zba {
+    fn names() -> &'static [&'static str] {
+        &["zba"]
+    }
+
+    fn init() {
+        ZBA_DATA.lock().get_or_init(Zba::new);
+    }
+
+    fn try_handle(inst_value: usize) -> bool {
+        let Some(inst) = ZbaInstruction::decode(inst_value) else {
+            return false;
+        };
+        let result = ZBA_DATA.lock().get_mut().unwrap().instruction(&inst);
+        if let Err(exception) = result {
+            exception.raise();
+        }
+        true
+    }
+}
@@ -0,0 +1,200 @@
+//! Emulation Zicfilp (Landing Pad)
+//! Ref: [https://github.com/riscv/riscv-cfi/releases/download/v1.0/riscv-cfi.pdf](https://github.com/riscv/riscv-cfi/releases/download/v1.0/riscv-cfi.pdf)
+//!
+//! `LPAD` is encoded as `AUIPC x0, label` and it is executed as a NOP by hardware without Zicfilp.
+//! So only instructions that reach hypervisor update or check the expected landing pad state (`ELP`):
+//! indirect jumps emulated by hypervisor set it, and the next trapped instruction must be `LPAD`.
+//!
+//! Indirect jumps and `LPAD` never trap on such hardware, so landing pads cannot be enforced.
+//! Therefore Zicfilp is not advertised to the guest and `LANDING_PAD` of SBI FWFT is not supported.
+
+use super::{EmulateExtension, EmulateResult, VsException};
+use crate::hart_local;
+
+use raki::{BaseIOpcode, COpcode, Instruction, OpcodeKind, ZicsrOpcode};
+use riscv::register::stval;
+
+/// Illegal instruction exception. (cause value)
+const ILLEGAL_INSTRUCTION: usize = 2;
+/// Software-check exception. (cause value)
+const SOFTWARE_CHECK_EXCEPTION: usize = 18;
+/// Landing pad fault. (tval value)
+const LANDING_PAD_FAULT: usize = 2;
+/// Encoding of `LPAD` except label. (`AUIPC x0, label`)
+const LPAD_ENCODING: usize = 0b0001_0111;
+/// Mask of `LPAD` encoding except label.
+const LPAD_MASK: usize = 0xfff;
+/// Register that holds the expected landing pad label. (x7)
+const LABEL_REG: usize = 7;
+
+/// Is the instruction `LPAD`?
+pub fn is_lpad(inst_value: usize) -> bool {
+    inst_value & LPAD_MASK == LPAD_ENCODING
+}
+
+/// Return software-check exception if a landing pad is expected but the instruction is not `LPAD`.
+pub fn check_landing_pad(inst_value: usize) -> EmulateResult {
+    hart_local()
+        .lock()
+        .get_mut()
+        .unwrap()
+        .guest_mut()
+        .context()
+        .landing_pad_mut()
+        .check(inst_value)
+}
+
+/// Landing pad state of a guest.
+///
+/// It is held by the guest `Context`, so `ELP` and `LPE` are never shared between guests.
+#[derive(Debug, Default)]
+pub struct LandingPad {
+    /// Is a landing pad expected? (`ELP`)
+    pub expected: bool,
+    /// Landing Pad Enable in henvcfg (for VS-mode)
+    pub henv_lpe: bool,
+    /// Landing Pad Enable in senvcfg (for VU-mode)
+    pub senv_lpe: bool,
+}
+
+impl LandingPad {
+    /// Return software-check exception if a landing pad is expected but the instruction is not `LPAD`.
+    fn check(&mut self, inst_value: usize) -> EmulateResult {
+        if self.expected && !is_lpad(inst_value) {
+            self.expected = false;
+            return Err(VsException::new(
+                SOFTWARE_CHECK_EXCEPTION,
+                LANDING_PAD_FAULT,
+            ));
+        }
+        Ok(())
+    }
+
+    /// Is landing pad enabled?
+    ///
+    /// Check corresponding `LPE` bit of xenvcfg.
+    fn is_lp_enable(&self, sstatus: usize) -> bool {
+        let spp = (sstatus >> 8) & 0x1;
+        if spp == 0 {
+            self.senv_lpe
+        } else {
+            self.henv_lpe
+        }
+    }
+
+    /// Emulate CSR field that already exists.
+    ///
+    /// It is called while `HART_DATA` is locked. (the state is a part of guest context)
+    pub fn csr_field(
+        &mut self,
+        inst: &Instruction,
+        write_to_csr_value: u64,
+        read_csr_value: &mut u64,
+    ) {
+        /// Register number of `Supervisor Environment Configuration Register`.
+        const CSR_SENVCFG: usize = 0x10a;
+
+        let csr_num = inst.rs2.unwrap();
+        if csr_num == CSR_SENVCFG {
+            // overwritten emulated csr field
+            *read_csr_value |= u64::from(self.senv_lpe) << 2;
+
+            // update emulated csr field
+            match inst.opc {
+                OpcodeKind::Zicsr(ZicsrOpcode::CSRRW | ZicsrOpcode::CSRRWI) => {
+                    self.senv_lpe = (write_to_csr_value >> 2) & 0x1 == 1;
+                }
+                OpcodeKind::Zicsr(ZicsrOpcode::CSRRS | ZicsrOpcode::CSRRSI) => {
+                    if (write_to_csr_value >> 2) & 0x1 == 1 {
+                        self.senv_lpe = true;
+                    }
+                }
+                OpcodeKind::Zicsr(ZicsrOpcode::CSRRC | ZicsrOpcode::CSRRCI) => {
+                    if (write_to_csr_value >> 2) & 0x1 == 1 {
+                        self.senv_lpe = false;
+                    }
+                }
+                _ => unreachable!(),
+            }
+        }
+    }
+}
+
+/// Emulator for Zicfilp extension
+///
+/// It has no state by itself: the landing pad state of the running guest is taken from its context.
+pub struct Zicfilp;
+
+impl EmulateExtension for Zicfilp {
+    /// Emulate `LPAD` and indirect jumps.
+    ///
+    /// `sepc` is updated by the indirect jumps themselves.
+    #[allow(
+        clippy::cast_possible_truncation,
+        clippy::cast_possible_wrap,
+        clippy::cast_sign_loss
+    )]
+    fn instruction(&mut self, inst: &Instruction) -> EmulateResult {
+        let mut hart_data = hart_local().lock();
+        let context = hart_data.get_mut().unwrap().guest_mut().context();
+        let sstatus = context.sstatus();
+
+        match inst.opc {
+            OpcodeKind::BaseI(BaseIOpcode::AUIPC) => {
+                if !core::mem::take(&mut context.landing_pad_mut().expected) {
+                    // fall-through `LPAD` is a NOP.
+                    return Ok(());
+                }
+
+                // label 0 matches any landing pad.
+                let label = inst.imm.unwrap() as u64 & 0xf_ffff;
+                if label != 0 && label != (context.xreg(LABEL_REG) >> 12) & 0xf_ffff {
+                    return Err(VsException::new(
+                        SOFTWARE_CHECK_EXCEPTION,
+                        LANDING_PAD_FAULT,
+                    ));
+                }
+            }
+            OpcodeKind::BaseI(BaseIOpcode::JALR) | OpcodeKind::C(COpcode::JR | COpcode::JALR) => {
+                let rs1 = inst.rs1.unwrap();
+                let offset = inst.imm.map_or(0, i64::from);
+                let target = (context.xreg(rs1) as i64).wrapping_add(offset) as usize & !1;
+                let link_reg = match inst.opc {
+                    OpcodeKind::BaseI(BaseIOpcode::JALR) => inst.rd.unwrap(),
+                    OpcodeKind::C(COpcode::JALR) => 1,
+                    _ => 0,
+                };
+                let inst_len = if inst.is_compressed { 2 } else { 4 };
+                if link_reg != 0 {
+                    context.set_xreg(link_reg, (context.sepc() + inst_len) as u64);
+                }
+                context.set_sepc(target);
+
+                // x1 and x5 are return, x7 is software guarded jump.
+                let landing_pad = context.landing_pad_mut();
+                if landing_pad.is_lp_enable(sstatus) && !matches!(rs1, 1 | 5 | LABEL_REG) {
+                    landing_pad.expected = true;
+                }
+            }
+            _ => return Err(VsException::new(ILLEGAL_INSTRUCTION, stval::read())),
+        }
+        Ok(())
+    }
+
+    /// Zicfilp has no CSRs.
+    fn csr(&mut self, _inst: &Instruction) -> EmulateResult {
+        unreachable!("Zicfilp has no CSRs");
+    }
+
+    /// Emulate CSR field that already exists.
+    fn csr_field(&mut self, inst: &Instruction, write_to_csr_value: u64, read_csr_value: &mut u64) {
+        hart_local()
+            .lock()
+            .get_mut()
+            .unwrap()
+            .guest_mut()
+            .context()
+            .landing_pad_mut()
+            .csr_field(inst, write_to_csr_value, read_csr_value);
+    }
+}
@@ -1,83 +1,100 @@
 //! Emulation Zicfiss (Shadow Stack)
 //! Ref: [https://github.com/riscv/riscv-cfi/releases/download/v1.0/riscv-cfi.pdf](https://github.com/riscv/riscv-cfi/releases/download/v1.0/riscv-cfi.pdf)
 
-use super::{pseudo_vs_exception, EmulateExtension, EmulatedCsr};
+use super::{EmulateExtension, EmulateResult, EmulatedCsr, VsException};
+use crate::hart_local;
 use crate::memmap::{
     page_table::{g_stage_trans_addr, vs_stage_trans_addr},
     GuestVirtualAddress,
 };
-use crate::HYPERVISOR_DATA;
 
-use core::cell::OnceCell;
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
 use raki::{Instruction, OpcodeKind, ZicfissOpcode, ZicsrOpcode};
-use spin::Mutex;
-
-/// Singleton for Zicfiss.
-/// TODO: change `OnceCell` to `LazyCell` when stable `LazyCell::force_mut`.
-pub static mut ZICFISS_DATA: Mutex<OnceCell<Zicfiss>> = Mutex::new(OnceCell::new());
 
 /// Software-check exception. (cause value)
 const SOFTWARE_CHECK_EXCEPTION: usize = 18;
+/// Illegal instruction exception.
+const ILLEGAL_INSTRUCTION: usize = 2;
+/// Store/AMO access fault
+const STORE_AMO_ACCESS_FAULT: usize = 7;
 /// Store/AMO page fault
 const STORE_AMO_PAGE_FAULT: usize = 15;
 /// Shadow stack fault. (tval value)
 const SHADOW_STACK_FAULT: usize = 3;
 
-/// Singleton for Zicfiss extension
-pub struct Zicfiss {
-    /// Shadow stack pointer
-    pub ssp: EmulatedCsr,
-    /// Shadow Stack Enable in henvcfg (for VS-mode)
-    pub henv_sse: bool,
-    /// Shadow Stack Enable in senvcfg (for VU-mode)
-    pub senv_sse: bool,
+/// Translate guest virtual address of shadow stack to host physical address.
+///
+/// Return store/AMO page fault if the translation fails.
+fn shadow_stack_hpa(gva: usize) -> Result<usize, VsException> {
+    vs_stage_trans_addr(GuestVirtualAddress(gva))
+        .and_then(g_stage_trans_addr)
+        .map(|hpa| hpa.0)
+        .map_err(|_| VsException::new(STORE_AMO_PAGE_FAULT, gva))
 }
 
-impl Zicfiss {
-    /// Constructor for `Zicfiss`.
-    pub fn new() -> Self {
-        Zicfiss {
-            ssp: EmulatedCsr(0),
-            henv_sse: false,
-            senv_sse: false,
-        }
+/// Atomically swap the value on shadow stack at `gva` with `value` and return the old value.
+///
+/// The old value of 32-bit swap is sign-extended.
+#[allow(
+    clippy::cast_possible_truncation,
+    clippy::cast_possible_wrap,
+    clippy::cast_sign_loss
+)]
+fn ss_amo_swap(gva: usize, value: u64, width: usize) -> Result<u64, VsException> {
+    // misaligned shadow stack access raises store/AMO access fault.
+    if gva % width != 0 {
+        return Err(VsException::new(STORE_AMO_ACCESS_FAULT, gva));
     }
 
-    /// Return host physical shadow stack pointer as `*mut usize`.
-    #[allow(clippy::similar_names, clippy::cast_possible_truncation)]
-    fn ssp_hp_ptr(&self) -> *mut usize {
-        if let Ok(gpa) = vs_stage_trans_addr(GuestVirtualAddress(self.ssp.0 as usize)) {
-            let hpa = g_stage_trans_addr(gpa).unwrap();
-            hpa.0 as *mut usize
+    let hpa = shadow_stack_hpa(gva)?;
+    unsafe {
+        if width == 4 {
+            let old = AtomicU32::from_ptr(hpa as *mut u32).swap(value as u32, Ordering::SeqCst);
+            Ok(i64::from(old as i32) as u64)
         } else {
-            unsafe {
-                HYPERVISOR_DATA.force_unlock();
-                ZICFISS_DATA.force_unlock();
-            }
-            pseudo_vs_exception(STORE_AMO_PAGE_FAULT, self.ssp.0 as usize);
+            Ok(AtomicU64::from_ptr(hpa as *mut u64).swap(value, Ordering::SeqCst))
         }
     }
+}
 
+/// Shadow stack state of a guest.
+///
+/// It is held by the guest `Context`, so guests never share `ssp`.
+#[derive(Debug, Default)]
+pub struct ShadowStack {
+    /// Shadow stack pointer
+    pub ssp: EmulatedCsr,
+    /// Shadow Stack Enable in henvcfg (for VS-mode)
+    pub henv_sse: bool,
+    /// Shadow Stack Enable in senvcfg (for VU-mode)
+    pub senv_sse: bool,
+}
+
+impl ShadowStack {
     /// Push value to shadow stack
-    pub fn ss_push(&mut self, value: usize) {
+    ///
+    /// `ssp` is not updated if the push faults.
+    #[allow(clippy::cast_possible_truncation)]
+    pub fn ss_push(&mut self, value: usize) -> EmulateResult {
+        let new_ssp = (self.ssp.0 as usize).wrapping_sub(core::mem::size_of::<usize>());
+        let push_ptr = shadow_stack_hpa(new_ssp)? as *mut usize;
         unsafe {
-            self.ssp = EmulatedCsr(
-                (self.ssp.0 as *const usize).byte_sub(core::mem::size_of::<usize>()) as u64,
-            );
-            self.ssp_hp_ptr().write_volatile(value);
+            push_ptr.write_volatile(value);
         }
+        self.ssp = EmulatedCsr(new_ssp as u64);
+        Ok(())
     }
 
     /// Pop value from shadow stack
-    pub fn ss_pop(&mut self) -> usize {
-        unsafe {
-            let pop_value = self.ssp_hp_ptr().read_volatile();
-            self.ssp = EmulatedCsr(
-                (self.ssp.0 as *const usize).byte_add(core::mem::size_of::<usize>()) as u64,
-            );
-
-            pop_value
-        }
+    ///
+    /// `ssp` is not updated if the pop faults.
+    #[allow(clippy::cast_possible_truncation)]
+    pub fn ss_pop(&mut self) -> Result<usize, VsException> {
+        let pop_ptr = shadow_stack_hpa(self.ssp.0 as usize)? as *const usize;
+        let pop_value = unsafe { pop_ptr.read_volatile() };
+        self.ssp =
+            EmulatedCsr((self.ssp.0 as usize).wrapping_add(core::mem::size_of::<usize>()) as u64);
+        Ok(pop_value)
     }
 
     /// Is shadow stack enabled?
@@ -91,145 +108,184 @@ impl Zicfiss {
             self.henv_sse
         }
     }
+
+    /// Emulate CSR field that already exists.
+    ///
+    /// It is called while `HART_DATA` is locked. (the state is a part of guest context)
+    /// Return true if `senvcfg.SSE` is newly enabled. (the caller checks the shadow stack page)
+    pub fn csr_field(
+        &mut self,
+        inst: &Instruction,
+        write_to_csr_value: u64,
+        read_csr_value: &mut u64,
+    ) -> bool {
+        /// Register number of `Supervisor Environment Configuration Register`.
+        const CSR_SENVCFG: usize = 0x10a;
+        /// Register number of `Hypervisor State Enable 0 Register`.
+        const CSR_HSTATEEN0: usize = 0x60c;
+        /// `ENVCFG` field of `hstateen0`.
+        const HSTATEEN0_ENVCFG: u64 = 1 << 62;
+
+        let was_enabled = self.senv_sse;
+        let csr_num = inst.rs2.unwrap();
+        if csr_num == CSR_HSTATEEN0 {
+            // `ENVCFG` reflects whether shadow stack is enabled in henvcfg (read only).
+            *read_csr_value =
+                (*read_csr_value & !HSTATEEN0_ENVCFG) | (u64::from(self.henv_sse) << 62);
+        }
+
+        if csr_num == CSR_SENVCFG {
+            // overwritten emulated csr field
+            *read_csr_value |= u64::from(self.senv_sse) << 3;
+
+            // update emulated csr field
+            match inst.opc {
+                OpcodeKind::Zicsr(
+                    ZicsrOpcode::CSRRW
+                    | ZicsrOpcode::CSRRS
+                    | ZicsrOpcode::CSRRWI
+                    | ZicsrOpcode::CSRRSI,
+                ) => {
+                    if (write_to_csr_value >> 3) & 0x1 == 1 {
+                        self.senv_sse = true;
+                    }
+                }
+                OpcodeKind::Zicsr(ZicsrOpcode::CSRRC | ZicsrOpcode::CSRRCI) => {
+                    if (write_to_csr_value >> 3) & 0x1 == 1 {
+                        self.senv_sse = false;
+                    }
+                }
+                _ => unreachable!(),
+            }
+        }
+
+        !was_enabled && self.senv_sse
+    }
 }
 
+/// Emulator for Zicfiss extension
+///
+/// It has no state by itself: the shadow stack of the running guest is taken from its context.
+pub struct Zicfiss;
+
 impl EmulateExtension for Zicfiss {
     /// Emulate Zicfiss instruction.
     #[allow(clippy::cast_possible_truncation)]
-    fn instruction(&mut self, inst: &Instruction) {
-        let mut context = unsafe { HYPERVISOR_DATA.lock() }
-            .get()
-            .unwrap()
-            .guest()
-            .context;
-        let sstatus = context.sstatus();
+    fn instruction(&mut self, inst: &Instruction) -> EmulateResult {
+        let mut hart_data = hart_local().lock();
+        let context = hart_data.get_mut().unwrap().guest_mut().context();
+        let is_ss_enable = context.shadow_stack().is_ss_enable(context.sstatus());
 
         match inst.opc {
             OpcodeKind::Zicfiss(ZicfissOpcode::SSPUSH) => {
-                if self.is_ss_enable(sstatus) {
+                if is_ss_enable {
                     let push_value = context.xreg(inst.rs2.unwrap());
-                    self.ss_push(push_value as usize);
+                    context.shadow_stack_mut().ss_push(push_value as usize)?;
                 }
             }
             OpcodeKind::Zicfiss(ZicfissOpcode::C_SSPUSH) => {
-                if self.is_ss_enable(sstatus) {
+                if is_ss_enable {
                     let push_value = context.xreg(inst.rd.unwrap());
-                    self.ss_push(push_value as usize);
+                    context.shadow_stack_mut().ss_push(push_value as usize)?;
                 }
             }
             OpcodeKind::Zicfiss(ZicfissOpcode::SSPOPCHK) => {
-                if self.is_ss_enable(sstatus) {
-                    let pop_value = self.ss_pop();
+                if is_ss_enable {
+                    let pop_value = context.shadow_stack_mut().ss_pop()?;
                     let expected_value = context.xreg(inst.rs1.unwrap()) as usize;
                     if pop_value != expected_value {
-                        unsafe {
-                            HYPERVISOR_DATA.force_unlock();
-                            ZICFISS_DATA.force_unlock();
-                        }
-                        pseudo_vs_exception(SOFTWARE_CHECK_EXCEPTION, SHADOW_STACK_FAULT)
+                        return Err(VsException::new(
+                            SOFTWARE_CHECK_EXCEPTION,
+                            SHADOW_STACK_FAULT,
+                        ));
                     }
                 }
             }
             OpcodeKind::Zicfiss(ZicfissOpcode::C_SSPOPCHK) => {
-                if self.is_ss_enable(sstatus) {
-                    let pop_value = self.ss_pop();
+                if is_ss_enable {
+                    let pop_value = context.shadow_stack_mut().ss_pop()?;
                     let expected_value = context.xreg(inst.rd.unwrap()) as usize;
                     if pop_value != expected_value {
-                        unsafe {
-                            HYPERVISOR_DATA.force_unlock();
-                            ZICFISS_DATA.force_unlock();
-                        }
-                        pseudo_vs_exception(SOFTWARE_CHECK_EXCEPTION, SHADOW_STACK_FAULT)
+                        return Err(VsException::new(
+                            SOFTWARE_CHECK_EXCEPTION,
+                            SHADOW_STACK_FAULT,
+                        ));
                     }
                 }
             }
             OpcodeKind::Zicfiss(ZicfissOpcode::SSRDP) => {
-                if self.is_ss_enable(sstatus) {
-                    context.set_xreg(inst.rd.unwrap(), self.ssp.0);
+                if is_ss_enable {
+                    let ssp = context.shadow_stack().ssp.bits();
+                    context.set_xreg(inst.rd.unwrap(), ssp);
                 } else {
                     context.set_xreg(inst.rd.unwrap(), 0);
                 }
             }
-            OpcodeKind::Zicfiss(ZicfissOpcode::SSAMOSWAP_W | ZicfissOpcode::SSAMOSWAP_D) => todo!(),
+            OpcodeKind::Zicfiss(ZicfissOpcode::SSAMOSWAP_W | ZicfissOpcode::SSAMOSWAP_D) => {
+                // unlike other instructions, it is not a no-op if shadow stack is disabled.
+                if !is_ss_enable {
+                    return Err(VsException::new(ILLEGAL_INSTRUCTION, 0));
+                }
+
+                let width = match inst.opc {
+                    OpcodeKind::Zicfiss(ZicfissOpcode::SSAMOSWAP_W) => 4,
+                    _ => 8,
+                };
+                let addr = context.xreg(inst.rs1.unwrap()) as usize;
+                let value = context.xreg(inst.rs2.unwrap());
+                let old_value = ss_amo_swap(addr, value, width)?;
+                context.set_xreg(inst.rd.unwrap(), old_value);
+            }
             _ => todo!(),
         }
+        Ok(())
     }
 
     /// Emulate Zicfiss CSRs access.
-    fn csr(&mut self, inst: &Instruction) {
+    #[allow(clippy::cast_possible_truncation)]
+    fn csr(&mut self, inst: &Instruction) -> EmulateResult {
         /// Register number of `Shadow Stack Pointer`.
         const CSR_SSP: usize = 0x11;
 
-        let hypervisor_data = unsafe { HYPERVISOR_DATA.lock() };
-        let mut context = hypervisor_data.get().unwrap().guest().context;
+        let mut hart_data = hart_local().lock();
+        let context = hart_data.get_mut().unwrap().guest_mut().context();
 
         let csr_num = inst.rs2.unwrap();
         match csr_num {
-            CSR_SSP => match inst.opc {
-                OpcodeKind::Zicsr(ZicsrOpcode::CSRRW) => {
-                    let rs1 = context.xreg(inst.rs1.unwrap());
-                    context.set_xreg(inst.rd.unwrap(), self.ssp.bits());
-                    self.ssp.write(rs1);
-                }
-                OpcodeKind::Zicsr(ZicsrOpcode::CSRRS) => {
-                    let rs1 = context.xreg(inst.rs1.unwrap());
-                    context.set_xreg(inst.rd.unwrap(), self.ssp.bits());
-                    self.ssp.set(rs1);
-                }
-                OpcodeKind::Zicsr(ZicsrOpcode::CSRRC) => {
-                    let rs1 = context.xreg(inst.rs1.unwrap());
-                    context.set_xreg(inst.rd.unwrap(), self.ssp.bits());
-                    self.ssp.clear(rs1);
-                }
-                OpcodeKind::Zicsr(ZicsrOpcode::CSRRWI) => {
-                    context.set_xreg(inst.rd.unwrap(), self.ssp.bits());
-                    self.ssp.write(inst.rs1.unwrap() as u64);
+            CSR_SSP => {
+                let rs1 = context.xreg(inst.rs1.unwrap());
+                context.set_xreg(inst.rd.unwrap(), context.shadow_stack().ssp.bits());
+                let ssp = &mut context.shadow_stack_mut().ssp;
+                match inst.opc {
+                    OpcodeKind::Zicsr(ZicsrOpcode::CSRRW) => ssp.write(rs1),
+                    OpcodeKind::Zicsr(ZicsrOpcode::CSRRS) => ssp.set(rs1),
+                    OpcodeKind::Zicsr(ZicsrOpcode::CSRRC) => ssp.clear(rs1),
+                    OpcodeKind::Zicsr(ZicsrOpcode::CSRRWI) => ssp.write(inst.rs1.unwrap() as u64),
+                    OpcodeKind::Zicsr(ZicsrOpcode::CSRRSI) => ssp.set(inst.rs1.unwrap() as u64),
+                    OpcodeKind::Zicsr(ZicsrOpcode::CSRRCI) => ssp.clear(inst.rs1.unwrap() as u64),
+                    _ => unreachable!(),
                 }
-                OpcodeKind::Zicsr(ZicsrOpcode::CSRRSI) => {
-                    context.set_xreg(inst.rd.unwrap(), self.ssp.bits());
-                    self.ssp.set(inst.rs1.unwrap() as u64);
-                }
-                OpcodeKind::Zicsr(ZicsrOpcode::CSRRCI) => {
-                    context.set_xreg(inst.rd.unwrap(), self.ssp.bits());
-                    self.ssp.clear(inst.rs1.unwrap() as u64);
-                }
-                _ => unreachable!(),
-            },
+            }
             unsupported_csr_num => {
                 unimplemented!("unsupported CSRs: {unsupported_csr_num:#x}")
             }
         }
+        Ok(())
     }
 
     /// Emulate CSR field that already exists.
     fn csr_field(&mut self, inst: &Instruction, write_to_csr_value: u64, read_csr_value: &mut u64) {
-        /// Register number of `Supervisor Environment Configuration Register`.
-        const CSR_SENVCFG: usize = 0x10a;
+        let mut hart_data = hart_local().lock();
+        let guest = hart_data.get_mut().unwrap().guest_mut();
+        let enabled =
+            guest
+                .context()
+                .shadow_stack_mut()
+                .csr_field(inst, write_to_csr_value, read_csr_value);
 
-        let csr_num = inst.rs2.unwrap();
-        if csr_num == CSR_SENVCFG {
-            // overwritten emulated csr field
-            *read_csr_value |= u64::from(self.senv_sse) << 3;
-
-            // update emulated csr field
-            match inst.opc {
-                OpcodeKind::Zicsr(
-                    ZicsrOpcode::CSRRW
-                    | ZicsrOpcode::CSRRS
-                    | ZicsrOpcode::CSRRWI
-                    | ZicsrOpcode::CSRRSI,
-                ) => {
-                    if (write_to_csr_value >> 3) & 0x1 == 1 {
-                        self.senv_sse = true;
-                    }
-                }
-                OpcodeKind::Zicsr(ZicsrOpcode::CSRRC | ZicsrOpcode::CSRRCI) => {
-                    if (write_to_csr_value >> 3) & 0x1 == 1 {
-                        self.senv_sse = false;
-                    }
-                }
-                _ => unreachable!(),
-            }
+        // shadow stack is not enabled on the page that cannot be used as shadow stack.
+        if enabled && !guest.map_next_shadow_stack_push() {
+            guest.context().shadow_stack_mut().senv_sse = false;
         }
     }
 }
@@ -0,0 +1,155 @@
+//! Emulation Zicntr and Zihpm (Base and Hardware Performance Counters)
+//! Ref: [https://github.com/riscv/riscv-isa-manual/releases/download/20240411/unpriv-isa-asciidoc.pdf](https://github.com/riscv/riscv-isa-manual/releases/download/20240411/unpriv-isa-asciidoc.pdf)
+//!
+//! Counters that are not implemented by the hardware (or not enabled by M-mode) raise illegal instruction.
+//! They are emulated as read-only counters:
+//! `cycle` and `instret` exclude the time spent in hypervisor, `time` is derived from CLINT and
+//! `hpmcounter3`-`hpmcounter31` are zero.
+//! If `cycle` and `instret` trap in HS-mode as well, CLINT mtime is returned as a monotonic value.
+
+use super::{EmulateExtension, EmulateResult, ExtensionModule, VsException};
+use crate::guest::context::{hs_counters_readable, pmu_context};
+use crate::{hart_local, DEVICES};
+
+use core::arch::asm;
+use core::cell::OnceCell;
+use core::ops::RangeInclusive;
+use spin::Mutex;
+
+/// Singleton for Zicntr.
+/// TODO: change `OnceCell` to `LazyCell` when stable `LazyCell::force_mut`.
+pub static ZICNTR_DATA: Mutex<OnceCell<Zicntr>> = Mutex::new(OnceCell::new());
+
+/// Opcode of CSR instructions. (SYSTEM)
+const OPCODE_SYSTEM: usize = 0b111_0011;
+/// CSR numbers of unprivileged counters. (`cycle` - `hpmcounter31`)
+const COUNTER_CSRS: RangeInclusive<usize> = 0xc00..=0xc1f;
+/// CSR number of `cycle`.
+const CSR_CYCLE: usize = 0xc00;
+/// CSR number of `time`.
+const CSR_TIME: usize = 0xc01;
+/// CSR number of `instret`.
+const CSR_INSTRET: usize = 0xc02;
+/// Illegal instruction exception.
+const ILLEGAL_INSTRUCTION: usize = 2;
+
+/// Decoded access to a counter CSR.
+///
+/// `raki` decodes `rdcycle`, `rdtime` and `rdinstret` as Zicntr without rs1,
+/// so it is decoded here to detect writes.
+#[derive(Debug)]
+pub struct CounterAccess {
+    /// Instruction value. (stored to vstval on illegal write)
+    inst_value: usize,
+    /// CSR number
+    csr_num: usize,
+    /// Destination register
+    rd: usize,
+    /// Does the instruction write the CSR?
+    is_write: bool,
+}
+
+impl CounterAccess {
+    /// Decode CSR instruction that accesses a counter.
+    ///
+    /// Return `None` if `inst_value` is not an access to counter CSRs.
+    pub fn decode(inst_value: usize) -> Option<Self> {
+        let funct3 = (inst_value >> 12) & 0x7;
+        let csr_num = (inst_value >> 20) & 0xfff;
+        if inst_value & 0x7f != OPCODE_SYSTEM
+            || matches!(funct3, 0b000 | 0b100)
+            || !COUNTER_CSRS.contains(&csr_num)
+        {
+            return None;
+        }
+
+        // CSRRW(I) always writes, CSRRS(I) and CSRRC(I) write unless rs1 (uimm) is zero.
+        let rs1 = (inst_value >> 15) & 0x1f;
+        Some(CounterAccess {
+            inst_value,
+            csr_num,
+            rd: (inst_value >> 7) & 0x1f,
+            is_write: funct3 & 0b11 == 0b01 || rs1 != 0,
+        })
+    }
+}
+
+/// Singleton for Zicntr and Zihpm extension
+pub struct Zicntr;
+
+impl Zicntr {
+    /// Constructor for `Zicntr`.
+    pub fn new() -> Self {
+        Zicntr
+    }
+}
+
+impl EmulateExtension<CounterAccess> for Zicntr {
+    /// Zicntr has no instructions other than CSR accesses.
+    fn instruction(&mut self, inst: &CounterAccess) -> EmulateResult {
+        self.csr(inst)
+    }
+
+    /// Emulate reading counter CSRs.
+    ///
+    /// Counters are read-only, so writes raise illegal instruction to the guest.
+    fn csr(&mut self, inst: &CounterAccess) -> EmulateResult {
+        if inst.is_write {
+            return Err(VsException::new(ILLEGAL_INSTRUCTION, inst.inst_value));
+        }
+
+        let mtime = || DEVICES.lock().get().unwrap().clint.read_mtime();
+        let value = match inst.csr_num {
+            CSR_CYCLE if hs_counters_readable() => pmu_context().cycle(),
+            CSR_INSTRET if hs_counters_readable() => pmu_context().instret(),
+            CSR_CYCLE | CSR_INSTRET => mtime(),
+            CSR_TIME => {
+                let htimedelta: u64;
+                unsafe {
+                    asm!("csrr {0}, htimedelta", out(reg) htimedelta);
+                }
+                mtime().wrapping_add(htimedelta)
+            }
+            // hpmcounter3 - hpmcounter31
+            _ => 0,
+        };
+
+        let mut hart_data = hart_local().lock();
+        let context = hart_data.get_mut().unwrap().guest_mut().context();
+        if inst.rd != 0 {
+            context.set_xreg(inst.rd, value);
+        }
+        Ok(())
+    }
+
+    /// Counters have no fields emulated over existing CSRs.
+    fn csr_field(
+        &mut self,
+        _inst: &CounterAccess,
+        _write_to_csr_value: u64,
+        _read_csr_value: &mut u64,
+    ) {
+        unreachable!("Zicntr has no emulated CSR fields");
+    }
+}
+
+impl ExtensionModule for Zicntr {
+    fn names() -> &'static [&'static str] {
+        &["zicntr", "zihpm"]
+    }
+
+    fn init() {
+        ZICNTR_DATA.lock().get_or_init(Zicntr::new);
+    }
+
+    fn try_handle(inst_value: usize) -> bool {
+        let Some(inst) = CounterAccess::decode(inst_value) else {
+            return false;
+        };
+        let result = ZICNTR_DATA.lock().get_mut().unwrap().csr(&inst);
+        if let Err(exception) = result {
+            exception.raise();
+        }
+        true
+    }
+}
@@ -0,0 +1,135 @@
+//! Emulation Zicond (Integer Conditional Operations)
+//! Ref: [https://github.com/riscv/riscv-isa-manual/releases/download/20240411/unpriv-isa-asciidoc.pdf](https://github.com/riscv/riscv-isa-manual/releases/download/20240411/unpriv-isa-asciidoc.pdf)
+
+use super::{EmulateExtension, EmulateResult, ExtensionModule};
+use crate::hart_local;
+
+use core::cell::OnceCell;
+use spin::Mutex;
+
+/// Singleton for Zicond.
+/// TODO: change `OnceCell` to `LazyCell` when stable `LazyCell::force_mut`.
+pub static ZICOND_DATA: Mutex<OnceCell<Zicond>> = Mutex::new(OnceCell::new());
+
+/// Opcode of Zicond instructions. (OP)
+const OPCODE_OP: usize = 0b011_0011;
+/// funct7 of Zicond instructions.
+const FUNCT7_CZERO: usize = 0b000_0111;
+/// funct3 of `CZERO.EQZ`.
+const FUNCT3_CZERO_EQZ: usize = 0b101;
+/// funct3 of `CZERO.NEZ`.
+const FUNCT3_CZERO_NEZ: usize = 0b111;
+
+/// Zicond instructions.
+#[allow(non_camel_case_types)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ZicondOpcode {
+    /// Moves zero to rd if rs2 is equal to zero, otherwise moves rs1 to rd.
+    CZERO_EQZ,
+    /// Moves zero to rd if rs2 is nonzero, otherwise moves rs1 to rd.
+    CZERO_NEZ,
+}
+
+/// Decoded Zicond instruction.
+///
+/// `raki` does not support Zicond, so it is decoded here.
+#[derive(Debug)]
+pub struct ZicondInstruction {
+    /// Opcode
+    opc: ZicondOpcode,
+    /// Destination register
+    rd: usize,
+    /// Source register 1
+    rs1: usize,
+    /// Source register 2
+    rs2: usize,
+}
+
+impl ZicondInstruction {
+    /// Decode Zicond instruction.
+    ///
+    /// Return `None` if `inst_value` is not a Zicond instruction.
+    pub fn decode(inst_value: usize) -> Option<Self> {
+        if inst_value & 0x7f != OPCODE_OP || (inst_value >> 25) & 0x7f != FUNCT7_CZERO {
+            return None;
+        }
+
+        let opc = match (inst_value >> 12) & 0x7 {
+            FUNCT3_CZERO_EQZ => ZicondOpcode::CZERO_EQZ,
+            FUNCT3_CZERO_NEZ => ZicondOpcode::CZERO_NEZ,
+            _ => return None,
+        };
+
+        Some(ZicondInstruction {
+            opc,
+            rd: (inst_value >> 7) & 0x1f,
+            rs1: (inst_value >> 15) & 0x1f,
+            rs2: (inst_value >> 20) & 0x1f,
+        })
+    }
+}
+
+/// Singleton for Zicond extension
+pub struct Zicond;
+
+impl Zicond {
+    /// Constructor for `Zicond`.
+    pub fn new() -> Self {
+        Zicond
+    }
+}
+
+impl EmulateExtension<ZicondInstruction> for Zicond {
+    /// Emulate Zicond instruction.
+    fn instruction(&mut self, inst: &ZicondInstruction) -> EmulateResult {
+        let mut hart_data = hart_local().lock();
+        let context = hart_data.get_mut().unwrap().guest_mut().context();
+        let condition = context.xreg(inst.rs2);
+        let result = match inst.opc {
+            ZicondOpcode::CZERO_EQZ if condition == 0 => 0,
+            ZicondOpcode::CZERO_NEZ if condition != 0 => 0,
+            _ => context.xreg(inst.rs1),
+        };
+
+        if inst.rd != 0 {
+            context.set_xreg(inst.rd, result);
+        }
+        Ok(())
+    }
+
+    /// Zicond has no CSRs.
+    fn csr(&mut self, _inst: &ZicondInstruction) -> EmulateResult {
+        unreachable!("Zicond has no CSRs");
+    }
+
+    /// Zicond has no CSRs.
+    fn csr_field(
+        &mut self,
+        _inst: &ZicondInstruction,
+        _write_to_csr_value: u64,
+        _read_csr_value: &mut u64,
+    ) {
+        unreachable!("Zicond has no CSRs");
+    }
+}
+
+impl ExtensionModule for Zicond {
+    fn names() -> &'static [&'static str] {
+        &["zicond"]
+    }
+
+    fn init() {
+        ZICOND_DATA.lock().get_or_init(Zicond::new);
+    }
+
+    fn try_handle(inst_value: usize) -> bool {
+        let Some(inst) = ZicondInstruction::decode(inst_value) else {
+            return false;
+        };
+        let result = ZICOND_DATA.lock().get_mut().unwrap().instruction(&inst);
+        if let Err(exception) = result {
+            exception.raise();
+        }
+        true
+    }
+}
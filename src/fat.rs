@@ -0,0 +1,212 @@
+//! Minimal FAT32 reader to load guest image from SD card.
+//!
+//! Only the first FAT32 partition in MBR and 8.3 file names in root directory are supported.
+//! Ref: Microsoft Extensible Firmware Initiative FAT32 File System Specification (fatgen103)
+
+use crate::memmap::page_table::constants::PAGE_SIZE;
+use crate::PageBlock;
+
+/// Size of a sector.
+pub const SECTOR_SIZE: usize = 512;
+/// Size of a directory entry.
+const DIR_ENTRY_SIZE: usize = 32;
+
+/// Offset of partition table in MBR.
+const MBR_PARTITION_TABLE: usize = 0x1be;
+/// Size of a partition table entry.
+const MBR_PARTITION_ENTRY_SIZE: usize = 16;
+/// Number of partition table entries.
+const MBR_PARTITION_NUM: usize = 4;
+/// Partition types of FAT32. (CHS, LBA)
+const PARTITION_TYPE_FAT32: [u8; 2] = [0x0b, 0x0c];
+
+/// Mask of valid bits in FAT32 entry.
+const FAT_ENTRY_MASK: u32 = 0x0fff_ffff;
+/// Minimum value of end of cluster chain.
+const FAT_END_OF_CHAIN: u32 = 0x0fff_fff8;
+
+/// Attribute of long file name entry.
+const ATTR_LONG_NAME: u8 = 0x0f;
+/// Attribute of volume label or directory.
+const ATTR_VOLUME_ID_OR_DIRECTORY: u8 = 0x18;
+/// First byte of deleted entry.
+const ENTRY_DELETED: u8 = 0xe5;
+
+/// Device that can be read in sectors.
+pub trait BlockDevice {
+    /// Read sectors starting at `lba` to `buf`. (`buf.len()` is a multiple of `SECTOR_SIZE`)
+    fn read_blocks(&self, lba: usize, buf: &mut [u8]);
+}
+
+/// Sector buffer for metadata. (aligned for DMA)
+#[repr(C, align(8))]
+struct Sector([u8; SECTOR_SIZE]);
+
+impl Sector {
+    /// Read little-endian u16 at `offset`.
+    fn u16(&self, offset: usize) -> u16 {
+        u16::from_le_bytes(self.0[offset..offset + 2].try_into().unwrap())
+    }
+
+    /// Read little-endian u32 at `offset`.
+    fn u32(&self, offset: usize) -> u32 {
+        u32::from_le_bytes(self.0[offset..offset + 4].try_into().unwrap())
+    }
+}
+
+/// FAT32 file system.
+pub struct Fat32<'a, D: BlockDevice> {
+    /// Block device containing the file system.
+    device: &'a D,
+    /// Sectors per cluster.
+    sectors_per_cluster: usize,
+    /// LBA of the first FAT.
+    fat_lba: usize,
+    /// LBA of the data region. (cluster 2)
+    data_lba: usize,
+    /// First cluster of root directory.
+    root_cluster: u32,
+}
+
+impl<'a, D: BlockDevice> Fat32<'a, D> {
+    /// Open the first FAT32 partition in MBR.
+    ///
+    /// Return `None` if no FAT32 partition is found.
+    pub fn open_first_partition(device: &'a D) -> Option<Self> {
+        let mut sector = Sector([0; SECTOR_SIZE]);
+        device.read_blocks(0, &mut sector.0);
+        if sector.u16(0x1fe) != 0xaa55 {
+            return None;
+        }
+
+        let partition_lba = (0..MBR_PARTITION_NUM)
+            .map(|index| MBR_PARTITION_TABLE + index * MBR_PARTITION_ENTRY_SIZE)
+            .find(|&entry| PARTITION_TYPE_FAT32.contains(&sector.0[entry + 4]))
+            .map(|entry| sector.u32(entry + 8) as usize)?;
+
+        // BIOS parameter block
+        device.read_blocks(partition_lba, &mut sector.0);
+        if sector.u16(0x1fe) != 0xaa55 || usize::from(sector.u16(0x0b)) != SECTOR_SIZE {
+            return None;
+        }
+        let sectors_per_cluster = usize::from(sector.0[0x0d]);
+        let reserved_sectors = usize::from(sector.u16(0x0e));
+        let fat_num = usize::from(sector.0[0x10]);
+        let fat_size = sector.u32(0x24) as usize;
+        let root_cluster = sector.u32(0x2c);
+        if sectors_per_cluster == 0 || fat_size == 0 {
+            return None;
+        }
+
+        let fat_lba = partition_lba + reserved_sectors;
+        Some(Fat32 {
+            device,
+            sectors_per_cluster,
+            fat_lba,
+            data_lba: fat_lba + fat_num * fat_size,
+            root_cluster,
+        })
+    }
+
+    /// Return size of a cluster in bytes.
+    fn cluster_size(&self) -> usize {
+        self.sectors_per_cluster * SECTOR_SIZE
+    }
+
+    /// Return LBA of `cluster`.
+    fn cluster_lba(&self, cluster: u32) -> usize {
+        self.data_lba + (cluster as usize - 2) * self.sectors_per_cluster
+    }
+
+    /// Return next cluster in the chain. (`None` if `cluster` is the last one)
+    fn next_cluster(&self, cluster: u32) -> Option<u32> {
+        let entry_offset = cluster as usize * 4;
+        let mut sector = Sector([0; SECTOR_SIZE]);
+        self.device
+            .read_blocks(self.fat_lba + entry_offset / SECTOR_SIZE, &mut sector.0);
+
+        let next = sector.u32(entry_offset % SECTOR_SIZE) & FAT_ENTRY_MASK;
+        (2..FAT_END_OF_CHAIN).contains(&next).then_some(next)
+    }
+
+    /// Find the file in root directory and return its first cluster and size.
+    fn find_root_entry(&self, short_name: &[u8; 11]) -> Option<(u32, usize)> {
+        let mut sector = Sector([0; SECTOR_SIZE]);
+        let mut cluster = self.root_cluster;
+        loop {
+            for lba in
+                self.cluster_lba(cluster)..self.cluster_lba(cluster) + self.sectors_per_cluster
+            {
+                self.device.read_blocks(lba, &mut sector.0);
+                for entry in (0..SECTOR_SIZE).step_by(DIR_ENTRY_SIZE) {
+                    let attr = sector.0[entry + 0x0b];
+                    // end of directory
+                    if sector.0[entry] == 0 {
+                        return None;
+                    }
+                    // deleted, long file name, volume label or directory
+                    if sector.0[entry] == ENTRY_DELETED
+                        || attr == ATTR_LONG_NAME
+                        || attr & ATTR_VOLUME_ID_OR_DIRECTORY != 0
+                    {
+                        continue;
+                    }
+
+                    if sector.0[entry..entry + 11] == short_name[..] {
+                        let first_cluster = (u32::from(sector.u16(entry + 0x14)) << 16)
+                            | u32::from(sector.u16(entry + 0x1a));
+                        return Some((first_cluster, sector.u32(entry + 0x1c) as usize));
+                    }
+                }
+            }
+            cluster = self.next_cluster(cluster)?;
+        }
+    }
+
+    /// Read the file in root directory to newly allocated pages.
+    ///
+    /// Return `None` if the file is not found.
+    pub fn read_file(&self, name: &str) -> Option<&'static [u8]> {
+        let (first_cluster, file_size) = self.find_root_entry(&short_name(name)?)?;
+        if file_size == 0 {
+            return Some(&[]);
+        }
+
+        // allocate whole clusters since they are read at once.
+        let buffer_size = file_size.next_multiple_of(self.cluster_size());
+        let buffer = unsafe {
+            core::slice::from_raw_parts_mut(
+                PageBlock::alloc_n(buffer_size.div_ceil(PAGE_SIZE)).raw() as *mut u8,
+                buffer_size,
+            )
+        };
+
+        let mut cluster = first_cluster;
+        for chunk in buffer.chunks_mut(self.cluster_size()) {
+            self.device.read_blocks(self.cluster_lba(cluster), chunk);
+            if let Some(next) = self.next_cluster(cluster) {
+                cluster = next;
+            } else {
+                break;
+            }
+        }
+
+        Some(&buffer[..file_size])
+    }
+}
+
+/// Convert file name to 8.3 format in directory entry. (e.g. `kernel.elf` -> `KERNEL  ELF`)
+///
+/// Return `None` if the name cannot be represented in 8.3 format.
+fn short_name(name: &str) -> Option<[u8; 11]> {
+    let (base, extension) = name.split_once('.').unwrap_or((name, ""));
+    if base.is_empty() || base.len() > 8 || extension.len() > 3 {
+        return None;
+    }
+
+    let mut short_name = [b' '; 11];
+    short_name[..base.len()].copy_from_slice(base.as_bytes());
+    short_name[8..8 + extension.len()].copy_from_slice(extension.as_bytes());
+    short_name.make_ascii_uppercase();
+    Some(short_name)
+}
@@ -1,20 +1,82 @@
 //! Guest data of each HARTs.
 
 pub mod context;
-
+pub mod dtb;
+pub mod image;
+pub mod layout;
+pub mod scheduler;
+pub mod snapshot;
+pub mod watchdog;
+
+use crate::h_extension::csrs::hgatp;
+use crate::h_extension::instruction::{hfence_gvma, hfence_gvma_all};
 use crate::memmap::page_table::sv39x4::FIRST_LV_PAGE_TABLE_LEN;
 use crate::memmap::{
-    constant::guest_memory,
+    constant::{MAX_HART_NUM, STACK_SIZE_PER_HART},
+    page_allocator::PAGE_ALLOCATOR,
     page_table,
     page_table::{constants::PAGE_SIZE, PageTableEntry, PteFlag},
-    GuestPhysicalAddress, HostPhysicalAddress, MemoryMap,
+    GuestPhysicalAddress, GuestVirtualAddress, HostPhysicalAddress, MemoryMap,
 };
-use crate::{PageBlock, GUEST_INITRD};
+use crate::DEVICES;
 use context::{Context, ContextData};
+use scheduler::{HostTime, SuspendedState, TIMER_DISABLED};
 
+use alloc::vec::Vec;
 use core::ops::Range;
 use elf::{endian::AnyEndian, ElfBytes};
 
+/// Initial time offset (ns) of guest RTC from host RTC.
+///
+/// It is configured by `HIKAMI_RTC_OFFSET_NS` at build time. (default: 0)
+#[allow(clippy::unreadable_literal)]
+const INITIAL_RTC_OFFSET: i64 = include!(concat!(env!("OUT_DIR"), "/rtc_offset.rs"));
+
+/// Load offset of flat binary guest kernel from the base address of the dram.
+///
+/// It is configured by `HIKAMI_FLAT_BINARY_OFFSET` at build time. (default: 0)
+pub const FLAT_BINARY_OFFSET: usize = include!(concat!(env!("OUT_DIR"), "/flat_binary_offset.rs"));
+
+/// Max size of memory that a guest can add by `Guest::extend_memory`.
+///
+/// It is configured by `HIKAMI_MAX_HOTPLUG_SIZE` at build time. (default: 64 MiB)
+#[allow(clippy::unreadable_literal)]
+const MAX_HOTPLUG_SIZE: usize = include!(concat!(env!("OUT_DIR"), "/max_hotplug_size.rs"));
+
+/// Error of `Guest::extend_memory`.
+#[derive(Debug)]
+pub enum HotplugError {
+    /// The size exceeds `MAX_HOTPLUG_SIZE` or the address space.
+    ExceedLimit,
+    /// A part of the range is already mapped. (e.g. devices)
+    AlreadyMapped,
+    /// Page frames cannot be allocated from the hypervisor heap.
+    OutOfMemory,
+}
+
+/// Check that `frame` to back guest memory does not overlap HS-mode stack of any HART.
+///
+/// # Panics
+/// It panics if it overlaps. (heap and stack regions in the linker script are broken)
+fn guest_frame(frame: HostPhysicalAddress) -> HostPhysicalAddress {
+    let stack_end = HostPhysicalAddress(core::ptr::addr_of!(crate::_stack_start) as usize);
+    let stack_start = stack_end - MAX_HART_NUM * STACK_SIZE_PER_HART;
+    assert!(
+        frame + PAGE_SIZE <= stack_start || stack_end <= frame,
+        "guest memory frame {frame:#x} overlaps hypervisor stacks {stack_start:#x}..{stack_end:#x}"
+    );
+    frame
+}
+
+/// Invalidate IOMMU translation cache for the GPA range after remapping.
+///
+/// Translation cache of HART must be flushed by `hfence.gvma` separately.
+fn flush_iommu_gpa_range(gpa_range: &Range<GuestPhysicalAddress>) {
+    if let Some(pci) = &DEVICES.lock().get().unwrap().pci {
+        pci.flush_iommu_gpa_range(gpa_range.start, gpa_range.end.raw() - gpa_range.start.raw());
+    }
+}
+
 /// Guest Information
 #[derive(Debug)]
 pub struct Guest {
@@ -28,8 +90,24 @@ pub struct Guest {
     stack_top_addr: HostPhysicalAddress,
     /// Allocated memory region
     memory_region: Range<GuestPhysicalAddress>,
+    /// Time offset (ns) of guest RTC from host RTC
+    rtc_offset: i64,
+    /// Read-only executable regions. (text segments of guest kernel)
+    ///
+    /// They are mapped without write permission in G-stage and stores to them are emulated.
+    text_regions: Vec<Range<GuestPhysicalAddress>>,
+    /// Text pages made writable by `grant_text_write`. (write protected again by `restore_text_exec`)
+    writable_text_pages: Vec<GuestPhysicalAddress>,
+    /// Pages added by `extend_memory` in GPA order. (owned by the guest to be freed by `shrink_memory`)
+    hotplugged_pages: Vec<HostPhysicalAddress>,
     /// Guest context data
-    pub context: Context,
+    context: Context,
+    /// SBI timer of the guest. (guest time)
+    timer_deadline: u64,
+    /// Saved state while other guest is running on the HART. (see `scheduler`)
+    suspended: Option<SuspendedState>,
+    /// Host time at the last context switch. (see `scheduler`)
+    switched_at: HostTime,
 }
 
 impl Guest {
@@ -40,19 +118,17 @@ impl Guest {
     pub fn new(
         hart_id: usize,
         root_page_table: &'static [PageTableEntry; FIRST_LV_PAGE_TABLE_LEN],
-        guest_dtb: &'static [u8; include_bytes!("../guest_image/guest.dtb").len()],
+        guest_dtb: &[u8],
     ) -> Self {
         // calculate guest memory region
-        let guest_memory_begin: GuestPhysicalAddress =
-            guest_memory::DRAM_BASE + (hart_id + 1) * guest_memory::DRAM_SIZE_PER_GUEST;
-        let memory_region =
-            guest_memory_begin..guest_memory_begin + guest_memory::DRAM_SIZE_PER_GUEST;
+        let memory_region = layout::guest_memory_layout().memory_region(hart_id);
 
-        let stack_top_addr = HostPhysicalAddress(core::ptr::addr_of!(crate::_stack_start) as usize);
+        let stack_top_addr = HostPhysicalAddress(core::ptr::addr_of!(crate::_stack_start) as usize)
+            - hart_id * STACK_SIZE_PER_HART;
         let page_table_addr = HostPhysicalAddress(root_page_table.as_ptr() as usize);
 
         // init page table
-        page_table::sv39x4::initialize_page_table(page_table_addr);
+        page_table::g_stage_initialize_page_table(page_table_addr);
 
         // load guest dtb to memory
         let dtb_addr = Self::map_guest_dtb(hart_id, page_table_addr, guest_dtb);
@@ -63,7 +139,14 @@ impl Guest {
             dtb_addr,
             stack_top_addr,
             memory_region,
+            rtc_offset: INITIAL_RTC_OFFSET,
+            text_regions: Vec::new(),
+            writable_text_pages: Vec::new(),
+            hotplugged_pages: Vec::new(),
             context: Context::new(stack_top_addr - core::mem::size_of::<ContextData>()),
+            timer_deadline: TIMER_DISABLED,
+            suspended: None,
+            switched_at: HostTime::default(),
         }
     }
 
@@ -73,34 +156,35 @@ impl Guest {
     fn map_guest_dtb(
         hart_id: usize,
         page_table_addr: HostPhysicalAddress,
-        guest_dtb: &'static [u8; include_bytes!("../guest_image/guest.dtb").len()],
+        guest_dtb: &[u8],
     ) -> GuestPhysicalAddress {
         use PteFlag::{Accessed, Dirty, Read, User, Valid, Write};
 
-        assert!(guest_dtb.len() < guest_memory::GUEST_DTB_REGION_SIZE);
+        let layout = layout::guest_memory_layout();
+        assert!(guest_dtb.len() < layout.dtb_region_size);
 
-        // guest device tree is loaded at end of guest memory region.
-        let guest_dtb_addr =
-            guest_memory::DRAM_BASE + hart_id * guest_memory::GUEST_DTB_REGION_SIZE;
+        // guest device tree is loaded at start of guest dram space.
+        let guest_dtb_addr = layout.dtb_addr(hart_id);
         let aligned_dtb_size = guest_dtb.len().div_ceil(PAGE_SIZE) * PAGE_SIZE;
 
         for offset in (0..aligned_dtb_size).step_by(PAGE_SIZE) {
             let guest_physical_addr = guest_dtb_addr + offset;
 
             // allocate memory from heap
-            let aligned_page_size_block_addr = PageBlock::alloc();
+            let aligned_page_size_block_addr = guest_frame(PAGE_ALLOCATOR.lock().alloc_zeroed());
 
-            // copy elf segment to new heap block
+            // copy dtb to new heap block
+            let copy_size = core::cmp::min(PAGE_SIZE, guest_dtb.len() - offset);
             unsafe {
                 core::ptr::copy(
                     guest_dtb.as_ptr().byte_add(offset),
                     aligned_page_size_block_addr.raw() as *mut u8,
-                    PAGE_SIZE,
+                    copy_size,
                 );
             }
 
             // create memory mapping
-            page_table::sv39x4::generate_page_table(
+            page_table::g_stage_generate_page_table(
                 page_table_addr,
                 &[MemoryMap::new(
                     guest_physical_addr..guest_physical_addr + PAGE_SIZE,
@@ -114,6 +198,11 @@ impl Guest {
         guest_dtb_addr
     }
 
+    /// Return guest context.
+    pub fn context(&mut self) -> &mut Context {
+        &mut self.context
+    }
+
     /// Return HART(HARdware Thread) id.
     pub fn hart_id(&self) -> usize {
         self.hart_id
@@ -124,18 +213,142 @@ impl Guest {
         self.stack_top_addr
     }
 
+    /// Free G-stage page tables of the guest that never runs again.
+    ///
+    /// Guest memory pages are not freed since devices may still access them by DMA.
+    pub fn destroy_page_table(&self) {
+        page_table::g_stage_destroy_page_table(self.page_table_addr);
+        hfence_gvma_all();
+        flush_iommu_gpa_range(&self.memory_region);
+    }
+
     /// Return guest device tree address. (GPA)
     pub fn guest_dtb_addr(&self) -> GuestPhysicalAddress {
         self.dtb_addr
     }
 
+    /// Return time offset (ns) of guest RTC.
+    pub fn rtc_offset(&self) -> i64 {
+        self.rtc_offset
+    }
+
+    /// Return mutable reference to time offset (ns) of guest RTC.
+    pub fn rtc_offset_mut(&mut self) -> &mut i64 {
+        &mut self.rtc_offset
+    }
+
+    /// Add write permission to the text page that contains `gpa`.
+    ///
+    /// It is used for stores to text region that cannot be emulated. (e.g. AMO to freed init text)
+    /// Exec permission is dropped instead, so the page is write protected again
+    /// when the guest executes it. (see `restore_text_exec`)
+    /// If the store instruction is on the page itself (`keep_exec`), the page is left writable and executable.
+    /// Return false if the page is not mapped.
+    pub fn grant_text_write(&mut self, gpa: GuestPhysicalAddress, keep_exec: bool) -> bool {
+        use PteFlag::{Accessed, Dirty, Exec, Read, User, Valid, Write};
+
+        let page_gpa = GuestPhysicalAddress(gpa.raw() & !(PAGE_SIZE - 1));
+        let flags: &[PteFlag] = if keep_exec {
+            &[Dirty, Accessed, Exec, Write, Read, User, Valid]
+        } else {
+            &[Dirty, Accessed, Write, Read, User, Valid]
+        };
+        if !self.remap_text_page(page_gpa, flags) {
+            return false;
+        }
+        if !keep_exec {
+            self.writable_text_pages.push(page_gpa);
+        }
+
+        true
+    }
+
+    /// Write protect the text page made writable by `grant_text_write` and make it executable again.
+    ///
+    /// Return false if the page that contains `gpa` is not such a page.
+    pub fn restore_text_exec(&mut self, gpa: GuestPhysicalAddress) -> bool {
+        use PteFlag::{Accessed, Dirty, Exec, Read, User, Valid};
+
+        let page_gpa = GuestPhysicalAddress(gpa.raw() & !(PAGE_SIZE - 1));
+        let Some(index) = self
+            .writable_text_pages
+            .iter()
+            .position(|&page| page == page_gpa)
+        else {
+            return false;
+        };
+        self.writable_text_pages.swap_remove(index);
+
+        // same permission as text segments of ELF. (see `load_guest_elf`)
+        self.remap_text_page(page_gpa, &[Dirty, Accessed, Read, Exec, User, Valid])
+    }
+
+    /// Change G-stage permission of the text page at `page_gpa`.
+    ///
+    /// Return false if the page is not mapped.
+    #[allow(clippy::similar_names)]
+    fn remap_text_page(&self, page_gpa: GuestPhysicalAddress, flags: &[PteFlag]) -> bool {
+        let Ok(page_hpa) = page_table::g_stage_trans_addr(page_gpa) else {
+            return false;
+        };
+        page_table::g_stage_generate_page_table(
+            self.page_table_addr,
+            &[MemoryMap::new(
+                page_gpa..page_gpa + PAGE_SIZE,
+                page_hpa..page_hpa + PAGE_SIZE,
+                flags,
+            )],
+        );
+        hfence_gvma(Some(page_gpa.raw()), hgatp::read().vmid());
+        flush_iommu_gpa_range(&(page_gpa..page_gpa + PAGE_SIZE));
+
+        true
+    }
+
+    /// Make the pages in `gpa_range` usable as shadow stack by hardware Zicfiss.
+    ///
+    /// The shadow stack encoding (`W` without `R`) is reserved in G-stage, so shadow stack needs
+    /// readable and writable G-stage pages. Guest memory is already mapped so except text regions,
+    /// and text regions are refused to keep them write protected.
+    /// Return false if any page is not mapped or in text regions.
+    pub fn map_shadow_stack(&self, gpa_range: Range<GuestPhysicalAddress>) -> bool {
+        let start = gpa_range.start.raw() & !(PAGE_SIZE - 1);
+        (start..gpa_range.end.raw())
+            .step_by(PAGE_SIZE)
+            .map(GuestPhysicalAddress)
+            .all(|page_gpa| {
+                page_table::g_stage_trans_addr(page_gpa).is_ok() && !self.is_text_region(page_gpa)
+            })
+    }
+
+    /// Map the page that next shadow stack push writes. (see `map_shadow_stack`)
+    ///
+    /// It is called when shadow stack is enabled. `ssp` that is not set yet (zero) is accepted.
+    #[allow(clippy::cast_possible_truncation)]
+    pub fn map_next_shadow_stack_push(&self) -> bool {
+        let ssp = self.context.shadow_stack().ssp.bits() as usize;
+        if ssp == 0 {
+            return true;
+        }
+
+        let push_gva = GuestVirtualAddress(ssp.wrapping_sub(core::mem::size_of::<usize>()));
+        page_table::vs_stage_trans_addr(push_gva).is_ok_and(|push_gpa| {
+            self.map_shadow_stack(push_gpa..push_gpa + core::mem::size_of::<usize>())
+        })
+    }
+
     /// Return guest dram space start
     pub fn memory_region(&self) -> &Range<GuestPhysicalAddress> {
         &self.memory_region
     }
 
+    /// Is the address in read-only executable regions?
+    pub fn is_text_region(&self, gpa: GuestPhysicalAddress) -> bool {
+        self.text_regions.iter().any(|region| region.contains(&gpa))
+    }
+
     /// Return guest dram space start
-    fn dram_base(&self) -> GuestPhysicalAddress {
+    pub fn dram_base(&self) -> GuestPhysicalAddress {
         self.memory_region.start
     }
 
@@ -152,7 +365,7 @@ impl Guest {
     /// * `guest_elf` - Elf loading guest space.
     /// * `elf_addr` - Elf address.
     pub fn load_guest_elf(
-        &self,
+        &mut self,
         guest_elf: &ElfBytes<AnyEndian>,
         elf_addr: *const u8,
     ) -> (GuestPhysicalAddress, GuestPhysicalAddress) {
@@ -178,14 +391,21 @@ impl Guest {
                 let aligned_segment_size = align_size(prog_header.p_memsz, prog_header.p_align);
                 let segment_file_offset = usize::try_from(prog_header.p_offset).unwrap();
                 let segment_file_size = usize::try_from(prog_header.p_filesz).unwrap();
+                if prog_header.p_flags & 0b111 == 0b101 {
+                    let segment_start =
+                        self.dram_base() + usize::try_from(prog_header.p_paddr).unwrap();
+                    self.text_regions
+                        .push(segment_start..segment_start + aligned_segment_size);
+                }
 
                 for offset in (0..aligned_segment_size).step_by(PAGE_SIZE) {
                     let guest_physical_addr =
                         self.dram_base() + prog_header.p_paddr.try_into().unwrap() + offset;
                     elf_end = core::cmp::max(elf_end, guest_physical_addr + PAGE_SIZE);
 
-                    // allocate memory from heap
-                    let aligned_page_size_block_addr = PageBlock::alloc();
+                    // allocate zeroed memory from heap (recycled frames may hold data of the other guest)
+                    let aligned_page_size_block_addr =
+                        guest_frame(PAGE_ALLOCATOR.lock().alloc_zeroed());
 
                     // Determine the range of data to copy
                     let copy_start = segment_file_offset + offset;
@@ -204,31 +424,21 @@ impl Guest {
                                 copy_size,
                             );
                         }
-
-                        if copy_size < PAGE_SIZE {
-                            // Zero-initialize the remaining part of the page
-                            core::ptr::write_bytes(
-                                (aligned_page_size_block_addr.raw() as *mut u8).add(copy_size),
-                                0,
-                                PAGE_SIZE - copy_size,
-                            );
-                        }
                     }
 
                     // create memory mapping
-                    page_table::sv39x4::generate_page_table(
+                    page_table::g_stage_generate_page_table(
                         self.page_table_addr,
                         &[MemoryMap::new(
                             guest_physical_addr..guest_physical_addr + PAGE_SIZE,
                             aligned_page_size_block_addr..aligned_page_size_block_addr + PAGE_SIZE,
                             match prog_header.p_flags & 0b111 {
                                 0b100 => &[Dirty, Accessed, Read, User, Valid],
-                                #[allow(clippy::match_same_arms)]
-                                // Add Write permission to RX for dynamic patch
+                                // stores for dynamic patch are emulated. (see `text_regions`)
                                 // ref: https://github.com/torvalds/linux/blob/67784a74e258a467225f0e68335df77acd67b7ab/arch/riscv/kernel/patch.c#L215C5-L215C21
-                                // TODO: switch enable/disable write permission corresponding to VS-stage page table.
-                                0b101 => &[Dirty, Accessed, Read, Write, Exec, User, Valid],
+                                0b101 => &[Dirty, Accessed, Read, Exec, User, Valid],
                                 // FIXME: Add Exec permission (RW -> RWX)
+                                #[allow(clippy::match_same_arms)]
                                 0b110 => &[Dirty, Accessed, Read, Write, Exec, User, Valid],
                                 0b111 => &[Dirty, Accessed, Exec, Write, Read, User, Valid],
                                 _ => panic!("unsupported flags"),
@@ -242,34 +452,146 @@ impl Guest {
         (self.dram_base(), elf_end)
     }
 
+    /// Load a RISC-V Linux kernel image (flat binary) to new allocated guest memory page.
+    ///
+    /// The image is placed at `text_offset` from the base address of the dram,
+    /// and the space before it is filled with zeroed pages.
+    ///
+    /// # Return
+    /// - Entry point address in Guest memory space.
+    /// - End address of the image. (for filling remind memory space)
+    ///
+    /// # Panics
+    /// It panics if the image header is invalid or the image does not fit in guest memory
+    /// except for `reserved_size` at the end. (e.g. initrd)
+    pub fn load_guest_image(
+        &self,
+        image: &[u8],
+        reserved_size: usize,
+    ) -> (GuestPhysicalAddress, GuestPhysicalAddress) {
+        let header = image::ImageHeader::parse(image)
+            .expect("guest kernel is neither ELF nor RISC-V Linux image");
+        let image_start = self.dram_base() + header.text_offset;
+        self.load_flat_image(image, image_start, header.image_size, reserved_size)
+    }
+
+    /// Load a flat binary (e.g. U-Boot or bare-metal payload) at `load_gpa`.
+    ///
+    /// Unlike RISC-V Linux image, it has no header, so the entry point is `load_gpa`.
+    ///
+    /// # Return
+    /// - Entry point address in Guest memory space.
+    /// - End address of the binary. (for filling remind memory space)
+    ///
+    /// # Panics
+    /// It panics if `load_gpa` is out of guest memory or the binary does not fit in guest memory
+    /// except for `reserved_size` at the end. (e.g. initrd)
+    pub fn load_flat_binary(
+        &self,
+        data: &[u8],
+        load_gpa: GuestPhysicalAddress,
+        reserved_size: usize,
+    ) -> (GuestPhysicalAddress, GuestPhysicalAddress) {
+        assert!(
+            self.memory_region.contains(&load_gpa),
+            "load address of flat binary ({load_gpa:#x}) is out of guest memory"
+        );
+        self.load_flat_image(data, load_gpa, data.len(), reserved_size)
+    }
+
+    /// Copy `image` to `image_start` and map guest memory up to the end of it.
+    ///
+    /// `image_size` may be larger than `image` (e.g. bss), and the space before `image_start`
+    /// is filled with zeroed pages.
+    fn load_flat_image(
+        &self,
+        image: &[u8],
+        image_start: GuestPhysicalAddress,
+        image_size: usize,
+        reserved_size: usize,
+    ) -> (GuestPhysicalAddress, GuestPhysicalAddress) {
+        use PteFlag::{Accessed, Dirty, Exec, Read, User, Valid, Write};
+
+        // no space is available if `image_start` or reserved area is beyond the guest memory.
+        let available_size = self
+            .memory_region
+            .end
+            .raw()
+            .checked_sub(image_start.raw())
+            .and_then(|size| size.checked_sub(reserved_size.next_multiple_of(PAGE_SIZE)))
+            .unwrap_or(0);
+        assert!(
+            image_size <= available_size,
+            "guest kernel image ({image_size:#x} bytes) exceeds available guest memory ({available_size:#x} bytes)"
+        );
+        assert!(image_start % PAGE_SIZE == 0);
+
+        let image_end = image_start + image_size.next_multiple_of(PAGE_SIZE);
+        for guest_physical_addr in (self.dram_base().raw()..image_end.raw()).step_by(PAGE_SIZE) {
+            let guest_physical_addr = GuestPhysicalAddress(guest_physical_addr);
+
+            // allocate memory from heap
+            let aligned_page_size_block_addr = guest_frame(PAGE_ALLOCATOR.lock().alloc_zeroed());
+
+            // copy image to new heap block
+            if guest_physical_addr >= image_start {
+                let offset = guest_physical_addr.raw() - image_start.raw();
+                let copy_size = core::cmp::min(PAGE_SIZE, image.len().saturating_sub(offset));
+                if copy_size > 0 {
+                    unsafe {
+                        core::ptr::copy(
+                            image.as_ptr().byte_add(offset),
+                            aligned_page_size_block_addr.raw() as *mut u8,
+                            copy_size,
+                        );
+                    }
+                }
+            }
+
+            // create memory mapping
+            page_table::g_stage_generate_page_table(
+                self.page_table_addr,
+                &[MemoryMap::new(
+                    guest_physical_addr..guest_physical_addr + PAGE_SIZE,
+                    aligned_page_size_block_addr..aligned_page_size_block_addr + PAGE_SIZE,
+                    &[Dirty, Accessed, Exec, Write, Read, User, Valid],
+                )],
+            );
+        }
+
+        (image_start, image_end)
+    }
+
     /// Allocate guest memory space from heap and create corresponding page table.
-    pub fn allocate_memory_region(&self, region: Range<GuestPhysicalAddress>) {
+    ///
+    /// `initrd` is copied to the end of the region.
+    pub fn allocate_memory_region(&self, region: Range<GuestPhysicalAddress>, initrd: &[u8]) {
         use PteFlag::{Accessed, Dirty, Exec, Read, User, Valid, Write};
 
         let all_pte_flags_are_set = &[Dirty, Accessed, Exec, Write, Read, User, Valid];
 
-        let aligned_initrd_size = GUEST_INITRD.len().div_ceil(PAGE_SIZE) * PAGE_SIZE;
+        let aligned_initrd_size = initrd.len().div_ceil(PAGE_SIZE) * PAGE_SIZE;
         let initrd_start = region.end - aligned_initrd_size;
-        if !GUEST_INITRD.is_empty() {
+        if !initrd.is_empty() {
             crate::println!(
                 "initrd (GPA): {:#x}..{:#x}",
-                initrd_start.raw(),
-                initrd_start.raw() + GUEST_INITRD.len()
+                initrd_start,
+                initrd_start + initrd.len()
             );
         }
 
         for guest_physical_addr in (region.start.raw()..region.end.raw()).step_by(PAGE_SIZE) {
             let guest_physical_addr = GuestPhysicalAddress(guest_physical_addr);
 
-            // allocate memory from heap
-            let aligned_page_size_block_addr = PageBlock::alloc();
+            // allocate zeroed memory from heap (recycled frames may hold data of the other guest)
+            let aligned_page_size_block_addr = guest_frame(PAGE_ALLOCATOR.lock().alloc_zeroed());
 
             // copy initrd to new heap block
             if (initrd_start..region.end).contains(&guest_physical_addr) {
                 unsafe {
                     let offset = guest_physical_addr.raw() - initrd_start.raw();
                     core::ptr::copy(
-                        GUEST_INITRD.as_ptr().byte_add(offset),
+                        initrd.as_ptr().byte_add(offset),
                         aligned_page_size_block_addr.raw() as *mut u8,
                         PAGE_SIZE,
                     );
@@ -277,7 +599,7 @@ impl Guest {
             }
 
             // create memory mapping
-            page_table::sv39x4::generate_page_table(
+            page_table::g_stage_generate_page_table(
                 self.page_table_addr,
                 &[MemoryMap::new(
                     guest_physical_addr..guest_physical_addr + PAGE_SIZE,
@@ -287,4 +609,104 @@ impl Guest {
             );
         }
     }
+
+    /// Map additional memory to the end of guest memory region at runtime.
+    ///
+    /// `additional_bytes` is rounded up to page size.
+    /// The total size of added memory is limited by `MAX_HOTPLUG_SIZE`.
+    /// Returns the newly mapped range.
+    pub fn extend_memory(
+        &mut self,
+        additional_bytes: usize,
+    ) -> Result<Range<GuestPhysicalAddress>, HotplugError> {
+        use PteFlag::{Accessed, Dirty, Exec, Read, User, Valid, Write};
+
+        let additional_size = additional_bytes
+            .checked_next_multiple_of(PAGE_SIZE)
+            .filter(|&size| size <= MAX_HOTPLUG_SIZE - self.hotplugged_pages.len() * PAGE_SIZE)
+            .ok_or(HotplugError::ExceedLimit)?;
+        let new_region_end = self
+            .memory_region
+            .end
+            .raw()
+            .checked_add(additional_size)
+            .ok_or(HotplugError::ExceedLimit)?;
+        let new_region = self.memory_region.end..GuestPhysicalAddress(new_region_end);
+
+        // the region may be used by devices.
+        let is_mapped = (new_region.start.raw()..new_region.end.raw())
+            .step_by(PAGE_SIZE)
+            .any(|gpa| page_table::g_stage_trans_addr(GuestPhysicalAddress(gpa)).is_ok());
+        if is_mapped {
+            return Err(HotplugError::AlreadyMapped);
+        }
+
+        let hotplugged_num = self.hotplugged_pages.len();
+        for guest_physical_addr in (new_region.start.raw()..new_region.end.raw()).step_by(PAGE_SIZE)
+        {
+            let guest_physical_addr = GuestPhysicalAddress(guest_physical_addr);
+            let Some(aligned_page_size_block_addr) =
+                PAGE_ALLOCATOR.lock().try_alloc_zeroed().map(guest_frame)
+            else {
+                // roll back pages added by this call. (they are not visible to the guest yet)
+                let added_pages = self.hotplugged_pages.split_off(hotplugged_num);
+                for (index, page) in added_pages.into_iter().enumerate() {
+                    page_table::g_stage_unmap_page(
+                        self.page_table_addr,
+                        new_region.start + index * PAGE_SIZE,
+                    );
+                    unsafe {
+                        PAGE_ALLOCATOR.lock().free(page);
+                    }
+                }
+                return Err(HotplugError::OutOfMemory);
+            };
+            page_table::g_stage_generate_page_table(
+                self.page_table_addr,
+                &[MemoryMap::new(
+                    guest_physical_addr..guest_physical_addr + PAGE_SIZE,
+                    aligned_page_size_block_addr..aligned_page_size_block_addr + PAGE_SIZE,
+                    &[Dirty, Accessed, Exec, Write, Read, User, Valid],
+                )],
+            );
+            self.hotplugged_pages.push(aligned_page_size_block_addr);
+        }
+
+        hfence_gvma_all();
+        flush_iommu_gpa_range(&new_region);
+        self.memory_region.end = new_region.end;
+
+        Ok(new_region)
+    }
+
+    /// Unmap memory from the end of guest memory region and free it.
+    ///
+    /// `bytes` is rounded up to page size and only pages added by `extend_memory` are removed.
+    /// Returns the unmapped range.
+    pub fn shrink_memory(&mut self, bytes: usize) -> Range<GuestPhysicalAddress> {
+        let page_num = core::cmp::min(bytes.div_ceil(PAGE_SIZE), self.hotplugged_pages.len());
+        let removed_region = self.memory_region.end - page_num * PAGE_SIZE..self.memory_region.end;
+
+        let removed_pages = self
+            .hotplugged_pages
+            .split_off(self.hotplugged_pages.len() - page_num);
+        for index in 0..page_num {
+            page_table::g_stage_unmap_page(
+                self.page_table_addr,
+                removed_region.start + index * PAGE_SIZE,
+            );
+        }
+
+        // pages must not be freed until stale translations are flushed.
+        hfence_gvma_all();
+        flush_iommu_gpa_range(&removed_region);
+        for page in removed_pages {
+            unsafe {
+                PAGE_ALLOCATOR.lock().free(page);
+            }
+        }
+        self.memory_region.end = removed_region.start;
+
+        removed_region
+    }
 }
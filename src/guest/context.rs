@@ -1,13 +1,38 @@
 //! Guest context.
 
-use crate::memmap::HostPhysicalAddress;
+use crate::current_hart_id;
+use crate::emulate_extension::zicfilp::LandingPad;
+use crate::emulate_extension::zicfiss::ShadowStack;
+use crate::memmap::{constant::MAX_HART_NUM, HostPhysicalAddress};
 
+use core::arch::asm;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use raki::Instruction;
+use riscv::register::{cycle, instret};
+use sbi_rt::{StartFlags, StopFlags};
+
+/// Counters that are virtualized by hypervisor instead of being stopped on trap. (cycle, time, instret)
+const FIXED_COUNTERS: u64 = 0b111;
+
+/// PMU counter state of the guest on each HART.
+///
+/// It is kept out of `HART_DATA` so that trap entry/exit does not need to lock it.
+static PMU_CONTEXTS: [PmuContext; MAX_HART_NUM] = [const { PmuContext::new() }; MAX_HART_NUM];
+
+/// Return PMU counter state of the guest running on current HART.
+pub fn pmu_context() -> &'static PmuContext {
+    &PMU_CONTEXTS[current_hart_id()]
+}
 
 /// Guest context on memory
 ///
 /// It place to hypervisor stack top.
-#[repr(C)]
+/// (aligned to 16 bytes to keep stack pointer aligned)
+///
+/// The hypervisor is built for `riscv64imac` and never touches floating-point registers,
+/// so they are saved and restored only when `sstatus.FS` is Dirty.
+#[repr(C, align(16))]
+#[derive(Debug, Clone, Default)]
 #[allow(dead_code)]
 #[allow(clippy::module_name_repetitions)]
 pub struct ContextData {
@@ -17,26 +42,59 @@ pub struct ContextData {
     pub sstatus: usize,
     /// Program counter
     pub sepc: usize,
+    /// Floating-point registers (saved and restored only if `sstatus.FS` is Dirty)
+    pub freg: [u64; 32],
+    /// Value of fcsr (saved and restored only if `sstatus.FS` is Dirty)
+    pub fcsr: usize,
 }
 
+// Offsets of the fields are hard-coded in trap entry/exit assembly. (`trap.rs` and `hypervisor_init.rs`)
+// The size is given by `size_of::<ContextData>()` and must fit in the immediate of `addi`.
+const _: () = {
+    assert!(core::mem::offset_of!(ContextData, xreg) == 0);
+    assert!(core::mem::offset_of!(ContextData, sstatus) == 32 * 8);
+    assert!(core::mem::offset_of!(ContextData, sepc) == 33 * 8);
+    assert!(core::mem::offset_of!(ContextData, freg) == 34 * 8);
+    assert!(core::mem::offset_of!(ContextData, fcsr) == 66 * 8);
+    assert!(size_of::<ContextData>() < 2048);
+};
+
 /// Guest context
-#[derive(Debug, Copy, Clone)]
+///
+/// It is borrowed from `Guest::context` so that the access to `ContextData` is exclusive.
+#[derive(Debug)]
 pub struct Context {
     /// Address of context storing.
     address: HostPhysicalAddress,
+    /// Emulated shadow stack of the guest. (Zicfiss)
+    shadow_stack: ShadowStack,
+    /// Emulated landing pad state of the guest. (Zicfilp)
+    landing_pad: LandingPad,
 }
 
 impl Context {
     /// Constructor for `Context`.
     pub fn new(address: HostPhysicalAddress) -> Self {
-        Context { address }
+        Context {
+            address,
+            shadow_stack: ShadowStack::default(),
+            landing_pad: LandingPad::default(),
+        }
     }
 }
 
 impl Context {
+    /// Get `ContextData` from raw address for reading.
+    pub(super) fn context_data(&self) -> &ContextData {
+        unsafe {
+            (self.address.raw() as *const ContextData)
+                .as_ref()
+                .expect("address of ContextData is invalid")
+        }
+    }
+
     /// Get `ContextData` from raw address.
-    #[allow(clippy::mut_from_ref)]
-    fn get_context(&self) -> &mut ContextData {
+    pub(super) fn get_context(&mut self) -> &mut ContextData {
         unsafe {
             (self.address.raw() as *mut ContextData)
                 .as_mut()
@@ -44,12 +102,27 @@ impl Context {
         }
     }
 
+    /// Return emulated shadow stack state.
+    pub fn shadow_stack(&self) -> &ShadowStack {
+        &self.shadow_stack
+    }
+
+    /// Return mutable emulated shadow stack state.
+    pub fn shadow_stack_mut(&mut self) -> &mut ShadowStack {
+        &mut self.shadow_stack
+    }
+
+    /// Return mutable emulated landing pad state.
+    pub fn landing_pad_mut(&mut self) -> &mut LandingPad {
+        &mut self.landing_pad
+    }
+
     /// Return regular register value.
-    pub fn xreg(self, index: usize) -> u64 {
+    pub fn xreg(&self, index: usize) -> u64 {
         if index == 0 {
             0
         } else {
-            self.get_context().xreg[index]
+            self.context_data().xreg[index]
         }
     }
 
@@ -59,8 +132,8 @@ impl Context {
     }
 
     /// Return sepc value.
-    pub fn sepc(self) -> usize {
-        self.get_context().sepc
+    pub fn sepc(&self) -> usize {
+        self.context_data().sepc
     }
 
     /// Set sepc.
@@ -80,8 +153,8 @@ impl Context {
     }
 
     /// Return sstatus value.
-    pub fn sstatus(self) -> usize {
-        self.get_context().sstatus
+    pub fn sstatus(&self) -> usize {
+        self.context_data().sstatus
     }
 
     /// Set sstatus.
@@ -89,3 +162,186 @@ impl Context {
         self.get_context().sstatus = value;
     }
 }
+
+/// Empty flags for stopping and restarting counters on trap.
+struct NoPmuFlags;
+impl StartFlags for NoPmuFlags {
+    fn raw(&self) -> usize {
+        0
+    }
+}
+impl StopFlags for NoPmuFlags {
+    fn raw(&self) -> usize {
+        0
+    }
+}
+
+/// PMU counter state of a guest.
+///
+/// Counters configured by the guest are stopped while hypervisor is running,
+/// and `cycle`/`instret` seen by the guest exclude the time spent in hypervisor.
+pub struct PmuContext {
+    /// Counter indices configured by the guest. (bitmask)
+    configured: AtomicU64,
+    /// Counter indices started by the guest. (bitmask)
+    running: AtomicU64,
+    /// Cycles spent in hypervisor.
+    hs_cycle: AtomicU64,
+    /// Instructions retired in hypervisor.
+    hs_instret: AtomicU64,
+    /// Cycle of the current trap entry. (0 if not in trap)
+    entry_cycle: AtomicU64,
+    /// Instret of the current trap entry.
+    entry_instret: AtomicU64,
+}
+
+impl PmuContext {
+    /// Constructor for `PmuContext`.
+    const fn new() -> Self {
+        PmuContext {
+            configured: AtomicU64::new(0),
+            running: AtomicU64::new(0),
+            hs_cycle: AtomicU64::new(0),
+            hs_instret: AtomicU64::new(0),
+            entry_cycle: AtomicU64::new(0),
+            entry_instret: AtomicU64::new(0),
+        }
+    }
+
+    /// Are all counters in `mask` configured by the guest?
+    pub fn owns(&self, mask: u64) -> bool {
+        mask & !self.configured.load(Ordering::Relaxed) == 0
+    }
+
+    /// Record the counter configured by the guest.
+    pub fn configure(&self, counter_idx: usize, started: bool) {
+        let mask = 1 << counter_idx;
+        self.configured.fetch_or(mask, Ordering::Relaxed);
+        if started {
+            self.running.fetch_or(mask, Ordering::Relaxed);
+        } else {
+            self.running.fetch_and(!mask, Ordering::Relaxed);
+        }
+    }
+
+    /// Record the counters started by the guest.
+    pub fn start(&self, mask: u64) {
+        self.running.fetch_or(mask, Ordering::Relaxed);
+    }
+
+    /// Record the counters stopped by the guest. (released if `reset` is true)
+    pub fn stop(&self, mask: u64, reset: bool) {
+        self.running.fetch_and(!mask, Ordering::Relaxed);
+        if reset {
+            self.configured.fetch_and(!mask, Ordering::Relaxed);
+        }
+    }
+
+    /// Return running counters that must be stopped while hypervisor is running.
+    fn running_mask(&self) -> usize {
+        usize::try_from(self.running.load(Ordering::Relaxed) & !FIXED_COUNTERS).unwrap()
+    }
+
+    /// Stop counters of the guest on trap entry.
+    pub fn trap_entry(&self) {
+        if hs_counters_readable() {
+            self.entry_cycle
+                .store(cycle::read() as u64, Ordering::Relaxed);
+            self.entry_instret
+                .store(instret::read() as u64, Ordering::Relaxed);
+        }
+
+        let running = self.running_mask();
+        if running != 0 {
+            sbi_rt::pmu_counter_stop(0, running, NoPmuFlags);
+        }
+    }
+
+    /// Restart counters of the guest and accumulate hypervisor time on trap exit.
+    pub fn trap_exit(&self) {
+        let running = self.running_mask();
+        if running != 0 {
+            sbi_rt::pmu_counter_start(0, running, NoPmuFlags, 0);
+        }
+
+        // `entry_cycle` stays zero if counters are not readable.
+        let entry_cycle = self.entry_cycle.swap(0, Ordering::Relaxed);
+        if entry_cycle != 0 {
+            self.hs_cycle.fetch_add(
+                (cycle::read() as u64).wrapping_sub(entry_cycle),
+                Ordering::Relaxed,
+            );
+            self.hs_instret.fetch_add(
+                (instret::read() as u64).wrapping_sub(self.entry_instret.load(Ordering::Relaxed)),
+                Ordering::Relaxed,
+            );
+        }
+    }
+
+    /// Return `cycle` seen by the guest.
+    ///
+    /// It must not be called if `hs_counters_readable` returns false.
+    pub fn cycle(&self) -> u64 {
+        (cycle::read() as u64).wrapping_sub(self.hs_cycle.load(Ordering::Relaxed))
+    }
+
+    /// Return `instret` seen by the guest.
+    ///
+    /// It must not be called if `hs_counters_readable` returns false.
+    pub fn instret(&self) -> u64 {
+        (instret::read() as u64).wrapping_sub(self.hs_instret.load(Ordering::Relaxed))
+    }
+}
+
+/// Are `cycle` and `instret` readable in HS-mode? (cleared by `probe_hs_counters`)
+static HS_COUNTERS_READABLE: AtomicBool = AtomicBool::new(true);
+
+/// Check that `cycle` and `instret` are readable in HS-mode.
+///
+/// Counters that M-mode does not expose by mcounteren raise illegal instruction,
+/// which is a fatal nested trap in hypervisor. It is called on each HART before running guests.
+pub fn probe_hs_counters() {
+    /// CSR number of `cycle`.
+    const CSR_CYCLE: usize = 0xc00;
+    /// CSR number of `instret`.
+    const CSR_INSTRET: usize = 0xc02;
+
+    let readable = is_readable_in_hs::<CSR_CYCLE>() && is_readable_in_hs::<CSR_INSTRET>();
+    HS_COUNTERS_READABLE.fetch_and(readable, Ordering::Relaxed);
+}
+
+/// Are `cycle` and `instret` readable in HS-mode?
+pub fn hs_counters_readable() -> bool {
+    HS_COUNTERS_READABLE.load(Ordering::Relaxed)
+}
+
+/// Read the CSR with a temporary trap vector that skips the read on exception.
+///
+/// Interrupts are disabled during the read, and CSRs updated by the trap are restored.
+fn is_readable_in_hs<const CSR: usize>() -> bool {
+    let readable: usize;
+    unsafe {
+        asm!(
+            "csrrci {sstatus}, sstatus, 0b10",
+            "csrr {hstatus}, hstatus",
+            "la {tmp}, 2f",
+            "csrrw {stvec}, stvec, {tmp}",
+            "li {readable}, 0",
+            "csrr {tmp}, {csr}",
+            "li {readable}, 1",
+            // the trap vector must be 4 byte aligned.
+            ".align 2",
+            "2:",
+            "csrw stvec, {stvec}",
+            "csrw hstatus, {hstatus}",
+            "csrw sstatus, {sstatus}",
+            csr = const CSR,
+            readable = out(reg) readable,
+            sstatus = out(reg) _,
+            hstatus = out(reg) _,
+            stvec = out(reg) _,
+            tmp = out(reg) _,
+        );
+    }
+    readable == 1
+}
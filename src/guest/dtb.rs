@@ -0,0 +1,468 @@
+//! Patching guest device tree.
+//! Ref: [https://devicetree-specification.readthedocs.io/en/stable/flattened-format.html](https://devicetree-specification.readthedocs.io/en/stable/flattened-format.html)
+
+use alloc::vec::Vec;
+use fdt::Fdt;
+
+/// Magic number of FDT header.
+const FDT_MAGIC: u32 = 0xd00d_feed;
+/// Size of FDT header.
+const FDT_HEADER_SIZE: usize = 40;
+
+/// Offset of `totalsize` in FDT header.
+const HEADER_TOTALSIZE: usize = 0x4;
+/// Offset of `off_dt_struct` in FDT header.
+const HEADER_OFF_DT_STRUCT: usize = 0x8;
+/// Offset of `off_dt_strings` in FDT header.
+const HEADER_OFF_DT_STRINGS: usize = 0xc;
+/// Offset of `size_dt_strings` in FDT header.
+const HEADER_SIZE_DT_STRINGS: usize = 0x20;
+/// Offset of `size_dt_struct` in FDT header.
+const HEADER_SIZE_DT_STRUCT: usize = 0x24;
+
+/// Structure block tokens.
+mod token {
+    /// Beginning of a node.
+    pub const BEGIN_NODE: u32 = 0x1;
+    /// End of a node.
+    pub const END_NODE: u32 = 0x2;
+    /// Property.
+    pub const PROP: u32 = 0x3;
+    /// Nop.
+    pub const NOP: u32 = 0x4;
+    /// End of the structure block.
+    pub const END: u32 = 0x9;
+}
+
+/// Property name of ISA extensions list.
+const ISA_EXTENSIONS: &str = "riscv,isa-extensions";
+/// Property name of address and size.
+const REG: &str = "reg";
+/// Property name of initrd start address.
+const INITRD_START: &str = "linux,initrd-start";
+/// Property name of initrd end address.
+const INITRD_END: &str = "linux,initrd-end";
+
+/// Read big-endian u32 at `offset`.
+fn read_be32(blob: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(blob[offset..offset + 4].try_into().unwrap())
+}
+
+/// Write big-endian u32 at `offset`.
+fn write_be32(blob: &mut [u8], offset: usize, value: u32) {
+    blob[offset..offset + 4].copy_from_slice(&value.to_be_bytes());
+}
+
+/// Return null terminated string starts at `offset` (without null).
+fn c_str(blob: &[u8], offset: usize) -> &[u8] {
+    let len = blob[offset..].iter().position(|&c| c == 0).unwrap();
+    &blob[offset..offset + len]
+}
+
+/// Return ISA extensions listed in `riscv,isa-extensions` of the first cpu node.
+pub fn isa_extensions<'a>(device_tree: &'a Fdt) -> Vec<&'a str> {
+    device_tree
+        .find_all_nodes("/cpus/cpu")
+        .next()
+        .and_then(|cpu| cpu.property(ISA_EXTENSIONS))
+        .map(|prop| {
+            prop.value
+                .split(|&c| c == 0)
+                .filter(|ext| !ext.is_empty())
+                .filter_map(|ext| core::str::from_utf8(ext).ok())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Append `extensions` to `riscv,isa-extensions` of each cpu node.
+///
+/// Extensions that are already listed are not appended again.
+/// Blob is returned as is if it isn't a valid FDT.
+#[allow(clippy::cast_possible_truncation)]
+pub fn append_isa_extensions(dtb: &[u8], extensions: &[&str]) -> Vec<u8> {
+    if dtb.len() < FDT_HEADER_SIZE || read_be32(dtb, 0) != FDT_MAGIC {
+        return dtb.to_vec();
+    }
+
+    let off_dt_struct = read_be32(dtb, HEADER_OFF_DT_STRUCT) as usize;
+    let off_dt_strings = read_be32(dtb, HEADER_OFF_DT_STRINGS) as usize;
+    let size_dt_strings = read_be32(dtb, HEADER_SIZE_DT_STRINGS) as usize;
+    let size_dt_struct = read_be32(dtb, HEADER_SIZE_DT_STRUCT) as usize;
+    // assume the layout generated by dtc (header, memory reservation block, structure block, strings block).
+    assert!(off_dt_struct + size_dt_struct <= off_dt_strings);
+
+    let dt_struct = &dtb[off_dt_struct..off_dt_struct + size_dt_struct];
+    let dt_strings = &dtb[off_dt_strings..off_dt_strings + size_dt_strings];
+
+    let mut new_struct: Vec<u8> = Vec::with_capacity(size_dt_struct);
+    // depth of current node and whether the node is a child of `/cpus`.
+    let mut depth = 0;
+    let mut in_cpus = false;
+    let mut in_cpu = false;
+    let mut offset = 0;
+    while offset < dt_struct.len() {
+        let token = read_be32(dt_struct, offset);
+        match token {
+            token::BEGIN_NODE => {
+                let name = c_str(dt_struct, offset + 4);
+                let next = (offset + 4 + name.len() + 1).next_multiple_of(4);
+                depth += 1;
+                match depth {
+                    2 => in_cpus = name == b"cpus",
+                    3 => in_cpu = in_cpus && name.starts_with(b"cpu@"),
+                    _ => (),
+                }
+                new_struct.extend_from_slice(&dt_struct[offset..next]);
+                offset = next;
+            }
+            token::END_NODE => {
+                match depth {
+                    2 => in_cpus = false,
+                    3 => in_cpu = false,
+                    _ => (),
+                }
+                depth -= 1;
+                new_struct.extend_from_slice(&dt_struct[offset..offset + 4]);
+                offset += 4;
+            }
+            token::PROP => {
+                let len = read_be32(dt_struct, offset + 4) as usize;
+                let name_offset = read_be32(dt_struct, offset + 8) as usize;
+                let value = &dt_struct[offset + 12..offset + 12 + len];
+                let next = (offset + 12 + len).next_multiple_of(4);
+
+                if in_cpu
+                    && depth == 3
+                    && c_str(dt_strings, name_offset) == ISA_EXTENSIONS.as_bytes()
+                {
+                    let mut new_value = value.to_vec();
+                    for ext in extensions {
+                        if !value.split(|&c| c == 0).any(|e| e == ext.as_bytes()) {
+                            new_value.extend_from_slice(ext.as_bytes());
+                            new_value.push(0);
+                        }
+                    }
+
+                    new_struct.extend_from_slice(&token::PROP.to_be_bytes());
+                    new_struct.extend_from_slice(&(new_value.len() as u32).to_be_bytes());
+                    new_struct.extend_from_slice(&(name_offset as u32).to_be_bytes());
+                    new_struct.extend_from_slice(&new_value);
+                    new_struct.resize(new_struct.len().next_multiple_of(4), 0);
+                } else {
+                    new_struct.extend_from_slice(&dt_struct[offset..next]);
+                }
+                offset = next;
+            }
+            token::NOP => {
+                new_struct.extend_from_slice(&dt_struct[offset..offset + 4]);
+                offset += 4;
+            }
+            token::END => {
+                new_struct.extend_from_slice(&dt_struct[offset..offset + 4]);
+                break;
+            }
+            _ => panic!("unknown FDT token: {:#x}", token),
+        }
+    }
+
+    // header and memory reservation block | structure block | strings block
+    let mut new_dtb = dtb[..off_dt_struct].to_vec();
+    new_dtb.extend_from_slice(&new_struct);
+    let new_off_dt_strings = new_dtb.len();
+    new_dtb.extend_from_slice(dt_strings);
+
+    let total_size = new_dtb.len();
+    write_be32(&mut new_dtb, HEADER_TOTALSIZE, total_size as u32);
+    write_be32(
+        &mut new_dtb,
+        HEADER_OFF_DT_STRINGS,
+        new_off_dt_strings as u32,
+    );
+    write_be32(&mut new_dtb, HEADER_SIZE_DT_STRUCT, new_struct.len() as u32);
+
+    new_dtb
+}
+
+/// Return offset of `name` in strings block. (appended if not found)
+#[allow(clippy::cast_possible_truncation)]
+fn string_offset(dt_strings: &mut Vec<u8>, name: &str) -> u32 {
+    let mut offset = 0;
+    for string in dt_strings.split(|&c| c == 0) {
+        if offset < dt_strings.len() && string == name.as_bytes() {
+            return offset as u32;
+        }
+        offset += string.len() + 1;
+    }
+
+    let offset = dt_strings.len();
+    dt_strings.extend_from_slice(name.as_bytes());
+    dt_strings.push(0);
+    offset as u32
+}
+
+/// Append property to structure block.
+#[allow(clippy::cast_possible_truncation)]
+fn push_prop(dt_struct: &mut Vec<u8>, name_offset: u32, value: &[u8]) {
+    dt_struct.extend_from_slice(&token::PROP.to_be_bytes());
+    dt_struct.extend_from_slice(&(value.len() as u32).to_be_bytes());
+    dt_struct.extend_from_slice(&name_offset.to_be_bytes());
+    dt_struct.extend_from_slice(value);
+    dt_struct.resize(dt_struct.len().next_multiple_of(4), 0);
+}
+
+/// Set `linux,initrd-start` and `linux,initrd-end` of `/chosen` to `start` and `end`.
+///
+/// Existing properties are overwritten and `/chosen` is created if it does not exist.
+/// Return false if the blob isn't a valid FDT.
+#[allow(clippy::cast_possible_truncation)]
+pub fn set_chosen_initrd(dtb: &mut Vec<u8>, start: u64, end: u64) -> bool {
+    if dtb.len() < FDT_HEADER_SIZE || read_be32(dtb, 0) != FDT_MAGIC {
+        return false;
+    }
+
+    let off_dt_struct = read_be32(dtb, HEADER_OFF_DT_STRUCT) as usize;
+    let off_dt_strings = read_be32(dtb, HEADER_OFF_DT_STRINGS) as usize;
+    let size_dt_strings = read_be32(dtb, HEADER_SIZE_DT_STRINGS) as usize;
+    let size_dt_struct = read_be32(dtb, HEADER_SIZE_DT_STRUCT) as usize;
+    // assume the layout generated by dtc (header, memory reservation block, structure block, strings block).
+    assert!(off_dt_struct + size_dt_struct <= off_dt_strings);
+
+    let dt_struct = &dtb[off_dt_struct..off_dt_struct + size_dt_struct];
+    let mut dt_strings = dtb[off_dt_strings..off_dt_strings + size_dt_strings].to_vec();
+    let start_name_offset = string_offset(&mut dt_strings, INITRD_START);
+    let end_name_offset = string_offset(&mut dt_strings, INITRD_END);
+    let push_initrd_props = |new_struct: &mut Vec<u8>| {
+        push_prop(new_struct, start_name_offset, &start.to_be_bytes());
+        push_prop(new_struct, end_name_offset, &end.to_be_bytes());
+    };
+
+    let mut new_struct: Vec<u8> = Vec::with_capacity(size_dt_struct);
+    let mut depth = 0;
+    let mut in_chosen = false;
+    let mut chosen_found = false;
+    let mut offset = 0;
+    while offset < dt_struct.len() {
+        let token = read_be32(dt_struct, offset);
+        match token {
+            token::BEGIN_NODE => {
+                let name = c_str(dt_struct, offset + 4);
+                let next = (offset + 4 + name.len() + 1).next_multiple_of(4);
+                depth += 1;
+                if depth == 2 && name == b"chosen" {
+                    in_chosen = true;
+                    chosen_found = true;
+                }
+                new_struct.extend_from_slice(&dt_struct[offset..next]);
+                offset = next;
+            }
+            token::END_NODE => {
+                match depth {
+                    // append properties to the end of `/chosen`.
+                    2 if in_chosen => {
+                        push_initrd_props(&mut new_struct);
+                        in_chosen = false;
+                    }
+                    // create `/chosen` at the end of root node.
+                    1 if !chosen_found => {
+                        new_struct.extend_from_slice(&token::BEGIN_NODE.to_be_bytes());
+                        new_struct.extend_from_slice(b"chosen\0\0");
+                        push_initrd_props(&mut new_struct);
+                        new_struct.extend_from_slice(&token::END_NODE.to_be_bytes());
+                    }
+                    _ => (),
+                }
+                depth -= 1;
+                new_struct.extend_from_slice(&dt_struct[offset..offset + 4]);
+                offset += 4;
+            }
+            token::PROP => {
+                let len = read_be32(dt_struct, offset + 4) as usize;
+                let name = c_str(&dt_strings, read_be32(dt_struct, offset + 8) as usize);
+                let next = (offset + 12 + len).next_multiple_of(4);
+
+                // existing properties are replaced.
+                if !(in_chosen
+                    && depth == 2
+                    && (name == INITRD_START.as_bytes() || name == INITRD_END.as_bytes()))
+                {
+                    new_struct.extend_from_slice(&dt_struct[offset..next]);
+                }
+                offset = next;
+            }
+            token::NOP => {
+                new_struct.extend_from_slice(&dt_struct[offset..offset + 4]);
+                offset += 4;
+            }
+            token::END => {
+                new_struct.extend_from_slice(&dt_struct[offset..offset + 4]);
+                break;
+            }
+            _ => panic!("unknown FDT token: {:#x}", token),
+        }
+    }
+
+    // header and memory reservation block | structure block | strings block
+    let mut new_dtb = dtb[..off_dt_struct].to_vec();
+    new_dtb.extend_from_slice(&new_struct);
+    let new_off_dt_strings = new_dtb.len();
+    new_dtb.extend_from_slice(&dt_strings);
+
+    let total_size = new_dtb.len();
+    write_be32(&mut new_dtb, HEADER_TOTALSIZE, total_size as u32);
+    write_be32(
+        &mut new_dtb,
+        HEADER_OFF_DT_STRINGS,
+        new_off_dt_strings as u32,
+    );
+    write_be32(&mut new_dtb, HEADER_SIZE_DT_STRUCT, new_struct.len() as u32);
+    write_be32(
+        &mut new_dtb,
+        HEADER_SIZE_DT_STRINGS,
+        dt_strings.len() as u32,
+    );
+
+    *dtb = new_dtb;
+    true
+}
+
+/// Overwrite `reg` of the top level `memory` node with `start` and `size`.
+///
+/// `reg` must have 2 address cells and 2 size cells.
+/// Return true if the property is overwritten.
+pub fn set_memory_region(dtb: &mut [u8], start: usize, size: usize) -> bool {
+    if dtb.len() < FDT_HEADER_SIZE || read_be32(dtb, 0) != FDT_MAGIC {
+        return false;
+    }
+
+    let off_dt_struct = read_be32(dtb, HEADER_OFF_DT_STRUCT) as usize;
+    let size_dt_struct = read_be32(dtb, HEADER_SIZE_DT_STRUCT) as usize;
+    let off_dt_strings = read_be32(dtb, HEADER_OFF_DT_STRINGS) as usize;
+    let (header, dt_strings) = dtb.split_at_mut(off_dt_strings);
+    let dt_struct = &mut header[off_dt_struct..off_dt_struct + size_dt_struct];
+
+    let mut depth = 0;
+    let mut in_memory = false;
+    let mut offset = 0;
+    while offset < dt_struct.len() {
+        let token = read_be32(dt_struct, offset);
+        match token {
+            token::BEGIN_NODE => {
+                let name = c_str(dt_struct, offset + 4);
+                depth += 1;
+                if depth == 2 {
+                    in_memory = node_name_matches(name, b"memory");
+                }
+                offset = (offset + 4 + name.len() + 1).next_multiple_of(4);
+            }
+            token::END_NODE => {
+                if depth == 2 {
+                    in_memory = false;
+                }
+                depth -= 1;
+                offset += 4;
+            }
+            token::PROP => {
+                let len = read_be32(dt_struct, offset + 4) as usize;
+                let name_offset = read_be32(dt_struct, offset + 8) as usize;
+                if in_memory
+                    && depth == 2
+                    && len == 16
+                    && c_str(dt_strings, name_offset) == REG.as_bytes()
+                {
+                    dt_struct[offset + 12..offset + 20]
+                        .copy_from_slice(&(start as u64).to_be_bytes());
+                    dt_struct[offset + 20..offset + 28]
+                        .copy_from_slice(&(size as u64).to_be_bytes());
+                    return true;
+                }
+                offset = (offset + 12 + len).next_multiple_of(4);
+            }
+            token::NOP => offset += 4,
+            token::END => break,
+            _ => panic!("unknown FDT token: {:#x}", token),
+        }
+    }
+
+    false
+}
+
+/// Is the node name matched to the path component?
+///
+/// Unit address can be omitted in the path component.
+fn node_name_matches(name: &[u8], component: &[u8]) -> bool {
+    name == component
+        || (!component.contains(&b'@') && name.split(|&c| c == b'@').next() == Some(component))
+}
+
+/// Remove the node at `path` (and its subnodes) by overwriting it with `FDT_NOP`.
+///
+/// Unit address in `path` can be omitted. (e.g. `/soc/pci` matches `/soc/pci@30000000`)
+/// Return true if the node is found.
+pub fn remove_node(dtb: &mut [u8], path: &str) -> bool {
+    if dtb.len() < FDT_HEADER_SIZE || read_be32(dtb, 0) != FDT_MAGIC {
+        return false;
+    }
+
+    let components: Vec<&[u8]> = path
+        .split('/')
+        .filter(|component| !component.is_empty())
+        .map(str::as_bytes)
+        .collect();
+    if components.is_empty() {
+        return false;
+    }
+
+    let off_dt_struct = read_be32(dtb, HEADER_OFF_DT_STRUCT) as usize;
+    let size_dt_struct = read_be32(dtb, HEADER_SIZE_DT_STRUCT) as usize;
+    let dt_struct = &mut dtb[off_dt_struct..off_dt_struct + size_dt_struct];
+
+    // depth of current node (root node is 1) and the number of matched path components.
+    let mut depth = 0;
+    let mut matched = 0;
+    let mut target_start = None;
+    let mut offset = 0;
+    while offset < dt_struct.len() {
+        let token = read_be32(dt_struct, offset);
+        match token {
+            token::BEGIN_NODE => {
+                let name = c_str(dt_struct, offset + 4);
+                let next = (offset + 4 + name.len() + 1).next_multiple_of(4);
+                depth += 1;
+                if depth >= 2
+                    && matched == depth - 2
+                    && matched < components.len()
+                    && node_name_matches(name, components[matched])
+                {
+                    matched += 1;
+                    if matched == components.len() {
+                        target_start = Some(offset);
+                    }
+                }
+                offset = next;
+            }
+            token::END_NODE => {
+                if depth >= 2 && matched == depth - 1 {
+                    if let Some(start) = target_start.filter(|_| matched == components.len()) {
+                        for nop_offset in (start..offset + 4).step_by(4) {
+                            write_be32(dt_struct, nop_offset, token::NOP);
+                        }
+                        return true;
+                    }
+                    matched -= 1;
+                }
+                depth -= 1;
+                offset += 4;
+            }
+            token::PROP => {
+                let len = read_be32(dt_struct, offset + 4) as usize;
+                offset = (offset + 12 + len).next_multiple_of(4);
+            }
+            token::NOP => offset += 4,
+            token::END => break,
+            _ => panic!("unknown FDT token: {:#x}", token),
+        }
+    }
+
+    false
+}
@@ -0,0 +1,53 @@
+//! RISC-V Linux kernel image (flat binary) header.
+//! Ref: [https://docs.kernel.org/arch/riscv/boot-image-header.html](https://docs.kernel.org/arch/riscv/boot-image-header.html)
+
+/// Size of image header.
+const HEADER_SIZE: usize = 64;
+/// Offset of `text_offset`.
+const HEADER_TEXT_OFFSET: usize = 8;
+/// Offset of `image_size`.
+const HEADER_IMAGE_SIZE: usize = 16;
+/// Offset of `magic` (deprecated but still set).
+const HEADER_MAGIC: usize = 48;
+/// Offset of `magic2`.
+const HEADER_MAGIC2: usize = 56;
+
+/// Magic number "RISCV\0\0\0".
+const MAGIC: [u8; 8] = *b"RISCV\0\0\0";
+/// Magic number "RSC\x05".
+const MAGIC2: [u8; 4] = *b"RSC\x05";
+
+/// Header of RISC-V Linux kernel image.
+#[derive(Debug)]
+pub struct ImageHeader {
+    /// Image load offset from start of RAM.
+    pub text_offset: usize,
+    /// Effective image size. (including bss)
+    pub image_size: usize,
+}
+
+impl ImageHeader {
+    /// Parse image header.
+    ///
+    /// Return `None` if `image` does not start with RISC-V image header.
+    pub fn parse(image: &[u8]) -> Option<Self> {
+        let read_u64 = |offset: usize| {
+            usize::try_from(u64::from_le_bytes(
+                image[offset..offset + 8].try_into().unwrap(),
+            ))
+            .unwrap()
+        };
+
+        if image.len() < HEADER_SIZE
+            || (image[HEADER_MAGIC..HEADER_MAGIC + 8] != MAGIC
+                && image[HEADER_MAGIC2..HEADER_MAGIC2 + 4] != MAGIC2)
+        {
+            return None;
+        }
+
+        Some(ImageHeader {
+            text_offset: read_u64(HEADER_TEXT_OFFSET),
+            image_size: read_u64(HEADER_IMAGE_SIZE),
+        })
+    }
+}
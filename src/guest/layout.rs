@@ -0,0 +1,130 @@
+//! Guest memory layout on guest physical address.
+//!
+//! The layout is derived from `/memory` of the host device tree at boot.
+//! Constants in `memmap::constant::guest_memory` are used as defaults. (e.g. embedded dtb on QEMU)
+//!
+//! | start                                     | size                  | region                     |
+//! |-------------------------------------------|-----------------------|----------------------------|
+//! | `dram_base`                               | `dtb_region_size` * N | device tree of each guest  |
+//! | `dram_base + (hart_id + 1) * guest_size`  | `dram_size_per_guest` | memory region of the guest |
+
+use super::scheduler::GUESTS_PER_HART;
+use crate::memmap::constant::{guest_memory, MAX_HART_NUM};
+use crate::memmap::GuestPhysicalAddress;
+use crate::{_hv_start, _stack_start};
+
+use core::cell::OnceCell;
+use core::ops::Range;
+use fdt::Fdt;
+use spin::Mutex;
+
+/// Guest memory layout shared among all HARTs.
+static GUEST_MEMORY_LAYOUT: Mutex<OnceCell<GuestMemoryLayout>> = Mutex::new(OnceCell::new());
+
+/// Alignment of guest memory size. (megapage)
+const GUEST_MEMORY_ALIGN: usize = 0x20_0000;
+/// Minimum memory size per guest.
+const MIN_DRAM_SIZE_PER_GUEST: usize = 64 * 1024 * 1024; // 64 MB = 0x400_0000
+
+/// Guest memory layout.
+#[derive(Debug, Clone, Copy)]
+pub struct GuestMemoryLayout {
+    /// Dram base address in guest memory.
+    pub dram_base: GuestPhysicalAddress,
+    /// Dram memory space per guest.
+    pub dram_size_per_guest: usize,
+    /// Guest DTB space size.
+    pub dtb_region_size: usize,
+    /// Number of guests.
+    pub guest_num: usize,
+}
+
+impl GuestMemoryLayout {
+    /// Default layout. (`memmap::constant::guest_memory`)
+    const DEFAULT: Self = GuestMemoryLayout {
+        dram_base: guest_memory::DRAM_BASE,
+        dram_size_per_guest: guest_memory::DRAM_SIZE_PER_GUEST,
+        dtb_region_size: guest_memory::GUEST_DTB_REGION_SIZE,
+        guest_num: 1,
+    };
+
+    /// Derive layout from host device tree.
+    ///
+    /// Host memory except for the hypervisor is split among guests (one per cpu node).
+    /// The size per guest is capped by the default since guest memory is allocated from the hypervisor heap.
+    ///
+    /// # Panics
+    /// It panics if the host memory is too small for even one guest.
+    fn from_device_tree(device_tree: &Fdt) -> Self {
+        let Some(memory) = device_tree.memory().regions().next() else {
+            return Self::DEFAULT;
+        };
+        let Some(memory_size) = memory.size else {
+            return Self::DEFAULT;
+        };
+        let memory_start = memory.starting_address as usize;
+
+        let hypervisor_size =
+            core::ptr::addr_of!(_stack_start) as usize - core::ptr::addr_of!(_hv_start) as usize;
+        let guest_num = device_tree
+            .find_all_nodes("/cpus/cpu")
+            .count()
+            .clamp(1, MAX_HART_NUM);
+
+        // guests on the same HART share guest physical address but not host memory.
+        let available_size = memory_size.saturating_sub(hypervisor_size);
+        let dram_size_per_guest =
+            available_size / (guest_num * GUESTS_PER_HART) / GUEST_MEMORY_ALIGN
+                * GUEST_MEMORY_ALIGN;
+        assert!(
+            dram_size_per_guest >= MIN_DRAM_SIZE_PER_GUEST,
+            "host memory ({memory_size:#x} bytes) is too small for {guest_num} guest(s): hypervisor uses {hypervisor_size:#x} bytes and a guest needs at least {MIN_DRAM_SIZE_PER_GUEST:#x} bytes"
+        );
+
+        GuestMemoryLayout {
+            dram_base: GuestPhysicalAddress(memory_start),
+            dram_size_per_guest: dram_size_per_guest.min(guest_memory::DRAM_SIZE_PER_GUEST),
+            dtb_region_size: guest_memory::GUEST_DTB_REGION_SIZE,
+            guest_num,
+        }
+    }
+
+    /// Return memory region of the guest running on `hart_id`.
+    pub fn memory_region(&self, hart_id: usize) -> Range<GuestPhysicalAddress> {
+        let start = self.dram_base + (hart_id + 1) * self.dram_size_per_guest;
+        start..start + self.dram_size_per_guest
+    }
+
+    /// Return device tree address of the guest running on `hart_id`.
+    pub fn dtb_addr(&self, hart_id: usize) -> GuestPhysicalAddress {
+        self.dram_base + hart_id * self.dtb_region_size
+    }
+
+    /// Return whole guest dram range including device trees.
+    pub fn dram_range(&self) -> Range<GuestPhysicalAddress> {
+        self.dram_base..self.dram_base + (self.guest_num + 1) * self.dram_size_per_guest
+    }
+}
+
+/// Derive guest memory layout from host device tree.
+///
+/// It is called once before creating guests.
+pub fn init(device_tree: &Fdt) {
+    let layout = *GUEST_MEMORY_LAYOUT
+        .lock()
+        .get_or_init(|| GuestMemoryLayout::from_device_tree(device_tree));
+    crate::println!(
+        "guest memory: {:#x} bytes per guest (guests: {})",
+        layout.dram_size_per_guest,
+        layout.guest_num
+    );
+}
+
+/// Return guest memory layout. (default layout if it is not initialized yet)
+pub fn guest_memory_layout() -> GuestMemoryLayout {
+    GUEST_MEMORY_LAYOUT
+        .lock()
+        .get()
+        .copied()
+        .unwrap_or(GuestMemoryLayout::DEFAULT)
+}
@@ -0,0 +1,386 @@
+//! Round-robin scheduler of guests sharing a HART.
+//!
+//! Guests on a HART are switched by HS-mode timer interrupt at the end of each time slice.
+//! The HS-mode timer is shared by the time slice and the SBI timer of the running guest,
+//! so the nearer one is programmed. (see `HartLocal::program_timer`)
+//! Time of a suspended guest is stopped by adjusting `htimedelta` and RTC offset on resume.
+//!
+//! Guests on the same HART have the same memory layout in guest physical address and share pass-through devices,
+//! but each of them has own G-stage page table and VMID.
+//! IOMMU only knows the page table of guest 0, so DMA capable PCI devices are refused at boot.
+
+use super::context::ContextData;
+use super::Guest;
+use crate::device::plic::{ContextId, IrqQueue, IRQ_QUEUE_LEN};
+use crate::device::rtc::RtcEmulation;
+use crate::h_extension::csrs::{
+    henvcfg, hgatp, htimedelta, hvip, vsatp, vscause, vsepc, vsie, vsscratch, vsstatus, vstimecmp,
+    vstval, vstvec, VsInterruptKind,
+};
+use crate::h_extension::instruction::{hfence_gvma_all, hfence_vvma};
+use crate::memmap::page_table::{sv39x4::FIRST_LV_PAGE_TABLE_LEN, PageTableEntry};
+use crate::memmap::GuestPhysicalAddress;
+use crate::{HartLocal, DEVICES};
+
+use alloc::alloc::alloc_zeroed;
+use core::alloc::Layout;
+use core::sync::atomic::{AtomicU64, Ordering};
+use fdt::Fdt;
+use riscv::register::{sie, sstatus::FS, time};
+use sbi_rt::SbiRet;
+
+/// Number of guests on each HART.
+#[cfg(feature = "multi_guest")]
+pub const GUESTS_PER_HART: usize = 2;
+/// Number of guests on each HART.
+#[cfg(not(feature = "multi_guest"))]
+pub const GUESTS_PER_HART: usize = 1;
+
+/// Length of time slice. (ms)
+const TIME_SLICE_MS: u64 = 10;
+/// Timebase frequency used if it is not found in device tree. (QEMU virt)
+const DEFAULT_TIMEBASE_FREQUENCY: u64 = 10_000_000;
+/// SBI timer value to clear timer without scheduling the next event. (SBI spec chapter 6.1)
+pub const TIMER_DISABLED: u64 = u64::MAX;
+
+/// Length of time slice in timer ticks. (derived from timebase frequency by `init`)
+static TIME_SLICE_TICKS: AtomicU64 =
+    AtomicU64::new(DEFAULT_TIMEBASE_FREQUENCY / 1000 * TIME_SLICE_MS);
+
+/// Derive length of time slice from `timebase-frequency` of host device tree.
+pub fn init(device_tree: &Fdt) {
+    let timebase_frequency = device_tree
+        .find_node("/cpus")
+        .and_then(|cpus| cpus.property("timebase-frequency"))
+        .and_then(fdt::node::NodeProperty::as_usize)
+        .map_or(DEFAULT_TIMEBASE_FREQUENCY, |freq| freq as u64);
+    TIME_SLICE_TICKS.store(timebase_frequency / 1000 * TIME_SLICE_MS, Ordering::Relaxed);
+}
+
+/// Convert milliseconds to timer ticks.
+pub fn ms_to_ticks(ms: u64) -> u64 {
+    TIME_SLICE_TICKS.load(Ordering::Relaxed) / TIME_SLICE_MS * ms
+}
+
+/// Allocate G-stage root page table for an additional guest.
+///
+/// The root page table is 16 KiB and must be aligned to 16 KiB.
+#[allow(clippy::cast_ptr_alignment)]
+pub fn alloc_root_page_table() -> &'static [PageTableEntry; FIRST_LV_PAGE_TABLE_LEN] {
+    let layout = Layout::new::<[PageTableEntry; FIRST_LV_PAGE_TABLE_LEN]>()
+        .align_to(FIRST_LV_PAGE_TABLE_LEN * core::mem::size_of::<PageTableEntry>())
+        .unwrap();
+    unsafe {
+        (alloc_zeroed(layout) as *const [PageTableEntry; FIRST_LV_PAGE_TABLE_LEN])
+            .as_ref()
+            .expect("failed to allocate root page table")
+    }
+}
+
+/// Register and device state of a guest that is not running.
+#[derive(Debug)]
+pub struct SuspendedState {
+    /// Guest context. (copied from HS-mode stack top)
+    context: ContextData,
+    /// Value of hgatp.
+    hgatp: usize,
+    /// VS-level CSRs. (vsstatus, vsie, vstvec, vsscratch, vsepc, vscause, vstval, vsatp)
+    vs_csrs: [usize; 8],
+    /// Value of vstimecmp. (`None` if Sstc is not enabled)
+    vstimecmp: Option<usize>,
+    /// Value of hvip.
+    hvip: usize,
+    /// `sie.SSIE` and `sie.SEIE`.
+    sie: (bool, bool),
+    /// Value of htimedelta.
+    htimedelta: usize,
+    /// Interrupts injected to the PLIC context of the guest.
+    pending_irqs: IrqQueue<IRQ_QUEUE_LEN>,
+}
+
+/// Host time taken on context switch.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct HostTime {
+    /// Value of `time` CSR.
+    pub ticks: u64,
+    /// Host RTC time (ns). (`None` if the host has no RTC)
+    pub rtc_ns: Option<u64>,
+}
+
+impl HostTime {
+    /// Take current host time.
+    fn now() -> Self {
+        HostTime {
+            ticks: time::read64(),
+            rtc_ns: DEVICES
+                .lock()
+                .get()
+                .unwrap()
+                .rtc
+                .as_ref()
+                .map(RtcEmulation::host_time),
+        }
+    }
+}
+
+impl SuspendedState {
+    /// Return state of a guest that has never run.
+    fn initial(context: ContextData) -> Self {
+        SuspendedState {
+            context,
+            hgatp: hgatp::read().bits(),
+            vs_csrs: [0; 8],
+            vstimecmp: henvcfg::read()
+                .stce()
+                .then_some(usize::try_from(TIMER_DISABLED).unwrap()),
+            hvip: 0,
+            sie: (true, true),
+            htimedelta: 0,
+            pending_irqs: IrqQueue::new(),
+        }
+    }
+}
+
+/// Save floating-point registers to `context` regardless of `sstatus.FS`.
+///
+/// They are restored on trap exit only if saved `sstatus.FS` is Dirty. (see `hstrap_exit`)
+fn save_fp_registers(context: &mut ContextData) {
+    let fcsr: usize;
+    unsafe {
+        core::arch::asm!(
+            ".option push
+            .option arch, +d
+            fsd f0, 0*8({freg})
+            fsd f1, 1*8({freg})
+            fsd f2, 2*8({freg})
+            fsd f3, 3*8({freg})
+            fsd f4, 4*8({freg})
+            fsd f5, 5*8({freg})
+            fsd f6, 6*8({freg})
+            fsd f7, 7*8({freg})
+            fsd f8, 8*8({freg})
+            fsd f9, 9*8({freg})
+            fsd f10, 10*8({freg})
+            fsd f11, 11*8({freg})
+            fsd f12, 12*8({freg})
+            fsd f13, 13*8({freg})
+            fsd f14, 14*8({freg})
+            fsd f15, 15*8({freg})
+            fsd f16, 16*8({freg})
+            fsd f17, 17*8({freg})
+            fsd f18, 18*8({freg})
+            fsd f19, 19*8({freg})
+            fsd f20, 20*8({freg})
+            fsd f21, 21*8({freg})
+            fsd f22, 22*8({freg})
+            fsd f23, 23*8({freg})
+            fsd f24, 24*8({freg})
+            fsd f25, 25*8({freg})
+            fsd f26, 26*8({freg})
+            fsd f27, 27*8({freg})
+            fsd f28, 28*8({freg})
+            fsd f29, 29*8({freg})
+            fsd f30, 30*8({freg})
+            fsd f31, 31*8({freg})
+            frcsr {fcsr}
+            .option pop",
+            freg = in(reg) context.freg.as_mut_ptr(),
+            fcsr = out(reg) fcsr,
+        );
+    }
+    context.fcsr = fcsr;
+    context.sstatus |= (FS::Dirty as usize) << 13;
+}
+
+impl Guest {
+    /// Set SBI timer of the guest. (guest time)
+    pub fn set_timer(&mut self, stime_value: u64) {
+        self.timer_deadline = stime_value;
+    }
+
+    /// Return host time at the last context switch of the guest. (suspend or resume)
+    pub fn switched_at(&self) -> HostTime {
+        self.switched_at
+    }
+
+    /// Make the guest start from `entry_point` when it is scheduled for the first time.
+    ///
+    /// hgatp must point to the root page table of the guest. (it is saved as the guest's one)
+    pub fn suspend_at_entry(&mut self, entry_point: GuestPhysicalAddress, sstatus: usize) {
+        let mut context = ContextData::default();
+        // a0 = hart id, a1 = dtb address (see `hart_entry`)
+        context.xreg[10] = self.hart_id as u64;
+        context.xreg[11] = self.dtb_addr.raw() as u64;
+        context.sepc = entry_point.raw();
+        context.sstatus = sstatus;
+
+        self.suspended = Some(SuspendedState::initial(context));
+        self.switched_at = HostTime::now();
+    }
+
+    /// Save state of the running guest and stop its time.
+    fn suspend(&mut self) {
+        let mut context = self.context.context_data().clone();
+        if (context.sstatus >> 13) & 0b11 != FS::Off as usize {
+            save_fp_registers(&mut context);
+        }
+
+        let context_id = ContextId::new(self.hart_id, true);
+        let pending_irqs = DEVICES.lock().get().unwrap().plic.pending_irqs(&context_id);
+
+        let sie = sie::read();
+        self.suspended = Some(SuspendedState {
+            context,
+            hgatp: hgatp::read().bits(),
+            vs_csrs: [
+                vsstatus::read().bits(),
+                vsie::read().bits(),
+                vstvec::read().bits(),
+                vsscratch::read().bits(),
+                vsepc::read().bits(),
+                vscause::read().bits(),
+                vstval::read().bits(),
+                vsatp::read().bits(),
+            ],
+            vstimecmp: henvcfg::read().stce().then(|| vstimecmp::read().bits()),
+            hvip: hvip::read().bits(),
+            sie: (sie.ssoft(), sie.sext()),
+            htimedelta: htimedelta::read().bits(),
+            pending_irqs,
+        });
+        self.switched_at = HostTime::now();
+    }
+
+    /// Restore state saved by `suspend` or `suspend_at_entry`.
+    #[allow(clippy::cast_possible_truncation)]
+    fn resume(&mut self) {
+        let state = self.suspended.take().expect("guest is not suspended");
+        *self.context.get_context() = state.context;
+
+        hgatp::write(state.hgatp);
+        // VMID may not be supported by hardware.
+        hfence_gvma_all();
+        super::flush_iommu_gpa_range(&self.memory_region);
+        hfence_vvma(None, None);
+
+        let [vsstatus, vsie, vstvec, vsscratch, vsepc, vscause, vstval, vsatp] = state.vs_csrs;
+        vsstatus::write(vsstatus);
+        vsie::write(vsie);
+        vstvec::write(vstvec);
+        vsscratch::write(vsscratch);
+        vsepc::write(vsepc);
+        vscause::write(vscause);
+        vstval::write(vstval);
+        vsatp::write(vsatp);
+        if let Some(vstimecmp) = state.vstimecmp {
+            vstimecmp::write(vstimecmp);
+        }
+        hvip::write(state.hvip);
+        unsafe {
+            let (ssoft, sext) = state.sie;
+            if ssoft {
+                sie::set_ssoft();
+            } else {
+                sie::clear_ssoft();
+            }
+            if sext {
+                sie::set_sext();
+            } else {
+                sie::clear_sext();
+            }
+        }
+
+        // the guest time does not advance while it is suspended.
+        let suspended_at = self.switched_at();
+        let now = HostTime::now();
+        let suspended_time = now.ticks - suspended_at.ticks;
+        htimedelta::write(state.htimedelta.wrapping_sub(suspended_time as usize));
+        if let (Some(suspended_rtc), Some(now_rtc)) = (suspended_at.rtc_ns, now.rtc_ns) {
+            self.rtc_offset = self
+                .rtc_offset
+                .wrapping_sub_unsigned(now_rtc.wrapping_sub(suspended_rtc));
+        }
+        self.switched_at = now;
+
+        let context_id = ContextId::new(self.hart_id, true);
+        DEVICES
+            .lock()
+            .get_mut()
+            .unwrap()
+            .plic
+            .set_pending_irqs(&context_id, state.pending_irqs);
+    }
+}
+
+impl HartLocal {
+    /// Add a guest prepared by `Guest::suspend_at_entry` and start time slice of the running guest.
+    ///
+    /// # Panics
+    /// It panics if the guest is not suspended.
+    pub fn add_guest(&mut self, guest: Guest) {
+        assert!(
+            guest.suspended.is_some(),
+            "added guest must be suspended until it is scheduled"
+        );
+        self.guests.push(guest);
+        self.slice_end = time::read64() + TIME_SLICE_TICKS.load(Ordering::Relaxed);
+        let _ = self.program_timer();
+    }
+
+    /// Program HS-mode timer to the nearer of the SBI timer of the running guest and the end of time slice.
+    ///
+    /// HS-mode timer interrupt is disabled if there is no event.
+    /// Return the result of SBI `set_timer`.
+    #[must_use]
+    #[allow(clippy::cast_possible_truncation)]
+    pub fn program_timer(&self) -> SbiRet {
+        let timer_deadline = self.guest().timer_deadline;
+        let guest_timer = (timer_deadline != TIMER_DISABLED)
+            .then(|| timer_deadline.wrapping_sub(htimedelta::read().bits() as u64));
+        let slice_end = (self.guests.len() > 1).then_some(self.slice_end);
+
+        if let Some(next_event) = guest_timer.into_iter().chain(slice_end).min() {
+            let sbi_ret = sbi_rt::set_timer(next_event);
+            unsafe {
+                sie::set_stimer();
+            }
+            sbi_ret
+        } else {
+            unsafe {
+                sie::clear_stimer();
+            }
+            SbiRet::success(0)
+        }
+    }
+
+    /// Handle HS-mode timer interrupt.
+    ///
+    /// Inject VS-mode timer interrupt if the SBI timer of the running guest is expired,
+    /// and switch guests if the time slice is over.
+    #[allow(clippy::cast_possible_truncation)]
+    pub fn timer_interrupt(&mut self) {
+        let now = time::read64();
+        let guest = self.guest_mut();
+        if guest.timer_deadline != TIMER_DISABLED
+            && now.wrapping_add(htimedelta::read().bits() as u64) >= guest.timer_deadline
+        {
+            guest.timer_deadline = TIMER_DISABLED;
+            hvip::set(VsInterruptKind::Timer);
+        }
+
+        if self.guests.len() > 1 && now >= self.slice_end {
+            self.switch_guest();
+        }
+        let _ = self.program_timer();
+    }
+
+    /// Suspend the running guest and resume the next one.
+    ///
+    /// The incoming guest is restored to `ContextData` on HS-mode stack top, so it runs on the next trap exit.
+    pub fn switch_guest(&mut self) {
+        self.guests[self.current].suspend();
+        self.current = (self.current + 1) % self.guests.len();
+        self.guests[self.current].resume();
+        self.slice_end = time::read64() + TIME_SLICE_TICKS.load(Ordering::Relaxed);
+    }
+}
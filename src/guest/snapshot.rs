@@ -0,0 +1,317 @@
+//! Snapshot of guest register and device state.
+//!
+//! Memory contents are not included. (they are expected to be handled by an external tool)
+//! The guest saves and restores the state through the hikami snapshot SBI extension.
+//!
+//! # Layout (version 2)
+//! All fields are little-endian `u64`.
+//! | field                                                                          | count |
+//! |--------------------------------------------------------------------------------|-------|
+//! | magic (`SNAPSHOT_MAGIC`)                                                       | 1     |
+//! | version (`SNAPSHOT_VERSION`)                                                   | 1     |
+//! | xreg                                                                           | 32    |
+//! | sstatus, sepc                                                                  | 2     |
+//! | freg                                                                           | 32    |
+//! | fcsr                                                                           | 1     |
+//! | vsstatus, vsie, vstvec, vsscratch, vsepc, vscause, vstval, vsip, vsatp         | 9     |
+//! | Zicfiss: initialized (0 or 1), ssp, `henvcfg.SSE`, `senvcfg.SSE`               | 4     |
+//! | PLIC: injected interrupt queue of the guest context (`IrqQueue::to_bits`)      | 1     |
+//! | RTC offset                                                                     | 1     |
+
+use super::Guest;
+use crate::device::plic::{ContextId, IrqQueue};
+use crate::h_extension::csrs::{
+    vsatp, vscause, vsepc, vsie, vsip, vsscratch, vsstatus, vstval, vstvec,
+};
+use crate::h_extension::instruction::hfence_vvma;
+use crate::memmap::page_table::{constants::PAGE_SIZE, g_stage_trans_addr, vs_stage_trans_addr};
+use crate::memmap::{GuestPhysicalAddress, GuestVirtualAddress, HostPhysicalAddress};
+use crate::DEVICES;
+
+use alloc::vec::Vec;
+
+/// Magic number of snapshot. ("HKMISNAP")
+const SNAPSHOT_MAGIC: u64 = u64::from_le_bytes(*b"HKMISNAP");
+/// Version of snapshot layout.
+const SNAPSHOT_VERSION: u64 = 2;
+/// Size of snapshot in bytes.
+pub const SNAPSHOT_SIZE: usize = 8 * (2 + 32 + 2 + 32 + 1 + 9 + 4 + 1 + 1);
+
+/// Sequential writer of snapshot fields.
+struct SnapshotWriter<'a> {
+    /// Destination buffer.
+    buf: &'a mut [u8],
+    /// Current offset.
+    offset: usize,
+}
+
+impl SnapshotWriter<'_> {
+    /// Write a field.
+    fn put(&mut self, value: u64) {
+        self.buf[self.offset..self.offset + 8].copy_from_slice(&value.to_le_bytes());
+        self.offset += 8;
+    }
+}
+
+/// Sequential reader of snapshot fields.
+struct SnapshotReader<'a> {
+    /// Source buffer.
+    buf: &'a [u8],
+    /// Current offset.
+    offset: usize,
+}
+
+impl SnapshotReader<'_> {
+    /// Read a field.
+    fn get(&mut self) -> u64 {
+        let value = u64::from_le_bytes(self.buf[self.offset..self.offset + 8].try_into().unwrap());
+        self.offset += 8;
+        value
+    }
+
+    /// Read a field as `usize`.
+    fn get_usize(&mut self) -> usize {
+        usize::try_from(self.get()).unwrap()
+    }
+}
+
+/// Index of sepc field. (after magic, version, xreg and sstatus)
+const SEPC_INDEX: usize = 2 + 32 + 1;
+/// Index of vsatp field. (the last of VS-level CSRs)
+const VSATP_INDEX: usize = SEPC_INDEX + 1 + 32 + 1 + 8;
+
+/// Bits of sstatus that are restored from snapshot. (FS, VS, SUM and MXR)
+///
+/// Others are kept from the current context (e.g. SIE, SPIE) and SPP is forced to VS-mode.
+const SSTATUS_GUEST_BITS: usize = (0b11 << 13) | (0b11 << 9) | (1 << 18) | (1 << 19);
+/// `sstatus.SPP`
+const SSTATUS_SPP: usize = 1 << 8;
+
+/// Return the field at `index` of snapshot.
+fn snapshot_field(buf: &[u8], index: usize) -> usize {
+    SnapshotReader {
+        buf,
+        offset: index * 8,
+    }
+    .get_usize()
+}
+
+impl Guest {
+    /// Is `buf` a snapshot that can be restored to the guest?
+    ///
+    /// The magic and version must match, and sepc must be in the guest memory.
+    /// (sepc is translated by vsatp of the snapshot)
+    fn is_valid_snapshot(&self, buf: &[u8]) -> bool {
+        let mut reader = SnapshotReader { buf, offset: 0 };
+        if reader.get() != SNAPSHOT_MAGIC || reader.get() != SNAPSHOT_VERSION {
+            return false;
+        }
+
+        let sepc = GuestVirtualAddress(snapshot_field(buf, SEPC_INDEX));
+        let running_vsatp = vsatp::read().bits();
+        vsatp::write(snapshot_field(buf, VSATP_INDEX));
+        let sepc_gpa = vs_stage_trans_addr(sepc);
+        vsatp::write(running_vsatp);
+
+        sepc.raw() % 2 == 0 && sepc_gpa.is_ok_and(|gpa| self.memory_region.contains(&gpa))
+    }
+
+    /// Return host physical chunks
+    /// Return host physical chunks `(address, length)` backing `gpa..gpa + size`.
+    ///
+    /// Return `None` if the range is not in the guest memory. (e.g. identity mapped devices)
+    fn guest_memory_chunks(
+        &self,
+        gpa: GuestPhysicalAddress,
+        size: usize,
+    ) -> Option<Vec<(HostPhysicalAddress, usize)>> {
+        let end = gpa.raw().checked_add(size)?;
+        if gpa < self.memory_region.start || end > self.memory_region.end.raw() {
+            return None;
+        }
+
+        let mut chunks = Vec::new();
+        let mut chunk_gpa = gpa.raw();
+        while chunk_gpa < end {
+            let chunk_len = (PAGE_SIZE - chunk_gpa % PAGE_SIZE).min(end - chunk_gpa);
+            let hpa = g_stage_trans_addr(GuestPhysicalAddress(chunk_gpa)).ok()?;
+            chunks.push((hpa, chunk_len));
+            chunk_gpa += chunk_len;
+        }
+        Some(chunks)
+    }
+
+    /// Save state of the guest to guest memory `gpa..gpa + size` and return the written size.
+    ///
+    /// Return `None` if the buffer is too small or not in the guest memory.
+    pub fn save_state_to_guest(&self, gpa: GuestPhysicalAddress, size: usize) -> Option<usize> {
+        if size < SNAPSHOT_SIZE {
+            return None;
+        }
+        let chunks = self.guest_memory_chunks(gpa, SNAPSHOT_SIZE)?;
+
+        let mut buf = [0u8; SNAPSHOT_SIZE];
+        let written = self.save_state(&mut buf);
+        let mut offset = 0;
+        for (hpa, len) in chunks {
+            unsafe {
+                core::ptr::copy_nonoverlapping(buf[offset..].as_ptr(), hpa.raw() as *mut u8, len);
+            }
+            offset += len;
+        }
+
+        Some(written)
+    }
+
+    /// Restore state of the guest from guest memory `gpa..gpa + size` saved by `save_state_to_guest`.
+    ///
+    /// Return `false` without changing the state if the buffer is not a valid snapshot.
+    pub fn restore_state_from_guest(&mut self, gpa: GuestPhysicalAddress, size: usize) -> bool {
+        if size < SNAPSHOT_SIZE {
+            return false;
+        }
+        let Some(chunks) = self.guest_memory_chunks(gpa, SNAPSHOT_SIZE) else {
+            return false;
+        };
+
+        let mut buf = [0u8; SNAPSHOT_SIZE];
+        let mut offset = 0;
+        for (hpa, len) in chunks {
+            unsafe {
+                core::ptr::copy_nonoverlapping(
+                    hpa.raw() as *const u8,
+                    buf[offset..].as_mut_ptr(),
+                    len,
+                );
+            }
+            offset += len;
+        }
+        if !self.is_valid_snapshot(&buf) {
+            return false;
+        }
+
+        self.restore_state(&buf);
+        // vsatp may point to another page table.
+        hfence_vvma(None, None);
+        true
+    }
+
+    /// Save register and device state of the guest to `buf` and return the written size.
+    ///
+    /// It must be called on the HART running the guest since VS-level CSRs are read directly.
+    #[allow(clippy::cast_sign_loss)]
+    pub fn save_state(&self, buf: &mut [u8]) -> usize {
+        assert!(
+            buf.len() >= SNAPSHOT_SIZE,
+            "snapshot buffer is too small: {:#x} < {:#x}",
+            buf.len(),
+            SNAPSHOT_SIZE
+        );
+        let mut writer = SnapshotWriter { buf, offset: 0 };
+        writer.put(SNAPSHOT_MAGIC);
+        writer.put(SNAPSHOT_VERSION);
+
+        let context_data = self.context.context_data();
+        context_data.xreg.iter().for_each(|&x| writer.put(x));
+        writer.put(context_data.sstatus as u64);
+        writer.put(context_data.sepc as u64);
+        context_data.freg.iter().for_each(|&f| writer.put(f));
+        writer.put(context_data.fcsr as u64);
+
+        [
+            vsstatus::read().bits(),
+            vsie::read().bits(),
+            vstvec::read().bits(),
+            vsscratch::read().bits(),
+            vsepc::read().bits(),
+            vscause::read().bits(),
+            vstval::read().bits(),
+            vsip::read().bits(),
+            vsatp::read().bits(),
+        ]
+        .iter()
+        .for_each(|&csr| writer.put(csr as u64));
+
+        // shadow stack state is always present. (the flag keeps the snapshot layout)
+        let shadow_stack = self.context.shadow_stack();
+        writer.put(1);
+        writer.put(shadow_stack.ssp.bits());
+        writer.put(u64::from(shadow_stack.henv_sse));
+        writer.put(u64::from(shadow_stack.senv_sse));
+
+        let context_id = ContextId::new(self.hart_id, true);
+        writer.put(
+            DEVICES
+                .lock()
+                .get()
+                .unwrap()
+                .plic
+                .pending_irqs(&context_id)
+                .to_bits(),
+        );
+
+        writer.put(self.rtc_offset as u64);
+
+        writer.offset
+    }
+
+    /// Restore register and device state of the guest from `buf` saved by `save_state`.
+    ///
+    /// It must be called on the HART that will run the guest since VS-level CSRs are written directly.
+    /// Only guest owned bits of sstatus are restored. (see `SSTATUS_GUEST_BITS`)
+    #[allow(clippy::cast_possible_truncation, clippy::cast_possible_wrap)]
+    pub fn restore_state(&mut self, buf: &[u8]) {
+        assert!(
+            buf.len() >= SNAPSHOT_SIZE,
+            "snapshot is too small: {:#x} < {:#x}",
+            buf.len(),
+            SNAPSHOT_SIZE
+        );
+        let mut reader = SnapshotReader { buf, offset: 0 };
+        assert_eq!(reader.get(), SNAPSHOT_MAGIC, "invalid snapshot magic");
+        let version = reader.get();
+        assert_eq!(
+            version, SNAPSHOT_VERSION,
+            "unsupported snapshot version: {version}"
+        );
+
+        let context_data = self.context.get_context();
+        context_data.xreg.iter_mut().for_each(|x| *x = reader.get());
+        context_data.sstatus = (context_data.sstatus & !SSTATUS_GUEST_BITS)
+            | (reader.get_usize() & SSTATUS_GUEST_BITS)
+            | SSTATUS_SPP;
+        context_data.sepc = reader.get_usize();
+        context_data.freg.iter_mut().for_each(|f| *f = reader.get());
+        context_data.fcsr = reader.get_usize();
+
+        vsstatus::write(reader.get_usize());
+        vsie::write(reader.get_usize());
+        vstvec::write(reader.get_usize());
+        vsscratch::write(reader.get_usize());
+        vsepc::write(reader.get_usize());
+        vscause::write(reader.get_usize());
+        vstval::write(reader.get_usize());
+        vsip::write(reader.get_usize());
+        vsatp::write(reader.get_usize());
+
+        let zicfiss_initialized = reader.get() != 0;
+        let (ssp, henv_sse, senv_sse) = (reader.get(), reader.get() != 0, reader.get() != 0);
+        if zicfiss_initialized {
+            let shadow_stack = self.context.shadow_stack_mut();
+            shadow_stack.ssp.write(ssp);
+            shadow_stack.henv_sse = henv_sse;
+            shadow_stack.senv_sse = senv_sse;
+        }
+
+        let context_id = ContextId::new(self.hart_id, true);
+        let pending_irqs = IrqQueue::from_bits(reader.get());
+        DEVICES
+            .lock()
+            .get_mut()
+            .unwrap()
+            .plic
+            .set_pending_irqs(&context_id, pending_irqs);
+
+        self.rtc_offset = reader.get() as i64;
+    }
+}
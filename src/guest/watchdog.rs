@@ -0,0 +1,196 @@
+//! Watchdog of guest panic loops.
+//!
+//! A panicking guest kernel often raises the same exception at the same address forever,
+//! and it cannot be distinguished from a hypervisor hang on the console.
+//! Exceptions forwarded to the guest are recorded, and if the same (scause, sepc, stval) repeats
+//! more than `REPEAT_THRESHOLD` times within `WINDOW_MS`, the guest state is dumped and the guest is stopped.
+//! stval is a part of the key so that demand faults at the same pc (e.g. a loop touching new pages)
+//! are not regarded as a panic loop.
+//!
+//! Exceptions emulated by hypervisor (e.g. MMIO) are not forwarded, so they are never recorded.
+
+use super::{scheduler, Guest};
+use crate::h_extension::csrs::{vsatp, vscause, vsepc, vsstatus, vstval, vstvec};
+use crate::memmap::page_table::{g_stage_trans_addr, vs_stage_trans_addr};
+use crate::memmap::{GuestVirtualAddress, HostPhysicalAddress};
+use crate::{current_hart_id, println, HartLocal};
+
+use core::arch::asm;
+use riscv::register::time;
+
+/// Number of repeats of the same exception regarded as a panic loop.
+///
+/// It is configured by `HIKAMI_WATCHDOG_THRESHOLD` at build time. (default: 8)
+const REPEAT_THRESHOLD: usize = include!(concat!(env!("OUT_DIR"), "/watchdog_threshold.rs"));
+/// Time window to count repeats. (ms)
+///
+/// It is configured by `HIKAMI_WATCHDOG_WINDOW_MS` at build time. (default: 100)
+const WINDOW_MS: u64 = include!(concat!(env!("OUT_DIR"), "/watchdog_window_ms.rs"));
+/// Number of recorded exceptions. (repeats older than `REPEAT_THRESHOLD` records are not needed)
+const HISTORY_LEN: usize = REPEAT_THRESHOLD;
+/// Number of doublewords of guest stack to dump.
+const STACK_DUMP_LEN: usize = 16;
+
+/// An exception forwarded to the guest.
+#[derive(Debug, Clone, Copy)]
+struct ExceptionRecord {
+    /// Exception cause.
+    scause: usize,
+    /// Guest pc of the exception.
+    sepc: usize,
+    /// Trap value of the exception.
+    stval: usize,
+    /// Host time when the exception occurred.
+    time: u64,
+}
+
+/// Recent exceptions forwarded to the guest.
+#[derive(Debug)]
+pub struct Watchdog {
+    /// Ring buffer of records.
+    history: [Option<ExceptionRecord>; HISTORY_LEN],
+    /// Index of the next record.
+    next: usize,
+}
+
+impl Watchdog {
+    /// Constructor for `Watchdog`.
+    pub const fn new() -> Self {
+        Watchdog {
+            history: [None; HISTORY_LEN],
+            next: 0,
+        }
+    }
+
+    /// Record an exception and return true if it looks like a panic loop.
+    fn record(&mut self, scause: usize, sepc: usize, stval: usize) -> bool {
+        let now = time::read64();
+        let window = scheduler::ms_to_ticks(WINDOW_MS);
+        let repeats = self
+            .history
+            .iter()
+            .flatten()
+            .filter(|record| {
+                record.scause == scause
+                    && record.sepc == sepc
+                    && record.stval == stval
+                    && now.wrapping_sub(record.time) < window
+            })
+            .count();
+
+        self.history[self.next] = Some(ExceptionRecord {
+            scause,
+            sepc,
+            stval,
+            time: now,
+        });
+        self.next = (self.next + 1) % HISTORY_LEN;
+
+        repeats >= REPEAT_THRESHOLD
+    }
+}
+
+/// Record an exception forwarded to the running guest and stop the guest if it is in a panic loop.
+pub fn check_forwarded_exception(hart: &mut HartLocal, scause: usize, stval: usize) {
+    let sepc = hart.guest_mut().context().sepc();
+    if hart.watchdog.record(scause, sepc, stval) {
+        dump_guest_state(hart, scause);
+        stop_guest(hart);
+    }
+}
+
+/// Translate guest virtual address to host physical address with two-stage translation.
+fn guest_to_host(gva: usize) -> Option<HostPhysicalAddress> {
+    vs_stage_trans_addr(GuestVirtualAddress(gva))
+        .ok()
+        .and_then(|gpa| g_stage_trans_addr(gpa).ok())
+}
+
+/// Print registers, faulting instruction and stack of the running guest.
+fn dump_guest_state(hart: &mut HartLocal, scause: usize) {
+    let context = hart.guest_mut().context();
+    let sepc = context.sepc();
+
+    println!("==================== guest panic loop ====================");
+    println!(
+        "[hart {}] scause {:#x} repeats at sepc {:#x}",
+        current_hart_id(),
+        scause,
+        sepc
+    );
+    for index in (0..32).step_by(4) {
+        println!(
+            "x{:<2}: {:#018x} x{:<2}: {:#018x} x{:<2}: {:#018x} x{:<2}: {:#018x}",
+            index,
+            context.xreg(index),
+            index + 1,
+            context.xreg(index + 1),
+            index + 2,
+            context.xreg(index + 2),
+            index + 3,
+            context.xreg(index + 3)
+        );
+    }
+    println!("sstatus: {:#x}", context.sstatus());
+    println!(
+        "vsstatus: {:#x}, vstvec: {:#x}, vsepc: {:#x}",
+        vsstatus::read().bits(),
+        vstvec::read().bits(),
+        vsepc::read().bits()
+    );
+    println!(
+        "vscause: {:#x}, vstval: {:#x}, vsatp: {:#x}",
+        vscause::read().bits(),
+        vstval::read().bits(),
+        vsatp::read().bits()
+    );
+
+    // the upper halfword is translated separately since it may lie on the next page.
+    let halfwords = [sepc, sepc + 2].map(|gva| {
+        guest_to_host(gva).map(|hpa| unsafe { (hpa.raw() as *const u16).read_unaligned() })
+    });
+    match halfwords {
+        [Some(lower), _] if lower & 0b11 != 0b11 => println!("instruction: {:#06x}", lower),
+        [Some(lower), Some(upper)] => println!(
+            "instruction: {:#010x}",
+            u32::from(upper) << 16 | u32::from(lower)
+        ),
+        _ => println!("instruction: <unmapped>"),
+    }
+
+    #[allow(clippy::cast_possible_truncation)]
+    let sp = context.xreg(2) as usize;
+    println!("stack:");
+    for offset in (0..STACK_DUMP_LEN * 8).step_by(8) {
+        match guest_to_host(sp + offset) {
+            Some(hpa) => println!("{:#018x}: {:#018x}", sp + offset, unsafe {
+                (hpa.raw() as *const u64).read_unaligned()
+            }),
+            None => println!("{:#018x}: <unmapped>", sp + offset),
+        }
+    }
+    println!("==========================================================");
+}
+
+/// Stop the guest on this HART.
+///
+/// The system is rebooted if `reboot_on_guest_panic` is enabled.
+/// Otherwise (or if the reboot fails), this HART is parked in `wfi` loop with all interrupts disabled.
+/// Other guests on this HART (`multi_guest`) are stopped as well and their page tables are freed.
+fn stop_guest(hart: &HartLocal) -> ! {
+    #[cfg(feature = "reboot_on_guest_panic")]
+    {
+        println!("rebooting...");
+        let _ = sbi_rt::system_reset(sbi_rt::ColdReboot, sbi_rt::SystemFailure);
+    }
+
+    hart.guests.iter().for_each(Guest::destroy_page_table);
+
+    println!("[hart {}] guest is stopped", current_hart_id());
+    unsafe {
+        asm!("csrw sie, zero");
+        loop {
+            asm!("wfi");
+        }
+    }
+}
@@ -93,6 +93,34 @@ pub enum VsInterruptKind {
     Software = 1 << 2,
 }
 
+pub mod vsstatus {
+    //! Virtual supervisor status register.
+    #![allow(dead_code)]
+
+    /// vsstatus register number.
+    const VSSTATUS: usize = 0x200;
+    /// Virtual supervisor status register.
+    pub struct Vsstatus(usize);
+
+    impl_bits!(Vsstatus);
+    read_csr_as!(Vsstatus, 0x200);
+    write_csr_as!(0x200);
+}
+
+pub mod vsie {
+    //! Virtual supervisor interrupt-enable register.
+    #![allow(dead_code)]
+
+    /// vsie register number.
+    const VSIE: usize = 0x204;
+    /// Virtual supervisor interrupt-enable register.
+    pub struct Vsie(usize);
+
+    impl_bits!(Vsie);
+    read_csr_as!(Vsie, 0x204);
+    write_csr_as!(0x204);
+}
+
 pub mod vstvec {
     //! Virtual supervisor trap handler base address.
     #![allow(dead_code)]
@@ -107,6 +135,62 @@ pub mod vstvec {
     write_csr_as!(0x205);
 }
 
+pub mod vsscratch {
+    //! Virtual supervisor scratch register.
+    #![allow(dead_code)]
+
+    /// vsscratch register number.
+    const VSSCRATCH: usize = 0x240;
+    /// Virtual supervisor scratch register.
+    pub struct Vsscratch(usize);
+
+    impl_bits!(Vsscratch);
+    read_csr_as!(Vsscratch, 0x240);
+    write_csr_as!(0x240);
+}
+
+pub mod vsepc {
+    //! Virtual supervisor exception program counter.
+    #![allow(dead_code)]
+
+    /// vsepc register number.
+    const VSEPC: usize = 0x241;
+    /// Virtual supervisor exception program counter.
+    pub struct Vsepc(usize);
+
+    impl_bits!(Vsepc);
+    read_csr_as!(Vsepc, 0x241);
+    write_csr_as!(0x241);
+}
+
+pub mod vscause {
+    //! Virtual supervisor cause register.
+    #![allow(dead_code)]
+
+    /// vscause register number.
+    const VSCAUSE: usize = 0x242;
+    /// Virtual supervisor cause register.
+    pub struct Vscause(usize);
+
+    impl_bits!(Vscause);
+    read_csr_as!(Vscause, 0x242);
+    write_csr_as!(0x242);
+}
+
+pub mod vstval {
+    //! Virtual supervisor trap value register.
+    #![allow(dead_code)]
+
+    /// vstval register number.
+    const VSTVAL: usize = 0x243;
+    /// Virtual supervisor trap value register.
+    pub struct Vstval(usize);
+
+    impl_bits!(Vstval);
+    read_csr_as!(Vstval, 0x243);
+    write_csr_as!(0x243);
+}
+
 pub mod vsip {
     //! Virtual supervisor interrupt pending.
     #![allow(dead_code)]
@@ -116,6 +200,7 @@ pub mod vsip {
     /// Virtual supervisor interrupt pending.
     pub struct Vsip(usize);
 
+    impl_bits!(Vsip);
     read_csr_as!(Vsip, 0x244);
     write_csr_as!(0x244);
 
@@ -149,6 +234,8 @@ pub mod vsatp {
     /// Virtual supervisor address translation and protection.
     pub struct Vsatp(usize);
 
+    impl_bits!(Vsatp);
+
     impl Vsatp {
         /// Current address-translation scheme
         #[inline]
@@ -183,6 +270,20 @@ pub mod vsatp {
     write_csr_as!(0x280);
 }
 
+pub mod vstimecmp {
+    //! Virtual supervisor timer compare register. (Sstc)
+    #![allow(dead_code)]
+
+    /// vstimecmp register number.
+    const VSTIMECMP: usize = 0x24d;
+    /// Virtual supervisor timer compare register.
+    pub struct Vstimecmp(usize);
+
+    impl_bits!(Vstimecmp);
+    read_csr_as!(Vstimecmp, 0x24d);
+    write_csr_as!(0x24d);
+}
+
 pub mod hstatus {
     //! hstatus util functions.
     #![allow(dead_code)]
@@ -204,6 +305,28 @@ pub mod hstatus {
             bits = in(reg) 0b1000_0000
         );
     }
+
+    /// set vtw bit (Virtual Timeout Wait, 21 bit)
+    ///
+    /// Executing `WFI` in VS-mode raises virtual instruction exception.
+    pub unsafe fn set_vtw() {
+        core::arch::asm!(
+            "
+            csrs hstatus, {bits}
+            ",
+            bits = in(reg) 1 << 21
+        );
+    }
+
+    /// clear vtw bit (Virtual Timeout Wait, 21 bit)
+    pub unsafe fn clear_vtw() {
+        core::arch::asm!(
+            "
+            csrc hstatus, {bits}
+            ",
+            bits = in(reg) 1 << 21
+        );
+    }
 }
 
 pub mod hedeleg {
@@ -233,8 +356,23 @@ pub mod hedeleg {
         StoreAmoPageFault = 0x8000,
     }
 
+    impl_bits!(Hedeleg);
     read_csr_as!(Hedeleg, 0x602);
     write_csr_as!(0x602);
+
+    /// Write `value` and read it back.
+    ///
+    /// Bits that are not delegatable are read-only zero, so it returns `Err(actual)`
+    /// if the read back value differs from `value`.
+    pub fn verify_write(value: usize) -> Result<usize, usize> {
+        write(value);
+        let actual = read().bits();
+        if actual == value {
+            Ok(actual)
+        } else {
+            Err(actual)
+        }
+    }
 }
 
 pub mod hideleg {
@@ -260,7 +398,10 @@ pub mod hie {
     /// Hypervisor interrupt-enable register.
     pub struct Hie(usize);
 
+    impl_bits!(Hie);
     set_csr_from_enum!(VsInterruptKind, 0x604);
+
+    read_csr_as!(Hie, 0x604);
 }
 
 pub mod hcounteren {
@@ -273,10 +414,56 @@ pub mod hcounteren {
     pub struct Hcounteren(usize);
 
     set_csr_as!(0x606);
+
+    /// clear tm bit (Time, 1 bit)
+    ///
+    /// Reading `time` in VS-mode raises virtual instruction exception.
+    pub fn clear_tm() {
+        unsafe {
+            core::arch::asm!(
+                "
+                csrc hcounteren, {bits}
+                ",
+                bits = in(reg) 1 << 1
+            );
+        }
+    }
+
+    /// clear cy bit (Cycle, 0 bit)
+    ///
+    /// Reading `cycle` in VS-mode raises virtual instruction exception.
+    pub fn clear_cy() {
+        unsafe {
+            core::arch::asm!(
+                "
+                csrc hcounteren, {bits}
+                ",
+                bits = in(reg) 1 << 0
+            );
+        }
+    }
+
+    /// clear ir bit (Instret, 2 bit)
+    ///
+    /// Reading `instret` in VS-mode raises virtual instruction exception.
+    pub fn clear_ir() {
+        unsafe {
+            core::arch::asm!(
+                "
+                csrc hcounteren, {bits}
+                ",
+                bits = in(reg) 1 << 2
+            );
+        }
+    }
 }
 
 pub mod hgeie {
     //! Hypervisor guest external interrupt-enable register.
+    //!
+    //! Guest external interrupt files (`hstatus.VGEIN`) are not used even if `GEILEN` > 0.
+    //! Interrupts reach them only as MSIs through IMSIC, which is absent in supported platforms,
+    //! so external interrupts are delivered through PLIC emulation.
     #![allow(dead_code)]
 
     /// hcounteren register number.
@@ -299,6 +486,20 @@ pub mod hgeie {
     set_csr_as!(0x607);
 }
 
+pub mod htimedelta {
+    //! Hypervisor time delta register.
+    #![allow(dead_code)]
+
+    /// htimedelta register number.
+    const HTIMEDELTA: usize = 0x605;
+    /// Hypervisor time delta register.
+    pub struct Htimedelta(usize);
+
+    impl_bits!(Htimedelta);
+    read_csr_as!(Htimedelta, 0x605);
+    write_csr_as!(0x605);
+}
+
 pub mod henvcfg {
     //! Hypervisor environment configuration register.
     #![allow(dead_code)]
@@ -308,6 +509,16 @@ pub mod henvcfg {
     /// Hypervisor environment configuration register.
     pub struct Henvcfg(usize);
 
+    impl Henvcfg {
+        /// Return STCE (63 bit)
+        pub fn stce(&self) -> bool {
+            (self.0 >> 63) & 1 == 1
+        }
+    }
+
+    impl_bits!(Henvcfg);
+    read_csr_as!(Henvcfg, 0x60a);
+
     /// set STCE (63 bit)
     pub fn set_stce() {
         unsafe {
@@ -405,6 +616,7 @@ pub mod hvip {
     /// Hypervisor virtual interrupt pending.
     pub struct Hvip(usize);
 
+    impl_bits!(Hvip);
     set_csr_from_enum!(VsInterruptKind, 0x645);
     clear_csr_from_enum!(VsInterruptKind, 0x645);
 
@@ -441,6 +653,11 @@ pub mod hgatp {
             self.0 & 0xfff_ffff_ffff // 44 bit
         }
 
+        /// Return vmid.
+        pub fn vmid(&self) -> usize {
+            (self.0 >> 44) & 0x3fff // 14 bit
+        }
+
         /// Return translation mode.
         pub fn mode(&self) -> Mode {
             match (self.0 >> 60) & 0b1111 {
@@ -454,6 +671,7 @@ pub mod hgatp {
     }
 
     /// Translation mode in G-stage.
+    #[derive(Clone, Copy)]
     #[allow(clippy::module_name_repetitions)]
     pub enum Mode {
         Bare = 0,
@@ -467,6 +685,19 @@ pub mod hgatp {
         write(((0xF & (mode as usize)) << 60) | ((0x3FFF & vmid) << 44) | 0x0FFF_FFFF_FFFF & ppn);
     }
 
+    /// Probe whether the translation mode is supported by hardware.
+    ///
+    /// `hgatp.MODE` is WARL, so writing unsupported mode has no effect.
+    /// It must not be called while G-stage translation is used.
+    pub fn is_supported(mode: Mode) -> bool {
+        let original = read().bits();
+        write((0xF & (mode as usize)) << 60);
+        let supported = (read().bits() >> 60) & 0xF == mode as usize;
+        write(original);
+
+        supported
+    }
+
     impl_bits!(Hgatp);
     read_csr_as!(Hgatp, 0x680);
     write_csr_as!(0x680);
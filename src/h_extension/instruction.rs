@@ -10,3 +10,32 @@ pub fn hfence_gvma_all() {
         asm!("hfence.gvma x0, x0");
     }
 }
+
+/// Hypervisor memory management fence for guest physical address `gpa` (all addresses if `None`) of `vmid`.
+#[inline(always)]
+#[allow(clippy::inline_always)]
+pub fn hfence_gvma(gpa: Option<usize>, vmid: usize) {
+    unsafe {
+        match gpa {
+            // rs1 holds guest physical address shifted right by 2 bits.
+            Some(gpa) => asm!("hfence.gvma {0}, {1}", in(reg) gpa >> 2, in(reg) vmid),
+            None => asm!("hfence.gvma x0, {0}", in(reg) vmid),
+        }
+    }
+}
+
+/// Hypervisor memory management fence for VS-stage of current VMID.
+///
+/// `None` means all virtual addresses or all ASIDs.
+#[inline(always)]
+#[allow(clippy::inline_always)]
+pub fn hfence_vvma(vaddr: Option<usize>, asid: Option<usize>) {
+    unsafe {
+        match (vaddr, asid) {
+            (Some(vaddr), Some(asid)) => asm!("hfence.vvma {0}, {1}", in(reg) vaddr, in(reg) asid),
+            (Some(vaddr), None) => asm!("hfence.vvma {0}, x0", in(reg) vaddr),
+            (None, Some(asid)) => asm!("hfence.vvma x0, {0}", in(reg) asid),
+            (None, None) => asm!("hfence.vvma x0, x0"),
+        }
+    }
+}
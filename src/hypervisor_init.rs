@@ -1,37 +1,99 @@
 //! HS-mode level initialization.
 
+use crate::device::Devices;
 use crate::emulate_extension;
+use crate::guest;
 use crate::guest::context::ContextData;
+use crate::guest::scheduler::GUESTS_PER_HART;
 use crate::guest::Guest;
 use crate::h_extension::csrs::{
     hcounteren, hedeleg, hedeleg::ExceptionKind, henvcfg, hgatp, hideleg, hie, hstatus, hvip,
-    vsatp, VsInterruptKind,
+    vsatp, vsstatus, VsInterruptKind,
 };
 use crate::h_extension::instruction::hfence_gvma_all;
 use crate::memmap::{
-    page_table::sv39x4::ROOT_PAGE_TABLE, GuestPhysicalAddress, HostPhysicalAddress,
+    constant::MAX_HART_NUM,
+    page_table::sv39x4::{FIRST_LV_PAGE_TABLE_LEN, ROOT_PAGE_TABLE},
+    page_table::{constants::PAGE_SIZE, PageTableEntry},
+    GuestPhysicalAddress, HostPhysicalAddress,
 };
+#[cfg(not(feature = "vectored_trap"))]
 use crate::trap::hstrap_vector;
+#[cfg(feature = "vectored_trap")]
+use crate::trap::hstrap_vector_table;
+use crate::trap::{hs_stack_top, hstrap_exit, restore_fp_registers, set_hs_stack_top};
 use crate::ALLOCATOR;
-use crate::{HypervisorData, GUEST_DTB, GUEST_INITRD, GUEST_KERNEL, HYPERVISOR_DATA};
+use crate::{HartLocal, DEVICES, GUEST_DTB, HART_DATA};
 use crate::{_hv_heap_size, _start_heap};
 
 use core::arch::asm;
+use core::sync::atomic::{AtomicBool, Ordering};
 
 use elf::{endian::AnyEndian, ElfBytes};
-use riscv::register::{sepc, sie, sscratch, sstatus, sstatus::FS, stvec};
+use fdt::Fdt;
+use riscv::register::{sepc, sie, sstatus, sstatus::FS, stvec, time};
+use spin::Mutex;
+
+/// Guest state to be resumed after system suspend. (see `prepare_warm_boot`)
+struct ResumeState {
+    /// hgatp of the suspended guest.
+    hgatp: usize,
+    /// vsstatus of the suspended guest.
+    vsstatus: usize,
+    /// Address where the guest resumes.
+    resume_addr: GuestPhysicalAddress,
+    /// Opaque value passed to the guest on resume. (a1)
+    opaque: u64,
+}
+
+/// Is the next `hstart` cold boot?
+///
+/// It is initialized to `true` to be placed in `.data`. (`.bss` is cleared in `hstart` and not initialized before it)
+static COLD_BOOT: AtomicBool = AtomicBool::new(true);
+
+/// Guest state to be resumed on warm boot.
+static RESUME_STATE: Mutex<Option<ResumeState>> = Mutex::new(None);
+
+/// Prepare to resume the guest at `resume_addr` after system suspend.
+///
+/// The SBI implementation resumes the system from hypervisor entry (`_start`) instead of `resume_addr`,
+/// because it cannot enter VS-mode.
+pub fn prepare_warm_boot(resume_addr: GuestPhysicalAddress, opaque: u64) {
+    *RESUME_STATE.lock() = Some(ResumeState {
+        hgatp: hgatp::read().bits(),
+        vsstatus: vsstatus::read().bits(),
+        resume_addr,
+        opaque,
+    });
+    COLD_BOOT.store(false, Ordering::Release);
+}
+
+/// Cancel `prepare_warm_boot` if system suspend is failed.
+pub fn cancel_warm_boot() {
+    COLD_BOOT.store(true, Ordering::Release);
+    *RESUME_STATE.lock() = None;
+}
 
 /// Entry point to HS-mode.
 #[inline(never)]
 pub extern "C" fn hstart(hart_id: usize, dtb_addr: usize) -> ! {
+    // set HART id to tp (see `current_hart_id`).
+    unsafe {
+        asm!("mv tp, {}", in(reg) hart_id);
+    }
+
+    // resumed from system suspend. (memory including `.bss` is retained)
+    if !COLD_BOOT.load(Ordering::Acquire) {
+        warm_boot(hart_id);
+    }
+
     crate::println!("welcome to hikami");
-    crate::println!(
-        "hart_id: {}, dtb address: {:#x}, initrd address: {:#x}",
-        hart_id,
-        dtb_addr,
-        GUEST_INITRD.as_ptr() as usize
-    );
+    crate::println!("hart_id: {}, dtb address: {:#x}", hart_id, dtb_addr);
 
+    assert!(
+        hart_id < MAX_HART_NUM,
+        "hart_id {hart_id} exceeds MAX_HART_NUM ({MAX_HART_NUM}). rebuild with larger HIKAMI_MAX_HART_NUM."
+    );
     // hart_id must be zero.
     assert_eq!(hart_id, 0);
 
@@ -58,6 +120,15 @@ pub extern "C" fn hstart(hart_id: usize, dtb_addr: usize) -> ! {
         );
     }
 
+    setup_hs_csrs();
+
+    vsmode_setup(hart_id, HostPhysicalAddress(dtb_addr));
+}
+
+/// Initialize HS-mode CSRs for running guests.
+///
+/// It is called on warm boot again since CSRs are lost in system suspend.
+fn setup_hs_csrs() {
     // clear all hs-mode to vs-mode interrupts.
     hvip::clear(VsInterruptKind::External);
     hvip::clear(VsInterruptKind::Timer);
@@ -66,6 +137,12 @@ pub extern "C" fn hstart(hart_id: usize, dtb_addr: usize) -> ! {
     // disable address translation.
     vsatp::write(0);
 
+    // trap `WFI` in VS-mode (see `instruction_handler::virtual_instruction`)
+    #[cfg(feature = "trap_guest_wfi")]
+    unsafe {
+        hstatus::set_vtw();
+    }
+
     // enable all hs-mode interrupts
     unsafe {
         sie::set_sext();
@@ -91,93 +168,437 @@ pub extern "C" fn hstart(hart_id: usize, dtb_addr: usize) -> ! {
     // enable hypervisor counter
     hcounteren::set(0xffff_ffff);
 
+    // emulate `rdtime` if the time counter is not available on the platform.
+    if !is_time_counter_available() {
+        crate::println!("time counter is not available: emulate rdtime by CLINT mtime");
+        hcounteren::clear_tm();
+    }
+
+    // emulate `rdcycle` and `rdinstret` to exclude the time spent in hypervisor.
+    hcounteren::clear_cy();
+    hcounteren::clear_ir();
+    guest::context::probe_hs_counters();
+
     // enable supervisor counter
     unsafe {
         asm!("csrw scounteren, {bits}", bits = in(reg) 0xffff_ffff_u32);
     }
 
     // specify delegation exception kinds.
-    hedeleg::write(
-        ExceptionKind::InstructionAddressMissaligned as usize
-            | ExceptionKind::Breakpoint as usize
-            | ExceptionKind::EnvCallFromUorVU as usize
-            | ExceptionKind::InstructionPageFault as usize
-            | ExceptionKind::LoadPageFault as usize
-            | ExceptionKind::StoreAmoPageFault as usize,
-    );
+    let exception_delegation = ExceptionKind::InstructionAddressMissaligned as usize
+        | ExceptionKind::Breakpoint as usize
+        | ExceptionKind::EnvCallFromUorVU as usize
+        | ExceptionKind::InstructionPageFault as usize
+        | ExceptionKind::LoadPageFault as usize
+        | ExceptionKind::StoreAmoPageFault as usize;
+    if let Err(actual) = hedeleg::verify_write(exception_delegation) {
+        // undelegated exceptions are still forwarded to the guest by hypervisor.
+        crate::println!(
+            "[warning] hedeleg: some exceptions are not delegatable: wrote {:#x}, read {:#x}",
+            exception_delegation,
+            actual
+        );
+        debug_assert_eq!(
+            actual, exception_delegation,
+            "hedeleg write is not verified"
+        );
+    }
     // specify delegation interrupt kinds.
     hideleg::write(
         VsInterruptKind::External as usize
             | VsInterruptKind::Timer as usize
             | VsInterruptKind::Software as usize,
     );
+}
 
-    vsmode_setup(hart_id, HostPhysicalAddress(dtb_addr));
+/// Resume the guest that requested system suspend. (see `sbi_susp_handler`)
+///
+/// `_start` sets sp to HS-mode stack top, so the current stack frames overlap `ContextData`.
+/// The stack is moved below it before the context is written in `warm_boot_resume`.
+fn warm_boot(hart_id: usize) -> ! {
+    let stack_top = hs_stack_top();
+    unsafe {
+        asm!(
+            "mv sp, {stack_top}
+            addi sp, sp, -{HS_CONTEXT_SIZE}
+            j {warm_boot_resume}",
+            HS_CONTEXT_SIZE = const size_of::<ContextData>(),
+            stack_top = in(reg) stack_top.raw(),
+            warm_boot_resume = sym warm_boot_resume,
+            in("a0") hart_id,
+            options(noreturn)
+        );
+    }
+}
+
+/// Resume the guest on the stack below `ContextData`. (see `warm_boot`)
+///
+/// HS-mode CSRs are initialized again and the guest enters `resume_addr` with
+/// a0 = hart id, a1 = opaque and `satp` = 0 (SBI specification chapter 13.1).
+extern "C" fn warm_boot_resume(hart_id: usize) -> ! {
+    /// `sstatus.SPIE`
+    const SSTATUS_SPIE: usize = 1 << 5;
+    /// `sstatus.SPP`
+    const SSTATUS_SPP: usize = 1 << 8;
+    /// `sstatus.FS`
+    const SSTATUS_FS: usize = 0b11 << 13;
+    /// `sstatus.FS` = Initial
+    const SSTATUS_FS_INITIAL: usize = 0b01 << 13;
+
+    COLD_BOOT.store(true, Ordering::Release);
+    crate::println!("resume from system suspend (hart: {})", hart_id);
+
+    // HART_DATA is locked by ecall handler that requested system suspend.
+    unsafe {
+        HART_DATA[hart_id].force_unlock();
+    }
+    let resume_state = RESUME_STATE
+        .lock()
+        .take()
+        .expect("resume state is not saved");
+
+    setup_hs_csrs();
+    hgatp::write(resume_state.hgatp);
+    hfence_gvma_all();
+    vsatp::write(0);
+    // sstatus.SIE of the guest is 0 on resume.
+    vsstatus::write(resume_state.vsstatus & !0b10);
+
+    let mut hart_data = HART_DATA[hart_id].lock();
+    let guest = hart_data.get_mut().unwrap().guest_mut();
+    let context = guest.context();
+    // the whole context is written since it holds stale values of the suspended guest.
+    (1..32).for_each(|index| context.set_xreg(index, 0));
+    let sstatus_val: usize;
+    unsafe {
+        asm!("csrr {}, sstatus", out(reg) sstatus_val);
+    }
+    context.set_sstatus(
+        (sstatus_val & !(SSTATUS_SPIE | SSTATUS_FS)) | SSTATUS_SPP | SSTATUS_FS_INITIAL,
+    );
+    context.set_sepc(resume_state.resume_addr.raw());
+    context.set_xreg(10, hart_id as u64);
+    context.set_xreg(11, resume_state.opaque);
+    drop(hart_data);
+
+    unsafe {
+        // hstatus.spv = 1 (enable V bit when sret executed)
+        hstatus::set_spv();
+        set_trap_vector();
+        hstrap_exit();
+    }
+}
+
+/// Set trap vector of HS-mode.
+unsafe fn set_trap_vector() {
+    #[cfg(not(feature = "vectored_trap"))]
+    {
+        assert!(hstrap_vector as *const fn() as usize % 4 == 0);
+        stvec::write(
+            hstrap_vector as *const fn() as usize,
+            stvec::TrapMode::Direct,
+        );
+    }
+    #[cfg(feature = "vectored_trap")]
+    {
+        assert!(hstrap_vector_table as *const fn() as usize % 4 == 0);
+        stvec::write(
+            hstrap_vector_table as *const fn() as usize,
+            stvec::TrapMode::Vectored,
+        );
+    }
+}
+
+/// Guest kernel and initrd images.
+struct GuestImage {
+    /// Guest kernel (ELF or RISC-V Linux image)
+    kernel: &'static [u8],
+    /// Guest initrd (empty if not exists)
+    initrd: &'static [u8],
+}
+
+impl GuestImage {
+    /// Load guest kernel to guest memory.
+    ///
+    /// The format (ELF or RISC-V Linux image) is detected from the first bytes.
+    /// Otherwise, it is loaded as flat binary at `FLAT_BINARY_OFFSET` from the dram base.
+    /// Return entry point and end address of the kernel.
+    fn load_kernel(&self, guest: &mut Guest) -> (GuestPhysicalAddress, GuestPhysicalAddress) {
+        /// Magic number of ELF.
+        const ELF_MAGIC: &[u8] = b"\x7fELF";
+
+        if self.kernel.starts_with(ELF_MAGIC) {
+            let guest_elf = ElfBytes::<AnyEndian>::minimal_parse(self.kernel).unwrap();
+            guest.load_guest_elf(&guest_elf, self.kernel.as_ptr())
+        } else if guest::image::ImageHeader::parse(self.kernel).is_some() {
+            guest.load_guest_image(self.kernel, self.initrd.len())
+        } else {
+            crate::println!("guest kernel is loaded as flat binary");
+            guest.load_flat_binary(
+                self.kernel,
+                guest.dram_base() + guest::FLAT_BINARY_OFFSET,
+                self.initrd.len(),
+            )
+        }
+    }
+
+    /// Return images embedded in hypervisor.
+    #[cfg(not(any(feature = "external_guest_image", feature = "load_from_mmc")))]
+    fn locate(_device_tree: &Fdt) -> Self {
+        use crate::{GUEST_INITRD, GUEST_KERNEL};
+
+        assert!(
+            !GUEST_KERNEL.is_empty(),
+            "guest kernel is not found: guest_image/vmlinux is empty"
+        );
+
+        GuestImage {
+            kernel: &GUEST_KERNEL,
+            initrd: &GUEST_INITRD,
+        }
+    }
+
+    /// Return kernel loaded from SD card and initrd embedded in hypervisor.
+    ///
+    /// The kernel is `kernel.elf` in root directory of the first FAT32 partition.
+    #[cfg(feature = "load_from_mmc")]
+    fn locate(device_tree: &Fdt) -> Self {
+        use crate::device::{axi_sdc::Mmc, MmioDevice};
+        use crate::fat::Fat32;
+        use crate::GUEST_INITRD;
+
+        /// File name of guest kernel.
+        const KERNEL_FILE_NAME: &str = "kernel.elf";
+
+        // the controller is used directly before the guest starts. (emulation state is reset by `init_devices`)
+        let mmc =
+            Mmc::try_new(device_tree, &["riscv,axi-sd-card-1.0"]).expect("mmc is not found in fdt");
+        let fat =
+            Fat32::open_first_partition(&mmc).expect("FAT32 partition is not found in SD card");
+        let kernel = fat
+            .read_file(KERNEL_FILE_NAME)
+            .unwrap_or_else(|| panic!("{KERNEL_FILE_NAME} is not found in SD card"));
+        crate::println!(
+            "guest kernel is loaded from SD card ({:#x} bytes)",
+            kernel.len()
+        );
+
+        GuestImage {
+            kernel,
+            initrd: &GUEST_INITRD,
+        }
+    }
+
+    /// Return images loaded by firmware.
+    ///
+    /// - kernel: `hikami,kernel-start` and `hikami,kernel-end` in `/chosen`
+    /// - initrd: `linux,initrd-start` and `linux,initrd-end` in `/chosen`
+    #[cfg(feature = "external_guest_image")]
+    fn locate(device_tree: &Fdt) -> Self {
+        use crate::device::{initrd::Initrd, MmioDevice};
+
+        let chosen = device_tree
+            .find_node("/chosen")
+            .expect("/chosen is not found in fdt");
+        let kernel_start = chosen
+            .property("hikami,kernel-start")
+            .and_then(fdt::node::NodeProperty::as_usize);
+        let kernel_end = chosen
+            .property("hikami,kernel-end")
+            .and_then(fdt::node::NodeProperty::as_usize);
+        let (Some(kernel_start), Some(kernel_end)) = (kernel_start, kernel_end) else {
+            panic!(
+                "guest kernel is not found: `hikami,kernel-start` and `hikami,kernel-end` are required in /chosen"
+            );
+        };
+        assert!(kernel_start < kernel_end, "invalid guest kernel region");
+
+        let kernel = Self::external_region(
+            "guest kernel",
+            HostPhysicalAddress(kernel_start),
+            kernel_end - kernel_start,
+        );
+        let initrd = Initrd::try_new_from_node_path(device_tree, "/chosen")
+            .map_or(&[][..], |initrd| {
+                Self::external_region("guest initrd", initrd.paddr(), initrd.size())
+            });
+
+        GuestImage { kernel, initrd }
+    }
+
+    /// Create slice from memory region that is loaded by firmware.
+    ///
+    /// It panics if the region overlaps hypervisor memory.
+    #[cfg(feature = "external_guest_image")]
+    fn external_region(name: &str, start: HostPhysicalAddress, size: usize) -> &'static [u8] {
+        use crate::{_hv_start, _stack_start};
+        use core::ptr::addr_of;
+
+        let hv_start = HostPhysicalAddress(addr_of!(_hv_start) as usize);
+        let hv_end = HostPhysicalAddress(addr_of!(_stack_start) as usize);
+        assert!(
+            start + size <= hv_start || hv_end <= start,
+            "{name} ({start:#x}..{:#x}) overlaps hypervisor memory ({hv_start:#x}..{hv_end:#x})",
+            start + size,
+        );
+
+        unsafe { core::slice::from_raw_parts(start.raw() as *const u8, size) }
+    }
+}
+
+/// Check that `time` CSR advances.
+///
+/// It reads zero on some platforms. (e.g. vivado-risc-v)
+fn is_time_counter_available() -> bool {
+    /// Max number of trial to read `time`.
+    const MAX_TRIAL: usize = 0x10_0000;
+
+    let first = time::read();
+    (0..MAX_TRIAL).any(|_| time::read() != first)
+}
+
+/// Remove device nodes that are absent on the host from guest device tree.
+///
+/// Unit addresses are omitted in the paths. (see `guest::dtb::remove_node`)
+fn remove_absent_devices(guest_dtb: &mut [u8], devices: &Devices) {
+    let absent_device_nodes = [
+        (devices.pci.is_none(), "/soc/pci"),
+        (devices.mmc.is_none(), "/soc/mmc"),
+        (devices.rtc.is_none(), "/soc/rtc"),
+    ];
+    for (_, path) in absent_device_nodes.iter().filter(|(absent, _)| *absent) {
+        if guest::dtb::remove_node(guest_dtb, path) {
+            crate::println!("{} is removed from guest device tree", path);
+        }
+    }
 }
 
 /// Setup for VS-mode
 ///
 /// * Parse DTB
 /// * Setup page table
+#[allow(clippy::too_many_lines)]
 fn vsmode_setup(hart_id: usize, dtb_addr: HostPhysicalAddress) -> ! {
-    // create new guest data
-    let new_guest = Guest::new(hart_id, &ROOT_PAGE_TABLE, &GUEST_DTB);
     let root_page_table_addr = HostPhysicalAddress(ROOT_PAGE_TABLE.as_ptr() as usize);
 
+    // select G-stage translation mode supported by hardware.
+    // page tables are generated according to the mode in hgatp.
+    let g_stage_mode = if hgatp::is_supported(hgatp::Mode::Sv48x4) {
+        hgatp::Mode::Sv48x4
+    } else {
+        hgatp::Mode::Sv39x4
+    };
+    hgatp::set(g_stage_mode, 0, root_page_table_addr.raw() >> 12);
+
     // parse device tree
     let device_tree = unsafe {
-        match fdt::Fdt::from_ptr(dtb_addr.raw() as *const u8) {
+        match Fdt::from_ptr(dtb_addr.raw() as *const u8) {
             Ok(fdt) => fdt,
             Err(e) => panic!("{}", e),
         }
     };
 
-    // initialize hypervisor data
-    let mut hypervisor_data = unsafe { HYPERVISOR_DATA.lock() };
-    hypervisor_data.get_or_init(|| HypervisorData::new(device_tree));
-
-    // load guest elf `from GUEST_KERNEL`
-    let guest_elf = unsafe {
-        ElfBytes::<AnyEndian>::minimal_parse(core::slice::from_raw_parts(
-            GUEST_KERNEL.as_ptr(),
-            GUEST_KERNEL.len(),
-        ))
-        .unwrap()
-    };
+    // record host dram range and derive guest memory layout from it
+    crate::memmap::init_dram_range(&device_tree);
+    guest::layout::init(&device_tree);
 
-    // load guest image
-    let (guest_entry_point, elf_end_addr) =
-        new_guest.load_guest_elf(&guest_elf, GUEST_KERNEL.as_ptr());
+    // derive time slice from timebase frequency
+    guest::scheduler::init(&device_tree);
 
-    // allocate page tables to all remain guest memory region
-    let guest_memory_end = new_guest.memory_region().end;
-    new_guest.allocate_memory_region(elf_end_addr..guest_memory_end);
+    // initialize emulate_extension data
+    emulate_extension::initialize();
 
-    // set device memory map
-    hypervisor_data
-        .get_mut()
-        .unwrap()
-        .devices()
-        .device_mapping_g_stage(root_page_table_addr);
-
-    // enable two-level address translation
-    hgatp::set(hgatp::Mode::Sv39x4, 0, root_page_table_addr.raw() >> 12);
+    // initialize devices data
+    let mut devices = DEVICES.lock();
+    devices.get_or_init(|| Devices::new(device_tree));
+
+    // advertise emulated extensions to guest
+    let mut guest_dtb =
+        guest::dtb::append_isa_extensions(&GUEST_DTB, &emulate_extension::emulated_extensions());
+    crate::println!(
+        "native extensions: {:?}",
+        guest::dtb::isa_extensions(&device_tree)
+    );
+    if let Ok(guest_device_tree) = Fdt::new(&guest_dtb) {
+        crate::println!(
+            "advertised extensions: {:?}",
+            guest::dtb::isa_extensions(&guest_device_tree)
+        );
+    }
+
+    // locate guest kernel and initrd
+    let guest_image = GuestImage::locate(&device_tree);
+
+    // hide devices that are absent on the host from guest
+    remove_absent_devices(&mut guest_dtb, devices.get().unwrap());
+
+    // tell guest memory region according to the layout
+    let guest_memory = guest::layout::guest_memory_layout().memory_region(hart_id);
+    assert!(
+        guest::dtb::set_memory_region(
+            &mut guest_dtb,
+            guest_memory.start.raw(),
+            guest_memory.end.raw() - guest_memory.start.raw(),
+        ),
+        "memory node is not found in guest device tree"
+    );
+
+    // tell guest initrd placed at the end of guest memory (see `Guest::allocate_memory_region`)
+    if !guest_image.initrd.is_empty() {
+        let initrd_start = guest_memory.end - guest_image.initrd.len().next_multiple_of(PAGE_SIZE);
+        assert!(
+            guest::dtb::set_chosen_initrd(
+                &mut guest_dtb,
+                initrd_start.raw() as u64,
+                (initrd_start + guest_image.initrd.len()).raw() as u64,
+            ),
+            "guest device tree is invalid"
+        );
+    }
+
+    // create new guest data
+    let (new_guest, guest_entry_point) = setup_guest(
+        hart_id,
+        &ROOT_PAGE_TABLE,
+        &guest_dtb,
+        &guest_image,
+        devices.get().unwrap(),
+    );
+
+    // initialize devices (IOMMU, MMC)
+    devices.get_mut().unwrap().init_devices();
+
+    // IOMMU translates DMA by G-stage page table of guest 0 only.
+    #[cfg(feature = "multi_guest")]
+    assert!(
+        devices
+            .get()
+            .unwrap()
+            .pci
+            .as_ref()
+            .is_none_or(|pci| !pci.has_dma_devices()),
+        "multi_guest does not support DMA capable PCI devices: they are translated by G-stage page table of guest 0 only"
+    );
+
+    // enable two-level address translation (flush G-stage translation caches of HART and IOMMU)
     hfence_gvma_all();
+    if let Some(pci) = &devices.get().unwrap().pci {
+        let guest_memory = new_guest.memory_region();
+        pci.flush_iommu_gpa_range(
+            guest_memory.start,
+            guest_memory.end.raw() - guest_memory.start.raw(),
+        );
+    }
 
-    // initialize IOMMU
-    hypervisor_data
-        .get_mut()
-        .unwrap()
-        .devices()
-        .pci
-        .as_ref()
-        .map(super::device::pci::Pci::init_pci_devices);
+    // release DEVICES lock
+    drop(devices);
 
-    // set new guest data
-    hypervisor_data.get_mut().unwrap().register_guest(new_guest);
+    // cache HS-mode stack top for returning to guest
+    set_hs_stack_top(new_guest.stack_top());
 
-    // initialize emulate_extension data
-    emulate_extension::initialize();
+    // set new guest data
+    let mut hart_data = HART_DATA[hart_id].lock();
+    hart_data.get_or_init(|| HartLocal::new(new_guest));
 
     unsafe {
         // sstatus.SUM = 1, sstatus.SPP = 0
@@ -195,13 +616,9 @@ fn vsmode_setup(hart_id: usize, dtb_addr: HostPhysicalAddress) -> ! {
         sepc::write(guest_entry_point.raw());
 
         // set trap vector
-        assert!(hstrap_vector as *const fn() as usize % 4 == 0);
-        stvec::write(
-            hstrap_vector as *const fn() as usize,
-            stvec::TrapMode::Direct,
-        );
+        set_trap_vector();
 
-        let mut context = hypervisor_data.get().unwrap().guest().context;
+        let context = hart_data.get_mut().unwrap().guest_mut().context();
         context.set_sepc(sepc::read());
 
         // set sstatus value to context
@@ -210,25 +627,103 @@ fn vsmode_setup(hart_id: usize, dtb_addr: HostPhysicalAddress) -> ! {
         context.set_sstatus(sstatus_val);
     }
 
-    let guest_dtb_addr = hypervisor_data.get().unwrap().guest().guest_dtb_addr();
+    // add guests that wait for their turn on this HART
+    // (the first time slice starts here, so it is done just before entering the guest)
+    let hart = hart_data.get_mut().unwrap();
+    let initial_sstatus = hart.guest_mut().context().sstatus();
+    while hart.guest_num() < GUESTS_PER_HART {
+        let vmid = hart.guest_num();
+        hart.add_guest(setup_waiting_guest(
+            hart_id,
+            vmid,
+            &guest_dtb,
+            &guest_image,
+            initial_sstatus,
+        ));
+    }
+
+    let guest_dtb_addr = hart_data.get().unwrap().guest().guest_dtb_addr();
 
-    // release HYPERVISOR_DATA lock
-    drop(hypervisor_data);
+    // release HART_DATA lock
+    drop(hart_data);
+
+    #[cfg(feature = "boot_selftest")]
+    {
+        crate::memmap::selftest::overlap_detection();
+        crate::memmap::selftest::misaligned_range();
+        crate::device::plic::selftest::register_decoding();
+        crate::memmap::page_table::selftest::g_stage_translation();
+        crate::device::pci::sata::selftest::two_outstanding_commands();
+        crate::device::selftest::misaligned_buffer();
+    }
 
     hart_entry(hart_id, guest_dtb_addr);
 }
 
+/// Create a guest whose memory is mapped by `root_page_table` and load guest image to it.
+///
+/// hgatp must point to `root_page_table` since page tables are generated according to it.
+/// Return the guest and its entry point.
+fn setup_guest(
+    hart_id: usize,
+    root_page_table: &'static [PageTableEntry; FIRST_LV_PAGE_TABLE_LEN],
+    guest_dtb: &[u8],
+    guest_image: &GuestImage,
+    devices: &Devices,
+) -> (Guest, GuestPhysicalAddress) {
+    let mut new_guest = Guest::new(hart_id, root_page_table, guest_dtb);
+
+    // load guest image
+    let (guest_entry_point, kernel_end_addr) = guest_image.load_kernel(&mut new_guest);
+
+    // allocate page tables to all remain guest memory region
+    let guest_memory_end = new_guest.memory_region().end;
+    new_guest.allocate_memory_region(kernel_end_addr..guest_memory_end, guest_image.initrd);
+
+    // set device memory map
+    devices.device_mapping_g_stage(HostPhysicalAddress(root_page_table.as_ptr() as usize));
+
+    (new_guest, guest_entry_point)
+}
+
+/// Create a guest that waits for its turn on this HART. (see `guest::scheduler`)
+///
+/// It has own G-stage root page table and VMID, and starts with `sstatus` from the entry point.
+fn setup_waiting_guest(
+    hart_id: usize,
+    vmid: usize,
+    guest_dtb: &[u8],
+    guest_image: &GuestImage,
+    sstatus: usize,
+) -> Guest {
+    let running_hgatp = hgatp::read();
+    let root_page_table = guest::scheduler::alloc_root_page_table();
+    hgatp::set(
+        running_hgatp.mode(),
+        vmid,
+        root_page_table.as_ptr() as usize >> 12,
+    );
+
+    let devices = DEVICES.lock();
+    let (mut waiting_guest, guest_entry_point) = setup_guest(
+        hart_id,
+        root_page_table,
+        guest_dtb,
+        guest_image,
+        devices.get().unwrap(),
+    );
+    drop(devices);
+    waiting_guest.suspend_at_entry(guest_entry_point, sstatus);
+
+    hgatp::write(running_hgatp.bits());
+    hfence_gvma_all();
+    waiting_guest
+}
+
 /// Entry for guest (VS-mode).
 #[inline(never)]
 fn hart_entry(hart_id: usize, dtb_addr: GuestPhysicalAddress) -> ! {
-    // aquire hypervisor data
-    let hypervisor_data = unsafe { HYPERVISOR_DATA.lock() };
-    let stack_top = hypervisor_data.get().unwrap().guest().stack_top();
-    // release HYPERVISOR_DATA lock
-    drop(hypervisor_data);
-
-    // init guest stack pointer is don't care
-    sscratch::write(0);
+    let stack_top = hs_stack_top();
 
     crate::println!("Guest start (hart: {})", hart_id);
     unsafe {
@@ -244,7 +739,9 @@ fn hart_entry(hart_id: usize, dtb_addr: GuestPhysicalAddress) -> ! {
             // restore sstatus 
             ld t0, 32*8(sp)
             csrw sstatus, t0
-
+            ",
+            restore_fp_registers!(),
+            "
             // restore pc
             ld t1, 33*8(sp)
             csrw sepc, t1
@@ -281,9 +778,12 @@ fn hart_entry(hart_id: usize, dtb_addr: GuestPhysicalAddress) -> ! {
             ld t5, 30*8(sp)
             ld t6, 31*8(sp)
 
-            // swap HS-mode sp for original mode sp.
+            // sscratch holds HS-mode stack top while guest is running. (see `hstrap_exit`)
             addi sp, sp, {HS_CONTEXT_SIZE}
-            csrrw sp, sscratch, sp
+            csrw sscratch, sp
+
+            // init guest stack pointer is don't care
+            li sp, 0
 
             sret
             ",
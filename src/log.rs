@@ -22,16 +22,29 @@ pub fn print_for_macro(args: fmt::Arguments) {
     writer.write_fmt(args).unwrap();
 }
 
-/// Print debug message to standard output.
+/// Debug function calling from debug macro
+///
+/// The sink is selected at compile time.
+/// - `debug_ring` feature: ring buffer of each HART (see `debug_ring`)
+/// - otherwise: standard output
+#[cfg(feature = "debug_log")]
+pub fn debug_for_macro(args: fmt::Arguments) {
+    #[cfg(feature = "debug_ring")]
+    crate::debug_ring::write_for_macro(args);
+    #[cfg(not(feature = "debug_ring"))]
+    print_for_macro(args);
+}
+
+/// Print debug message to debug sink.
 #[macro_export]
 macro_rules! debug {
     ($($arg:tt)*) => {
         #[cfg(feature = "debug_log")]
-        $crate::log::print_for_macro(format_args!($($arg)*))
+        $crate::log::debug_for_macro(format_args!($($arg)*))
     };
 }
 
-/// Print debug message with linebreak to standard output.
+/// Print debug message with linebreak to debug sink.
 #[macro_export]
 macro_rules! debugln {
     ($fmt:expr) => ($crate::debug!(concat!($fmt, "\n")));
@@ -6,41 +6,80 @@
 // TODO: FIX AND REMOVE IT!!!
 #![allow(static_mut_refs)]
 
+#[cfg(all(feature = "external_guest_image", feature = "load_from_mmc"))]
+compile_error!("`external_guest_image` and `load_from_mmc` cannot be enabled at the same time");
+
 extern crate alloc;
+#[cfg(feature = "debug_ring")]
+mod debug_ring;
 mod device;
 mod emulate_extension;
+#[cfg(feature = "load_from_mmc")]
+mod fat;
 mod guest;
 mod h_extension;
 mod hypervisor_init;
 mod log;
 mod memmap;
+mod stats;
 mod trap;
 
 use alloc::boxed::Box;
 use alloc::vec::Vec;
-use core::arch::naked_asm;
+use core::alloc::{GlobalAlloc, Layout};
+use core::arch::{asm, naked_asm};
 use core::cell::OnceCell;
+use core::ops::Deref;
 use core::panic::PanicInfo;
 
-use fdt::Fdt;
 use linked_list_allocator::LockedHeap;
 use spin::Mutex;
 
 use crate::device::Devices;
-use crate::guest::Guest;
+use crate::guest::{watchdog::Watchdog, Guest};
 use crate::hypervisor_init::hstart;
 use crate::memmap::constant::{DRAM_BASE, MAX_HART_NUM, STACK_SIZE_PER_HART};
 use crate::memmap::HostPhysicalAddress;
 
 #[global_allocator]
 /// Global allocator.
-static ALLOCATOR: LockedHeap = LockedHeap::empty();
+static ALLOCATOR: StatsHeap = StatsHeap(LockedHeap::empty());
+
+/// Heap allocator that records heap high-water mark. (see `stats`)
+struct StatsHeap(LockedHeap);
+
+impl Deref for StatsHeap {
+    type Target = LockedHeap;
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+unsafe impl GlobalAlloc for StatsHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let mut heap = self.0.lock();
+        let ptr = heap
+            .allocate_first_fit(layout)
+            .map_or(core::ptr::null_mut(), core::ptr::NonNull::as_ptr);
+        stats::record_heap_usage(heap.used());
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.0.dealloc(ptr, layout);
+    }
+}
 // static mut ALLOCATOR: WildScreenAlloc = WildScreenAlloc::empty();
 
-/// Singleton for this hypervisor.
-static mut HYPERVISOR_DATA: Mutex<OnceCell<HypervisorData>> = Mutex::new(OnceCell::new());
+/// Data of each HARTs.
+static HART_DATA: [Mutex<OnceCell<HartLocal>>; MAX_HART_NUM] =
+    [const { Mutex::new(OnceCell::new()) }; MAX_HART_NUM];
+
+/// Devices data shared among all HARTs.
+static DEVICES: Mutex<OnceCell<Devices>> = Mutex::new(OnceCell::new());
 
 /// Guest kernel image
+#[cfg(not(any(feature = "external_guest_image", feature = "load_from_mmc")))]
 #[link_section = ".guest_kernel"]
 static GUEST_KERNEL: [u8; include_bytes!("../guest_image/vmlinux").len()] =
     *include_bytes!("../guest_image/vmlinux");
@@ -51,11 +90,14 @@ static GUEST_DTB: [u8; include_bytes!("../guest_image/guest.dtb").len()] =
     *include_bytes!("../guest_image/guest.dtb");
 
 /// Guest intird
+#[cfg(not(feature = "external_guest_image"))]
 #[link_section = ".guest_initrd"]
 static GUEST_INITRD: [u8; include_bytes!("../guest_image/initrd").len()] =
     *include_bytes!("../guest_image/initrd");
 
 extern "C" {
+    /// start of hypervisor memory (defined in `memory.x`)
+    static _hv_start: u8;
     /// stack top (defined in `memory.x`)
     static _stack_start: u8;
     /// start of heap (defined in `memory.x`)
@@ -73,7 +115,12 @@ extern "C" {
 /// Panic handler
 #[panic_handler]
 pub fn panic(info: &PanicInfo) -> ! {
+    #[cfg(feature = "debug_ring")]
+    debug_ring::flush();
+    #[cfg(feature = "csr_log")]
+    emulate_extension::csr_access_log::dump();
     println!("{}", info);
+    stats::print_summary();
     loop {
         riscv::asm::wfi();
     }
@@ -85,73 +132,100 @@ struct PageBlock([u8; 0x1000]);
 
 impl PageBlock {
     /// Return aligned address of page size memory block.
+    ///
+    /// Use `PAGE_ALLOCATOR` instead to make the block freeable.
     fn alloc() -> HostPhysicalAddress {
-        let mut host_physical_block_as_vec: Vec<core::mem::MaybeUninit<PageBlock>> =
-            Vec::with_capacity(1);
+        Self::alloc_n(1)
+    }
+
+    /// Return aligned address of page size memory block, or `None` if the heap is exhausted.
+    fn try_alloc() -> Option<HostPhysicalAddress> {
+        let mut host_physical_block_as_vec: Vec<core::mem::MaybeUninit<PageBlock>> = Vec::new();
+        host_physical_block_as_vec.try_reserve_exact(1).ok()?;
         unsafe {
             host_physical_block_as_vec.set_len(1);
         }
 
+        let host_physical_block_slice = host_physical_block_as_vec.into_boxed_slice();
+        Some(HostPhysicalAddress(
+            Box::into_raw(host_physical_block_slice) as *const u8 as usize,
+        ))
+    }
+
+    /// Return aligned address of `count` contiguous page size memory blocks.
+    fn alloc_n(count: usize) -> HostPhysicalAddress {
+        let mut host_physical_block_as_vec: Vec<core::mem::MaybeUninit<PageBlock>> =
+            Vec::with_capacity(count);
+        unsafe {
+            host_physical_block_as_vec.set_len(count);
+        }
+
         let host_physical_block_slice = host_physical_block_as_vec.into_boxed_slice();
         HostPhysicalAddress(Box::into_raw(host_physical_block_slice) as *const u8 as usize)
     }
 }
 
-/// Global data for hypervisor.
+/// Return id of the HART that executing this code.
 ///
-/// FIXME: Rename me!
+/// `tp` holds HART id while running in HS-mode. (set by `hstart` and `hstrap_vector`)
+#[must_use]
+#[inline(always)]
+#[allow(clippy::inline_always)]
+pub fn current_hart_id() -> usize {
+    let hart_id;
+    unsafe {
+        asm!("mv {}, tp", out(reg) hart_id);
+    }
+    hart_id
+}
+
+/// Return data of current HART.
+#[must_use]
+pub fn hart_local() -> &'static Mutex<OnceCell<HartLocal>> {
+    &HART_DATA[current_hart_id()]
+}
+
+/// Data for each HART.
 #[derive(Debug)]
-pub struct HypervisorData {
-    /// Current hart id (zero indexed).
-    current_hart: usize,
-    /// Guests data
-    guests: [Option<guest::Guest>; MAX_HART_NUM],
-    /// Devices data.
-    devices: device::Devices,
+pub struct HartLocal {
+    /// Guests on this HART. (time-multiplexed by `guest::scheduler` if there are more than one)
+    guests: Vec<Guest>,
+    /// Index of the running guest.
+    current: usize,
+    /// Host time when the time slice of the running guest ends.
+    slice_end: u64,
+    /// Watchdog of guest panic loops.
+    watchdog: Watchdog,
 }
 
-impl HypervisorData {
-    /// Initialize hypervisor.
-    ///
-    /// # Panics
-    /// It will be panic when parsing device tree failed.
+impl HartLocal {
+    /// Constructor for `HartLocal`.
     #[must_use]
-    pub fn new(device_tree: Fdt) -> Self {
-        HypervisorData {
-            current_hart: 0,
-            guests: [const { None }; MAX_HART_NUM],
-            devices: Devices::new(device_tree),
+    pub fn new(guest: Guest) -> Self {
+        HartLocal {
+            guests: alloc::vec![guest],
+            current: 0,
+            slice_end: 0,
+            watchdog: Watchdog::new(),
         }
     }
 
-    /// Return Device objects.
-    ///
-    /// # Panics
-    /// It will be panic if devices are uninitialized.
+    /// Return guest running on this HART.
     #[must_use]
-    pub fn devices(&mut self) -> &mut device::Devices {
-        &mut self.devices
+    pub fn guest(&self) -> &Guest {
+        &self.guests[self.current]
     }
 
-    /// Return current hart's guest.
-    ///
-    /// # Panics
-    /// It will be panic if current HART's guest data is empty.
+    /// Return mutable guest running on this HART.
     #[must_use]
-    pub fn guest(&self) -> &Guest {
-        self.guests[self.current_hart]
-            .as_ref()
-            .expect("guest data not found")
+    pub fn guest_mut(&mut self) -> &mut Guest {
+        &mut self.guests[self.current]
     }
 
-    /// Add new guest data.
-    ///
-    /// # Panics
-    /// It will be panic if `hart_id` is greater than `MAX_HART_NUM`.
-    pub fn register_guest(&mut self, new_guest: Guest) {
-        let hart_id = new_guest.hart_id();
-        assert!(hart_id < MAX_HART_NUM);
-        self.guests[hart_id] = Some(new_guest);
+    /// Return number of guests on this HART.
+    #[must_use]
+    pub fn guest_num(&self) -> usize {
+        self.guests.len()
     }
 }
 
@@ -1,10 +1,70 @@
 //! See `memmap/constant` module for specefic memmory map.
 
 pub mod constant;
+pub mod page_allocator;
 pub mod page_table;
+#[cfg(feature = "boot_selftest")]
+pub mod selftest;
 
-use crate::memmap::page_table::PteFlag;
+use crate::memmap::page_table::{constants::PAGE_SIZE, PageTableLevel, PteFlag};
+use core::cell::OnceCell;
 use core::ops::Range;
+use fdt::Fdt;
+use spin::Mutex;
+
+/// Host DRAM range parsed from `/memory` of the host device tree.
+static HOST_DRAM_RANGE: Mutex<OnceCell<Range<usize>>> = Mutex::new(OnceCell::new());
+
+/// Record host DRAM range from the host device tree.
+///
+/// `constant::DRAM_BASE` and `constant::DRAM_SIZE` are used if it has no `/memory`.
+pub fn init_dram_range(device_tree: &Fdt) {
+    HOST_DRAM_RANGE.lock().get_or_init(|| {
+        device_tree
+            .memory()
+            .regions()
+            .next()
+            .and_then(|region| {
+                let start = region.starting_address as usize;
+                Some(start..start.checked_add(region.size?)?)
+            })
+            .unwrap_or_else(default_dram_range)
+    });
+}
+
+/// Return host DRAM range in `memmap::constant`.
+fn default_dram_range() -> Range<usize> {
+    constant::DRAM_BASE..constant::DRAM_BASE + constant::DRAM_SIZE
+}
+
+/// Arithmetic operation on address.
+#[derive(Clone, Copy)]
+enum AddressOp {
+    /// Addition
+    Add,
+    /// Subtraction
+    Sub,
+}
+
+/// Apply `op` to address `lhs` and offset `rhs`.
+///
+/// It panics on overflow (or underflow) in debug build and wraps around in release build.
+fn address_arith(lhs: usize, op: AddressOp, rhs: usize) -> usize {
+    let (result, wrapped, kind, symbol) = match op {
+        AddressOp::Add => (lhs.checked_add(rhs), lhs.wrapping_add(rhs), "overflow", '+'),
+        AddressOp::Sub => (
+            lhs.checked_sub(rhs),
+            lhs.wrapping_sub(rhs),
+            "underflow",
+            '-',
+        ),
+    };
+    if cfg!(debug_assertions) {
+        result.unwrap_or_else(|| panic!("address {kind}: {lhs:#x} {symbol} {rhs:#x}"))
+    } else {
+        wrapped
+    }
+}
 
 /// Utility for `Range<Address>`
 trait AddressRangeUtil {
@@ -13,11 +73,55 @@ trait AddressRangeUtil {
 }
 
 /// Guest Virtual Address
-#[derive(Default, Debug, Copy, Clone)]
+#[derive(Default, Debug, Copy, Clone, PartialEq, PartialOrd, Ord, Eq)]
 pub struct GuestVirtualAddress(pub usize);
 
+impl GuestVirtualAddress {
+    /// Convert to usize.
+    pub fn raw(self) -> usize {
+        self.0
+    }
+
+    /// Addition that panics on overflow in debug build.
+    pub fn checked_add(self, other: usize) -> Self {
+        GuestVirtualAddress(address_arith(self.0, AddressOp::Add, other))
+    }
+
+    /// Subtraction that panics on underflow in debug build.
+    pub fn checked_sub(self, other: usize) -> Self {
+        GuestVirtualAddress(address_arith(self.0, AddressOp::Sub, other))
+    }
+}
+
+impl core::ops::Add<usize> for GuestVirtualAddress {
+    type Output = GuestVirtualAddress;
+    fn add(self, other: usize) -> Self::Output {
+        self.checked_add(other)
+    }
+}
+
+impl core::ops::Sub<usize> for GuestVirtualAddress {
+    type Output = GuestVirtualAddress;
+    fn sub(self, other: usize) -> Self::Output {
+        self.checked_sub(other)
+    }
+}
+
+impl core::ops::Rem<usize> for GuestVirtualAddress {
+    type Output = usize;
+    fn rem(self, other: usize) -> Self::Output {
+        self.0 % other
+    }
+}
+
+impl AddressRangeUtil for Range<GuestVirtualAddress> {
+    fn len(&self) -> usize {
+        self.end.raw() - self.start.raw()
+    }
+}
+
 /// Guest Physical Address
-#[derive(Default, Debug, Copy, Clone, PartialEq, PartialOrd, Ord, Eq)]
+#[derive(Default, Copy, Clone, PartialEq, PartialOrd, Ord, Eq)]
 pub struct GuestPhysicalAddress(pub usize);
 
 impl GuestPhysicalAddress {
@@ -25,19 +129,29 @@ impl GuestPhysicalAddress {
     pub fn raw(self) -> usize {
         self.0
     }
+
+    /// Addition that panics on overflow in debug build.
+    pub fn checked_add(self, other: usize) -> Self {
+        GuestPhysicalAddress(address_arith(self.0, AddressOp::Add, other))
+    }
+
+    /// Subtraction that panics on underflow in debug build.
+    pub fn checked_sub(self, other: usize) -> Self {
+        GuestPhysicalAddress(address_arith(self.0, AddressOp::Sub, other))
+    }
 }
 
 impl core::ops::Add<usize> for GuestPhysicalAddress {
     type Output = GuestPhysicalAddress;
     fn add(self, other: usize) -> Self::Output {
-        GuestPhysicalAddress(self.0 + other)
+        self.checked_add(other)
     }
 }
 
 impl core::ops::Sub<usize> for GuestPhysicalAddress {
     type Output = GuestPhysicalAddress;
     fn sub(self, other: usize) -> Self::Output {
-        GuestPhysicalAddress(self.0 - other)
+        self.checked_sub(other)
     }
 }
 
@@ -48,6 +162,27 @@ impl core::ops::Rem<usize> for GuestPhysicalAddress {
     }
 }
 
+/// Print as `GPA(0x...)`.
+impl core::fmt::Debug for GuestPhysicalAddress {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "GPA({:#x})", self.0)
+    }
+}
+
+/// Print in decimal. (e.g. for sizes)
+impl core::fmt::Display for GuestPhysicalAddress {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        core::fmt::Display::fmt(&self.0, f)
+    }
+}
+
+/// Print in hexadecimal. (`{:#x}`)
+impl core::fmt::LowerHex for GuestPhysicalAddress {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        core::fmt::LowerHex::fmt(&self.0, f)
+    }
+}
+
 impl AddressRangeUtil for Range<GuestPhysicalAddress> {
     fn len(&self) -> usize {
         self.end.raw() - self.start.raw()
@@ -55,7 +190,7 @@ impl AddressRangeUtil for Range<GuestPhysicalAddress> {
 }
 
 /// Host Physical Address
-#[derive(Default, Debug, Copy, Clone, PartialEq, PartialOrd)]
+#[derive(Default, Copy, Clone, PartialEq, PartialOrd)]
 pub struct HostPhysicalAddress(pub usize);
 
 impl HostPhysicalAddress {
@@ -63,19 +198,39 @@ impl HostPhysicalAddress {
     pub fn raw(self) -> usize {
         self.0
     }
+
+    /// Addition that panics on overflow in debug build.
+    pub fn checked_add(self, other: usize) -> Self {
+        HostPhysicalAddress(address_arith(self.0, AddressOp::Add, other))
+    }
+
+    /// Subtraction that panics on underflow in debug build.
+    pub fn checked_sub(self, other: usize) -> Self {
+        HostPhysicalAddress(address_arith(self.0, AddressOp::Sub, other))
+    }
+
+    /// Is the address in host DRAM? (see `init_dram_range`)
+    pub fn in_dram_range(self) -> bool {
+        HOST_DRAM_RANGE
+            .lock()
+            .get()
+            .cloned()
+            .unwrap_or_else(default_dram_range)
+            .contains(&self.0)
+    }
 }
 
 impl core::ops::Add<usize> for HostPhysicalAddress {
     type Output = HostPhysicalAddress;
     fn add(self, other: usize) -> Self::Output {
-        HostPhysicalAddress(self.0 + other)
+        self.checked_add(other)
     }
 }
 
 impl core::ops::Sub<usize> for HostPhysicalAddress {
     type Output = HostPhysicalAddress;
     fn sub(self, other: usize) -> Self::Output {
-        HostPhysicalAddress(self.0 - other)
+        self.checked_sub(other)
     }
 }
 
@@ -86,12 +241,49 @@ impl core::ops::Rem<usize> for HostPhysicalAddress {
     }
 }
 
+/// Print as `HPA(0x...)`.
+impl core::fmt::Debug for HostPhysicalAddress {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "HPA({:#x})", self.0)
+    }
+}
+
+/// Print in decimal. (e.g. for sizes)
+impl core::fmt::Display for HostPhysicalAddress {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        core::fmt::Display::fmt(&self.0, f)
+    }
+}
+
+/// Print in hexadecimal. (`{:#x}`)
+impl core::fmt::LowerHex for HostPhysicalAddress {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        core::fmt::LowerHex::fmt(&self.0, f)
+    }
+}
+
 impl AddressRangeUtil for Range<HostPhysicalAddress> {
     fn len(&self) -> usize {
         self.end.raw() - self.start.raw()
     }
 }
 
+/// Error of `MemoryMap` validation.
+#[derive(Debug, Clone, Copy)]
+#[allow(clippy::module_name_repetitions)]
+pub enum MemoryMapError {
+    /// Start address is not aligned to page size of the mapping.
+    MisalignedStart,
+    /// End address is not aligned to page boundary.
+    MisalignedEnd,
+    /// Range is empty.
+    ZeroLength,
+    /// Virtual and physical ranges have different length.
+    LengthMismatch,
+    /// Flags are inconsistent (e.g. `Write` without `Read`).
+    InvalidFlags,
+}
+
 /// Struct for represent memory regtion.
 #[derive(Debug, Clone)]
 pub struct MemoryMap {
@@ -107,15 +299,106 @@ impl MemoryMap {
     /// Create new `MemoryMap`.
     ///
     /// `flags` is mapped to bitmap.
+    /// It is for ranges fixed by the hypervisor.
+    /// Ranges that come from outside (e.g. device tree) should be validated by `MemoryMapBuilder::build`.
+    ///
+    /// # Panics
+    /// It panics if the memory map is invalid. (see `MemoryMapBuilder::build`)
     pub fn new(
         virt: Range<GuestPhysicalAddress>,
         phys: Range<HostPhysicalAddress>,
         flags: &[PteFlag],
     ) -> Self {
-        Self {
-            virt,
-            phys,
-            flags: flags.iter().fold(0, |pte_f, f| (pte_f | *f as u8)),
+        MemoryMapBuilder::new()
+            .virt_range(virt)
+            .phys_range(phys)
+            .flags(flags)
+            .build()
+            .expect("invalid memory map")
+    }
+}
+
+/// Return true if guest physical address ranges of `a` and `b` overlap.
+pub fn overlaps(a: &MemoryMap, b: &MemoryMap) -> bool {
+    a.virt.start < b.virt.end && b.virt.start < a.virt.end
+}
+
+/// Builder for `MemoryMap`.
+#[derive(Debug, Default)]
+#[allow(clippy::module_name_repetitions)]
+pub struct MemoryMapBuilder {
+    /// Guest physical address
+    virt: Range<GuestPhysicalAddress>,
+    /// Host physical address
+    phys: Range<HostPhysicalAddress>,
+    /// Page table entry flags
+    flags: u8,
+    /// Is it shadow stack mapping? (`Write` without `Read` is allowed)
+    shadow_stack: bool,
+}
+
+impl MemoryMapBuilder {
+    /// Create new `MemoryMapBuilder`.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set guest physical address range.
+    pub fn virt_range(mut self, virt: Range<GuestPhysicalAddress>) -> Self {
+        self.virt = virt;
+        self
+    }
+
+    /// Set host physical address range.
+    pub fn phys_range(mut self, phys: Range<HostPhysicalAddress>) -> Self {
+        self.phys = phys;
+        self
+    }
+
+    /// Set page table entry flags.
+    pub fn flags(mut self, flags: &[PteFlag]) -> Self {
+        self.flags = PteFlag::combine(flags);
+        self.shadow_stack = flags
+            .iter()
+            .any(|flag| matches!(flag, PteFlag::ShadowStack));
+        self
+    }
+
+    /// Validate and build `MemoryMap`.
+    ///
+    /// # Errors
+    /// It returns `MemoryMapError` if
+    /// - ranges are empty or have different length.
+    /// - start addresses are not aligned to page size used for the mapping.
+    /// - end addresses are not aligned to page boundary.
+    /// - `Write` is set without `Read`. (except `ShadowStack`)
+    pub fn build(self) -> Result<MemoryMap, MemoryMapError> {
+        if self.virt.is_empty() {
+            return Err(MemoryMapError::ZeroLength);
+        }
+        if self.virt.len() != self.phys.len() {
+            return Err(MemoryMapError::LengthMismatch);
         }
+
+        let page_size = PageTableLevel::from_map_len(self.virt.len()).size();
+        if self.virt.start % page_size != 0 || self.phys.start % page_size != 0 {
+            return Err(MemoryMapError::MisalignedStart);
+        }
+        if self.virt.end % PAGE_SIZE != 0 || self.phys.end % PAGE_SIZE != 0 {
+            return Err(MemoryMapError::MisalignedEnd);
+        }
+
+        if PteFlag::has(self.flags, PteFlag::Write)
+            && !PteFlag::has(self.flags, PteFlag::Read)
+            && !self.shadow_stack
+        {
+            return Err(MemoryMapError::InvalidFlags);
+        }
+
+        Ok(MemoryMap {
+            virt: self.virt,
+            phys: self.phys,
+            flags: self.flags,
+        })
     }
 }
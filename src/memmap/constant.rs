@@ -10,14 +10,34 @@
 //! | `0x9fff_d000` | `0xa000_0000` | Device tree of guest 1   |
 
 /// Max number of HART
-pub const MAX_HART_NUM: usize = 8;
+///
+/// It is configured by `HIKAMI_MAX_HART_NUM` at build time. (default: 8)
+pub const MAX_HART_NUM: usize = include!(concat!(env!("OUT_DIR"), "/max_hart_num.rs"));
 /// Base address of dram.
 pub const DRAM_BASE: usize = 0x8000_0000;
+/// Default size of dram.
+///
+/// It is used if the host device tree has no `/memory`. (QEMU is launched with `-m 2G`)
+pub const DRAM_SIZE: usize = 0x8000_0000;
 /// Stack size for each HART.
 pub const STACK_SIZE_PER_HART: usize = 0x1_0000;
 
+// stacks of all HARTs are placed under `_stack_start`.
+const _: () = {
+    /// Size of the stack region in the linker script. (`L2_LIM` in `memory.x`)
+    #[allow(clippy::unreadable_literal)]
+    const STACK_REGION_SIZE: usize = include!(concat!(env!("OUT_DIR"), "/stack_region_size.rs"));
+
+    assert!(
+        MAX_HART_NUM * STACK_SIZE_PER_HART <= STACK_REGION_SIZE,
+        "stacks of MAX_HART_NUM HARTs exceed the stack region in memory.x"
+    );
+};
+
 pub mod guest_memory {
     //! Guest memory region on Guest Physical Address
+    //!
+    //! They are defaults of the layout derived from host device tree at boot. (see `guest::layout`)
 
     use crate::memmap::GuestPhysicalAddress;
 
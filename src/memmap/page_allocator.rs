@@ -0,0 +1,83 @@
+//! Page frame allocator.
+//!
+//! Frames are taken from the hypervisor heap and recycled through a free list when they are freed.
+
+use crate::memmap::{page_table::constants::PAGE_SIZE, HostPhysicalAddress};
+use crate::PageBlock;
+
+use spin::Mutex;
+
+/// Global page frame allocator.
+pub static PAGE_ALLOCATOR: Mutex<PageAllocator> = Mutex::new(PageAllocator::new());
+
+/// Page frame allocator.
+///
+/// Freed frames are linked as an intrusive list.
+/// (the first 8 bytes of a free frame hold the address of the next free frame)
+#[derive(Debug)]
+pub struct PageAllocator {
+    /// Head of free frame list. (`None` if the list is empty)
+    free_list_head: Option<HostPhysicalAddress>,
+}
+
+impl PageAllocator {
+    /// Constructor for `PageAllocator`.
+    const fn new() -> Self {
+        PageAllocator {
+            free_list_head: None,
+        }
+    }
+
+    /// Return page aligned frame.
+    ///
+    /// A freed frame is reused if exists, otherwise new frame is allocated from heap.
+    pub fn alloc(&mut self) -> HostPhysicalAddress {
+        match self.free_list_head {
+            Some(frame) => {
+                let next = unsafe { (frame.raw() as *const usize).read() };
+                self.free_list_head = if next == 0 {
+                    None
+                } else {
+                    Some(HostPhysicalAddress(next))
+                };
+                frame
+            }
+            None => PageBlock::alloc(),
+        }
+    }
+
+    /// Return page aligned frame filled with zero.
+    pub fn alloc_zeroed(&mut self) -> HostPhysicalAddress {
+        let frame = self.alloc();
+        unsafe {
+            core::ptr::write_bytes(frame.raw() as *mut u8, 0u8, PAGE_SIZE);
+        }
+        frame
+    }
+
+    /// Return page aligned frame filled with zero, or `None` if the heap is exhausted.
+    ///
+    /// It is used for allocations requested by guests, which must not bring the hypervisor down.
+    pub fn try_alloc_zeroed(&mut self) -> Option<HostPhysicalAddress> {
+        let frame = match self.free_list_head {
+            Some(_) => self.alloc(),
+            None => PageBlock::try_alloc()?,
+        };
+        unsafe {
+            core::ptr::write_bytes(frame.raw() as *mut u8, 0u8, PAGE_SIZE);
+        }
+        Some(frame)
+    }
+
+    /// Return the frame to the allocator.
+    ///
+    /// # Safety
+    /// `frame` must be returned by `alloc` or `alloc_zeroed` and must not be used after freed.
+    pub unsafe fn free(&mut self, frame: HostPhysicalAddress) {
+        assert!(frame % PAGE_SIZE == 0);
+
+        let next = self.free_list_head.map_or(0, HostPhysicalAddress::raw);
+        (frame.raw() as *mut usize).write(next);
+        self.free_list_head = Some(frame);
+    }
+}
@@ -1,10 +1,15 @@
 //! Page table for address translation.
 
+#[cfg(feature = "boot_selftest")]
+pub mod selftest;
 pub mod sv39;
 pub mod sv39x4;
+pub mod sv48;
+pub mod sv48x4;
 pub mod sv57;
 
-use crate::memmap::{GuestPhysicalAddress, GuestVirtualAddress, HostPhysicalAddress};
+use crate::memmap::page_allocator::PAGE_ALLOCATOR;
+use crate::memmap::{GuestPhysicalAddress, GuestVirtualAddress, HostPhysicalAddress, MemoryMap};
 
 pub mod constants {
     //! Constants of page table.
@@ -21,9 +26,28 @@ pub mod constants {
 #[derive(Debug)]
 pub enum TransAddrError {
     /// Invalid page table entry.
-    InvalidEntry,
+    InvalidEntry {
+        /// Address failed to translate. (GVA or GPA)
+        addr: usize,
+        /// Page table level (index of VPN) where the translation failed.
+        level: usize,
+    },
     /// Cannot reach leaf entry.
-    NoLeafEntry,
+    NoLeafEntry {
+        /// Address failed to translate. (GVA or GPA)
+        addr: usize,
+    },
+}
+
+impl core::fmt::Display for TransAddrError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            TransAddrError::InvalidEntry { addr, level } => {
+                write!(f, "invalid entry at level {level} ({addr:#x})")
+            }
+            TransAddrError::NoLeafEntry { addr } => write!(f, "no leaf entry ({addr:#x})"),
+        }
+    }
 }
 
 /// Page table level.
@@ -31,7 +55,7 @@ pub enum TransAddrError {
 /// ref: The RISC-V Instruction Set Manual: Volume II p151.
 #[derive(Copy, Clone, PartialEq)]
 #[allow(clippy::module_name_repetitions)]
-enum PageTableLevel {
+pub(super) enum PageTableLevel {
     /// 256TB = 48 bit = vpn\[3\] (9 bit) + vpn\[2\] (9 bit) + vpn\[1\] (9 bit) + vpn\[0\] (9 bit) + offset (12 bit)
     Lv256TB = 4,
     /// 512GB = 39 bit = vpn\[2\] (9 bit) + vpn\[1\] (9 bit) + vpn\[0\] (9 bit) + offset (12 bit)
@@ -45,8 +69,17 @@ enum PageTableLevel {
 }
 
 impl PageTableLevel {
+    /// Decide page level used for mapping the memory range from its length.
+    pub(super) fn from_map_len(len: usize) -> Self {
+        match len {
+            0x0..=0x001f_ffff => Self::Lv4KB,
+            0x0020_0000..=0x3fff_ffff => Self::Lv2MB,
+            0x4000_0000.. => Self::Lv1GB,
+        }
+    }
+
     /// Return usize.
-    fn size(self) -> usize {
+    pub(super) fn size(self) -> usize {
         match self {
             Self::Lv256TB => 0x1_0000_0000_0000,
             Self::Lv512GB => 0x80_0000_0000,
@@ -57,10 +90,6 @@ impl PageTableLevel {
     }
 }
 
-/// Heap memory region for page table.
-#[repr(C, align(4096))]
-struct PageTableMemory([PageTableEntry; constants::PAGE_TABLE_LEN]);
-
 /// Each flags for page tables.
 #[allow(dead_code)]
 #[derive(Copy, Clone)]
@@ -81,6 +110,48 @@ pub enum PteFlag {
     Accessed = 0b0100_0000,
     /// This page has been written.
     Dirty = 0b1000_0000,
+    /// Shadow stack page of Zicfiss. (pseudo flag: `W` without `R` and `X`)
+    ///
+    /// It is valid only in VS-stage page tables. (reserved in G-stage)
+    ShadowStack,
+}
+
+impl PteFlag {
+    /// Mask of `R`, `W` and `X`.
+    const RWX_MASK: u8 = PteFlag::Read as u8 | PteFlag::Write as u8 | PteFlag::Exec as u8;
+
+    /// Return bit of the flag in page table entry.
+    fn bits(self) -> u8 {
+        match self {
+            PteFlag::ShadowStack => PteFlag::Write as u8,
+            flag => flag as u8,
+        }
+    }
+
+    /// Combine flags into the bitmap of page table entry.
+    ///
+    /// `ShadowStack` clears `R` and `X`.
+    pub fn combine(flags: &[PteFlag]) -> u8 {
+        let combined = flags
+            .iter()
+            .fold(0, |combined, flag| combined | flag.bits());
+        if flags
+            .iter()
+            .any(|flag| matches!(flag, PteFlag::ShadowStack))
+        {
+            combined & !PteFlag::RWX_MASK | PteFlag::Write as u8
+        } else {
+            combined
+        }
+    }
+
+    /// Is `flag` set in the combined flags?
+    pub fn has(combined: u8, flag: PteFlag) -> bool {
+        match flag {
+            PteFlag::ShadowStack => combined & PteFlag::RWX_MASK == PteFlag::Write as u8,
+            flag => combined & flag as u8 != 0,
+        }
+    }
 }
 
 /// Page table entry
@@ -95,26 +166,18 @@ impl PageTableEntry {
     }
 
     /// Is leaf page table entry
+    ///
+    /// Any of `R`, `W` and `X` is set. (`W` without `R` and `X` is a shadow stack page)
     fn is_leaf(self) -> bool {
-        let pte_r = (self.0 >> 1) & 0x1;
-        let pte_w = (self.0 >> 2) & 0x1;
-        let pte_x = (self.0 >> 3) & 0x1;
-
-        // For Zicfilp (TODO: remove it)
-        pte_r == 1 || pte_x == 1 || (pte_r == 0 && pte_w == 1 && pte_x == 0)
+        self.0 & u64::from(PteFlag::RWX_MASK) != 0
     }
 
     /// Is pte invalid?
+    ///
+    /// `W` and `X` without `R` is reserved.
     fn is_invalid(self) -> bool {
-        let pte_v = self.0 & 0x1;
-        let _pte_r = (self.0 >> 1) & 0x1;
-        let _pte_w = (self.0 >> 2) & 0x1;
-
-        // For normal environment
-        // pte_v == 0 || (pte_r == 0 && pte_w == 1)
-
-        // For Zicfilp (TODO: remove it)
-        pte_v == 0
+        self.0 & PteFlag::Valid as u64 == 0
+            || self.0 & u64::from(PteFlag::RWX_MASK) == PteFlag::Write as u64 | PteFlag::Exec as u64
     }
 
     /// Is it has already been created
@@ -132,12 +195,6 @@ impl PageTableEntry {
 #[derive(Copy, Clone)]
 struct PageTableAddress(usize);
 
-impl From<*mut PageTableMemory> for PageTableAddress {
-    fn from(f: *mut PageTableMemory) -> Self {
-        PageTableAddress(f as *const u64 as usize)
-    }
-}
-
 impl PageTableAddress {
     /// Return page number
     fn page_number(self) -> u64 {
@@ -150,9 +207,9 @@ impl PageTableAddress {
     }
 
     /// Convert guest physical page table address to host physical one.
-    fn to_host_physical_ptr(self) -> *mut PageTableEntry {
-        let hpa = g_stage_trans_addr(GuestPhysicalAddress(self.0)).unwrap();
-        hpa.0 as *mut PageTableEntry
+    fn to_host_physical_ptr(self) -> Result<*mut PageTableEntry, (TransAddrError, &'static str)> {
+        let hpa = g_stage_trans_addr(GuestPhysicalAddress(self.0))?;
+        Ok(hpa.0 as *mut PageTableEntry)
     }
 }
 
@@ -185,10 +242,12 @@ pub fn vs_stage_trans_addr(
 
     let vsatp = vsatp::read();
     match vsatp.mode() {
-        vsatp::Mode::Bare => unreachable!("no trans addr"),
+        // VS-stage translation is disabled. (e.g. early boot of guest)
+        vsatp::Mode::Bare => Ok(GuestPhysicalAddress(gva.raw())),
         vsatp::Mode::Sv39 => sv39::trans_addr(gva),
+        vsatp::Mode::Sv48 => sv48::trans_addr(gva),
         vsatp::Mode::Sv57 => sv57::trans_addr(gva),
-        vsatp::Mode::Sv48 | vsatp::Mode::Sv64 => unimplemented!(),
+        vsatp::Mode::Sv64 => unimplemented!(),
     }
 }
 
@@ -196,12 +255,127 @@ pub fn vs_stage_trans_addr(
 pub fn g_stage_trans_addr(
     gpa: GuestPhysicalAddress,
 ) -> Result<HostPhysicalAddress, (TransAddrError, &'static str)> {
+    use crate::guest::layout::guest_memory_layout;
     use crate::h_extension::csrs::hgatp;
 
     let hgatp = hgatp::read();
-    match hgatp.mode() {
+    let hpa = match hgatp.mode() {
         hgatp::Mode::Bare => unreachable!("no trans addr"),
         hgatp::Mode::Sv39x4 => sv39x4::trans_addr(gpa),
-        hgatp::Mode::Sv48x4 | hgatp::Mode::Sv57x4 => unimplemented!(),
+        hgatp::Mode::Sv48x4 => sv48x4::trans_addr(gpa),
+        hgatp::Mode::Sv57x4 => unimplemented!(),
+    }?;
+
+    // guest dram must be backed by host dram. (devices are identity mapped)
+    debug_assert!(
+        !guest_memory_layout().dram_range().contains(&gpa) || hpa.in_dram_range(),
+        "guest dram {gpa:#x} is translated to outside of host dram: {hpa:#x}"
+    );
+
+    Ok(hpa)
+}
+
+/// Zero filling G-stage root page table in the mode of `hgatp`.
+pub fn g_stage_initialize_page_table(root_table_start_addr: HostPhysicalAddress) {
+    use crate::h_extension::csrs::hgatp;
+
+    match hgatp::read().mode() {
+        hgatp::Mode::Bare => unreachable!("G-stage translation mode is not set"),
+        hgatp::Mode::Sv39x4 => sv39x4::initialize_page_table(root_table_start_addr),
+        hgatp::Mode::Sv48x4 => sv48x4::initialize_page_table(root_table_start_addr),
+        hgatp::Mode::Sv57x4 => unimplemented!(),
+    }
+}
+
+/// Generate G-stage page table in the mode of `hgatp`.
+pub fn g_stage_generate_page_table(
+    root_table_start_addr: HostPhysicalAddress,
+    memmaps: &[MemoryMap],
+) {
+    use crate::h_extension::csrs::hgatp;
+
+    match hgatp::read().mode() {
+        hgatp::Mode::Bare => unreachable!("G-stage translation mode is not set"),
+        hgatp::Mode::Sv39x4 => sv39x4::generate_page_table(root_table_start_addr, memmaps),
+        hgatp::Mode::Sv48x4 => sv48x4::generate_page_table(root_table_start_addr, memmaps),
+        hgatp::Mode::Sv57x4 => unimplemented!(),
+    }
+}
+
+/// Clear the G-stage 4 KiB leaf entry that maps `gpa` in the mode of `hgatp`.
+pub fn g_stage_unmap_page(root_table_start_addr: HostPhysicalAddress, gpa: GuestPhysicalAddress) {
+    use crate::h_extension::csrs::hgatp;
+
+    match hgatp::read().mode() {
+        hgatp::Mode::Bare => unreachable!("G-stage translation mode is not set"),
+        hgatp::Mode::Sv39x4 => unmap_page(root_table_start_addr, gpa, PageTableLevel::Lv1GB),
+        hgatp::Mode::Sv48x4 => unmap_page(root_table_start_addr, gpa, PageTableLevel::Lv512GB),
+        hgatp::Mode::Sv57x4 => unimplemented!(),
+    }
+}
+
+/// Clear the G-stage 4 KiB leaf entry that maps `gpa`.
+///
+/// `root_level` is the level of the root page table. (e.g. `Lv1GB` in Sv39x4)
+/// Non-leaf tables are left as is.
+/// It does nothing if `gpa` is not mapped.
+#[allow(clippy::cast_possible_truncation)]
+fn unmap_page(
+    root_table_start_addr: HostPhysicalAddress,
+    gpa: GuestPhysicalAddress,
+    root_level: PageTableLevel,
+) {
+    let mut table_addr = PageTableAddress(root_table_start_addr.raw());
+    // root page table of G-stage is widened by 2 bits. (x4)
+    let mut table_len = constants::PAGE_TABLE_LEN << 2;
+    for level in (0..=root_level as usize).rev() {
+        let page_table =
+            unsafe { core::slice::from_raw_parts_mut(table_addr.to_pte_ptr(), table_len) };
+        let vpn = (gpa.raw() >> (12 + 9 * level)) % table_len;
+        if page_table[vpn].is_invalid() {
+            return;
+        }
+
+        if page_table[vpn].is_leaf() {
+            assert!(
+                level == PageTableLevel::Lv4KB as usize,
+                "unmapping a part of superpage is not supported"
+            );
+            page_table[vpn] = PageTableEntry(0);
+            return;
+        }
+
+        table_addr = PageTableAddress(page_table[vpn].entire_ppn() as usize * constants::PAGE_SIZE);
+        table_len = constants::PAGE_TABLE_LEN;
+    }
+}
+
+/// Free all page tables under the G-stage root page table in the mode of `hgatp`.
+pub fn g_stage_destroy_page_table(root_table_start_addr: HostPhysicalAddress) {
+    use crate::h_extension::csrs::hgatp;
+
+    match hgatp::read().mode() {
+        hgatp::Mode::Bare => unreachable!("G-stage translation mode is not set"),
+        hgatp::Mode::Sv39x4 => sv39x4::destroy_page_table(root_table_start_addr),
+        hgatp::Mode::Sv48x4 => sv48x4::destroy_page_table(root_table_start_addr),
+        hgatp::Mode::Sv57x4 => unimplemented!(),
+    }
+}
+
+/// Free page tables pointed by non-leaf entries of the table recursively and clear the entries.
+#[allow(clippy::cast_possible_truncation)]
+fn destroy_sub_tables(table_addr: PageTableAddress, table_len: usize) {
+    let page_table = unsafe { core::slice::from_raw_parts_mut(table_addr.to_pte_ptr(), table_len) };
+    for pte in page_table.iter_mut() {
+        if !pte.is_invalid() && !pte.is_leaf() {
+            let sub_table_addr = PageTableAddress(pte.entire_ppn() as usize * constants::PAGE_SIZE);
+            destroy_sub_tables(sub_table_addr, constants::PAGE_TABLE_LEN);
+            unsafe {
+                PAGE_ALLOCATOR
+                    .lock()
+                    .free(HostPhysicalAddress(sub_table_addr.0));
+            }
+        }
+        *pte = PageTableEntry(0);
     }
 }
@@ -0,0 +1,408 @@
+//! Boot-time self test of G-stage page table generation and address translation.
+//!
+//! Synthetic mappings are generated into a scratch root page table,
+//! and `g_stage_trans_addr` is checked against them while hgatp temporarily points to the table.
+//! VS-stage (Sv39, Sv48 and Sv57) translation is checked in the same way by pointing vsatp
+//! to page tables that are mapped in the scratch G-stage table.
+//! 4 KiB leaves of VS-stage are shadow stack pages (`PteFlag::ShadowStack`) to check that
+//! the walkers accept the encoding.
+
+use super::{
+    constants::PAGE_SIZE, g_stage_destroy_page_table, g_stage_generate_page_table,
+    g_stage_trans_addr, sv39x4::FIRST_LV_PAGE_TABLE_LEN, vs_stage_trans_addr, PageTableEntry,
+    PageTableLevel, PteFlag, TransAddrError,
+};
+use crate::h_extension::csrs::{hgatp, vsatp};
+use crate::h_extension::instruction::hfence_gvma_all;
+use crate::memmap::constant::DRAM_BASE;
+use crate::memmap::page_allocator::PAGE_ALLOCATOR;
+use crate::memmap::{GuestPhysicalAddress, GuestVirtualAddress, HostPhysicalAddress, MemoryMap};
+
+use alloc::alloc::{alloc_zeroed, dealloc};
+use alloc::vec::Vec;
+use core::alloc::Layout;
+
+/// Synthetic mappings. (GPA, HPA, size, expected leaf level)
+///
+/// They are placed at the top of 39-bit and 41-bit (Sv39x4) guest physical address spaces.
+const MAPPINGS: [(usize, usize, usize, PageTableLevel); 3] = [
+    // top 1 GiB of 41-bit space
+    (
+        0x1ff_c000_0000,
+        DRAM_BASE,
+        0x4000_0000,
+        PageTableLevel::Lv1GB,
+    ),
+    // top 2 MiB of 39-bit space
+    (
+        0x7f_ffe0_0000,
+        DRAM_BASE + 0x20_0000,
+        0x20_0000,
+        PageTableLevel::Lv2MB,
+    ),
+    // two 4 KiB pages just below the 2 MiB region
+    (
+        0x7f_ffdf_e000,
+        DRAM_BASE + 0x1000,
+        0x2000,
+        PageTableLevel::Lv4KB,
+    ),
+];
+
+/// Unmapped GPAs next to the mappings and the level where the translation must stop.
+const UNMAPPED: [(usize, PageTableLevel); 3] = [
+    // 1 GiB region below the 1 GiB leaf (no entry in 1 GiB level table)
+    (0x1ff_bfff_f000, PageTableLevel::Lv1GB),
+    // 2 MiB region below the 4 KiB leaves (no entry in second-level table)
+    (0x7f_ffa0_0000, PageTableLevel::Lv2MB),
+    // 4 KiB page below the 4 KiB leaves (no entry in third-level table)
+    (0x7f_ffdf_d000, PageTableLevel::Lv4KB),
+];
+
+/// Guest physical address where VS-stage page tables of the self test are placed.
+///
+/// It is in the 1 GiB region of the 4 KiB leaves but apart from `UNMAPPED`.
+const VS_TABLE_GPA_BASE: usize = 0x7f_ff00_0000;
+
+/// Levels of Sv39 page table walk.
+const SV39_LEVELS: [PageTableLevel; 3] = [
+    PageTableLevel::Lv1GB,
+    PageTableLevel::Lv2MB,
+    PageTableLevel::Lv4KB,
+];
+
+/// Synthetic Sv39 mappings at the top of lower half. (GVA, GPA, size, expected leaf level)
+const SV39_MAPPINGS: [(usize, usize, usize, PageTableLevel); 3] = [
+    (
+        0x3f_c000_0000,
+        0x8000_0000,
+        0x4000_0000,
+        PageTableLevel::Lv1GB,
+    ),
+    (
+        0x3f_7fe0_0000,
+        0x8020_0000,
+        0x20_0000,
+        PageTableLevel::Lv2MB,
+    ),
+    (0x3f_7fdf_e000, 0x8000_1000, 0x2000, PageTableLevel::Lv4KB),
+];
+
+/// Unmapped Sv39 GVAs next to the mappings and the level where the translation must stop.
+const SV39_UNMAPPED: [(usize, PageTableLevel); 3] = [
+    (0x3f_8000_0000, PageTableLevel::Lv1GB),
+    (0x3f_7fa0_0000, PageTableLevel::Lv2MB),
+    (0x3f_7fdf_d000, PageTableLevel::Lv4KB),
+];
+
+/// Levels of Sv48 page table walk.
+const SV48_LEVELS: [PageTableLevel; 4] = [
+    PageTableLevel::Lv512GB,
+    PageTableLevel::Lv1GB,
+    PageTableLevel::Lv2MB,
+    PageTableLevel::Lv4KB,
+];
+
+/// Synthetic Sv48 mappings at the top of lower half. (GVA, GPA, size, expected leaf level)
+const SV48_MAPPINGS: [(usize, usize, usize, PageTableLevel); 3] = [
+    (
+        0x7fff_c000_0000,
+        0x8000_0000,
+        0x4000_0000,
+        PageTableLevel::Lv1GB,
+    ),
+    (
+        0x7fff_7fe0_0000,
+        0x8020_0000,
+        0x20_0000,
+        PageTableLevel::Lv2MB,
+    ),
+    (0x7fff_7fdf_e000, 0x8000_1000, 0x2000, PageTableLevel::Lv4KB),
+];
+
+/// Unmapped Sv48 GVAs next to the mappings and the level where the translation must stop.
+const SV48_UNMAPPED: [(usize, PageTableLevel); 4] = [
+    (0x7f7f_c000_0000, PageTableLevel::Lv512GB),
+    (0x7fff_8000_0000, PageTableLevel::Lv1GB),
+    (0x7fff_7fa0_0000, PageTableLevel::Lv2MB),
+    (0x7fff_7fdf_d000, PageTableLevel::Lv4KB),
+];
+
+/// Levels of Sv57 page table walk.
+const SV57_LEVELS: [PageTableLevel; 5] = [
+    PageTableLevel::Lv256TB,
+    PageTableLevel::Lv512GB,
+    PageTableLevel::Lv1GB,
+    PageTableLevel::Lv2MB,
+    PageTableLevel::Lv4KB,
+];
+
+/// Synthetic Sv57 mappings at the top of lower half. (GVA, GPA, size, expected leaf level)
+const SV57_MAPPINGS: [(usize, usize, usize, PageTableLevel); 3] = [
+    (
+        0xff_ffff_c000_0000,
+        0x8000_0000,
+        0x4000_0000,
+        PageTableLevel::Lv1GB,
+    ),
+    (
+        0xff_ffff_7fe0_0000,
+        0x8020_0000,
+        0x20_0000,
+        PageTableLevel::Lv2MB,
+    ),
+    (
+        0xff_ffff_7fdf_e000,
+        0x8000_1000,
+        0x2000,
+        PageTableLevel::Lv4KB,
+    ),
+];
+
+/// Unmapped Sv57 GVAs next to the mappings and the level where the translation must stop.
+const SV57_UNMAPPED: [(usize, PageTableLevel); 5] = [
+    (0x7f_ffff_c000_0000, PageTableLevel::Lv256TB),
+    (0xff_ff7f_c000_0000, PageTableLevel::Lv512GB),
+    (0xff_ffff_8000_0000, PageTableLevel::Lv1GB),
+    (0xff_ffff_7fa0_0000, PageTableLevel::Lv2MB),
+    (0xff_ffff_7fdf_d000, PageTableLevel::Lv4KB),
+];
+
+/// Run the self test in G-stage translation mode of current hgatp, then VS-stage one on top of it.
+///
+/// # Panics
+/// It panics with the offending address and level if the translation result is wrong.
+pub fn g_stage_translation() {
+    let layout = Layout::new::<[PageTableEntry; FIRST_LV_PAGE_TABLE_LEN]>()
+        .align_to(FIRST_LV_PAGE_TABLE_LEN * core::mem::size_of::<PageTableEntry>())
+        .unwrap();
+    let scratch_root = unsafe { alloc_zeroed(layout) };
+    assert!(
+        !scratch_root.is_null(),
+        "failed to allocate scratch page table"
+    );
+    let scratch_root_addr = HostPhysicalAddress(scratch_root as usize);
+
+    let running_hgatp = hgatp::read();
+    hgatp::set(
+        running_hgatp.mode(),
+        running_hgatp.vmid(),
+        scratch_root_addr.raw() >> 12,
+    );
+
+    let memmaps = MAPPINGS.map(|(gpa, hpa, size, _)| {
+        MemoryMap::new(
+            GuestPhysicalAddress(gpa)..GuestPhysicalAddress(gpa + size),
+            HostPhysicalAddress(hpa)..HostPhysicalAddress(hpa + size),
+            &[
+                PteFlag::Dirty,
+                PteFlag::Accessed,
+                PteFlag::Write,
+                PteFlag::Read,
+                PteFlag::User,
+                PteFlag::Valid,
+            ],
+        )
+    });
+    g_stage_generate_page_table(scratch_root_addr, &memmaps);
+
+    for (gpa, hpa, size, level) in MAPPINGS {
+        // first byte, middle of the last page and last byte.
+        for offset in [0, size - 0x800, size - 1] {
+            let result = g_stage_trans_addr(GuestPhysicalAddress(gpa + offset));
+            assert!(
+                matches!(result, Ok(translated) if translated.raw() == hpa + offset),
+                "[selftest] G-stage translation of {:#x} (leaf level {}) returns {:x?}, expected {:#x}",
+                gpa + offset,
+                level as usize,
+                result,
+                hpa + offset
+            );
+        }
+    }
+
+    for (gpa, level) in UNMAPPED {
+        let result = g_stage_trans_addr(GuestPhysicalAddress(gpa));
+        assert!(
+            matches!(
+                result,
+                Err((TransAddrError::InvalidEntry { addr, level: failed_level }, _))
+                    if addr == gpa && failed_level == level as usize
+            ),
+            "[selftest] G-stage translation of unmapped {:#x} returns {:x?}, expected invalid entry at level {}",
+            gpa,
+            result,
+            level as usize
+        );
+    }
+
+    vs_stage_translation(scratch_root_addr);
+
+    g_stage_destroy_page_table(scratch_root_addr);
+    hgatp::write(running_hgatp.bits());
+    hfence_gvma_all();
+    unsafe {
+        dealloc(scratch_root, layout);
+    }
+}
+
+/// Build VS-stage page tables of the mappings and return the root table address.
+///
+/// New tables are pushed to `tables` and placed at `VS_TABLE_GPA_BASE + index * PAGE_SIZE` in guest.
+/// 4 KiB leaves are mapped as shadow stack pages.
+#[allow(clippy::cast_possible_truncation)]
+fn vs_stage_generate_page_table(
+    tables: &mut Vec<HostPhysicalAddress>,
+    levels: &[PageTableLevel],
+    mappings: &[(usize, usize, usize, PageTableLevel)],
+) -> GuestPhysicalAddress {
+    let root_index = tables.len();
+    tables.push(PAGE_ALLOCATOR.lock().alloc_zeroed());
+
+    for &(gva, gpa, size, leaf_level) in mappings {
+        for offset in (0..size).step_by(leaf_level.size()) {
+            let mut table_index = root_index;
+            for &level in levels {
+                let vpn = ((gva + offset) >> (12 + 9 * level as usize)) & 0x1ff;
+                let pte =
+                    unsafe { &mut *(tables[table_index].raw() as *mut PageTableEntry).add(vpn) };
+                if level == leaf_level {
+                    let flags = if level == PageTableLevel::Lv4KB {
+                        PteFlag::combine(&[
+                            PteFlag::Dirty,
+                            PteFlag::Accessed,
+                            PteFlag::ShadowStack,
+                            PteFlag::Valid,
+                        ])
+                    } else {
+                        PteFlag::combine(&[
+                            PteFlag::Dirty,
+                            PteFlag::Accessed,
+                            PteFlag::Write,
+                            PteFlag::Read,
+                            PteFlag::Valid,
+                        ])
+                    };
+                    *pte = PageTableEntry::new(((gpa + offset) / PAGE_SIZE) as u64, flags);
+                    break;
+                }
+
+                if !pte.already_created() {
+                    tables.push(PAGE_ALLOCATOR.lock().alloc_zeroed());
+                    *pte = PageTableEntry::new(
+                        (VS_TABLE_GPA_BASE / PAGE_SIZE + tables.len() - 1) as u64,
+                        PteFlag::Valid.bits(),
+                    );
+                }
+                table_index = pte.entire_ppn() as usize - VS_TABLE_GPA_BASE / PAGE_SIZE;
+            }
+        }
+    }
+
+    GuestPhysicalAddress(VS_TABLE_GPA_BASE + root_index * PAGE_SIZE)
+}
+
+/// Run the VS-stage self test on the scratch G-stage page table.
+///
+/// The translation mode is skipped if vsatp does not accept it.
+///
+/// # Panics
+/// It panics with the offending GVA and level if the translation result is wrong.
+fn vs_stage_translation(scratch_root_addr: HostPhysicalAddress) {
+    let mut tables = Vec::new();
+    let sv39_root = vs_stage_generate_page_table(&mut tables, &SV39_LEVELS, &SV39_MAPPINGS);
+    let sv48_root = vs_stage_generate_page_table(&mut tables, &SV48_LEVELS, &SV48_MAPPINGS);
+    let sv57_root = vs_stage_generate_page_table(&mut tables, &SV57_LEVELS, &SV57_MAPPINGS);
+
+    // make the VS-stage page tables visible from the guest physical address space.
+    let memmaps = tables
+        .iter()
+        .enumerate()
+        .map(|(index, &table)| {
+            let gpa = VS_TABLE_GPA_BASE + index * PAGE_SIZE;
+            MemoryMap::new(
+                GuestPhysicalAddress(gpa)..GuestPhysicalAddress(gpa + PAGE_SIZE),
+                table..table + PAGE_SIZE,
+                &[
+                    PteFlag::Dirty,
+                    PteFlag::Accessed,
+                    PteFlag::Write,
+                    PteFlag::Read,
+                    PteFlag::User,
+                    PteFlag::Valid,
+                ],
+            )
+        })
+        .collect::<Vec<_>>();
+    g_stage_generate_page_table(scratch_root_addr, &memmaps);
+
+    let running_vsatp = vsatp::read();
+    for (name, mode, root, mappings, unmapped) in [
+        (
+            "Sv39",
+            vsatp::Mode::Sv39,
+            sv39_root,
+            &SV39_MAPPINGS[..],
+            &SV39_UNMAPPED[..],
+        ),
+        (
+            "Sv48",
+            vsatp::Mode::Sv48,
+            sv48_root,
+            &SV48_MAPPINGS[..],
+            &SV48_UNMAPPED[..],
+        ),
+        (
+            "Sv57",
+            vsatp::Mode::Sv57,
+            sv57_root,
+            &SV57_MAPPINGS[..],
+            &SV57_UNMAPPED[..],
+        ),
+    ] {
+        let new_vsatp = ((mode as usize) << 60) | (root.raw() / PAGE_SIZE);
+        vsatp::write(new_vsatp);
+        if vsatp::read().bits() != new_vsatp {
+            crate::println!("[selftest] {} is not supported: skip VS-stage test", name);
+            continue;
+        }
+
+        for &(gva, gpa, size, level) in mappings {
+            for offset in [0, size - 0x800, size - 1] {
+                let result = vs_stage_trans_addr(GuestVirtualAddress(gva + offset));
+                assert!(
+                    matches!(result, Ok(translated) if translated.raw() == gpa + offset),
+                    "[selftest] {} translation of {:#x} (leaf level {}) returns {:x?}, expected {:#x}",
+                    name,
+                    gva + offset,
+                    level as usize,
+                    result,
+                    gpa + offset
+                );
+            }
+        }
+
+        for &(gva, level) in unmapped {
+            let result = vs_stage_trans_addr(GuestVirtualAddress(gva));
+            assert!(
+                matches!(
+                    result,
+                    Err((TransAddrError::InvalidEntry { addr, level: failed_level }, _))
+                        if addr == gva && failed_level == level as usize
+                ),
+                "[selftest] {} translation of unmapped {:#x} returns {:x?}, expected invalid entry at level {}",
+                name,
+                gva,
+                result,
+                level as usize
+            );
+        }
+    }
+    vsatp::write(running_vsatp.bits());
+
+    for table in tables {
+        unsafe {
+            PAGE_ALLOCATOR.lock().free(table);
+        }
+    }
+}
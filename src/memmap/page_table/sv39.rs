@@ -61,11 +61,14 @@ pub fn trans_addr(
         PageTableLevel::Lv4KB,
     ] {
         let page_table =
-            unsafe { from_raw_parts_mut(page_table_addr.to_host_physical_ptr(), PAGE_TABLE_LEN) };
+            unsafe { from_raw_parts_mut(page_table_addr.to_host_physical_ptr()?, PAGE_TABLE_LEN) };
         let pte = page_table[gva.vpn(level as usize)];
         if pte.is_invalid() {
             return Err((
-                TransAddrError::InvalidEntry,
+                TransAddrError::InvalidEntry {
+                    addr: gva.raw(),
+                    level: level as usize,
+                },
                 "Address translation failed: invalid pte",
             ));
         }
@@ -76,13 +79,19 @@ pub fn trans_addr(
                 PageTableLevel::Lv1GB => {
                     if pte.ppn(0) != 0 {
                         return Err((
-                            TransAddrError::InvalidEntry,
+                            TransAddrError::InvalidEntry {
+                                addr: gva.raw(),
+                                level: level as usize,
+                            },
                             "Address translation failed: pte.ppn[0] != 0",
                         ));
                     }
                     if pte.ppn(1) != 0 {
                         return Err((
-                            TransAddrError::InvalidEntry,
+                            TransAddrError::InvalidEntry {
+                                addr: gva.raw(),
+                                level: level as usize,
+                            },
                             "Address translation failed: pte.ppn[1] != 0",
                         ));
                     }
@@ -97,7 +106,10 @@ pub fn trans_addr(
                 PageTableLevel::Lv2MB => {
                     if pte.ppn(0) != 0 {
                         return Err((
-                            TransAddrError::InvalidEntry,
+                            TransAddrError::InvalidEntry {
+                                addr: gva.raw(),
+                                level: level as usize,
+                            },
                             "Address translation failed: pte.ppn[0] != 0",
                         ));
                     }
@@ -124,7 +136,7 @@ pub fn trans_addr(
     }
 
     Err((
-        TransAddrError::NoLeafEntry,
+        TransAddrError::NoLeafEntry { addr: gva.raw() },
         "[sv39] cannnot reach to leaf entry",
     ))
 }
@@ -5,17 +5,25 @@
 
 use super::{
     constants::{PAGE_SIZE, PAGE_TABLE_LEN},
-    PageTableAddress, PageTableEntry, PageTableLevel, PageTableMemory, PteFlag, TransAddrError,
+    destroy_sub_tables, PageTableAddress, PageTableEntry, PageTableLevel, PteFlag, TransAddrError,
 };
 use crate::h_extension::csrs::hgatp;
+use crate::memmap::page_allocator::PAGE_ALLOCATOR;
 use crate::memmap::{GuestPhysicalAddress, HostPhysicalAddress, MemoryMap};
 
-use alloc::boxed::Box;
 use core::slice::from_raw_parts_mut;
 
 /// First page table size
 pub const FIRST_LV_PAGE_TABLE_LEN: usize = 2048;
 
+/// Size of root page table in bytes. (Sv39x4 root page table is 16 KiB)
+const ROOT_PAGE_TABLE_SIZE: usize = 16 * 1024;
+
+// the root page table must cover whole 16 KiB region that `hgatp.PPN` points.
+const _: () = assert!(
+    FIRST_LV_PAGE_TABLE_LEN * core::mem::size_of::<PageTableEntry>() == ROOT_PAGE_TABLE_SIZE
+);
+
 /// Device tree blob that is passed to guest
 #[link_section = ".root_page_table"]
 pub static ROOT_PAGE_TABLE: [PageTableEntry; FIRST_LV_PAGE_TABLE_LEN] =
@@ -79,7 +87,7 @@ pub fn initialize_page_table(root_table_start_addr: HostPhysicalAddress) {
 pub fn generate_page_table(root_table_start_addr: HostPhysicalAddress, memmaps: &[MemoryMap]) {
     use crate::memmap::AddressRangeUtil;
 
-    assert!(root_table_start_addr % (16 * 1024) == 0); // root_table_start_addr must be aligned 16 KiB
+    assert!(root_table_start_addr % ROOT_PAGE_TABLE_SIZE == 0); // root_table_start_addr must be aligned 16 KiB
 
     let first_lv_page_table: &mut [PageTableEntry] = unsafe {
         from_raw_parts_mut(
@@ -88,17 +96,12 @@ pub fn generate_page_table(root_table_start_addr: HostPhysicalAddress, memmaps:
         )
     };
 
+    // `memmaps` are already validated by `MemoryMapBuilder::build`,
+    // but `phys` is public and may be rewritten after that.
     for memmap in memmaps {
-        assert!(memmap.virt.len() == memmap.phys.len());
-
         // decide page level from memory range
-        let trans_page_level = match memmap.virt.len() {
-            0x0..=0x001f_ffff => PageTableLevel::Lv4KB,
-            0x0020_0000..=0x3fff_ffff => PageTableLevel::Lv2MB,
-            0x4000_0000..=usize::MAX => PageTableLevel::Lv1GB,
-            _ => unreachable!(),
-        };
-
+        let trans_page_level = PageTableLevel::from_map_len(memmap.virt.len());
+        // superpage must be aligned in both address spaces. (ppn[0] of 2MB page must be 0)
         assert!(memmap.virt.start % trans_page_level.size() == 0);
         assert!(memmap.phys.start % trans_page_level.size() == 0);
 
@@ -135,10 +138,8 @@ pub fn generate_page_table(root_table_start_addr: HostPhysicalAddress, memmaps:
                         usize::try_from(current_page_table[vpn].entire_ppn()).unwrap() * PAGE_SIZE,
                     )
                 } else {
-                    let next_page_table =
-                        Box::new(PageTableMemory([PageTableEntry::default(); PAGE_TABLE_LEN]));
-                    let next_page_table_addr: PageTableAddress =
-                        Box::into_raw(next_page_table).into();
+                    let next_page_table_addr =
+                        PageTableAddress(PAGE_ALLOCATOR.lock().alloc_zeroed().raw());
 
                     current_page_table[vpn] = PageTableEntry::new(
                         next_page_table_addr.page_number(),
@@ -152,6 +153,17 @@ pub fn generate_page_table(root_table_start_addr: HostPhysicalAddress, memmaps:
     }
 }
 
+/// Free all page tables under the root page table. (Sv39x4)
+///
+/// The root page table itself is not freed but zero filled.
+/// Leaf pages are not freed since they are owned by the mappers. (e.g. guest memory, MMIO)
+pub fn destroy_page_table(root_table_start_addr: HostPhysicalAddress) {
+    destroy_sub_tables(
+        PageTableAddress(root_table_start_addr.raw()),
+        FIRST_LV_PAGE_TABLE_LEN,
+    );
+}
+
 /// Translate gpa to hpa in sv39x4
 #[allow(clippy::cast_possible_truncation)]
 pub fn trans_addr(
@@ -177,7 +189,10 @@ pub fn trans_addr(
         let pte = page_table[gpa.vpn(level as usize)];
         if pte.is_invalid() {
             return Err((
-                TransAddrError::InvalidEntry,
+                TransAddrError::InvalidEntry {
+                    addr: gpa.raw(),
+                    level: level as usize,
+                },
                 "Address translation failed: invalid pte",
             ));
         }
@@ -188,13 +203,19 @@ pub fn trans_addr(
                 PageTableLevel::Lv1GB => {
                     if pte.ppn(1) != 0 {
                         return Err((
-                            TransAddrError::InvalidEntry,
+                            TransAddrError::InvalidEntry {
+                                addr: gpa.raw(),
+                                level: level as usize,
+                            },
                             "Address translation failed: pte.ppn[1] != 0",
                         ));
                     }
                     if pte.ppn(0) != 0 {
                         return Err((
-                            TransAddrError::InvalidEntry,
+                            TransAddrError::InvalidEntry {
+                                addr: gpa.raw(),
+                                level: level as usize,
+                            },
                             "Address translation failed: pte.ppn[0] != 0",
                         ));
                     }
@@ -209,7 +230,10 @@ pub fn trans_addr(
                 PageTableLevel::Lv2MB => {
                     if pte.ppn(0) != 0 {
                         return Err((
-                            TransAddrError::InvalidEntry,
+                            TransAddrError::InvalidEntry {
+                                addr: gpa.raw(),
+                                level: level as usize,
+                            },
                             "Address translation failed: pte.ppn[0] != 0",
                         ));
                     }
@@ -236,7 +260,7 @@ pub fn trans_addr(
     }
 
     Err((
-        TransAddrError::NoLeafEntry,
+        TransAddrError::NoLeafEntry { addr: gpa.raw() },
         "[sv39x4] cannnot reach to leaf entry",
     ))
 }
@@ -0,0 +1,213 @@
+//! Sv48x4: Page-Based 48-bit Virtual-Memory System **in G-stage**.  
+//! For guest physical address translation.
+//!
+//! [The RISC-V Instruction Set Manual: Volume II Version 20240411](https://github.com/riscv/riscv-isa-manual/releases/download/20240411/priv-isa-asciidoc.pdf) p.151
+
+use super::{
+    constants::{PAGE_SIZE, PAGE_TABLE_LEN},
+    destroy_sub_tables, PageTableAddress, PageTableEntry, PageTableLevel, PteFlag, TransAddrError,
+};
+use crate::h_extension::csrs::hgatp;
+use crate::memmap::page_allocator::PAGE_ALLOCATOR;
+use crate::memmap::{GuestPhysicalAddress, HostPhysicalAddress, MemoryMap};
+
+use core::slice::from_raw_parts_mut;
+
+/// First page table size
+///
+/// vpn\[3\] is widened by 2 bits (11 bit) as well as Sv39x4. (16 KiB root page table)
+pub const FIRST_LV_PAGE_TABLE_LEN: usize = 2048;
+
+/// Pte field for Sv48x4
+trait PteFieldSv48x4 {
+    /// Return entire ppn field
+    fn ppn(self, index: usize) -> usize;
+}
+
+impl PteFieldSv48x4 for PageTableEntry {
+    /// Return ppn
+    #[allow(clippy::cast_possible_truncation)]
+    fn ppn(self, index: usize) -> usize {
+        match index {
+            3 => (self.0 as usize >> 37) & 0x1_ffff, // 17 bit
+            2 => (self.0 as usize >> 28) & 0x1ff,    // 9 bit
+            1 => (self.0 as usize >> 19) & 0x1ff,    // 9 bit
+            0 => (self.0 as usize >> 10) & 0x1ff,    // 9 bit
+            _ => unreachable!(),
+        }
+    }
+}
+
+/// Virtual address field for Sv48x4
+trait AddressFieldSv48x4 {
+    /// Return virtual page number
+    fn vpn(self, index: usize) -> usize;
+}
+
+impl AddressFieldSv48x4 for GuestPhysicalAddress {
+    /// Return vpn value with index.
+    fn vpn(self, index: usize) -> usize {
+        match index {
+            3 => (self.0 >> 39) & 0x7ff,
+            2 => (self.0 >> 30) & 0x1ff,
+            1 => (self.0 >> 21) & 0x1ff,
+            0 => (self.0 >> 12) & 0x1ff,
+            _ => unreachable!(),
+        }
+    }
+}
+
+/// Zero filling root page table
+pub fn initialize_page_table(root_table_start_addr: HostPhysicalAddress) {
+    let first_lv_page_table: &mut [PageTableEntry] = unsafe {
+        from_raw_parts_mut(
+            root_table_start_addr.raw() as *mut PageTableEntry,
+            FIRST_LV_PAGE_TABLE_LEN,
+        )
+    };
+
+    // zero filling page table
+    first_lv_page_table.fill(PageTableEntry(0));
+}
+
+/// Generate fourth-level page table. (Sv48x4)
+///
+/// The number of address translation stages is determined by the size of the range.
+#[allow(clippy::module_name_repetitions)]
+pub fn generate_page_table(root_table_start_addr: HostPhysicalAddress, memmaps: &[MemoryMap]) {
+    use crate::memmap::AddressRangeUtil;
+
+    assert!(root_table_start_addr % (16 * 1024) == 0); // root_table_start_addr must be aligned 16 KiB
+
+    let first_lv_page_table: &mut [PageTableEntry] = unsafe {
+        from_raw_parts_mut(
+            root_table_start_addr.raw() as *mut PageTableEntry,
+            FIRST_LV_PAGE_TABLE_LEN,
+        )
+    };
+
+    // `memmaps` are already validated by `MemoryMapBuilder::build`.
+    for memmap in memmaps {
+        // decide page level from memory range
+        let trans_page_level = PageTableLevel::from_map_len(memmap.virt.len());
+
+        for offset in (0..memmap.virt.len()).step_by(trans_page_level.size()) {
+            let v_start = memmap.virt.start + offset;
+            let p_start = memmap.phys.start + offset;
+
+            let mut next_table_addr: PageTableAddress = PageTableAddress(0);
+            for current_level in [
+                PageTableLevel::Lv512GB,
+                PageTableLevel::Lv1GB,
+                PageTableLevel::Lv2MB,
+                PageTableLevel::Lv4KB,
+            ] {
+                let vpn = v_start.vpn(current_level as usize);
+                let current_page_table = match current_level {
+                    PageTableLevel::Lv256TB => unreachable!(),
+                    PageTableLevel::Lv512GB => &mut *first_lv_page_table,
+                    PageTableLevel::Lv1GB | PageTableLevel::Lv2MB | PageTableLevel::Lv4KB => unsafe {
+                        from_raw_parts_mut(next_table_addr.to_pte_ptr(), PAGE_TABLE_LEN)
+                    },
+                };
+
+                // End of translation
+                if current_level == trans_page_level {
+                    current_page_table[vpn] =
+                        PageTableEntry::new(p_start.page_number(), memmap.flags);
+
+                    break;
+                }
+
+                // Create next level page table
+                next_table_addr = if current_page_table[vpn].already_created() {
+                    PageTableAddress(
+                        usize::try_from(current_page_table[vpn].entire_ppn()).unwrap() * PAGE_SIZE,
+                    )
+                } else {
+                    let next_page_table_addr =
+                        PageTableAddress(PAGE_ALLOCATOR.lock().alloc_zeroed().raw());
+
+                    current_page_table[vpn] = PageTableEntry::new(
+                        next_page_table_addr.page_number(),
+                        PteFlag::Valid as u8,
+                    );
+
+                    next_page_table_addr
+                };
+            }
+        }
+    }
+}
+
+/// Free all page tables under the root page table. (Sv48x4)
+///
+/// The root page table itself is not freed but zero filled.
+/// Leaf pages are not freed since they are owned by the mappers. (e.g. guest memory, MMIO)
+pub fn destroy_page_table(root_table_start_addr: HostPhysicalAddress) {
+    destroy_sub_tables(
+        PageTableAddress(root_table_start_addr.raw()),
+        FIRST_LV_PAGE_TABLE_LEN,
+    );
+}
+
+/// Translate gpa to hpa in sv48x4
+#[allow(clippy::cast_possible_truncation)]
+pub fn trans_addr(
+    gpa: GuestPhysicalAddress,
+) -> Result<HostPhysicalAddress, (TransAddrError, &'static str)> {
+    let hgatp = hgatp::read();
+    let mut page_table_addr = PageTableAddress(hgatp.ppn() << 12);
+    assert!(matches!(hgatp.mode(), hgatp::Mode::Sv48x4));
+    for level in [
+        PageTableLevel::Lv512GB,
+        PageTableLevel::Lv1GB,
+        PageTableLevel::Lv2MB,
+        PageTableLevel::Lv4KB,
+    ] {
+        let page_table = match level {
+            PageTableLevel::Lv256TB => unreachable!(),
+            PageTableLevel::Lv512GB => unsafe {
+                from_raw_parts_mut(page_table_addr.to_pte_ptr(), FIRST_LV_PAGE_TABLE_LEN)
+            },
+            PageTableLevel::Lv1GB | PageTableLevel::Lv2MB | PageTableLevel::Lv4KB => unsafe {
+                from_raw_parts_mut(page_table_addr.to_pte_ptr(), PAGE_TABLE_LEN)
+            },
+        };
+        let pte = page_table[gpa.vpn(level as usize)];
+        if pte.is_invalid() {
+            return Err((
+                TransAddrError::InvalidEntry {
+                    addr: gpa.raw(),
+                    level: level as usize,
+                },
+                "Address translation failed: invalid pte",
+            ));
+        }
+
+        if pte.is_leaf() {
+            // lower ppn fields of superpage must be zero.
+            if (0..level as usize).any(|index| pte.ppn(index) != 0) {
+                return Err((
+                    TransAddrError::InvalidEntry {
+                        addr: gpa.raw(),
+                        level: level as usize,
+                    },
+                    "Address translation failed: misaligned superpage",
+                ));
+            }
+
+            let page_offset_mask = level.size() - 1;
+            return Ok(HostPhysicalAddress(
+                (pte.entire_ppn() as usize * PAGE_SIZE) | (gpa.raw() & page_offset_mask),
+            ));
+        }
+
+        page_table_addr = PageTableAddress(pte.entire_ppn() as usize * PAGE_SIZE);
+    }
+
+    Err((
+        TransAddrError::NoLeafEntry { addr: gpa.raw() },
+        "[sv48x4] cannnot reach to leaf entry",
+    ))
+}
@@ -67,11 +67,14 @@ pub fn trans_addr(
         PageTableLevel::Lv4KB,
     ] {
         let page_table =
-            unsafe { from_raw_parts_mut(page_table_addr.to_host_physical_ptr(), PAGE_TABLE_LEN) };
+            unsafe { from_raw_parts_mut(page_table_addr.to_host_physical_ptr()?, PAGE_TABLE_LEN) };
         let pte = page_table[gva.vpn(level as usize)];
         if pte.is_invalid() {
             return Err((
-                TransAddrError::InvalidEntry,
+                TransAddrError::InvalidEntry {
+                    addr: gva.raw(),
+                    level: level as usize,
+                },
                 "Address translation failed: invalid pte",
             ));
         }
@@ -81,25 +84,37 @@ pub fn trans_addr(
                 PageTableLevel::Lv256TB => {
                     if pte.ppn(0) != 0 {
                         return Err((
-                            TransAddrError::InvalidEntry,
+                            TransAddrError::InvalidEntry {
+                                addr: gva.raw(),
+                                level: level as usize,
+                            },
                             "Address translation failed: pte.ppn[0] != 0",
                         ));
                     }
                     if pte.ppn(1) != 0 {
                         return Err((
-                            TransAddrError::InvalidEntry,
+                            TransAddrError::InvalidEntry {
+                                addr: gva.raw(),
+                                level: level as usize,
+                            },
                             "Address translation failed: pte.ppn[1] != 0",
                         ));
                     }
                     if pte.ppn(2) != 0 {
                         return Err((
-                            TransAddrError::InvalidEntry,
+                            TransAddrError::InvalidEntry {
+                                addr: gva.raw(),
+                                level: level as usize,
+                            },
                             "Address translation failed: pte.ppn[2] != 0",
                         ));
                     }
                     if pte.ppn(3) != 0 {
                         return Err((
-                            TransAddrError::InvalidEntry,
+                            TransAddrError::InvalidEntry {
+                                addr: gva.raw(),
+                                level: level as usize,
+                            },
                             "Address translation failed: pte.ppn[3] != 0",
                         ));
                     }
@@ -114,18 +129,33 @@ pub fn trans_addr(
                     ));
                 }
                 PageTableLevel::Lv512GB => {
-                    assert!(
-                        pte.ppn(2) == 0,
-                        "Address translation failed: pte.ppn[2] != 0"
-                    );
-                    assert!(
-                        pte.ppn(1) == 0,
-                        "Address translation failed: pte.ppn[1] != 0"
-                    );
-                    assert!(
-                        pte.ppn(0) == 0,
-                        "Address translation failed: pte.ppn[0] != 0"
-                    );
+                    if pte.ppn(2) != 0 {
+                        return Err((
+                            TransAddrError::InvalidEntry {
+                                addr: gva.raw(),
+                                level: level as usize,
+                            },
+                            "Address translation failed: pte.ppn[2] != 0",
+                        ));
+                    }
+                    if pte.ppn(1) != 0 {
+                        return Err((
+                            TransAddrError::InvalidEntry {
+                                addr: gva.raw(),
+                                level: level as usize,
+                            },
+                            "Address translation failed: pte.ppn[1] != 0",
+                        ));
+                    }
+                    if pte.ppn(0) != 0 {
+                        return Err((
+                            TransAddrError::InvalidEntry {
+                                addr: gva.raw(),
+                                level: level as usize,
+                            },
+                            "Address translation failed: pte.ppn[0] != 0",
+                        ));
+                    }
                     return Ok(GuestPhysicalAddress(
                         (pte.ppn(4) << 48)
                             | (pte.ppn(3) << 39)
@@ -138,13 +168,19 @@ pub fn trans_addr(
                 PageTableLevel::Lv1GB => {
                     if pte.ppn(1) != 0 {
                         return Err((
-                            TransAddrError::InvalidEntry,
+                            TransAddrError::InvalidEntry {
+                                addr: gva.raw(),
+                                level: level as usize,
+                            },
                             "Address translation failed: pte.ppn[1] != 0",
                         ));
                     }
                     if pte.ppn(0) != 0 {
                         return Err((
-                            TransAddrError::InvalidEntry,
+                            TransAddrError::InvalidEntry {
+                                addr: gva.raw(),
+                                level: level as usize,
+                            },
                             "Address translation failed: pte.ppn[0] != 0",
                         ));
                     }
@@ -161,7 +197,10 @@ pub fn trans_addr(
                 PageTableLevel::Lv2MB => {
                     if pte.ppn(0) != 0 {
                         return Err((
-                            TransAddrError::InvalidEntry,
+                            TransAddrError::InvalidEntry {
+                                addr: gva.raw(),
+                                level: level as usize,
+                            },
                             "Address translation failed: pte.ppn[0] != 0",
                         ));
                     }
@@ -191,7 +230,7 @@ pub fn trans_addr(
     }
 
     Err((
-        TransAddrError::NoLeafEntry,
+        TransAddrError::NoLeafEntry { addr: gva.raw() },
         "[sv57] cannnot reach to leaf entry",
     ))
 }
@@ -0,0 +1,83 @@
+//! Boot-time self test of memory map validation and overlap detection. (see `overlaps`)
+
+use super::{
+    overlaps, GuestPhysicalAddress, HostPhysicalAddress, MemoryMap, MemoryMapBuilder,
+    MemoryMapError,
+};
+use crate::memmap::page_table::PteFlag;
+
+/// Pairs of ranges and whether they overlap. (start A, size A, start B, size B, overlap)
+const CASES: [(usize, usize, usize, usize, bool); 5] = [
+    // adjacent
+    (0x8000_0000, 0x1000, 0x8000_1000, 0x1000, false),
+    // nested
+    (0x8000_0000, 0x4000, 0x8000_1000, 0x1000, true),
+    // identical
+    (0x8000_0000, 0x2000, 0x8000_0000, 0x2000, true),
+    // disjoint
+    (0x8000_0000, 0x1000, 0x9000_0000, 0x1000, false),
+    // partially overlapping
+    (0x8000_0000, 0x2000, 0x8000_1000, 0x2000, true),
+];
+
+/// Return memory map of `size` bytes from `start`. (identity mapped)
+fn identity_map(start: usize, size: usize) -> MemoryMap {
+    MemoryMap::new(
+        GuestPhysicalAddress(start)..GuestPhysicalAddress(start + size),
+        HostPhysicalAddress(start)..HostPhysicalAddress(start + size),
+        &[PteFlag::Read, PteFlag::Valid],
+    )
+}
+
+/// Check `overlaps` in both argument orders.
+///
+/// # Panics
+/// It panics with the offending ranges if the result is wrong.
+pub fn overlap_detection() {
+    for (a_start, a_size, b_start, b_size, expected) in CASES {
+        let a = identity_map(a_start, a_size);
+        let b = identity_map(b_start, b_size);
+        assert!(
+            overlaps(&a, &b) == expected && overlaps(&b, &a) == expected,
+            "[selftest] overlaps of {:#x}..{:#x} and {:#x}..{:#x} is not {}",
+            a_start,
+            a_start + a_size,
+            b_start,
+            b_start + b_size,
+            expected
+        );
+    }
+}
+
+/// Check that `MemoryMapBuilder::build` rejects ranges that are not page aligned.
+///
+/// # Panics
+/// It panics if an unaligned range is accepted.
+pub fn misaligned_range() {
+    let build = |start: usize, size: usize| {
+        MemoryMapBuilder::new()
+            .virt_range(GuestPhysicalAddress(start)..GuestPhysicalAddress(start + size))
+            .phys_range(HostPhysicalAddress(start)..HostPhysicalAddress(start + size))
+            .flags(&[PteFlag::Read, PteFlag::Valid])
+            .build()
+    };
+
+    assert!(
+        matches!(
+            build(0x8000_0000, 0x1100),
+            Err(MemoryMapError::MisalignedEnd)
+        ),
+        "[selftest] unaligned end is accepted"
+    );
+    assert!(
+        matches!(
+            build(0x8000_0100, 0x1000),
+            Err(MemoryMapError::MisalignedStart)
+        ),
+        "[selftest] unaligned start is accepted"
+    );
+    assert!(
+        build(0x8000_0000, 0x1000).is_ok(),
+        "[selftest] aligned range is rejected"
+    );
+}
@@ -0,0 +1,345 @@
+//! Hypervisor statistics.
+//!
+//! Counters are kept per HART and exposed to the guest through the vendor SBI extension.
+//! (see `sbi_handler::sbi_stats_handler`)
+//!
+//! # Counter id
+//! | id                      | counter                                         |
+//! |-------------------------|-------------------------------------------------|
+//! | `0x000 + code`          | traps by exception code                         |
+//! | `0x040 + code`          | traps by interrupt code                         |
+//! | `0x080 + MmioDevice`    | MMIO emulation hits per device                  |
+//! | `0x0c0`                 | stolen time (ticks spent in hypervisor)         |
+//! | `0x0c1`                 | heap high-water mark in bytes (shared by HARTs) |
+//! | `0x0c2`                 | instructions emulated on illegal instruction    |
+//! | `0x0c3`                 | exceptions forwarded to the guest               |
+//! | `0x1_0000_0000 \| EID`  | SBI calls by EID                                |
+
+use crate::device::DeviceEmulateError;
+use crate::memmap::constant::MAX_HART_NUM;
+use crate::{current_hart_id, println};
+
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use riscv::register::time;
+
+/// Max number of trap cause code that is counted.
+const TRAP_CAUSE_NUM: usize = 64;
+/// Max number of distinct SBI extensions that is counted per HART.
+const SBI_EID_SLOT_NUM: usize = 16;
+/// Empty SBI EID slot.
+const SBI_EID_EMPTY: usize = usize::MAX;
+
+/// Counter id base of traps by exception code.
+const COUNTER_EXCEPTION_BASE: usize = 0x000;
+/// Counter id base of traps by interrupt code.
+const COUNTER_INTERRUPT_BASE: usize = 0x040;
+/// Counter id base of MMIO emulation hits.
+const COUNTER_MMIO_BASE: usize = 0x080;
+/// Counter id of stolen time.
+const COUNTER_STOLEN_TIME: usize = 0x0c0;
+/// Counter id of heap high-water mark.
+const COUNTER_HEAP_HIGH_WATER: usize = 0x0c1;
+/// Counter id of emulated instructions.
+const COUNTER_EMULATED_INST: usize = 0x0c2;
+/// Counter id of exceptions forwarded to the guest.
+const COUNTER_FORWARDED_EXCEPTION: usize = 0x0c3;
+/// Counter id flag of SBI calls. (lower 32 bits are EID)
+const COUNTER_SBI_CALL_FLAG: usize = 0x1_0000_0000;
+
+/// Emulated MMIO devices.
+#[derive(Debug, Clone, Copy)]
+pub enum MmioDevice {
+    /// Platform-Level Interrupt Controller
+    Plic = 0,
+    /// Serial ATA
+    Sata,
+    /// AXI SD card
+    Mmc,
+    /// Real time clock
+    Rtc,
+    /// Hypervisor owned Virt IO
+    VirtIo,
+    /// PCI devices that are not emulated
+    UnknownPci,
+}
+
+impl MmioDevice {
+    /// Number of devices.
+    const NUM: usize = 6;
+    /// All devices in counter id order.
+    const ALL: [MmioDevice; Self::NUM] = [
+        Self::Plic,
+        Self::Sata,
+        Self::Mmc,
+        Self::Rtc,
+        Self::VirtIo,
+        Self::UnknownPci,
+    ];
+
+    /// Return device name.
+    fn name(self) -> &'static str {
+        match self {
+            Self::Plic => "plic",
+            Self::Sata => "sata",
+            Self::Mmc => "mmc",
+            Self::Rtc => "rtc",
+            Self::VirtIo => "virtio",
+            Self::UnknownPci => "unknown pci",
+        }
+    }
+}
+
+/// Counters of each HART.
+struct HartStats {
+    /// Traps by exception code.
+    exceptions: [AtomicU64; TRAP_CAUSE_NUM],
+    /// Traps by interrupt code.
+    interrupts: [AtomicU64; TRAP_CAUSE_NUM],
+    /// MMIO emulation hits per device.
+    mmio: [AtomicU64; MmioDevice::NUM],
+    /// EIDs of `sbi_calls`.
+    sbi_eids: [AtomicUsize; SBI_EID_SLOT_NUM],
+    /// SBI calls by EID.
+    sbi_calls: [AtomicU64; SBI_EID_SLOT_NUM],
+    /// Ticks spent in hypervisor.
+    stolen_time: AtomicU64,
+    /// Instructions emulated on illegal instruction exception.
+    emulated_insts: AtomicU64,
+    /// Exceptions forwarded (or injected) to the guest.
+    forwarded_exceptions: AtomicU64,
+    /// Time of the current trap entry. (0 if not in trap)
+    trap_entry_time: AtomicU64,
+}
+
+impl HartStats {
+    /// Constructor for `HartStats`.
+    const fn new() -> Self {
+        HartStats {
+            exceptions: [const { AtomicU64::new(0) }; TRAP_CAUSE_NUM],
+            interrupts: [const { AtomicU64::new(0) }; TRAP_CAUSE_NUM],
+            mmio: [const { AtomicU64::new(0) }; MmioDevice::NUM],
+            sbi_eids: [const { AtomicUsize::new(SBI_EID_EMPTY) }; SBI_EID_SLOT_NUM],
+            sbi_calls: [const { AtomicU64::new(0) }; SBI_EID_SLOT_NUM],
+            stolen_time: AtomicU64::new(0),
+            emulated_insts: AtomicU64::new(0),
+            forwarded_exceptions: AtomicU64::new(0),
+            trap_entry_time: AtomicU64::new(0),
+        }
+    }
+
+    /// Return counter of SBI calls. (allocate the slot if `alloc` is true)
+    fn sbi_call_counter(&self, eid: usize, alloc: bool) -> Option<&AtomicU64> {
+        self.sbi_eids
+            .iter()
+            .zip(&self.sbi_calls)
+            .find_map(|(slot_eid, count)| {
+                let current = slot_eid.load(Ordering::Relaxed);
+                if current == eid
+                    || (alloc
+                        && current == SBI_EID_EMPTY
+                        && slot_eid
+                            .compare_exchange(
+                                SBI_EID_EMPTY,
+                                eid,
+                                Ordering::Relaxed,
+                                Ordering::Relaxed,
+                            )
+                            .is_ok())
+                {
+                    Some(count)
+                } else {
+                    None
+                }
+            })
+    }
+
+    /// Return counter corresponding to the counter id.
+    fn counter(&self, counter_id: usize) -> Option<&AtomicU64> {
+        match counter_id {
+            id if id & COUNTER_SBI_CALL_FLAG != 0 => {
+                self.sbi_call_counter(id & !COUNTER_SBI_CALL_FLAG, false)
+            }
+            COUNTER_EXCEPTION_BASE..COUNTER_INTERRUPT_BASE => {
+                self.exceptions.get(counter_id - COUNTER_EXCEPTION_BASE)
+            }
+            COUNTER_INTERRUPT_BASE..COUNTER_MMIO_BASE => {
+                self.interrupts.get(counter_id - COUNTER_INTERRUPT_BASE)
+            }
+            COUNTER_MMIO_BASE..COUNTER_STOLEN_TIME => self.mmio.get(counter_id - COUNTER_MMIO_BASE),
+            COUNTER_STOLEN_TIME => Some(&self.stolen_time),
+            COUNTER_EMULATED_INST => Some(&self.emulated_insts),
+            COUNTER_FORWARDED_EXCEPTION => Some(&self.forwarded_exceptions),
+            _ => None,
+        }
+    }
+
+    /// Clear all counters.
+    fn reset(&self) {
+        self.exceptions
+            .iter()
+            .chain(&self.interrupts)
+            .chain(&self.mmio)
+            .chain(&self.sbi_calls)
+            .chain([
+                &self.stolen_time,
+                &self.emulated_insts,
+                &self.forwarded_exceptions,
+            ])
+            .for_each(|counter| counter.store(0, Ordering::Relaxed));
+        self.sbi_eids
+            .iter()
+            .for_each(|eid| eid.store(SBI_EID_EMPTY, Ordering::Relaxed));
+    }
+}
+
+/// Statistics of each HART.
+static HART_STATS: [HartStats; MAX_HART_NUM] = [const { HartStats::new() }; MAX_HART_NUM];
+
+/// Heap high-water mark in bytes.
+static HEAP_HIGH_WATER: AtomicUsize = AtomicUsize::new(0);
+
+/// Return statistics of current HART.
+fn hart_stats() -> &'static HartStats {
+    &HART_STATS[current_hart_id()]
+}
+
+/// Record trap entry.
+fn record_trap_entry() {
+    hart_stats()
+        .trap_entry_time
+        .store(time::read() as u64, Ordering::Relaxed);
+}
+
+/// Record exception trap.
+pub fn record_exception(code: usize) {
+    if let Some(counter) = hart_stats().exceptions.get(code) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+    record_trap_entry();
+}
+
+/// Record interrupt trap.
+pub fn record_interrupt(code: usize) {
+    if let Some(counter) = hart_stats().interrupts.get(code) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+    record_trap_entry();
+}
+
+/// Record returning to guest and accumulate stolen time.
+pub fn record_trap_exit() {
+    let stats = hart_stats();
+    let entry_time = stats.trap_entry_time.swap(0, Ordering::Relaxed);
+    if entry_time != 0 {
+        stats.stolen_time.fetch_add(
+            (time::read() as u64).wrapping_sub(entry_time),
+            Ordering::Relaxed,
+        );
+    }
+}
+
+/// Record SBI call from guest.
+pub fn record_sbi_call(eid: usize) {
+    if let Some(counter) = hart_stats().sbi_call_counter(eid, true) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Record instruction emulated on illegal instruction exception.
+pub fn record_emulated_instruction() {
+    hart_stats().emulated_insts.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Record exception forwarded (or injected) to the guest.
+pub fn record_forwarded_exception() {
+    hart_stats()
+        .forwarded_exceptions
+        .fetch_add(1, Ordering::Relaxed);
+}
+
+/// Record MMIO emulation hit if the device handled the access.
+pub fn record_mmio<T>(
+    device: MmioDevice,
+    result: Result<T, DeviceEmulateError>,
+) -> Result<T, DeviceEmulateError> {
+    if !matches!(result, Err(DeviceEmulateError::InvalidAddress)) {
+        hart_stats().mmio[device as usize].fetch_add(1, Ordering::Relaxed);
+    }
+    result
+}
+
+/// Record current heap usage.
+pub fn record_heap_usage(used: usize) {
+    HEAP_HIGH_WATER.fetch_max(used, Ordering::Relaxed);
+}
+
+/// Return value of the counter.
+///
+/// Return `None` if the HART id or the counter id is invalid.
+pub fn read_counter(hart_id: usize, counter_id: usize) -> Option<u64> {
+    if counter_id == COUNTER_HEAP_HIGH_WATER {
+        return Some(HEAP_HIGH_WATER.load(Ordering::Relaxed) as u64);
+    }
+
+    HART_STATS
+        .get(hart_id)?
+        .counter(counter_id)
+        .map(|counter| counter.load(Ordering::Relaxed))
+}
+
+/// Clear all counters of all HARTs.
+pub fn reset() {
+    HART_STATS.iter().for_each(HartStats::reset);
+    HEAP_HIGH_WATER.store(0, Ordering::Relaxed);
+}
+
+/// Print non-zero counters.
+pub fn print_summary() {
+    println!("==================== hypervisor statistics ====================");
+    println!(
+        "heap high-water mark: {:#x} bytes",
+        HEAP_HIGH_WATER.load(Ordering::Relaxed)
+    );
+    for (hart_id, stats) in HART_STATS.iter().enumerate() {
+        let stolen_time = stats.stolen_time.load(Ordering::Relaxed);
+        // the HART has never trapped.
+        if stolen_time == 0 {
+            continue;
+        }
+
+        println!("[hart {}] stolen time: {} ticks", hart_id, stolen_time);
+        println!(
+            "  emulated inst : {}",
+            stats.emulated_insts.load(Ordering::Relaxed)
+        );
+        println!(
+            "  forwarded exc : {}",
+            stats.forwarded_exceptions.load(Ordering::Relaxed)
+        );
+        for (code, count) in stats.exceptions.iter().enumerate() {
+            let count = count.load(Ordering::Relaxed);
+            if count != 0 {
+                println!("  exception {:>2}  : {}", code, count);
+            }
+        }
+        for (code, count) in stats.interrupts.iter().enumerate() {
+            let count = count.load(Ordering::Relaxed);
+            if count != 0 {
+                println!("  interrupt {:>2}  : {}", code, count);
+            }
+        }
+        for device in MmioDevice::ALL {
+            let count = stats.mmio[device as usize].load(Ordering::Relaxed);
+            if count != 0 {
+                println!("  mmio {:<9} : {}", device.name(), count);
+            }
+        }
+        for (eid, count) in stats.sbi_eids.iter().zip(&stats.sbi_calls) {
+            let eid = eid.load(Ordering::Relaxed);
+            if eid != SBI_EID_EMPTY {
+                println!("  sbi {:#010x} : {}", eid, count.load(Ordering::Relaxed));
+            }
+        }
+    }
+    println!("===============================================================");
+}
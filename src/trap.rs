@@ -3,23 +3,152 @@
 mod exception;
 mod interrupt;
 
-use crate::guest::context::ContextData;
+use crate::guest::context::{pmu_context, ContextData};
 use exception::trap_exception;
 use interrupt::trap_interrupt;
 
-use crate::HYPERVISOR_DATA;
+use crate::h_extension::csrs::{htinst, htval};
+use crate::memmap::constant::{MAX_HART_NUM, STACK_SIZE_PER_HART};
+use crate::memmap::HostPhysicalAddress;
+use crate::{_stack_start, current_hart_id, println};
 use core::arch::asm;
+use core::sync::atomic::{AtomicUsize, Ordering};
+#[cfg(feature = "vectored_trap")]
+use riscv::register::scause::Interrupt;
 use riscv::register::scause::{self, Trap};
+use riscv::register::{sepc, stval};
+
+/// HS-mode stack top of each HART.
+///
+/// It is written once in `vsmode_setup` so that returning to guest does not need to lock `HART_DATA`.
+static HS_STACK_TOP: [AtomicUsize; MAX_HART_NUM] = [const { AtomicUsize::new(0) }; MAX_HART_NUM];
+
+/// Size of guard region at the bottom of HS-mode stack.
+const STACK_GUARD_SIZE: usize = 0x100;
+/// Value filled in the stack guard region.
+const STACK_GUARD_MAGIC: u64 = u64::from_le_bytes(*b"HKMIGARD");
+
+/// Size of emergency stack of each HART. (must be power of 2)
+const EMERGENCY_STACK_SIZE: usize = 0x2000;
+
+/// Stack to report nested HS trap.
+#[repr(C, align(16))]
+struct EmergencyStack([u8; EMERGENCY_STACK_SIZE]);
+
+/// Emergency stack of each HART.
+///
+/// HS-mode stack is not reused because it may be the cause of the nested trap.
+static mut EMERGENCY_STACKS: [EmergencyStack; MAX_HART_NUM] =
+    [const { EmergencyStack([0; EMERGENCY_STACK_SIZE]) }; MAX_HART_NUM];
+
+/// Set HS-mode stack top of current HART and fill the stack guard region.
+pub fn set_hs_stack_top(stack_top: HostPhysicalAddress) {
+    HS_STACK_TOP[current_hart_id()].store(stack_top.raw(), Ordering::Relaxed);
+    stack_guard(stack_top).fill(STACK_GUARD_MAGIC);
+}
+
+/// Return guard region at the bottom of HS-mode stack.
+///
+/// HS-mode runs without address translation, so the guard cannot be an unmapped page.
+/// Instead, the region is filled with `STACK_GUARD_MAGIC` and checked before returning to guest.
+fn stack_guard(stack_top: HostPhysicalAddress) -> &'static mut [u64] {
+    let stack_bottom = stack_top - STACK_SIZE_PER_HART;
+    unsafe {
+        core::slice::from_raw_parts_mut(
+            stack_bottom.raw() as *mut u64,
+            STACK_GUARD_SIZE / size_of::<u64>(),
+        )
+    }
+}
+
+/// Check the stack guard region of current HART.
+///
+/// # Panics
+/// It panics if the guard region is overwritten. (the stack overflowed into the next HART's stack)
+fn check_stack_guard(stack_top: HostPhysicalAddress) {
+    assert!(
+        stack_guard(stack_top)
+            .iter()
+            .all(|&word| word == STACK_GUARD_MAGIC),
+        "stack overflow detected on hart {}",
+        current_hart_id()
+    );
+}
+
+/// Return HS-mode stack top of current HART.
+pub fn hs_stack_top() -> HostPhysicalAddress {
+    let stack_top = HS_STACK_TOP[current_hart_id()].load(Ordering::Relaxed);
+    debug_assert_ne!(stack_top, 0, "HS-mode stack top is not set");
+    HostPhysicalAddress(stack_top)
+}
+
+/// Assembly to restore floating-point registers from `ContextData` at `sp`.
+///
+/// `t0` must hold the restored sstatus and they are restored only if `sstatus.FS` is Dirty.
+/// It clobbers `t0` and `t1`. (used by `hstrap_exit` and `hart_entry`)
+macro_rules! restore_fp_registers {
+    () => {
+        "
+        // restore floating-point registers if sstatus.FS == Dirty
+        srli t0, t0, 13
+        andi t0, t0, 0b11
+        li t1, 0b11
+        bne t0, t1, 1f
+        .option push
+        .option arch, +d
+        fld f0, 34*8(sp)
+        fld f1, 35*8(sp)
+        fld f2, 36*8(sp)
+        fld f3, 37*8(sp)
+        fld f4, 38*8(sp)
+        fld f5, 39*8(sp)
+        fld f6, 40*8(sp)
+        fld f7, 41*8(sp)
+        fld f8, 42*8(sp)
+        fld f9, 43*8(sp)
+        fld f10, 44*8(sp)
+        fld f11, 45*8(sp)
+        fld f12, 46*8(sp)
+        fld f13, 47*8(sp)
+        fld f14, 48*8(sp)
+        fld f15, 49*8(sp)
+        fld f16, 50*8(sp)
+        fld f17, 51*8(sp)
+        fld f18, 52*8(sp)
+        fld f19, 53*8(sp)
+        fld f20, 54*8(sp)
+        fld f21, 55*8(sp)
+        fld f22, 56*8(sp)
+        fld f23, 57*8(sp)
+        fld f24, 58*8(sp)
+        fld f25, 59*8(sp)
+        fld f26, 60*8(sp)
+        fld f27, 61*8(sp)
+        fld f28, 62*8(sp)
+        fld f29, 63*8(sp)
+        fld f30, 64*8(sp)
+        fld f31, 65*8(sp)
+        ld t0, 66*8(sp)
+        fscsr t0
+        .option pop
+        1:
+        "
+    };
+}
+pub(crate) use restore_fp_registers;
 
 /// Switch to original mode stack and save contexts.
+///
+/// sscratch is set to HS-mode stack top again, so that the next trap is not regarded as nested.
 #[inline(always)]
 #[allow(clippy::inline_always)]
 pub unsafe fn hstrap_exit() -> ! {
-    // aquire hypervisor data
-    let hypervisor_data = unsafe { HYPERVISOR_DATA.lock() };
-    let stack_top = hypervisor_data.get().unwrap().guest().stack_top();
-    // release HYPERVISOR_DATA lock
-    drop(hypervisor_data);
+    let stack_top = hs_stack_top();
+    check_stack_guard(stack_top);
+
+    crate::stats::record_trap_exit();
+    pmu_context().trap_exit();
+    crate::device::plic::assert_deferred_irq();
 
     asm!(
         ".align 4
@@ -32,7 +161,9 @@ pub unsafe fn hstrap_exit() -> ! {
         // restore sstatus 
         ld t0, 32*8(sp)
         csrw sstatus, t0
-
+        ",
+        restore_fp_registers!(),
+        "
         // restore pc
         ld t1, 33*8(sp)
         csrw sepc, t1
@@ -41,7 +172,6 @@ pub unsafe fn hstrap_exit() -> ! {
         ld ra, 1*8(sp)
         ld gp, 3*8(sp)
         ld tp, 4*8(sp)
-        ld t0, 5*8(sp)
         ld t1, 6*8(sp)
         ld t2, 7*8(sp)
         ld s0, 8*8(sp)
@@ -69,9 +199,13 @@ pub unsafe fn hstrap_exit() -> ! {
         ld t5, 30*8(sp)
         ld t6, 31*8(sp)
 
-        // swap HS-mode sp for original mode sp.
-        addi sp, sp, {HS_CONTEXT_SIZE}
-        csrrw sp, sscratch, sp
+        // leave trap handler: sscratch holds HS-mode stack top while original mode is running.
+        addi t0, sp, {HS_CONTEXT_SIZE}
+        csrw sscratch, t0
+
+        // restore original mode sp at last.
+        ld t0, 5*8(sp)
+        ld sp, 2*8(sp)
 
         sret
         ",
@@ -81,19 +215,17 @@ pub unsafe fn hstrap_exit() -> ! {
     );
 }
 
-/// Trap vector for HS-mode.
 /// Switch to hypervisor stack and save contexts.
 ///
-/// ## `fn_align`
-/// function alignment (feature `fn_align`).  
-/// See: [https://github.com/rust-lang/rust/issues/82232](https://github.com/rust-lang/rust/issues/82232).
-/// ```no_run
-/// #[repr(align(4))]
-/// pub unsafe extern "C" fn hstrap_vector() -> ! { }
-/// ```
-#[no_mangle]
-#[inline(never)]
-pub unsafe extern "C" fn hstrap_vector() -> ! {
+/// It must be called at the beginning of trap vectors.
+///
+/// sscratch is 0 while a trap is handled.
+/// A trap taken in the handler (nested HS trap) is detected by it and jumps to `hstrap_nested`
+/// before the saved context is overwritten.
+/// Interrupts never nest because `sstatus.SIE` is cleared on trap and not set in handlers.
+#[inline(always)]
+#[allow(clippy::inline_always, clippy::too_many_lines)]
+unsafe fn save_context() {
     unsafe {
         asm!(
             ".align 4
@@ -101,6 +233,17 @@ pub unsafe extern "C" fn hstrap_vector() -> ! {
 
             // swap original mode sp for HS-mode sp 
             csrrw sp, sscratch, sp
+            bnez sp, 2f
+
+            // nested HS trap: switch to emergency stack of this HART (tp holds HART id)
+            csrrw sp, sscratch, sp
+            la sp, {emergency_stacks}
+            addi t0, tp, 1
+            slli t0, t0, {emergency_stack_shift}
+            add sp, sp, t0
+            j {nested}
+
+            2:
             addi sp, sp, -{HS_CONTEXT_SIZE}
 
             // save registers
@@ -142,16 +285,195 @@ pub unsafe extern "C" fn hstrap_vector() -> ! {
             // save pc
             csrr t1, sepc
             sd t1, 33*8(sp)
+
+            // save floating-point registers if sstatus.FS == Dirty
+            srli t0, t0, 13
+            andi t0, t0, 0b11
+            li t1, 0b11
+            bne t0, t1, 1f
+            .option push
+            .option arch, +d
+            fsd f0, 34*8(sp)
+            fsd f1, 35*8(sp)
+            fsd f2, 36*8(sp)
+            fsd f3, 37*8(sp)
+            fsd f4, 38*8(sp)
+            fsd f5, 39*8(sp)
+            fsd f6, 40*8(sp)
+            fsd f7, 41*8(sp)
+            fsd f8, 42*8(sp)
+            fsd f9, 43*8(sp)
+            fsd f10, 44*8(sp)
+            fsd f11, 45*8(sp)
+            fsd f12, 46*8(sp)
+            fsd f13, 47*8(sp)
+            fsd f14, 48*8(sp)
+            fsd f15, 49*8(sp)
+            fsd f16, 50*8(sp)
+            fsd f17, 51*8(sp)
+            fsd f18, 52*8(sp)
+            fsd f19, 53*8(sp)
+            fsd f20, 54*8(sp)
+            fsd f21, 55*8(sp)
+            fsd f22, 56*8(sp)
+            fsd f23, 57*8(sp)
+            fsd f24, 58*8(sp)
+            fsd f25, 59*8(sp)
+            fsd f26, 60*8(sp)
+            fsd f27, 61*8(sp)
+            fsd f28, 62*8(sp)
+            fsd f29, 63*8(sp)
+            fsd f30, 64*8(sp)
+            fsd f31, 65*8(sp)
+            frcsr t0
+            sd t0, 66*8(sp)
+            .option pop
+            1:
+
+            // set HART id to tp
+            // tp = (_stack_start - stack top) / STACK_SIZE_PER_HART
+            la t0, {stack_start}
+            addi t1, sp, {HS_CONTEXT_SIZE}
+            sub t0, t0, t1
+            li t1, {stack_size_per_hart}
+            divu tp, t0, t1
+
+            // save original mode sp and enter trap handler
+            csrrw t0, sscratch, zero
+            sd t0, 2*8(sp)
             ",
             HS_CONTEXT_SIZE = const size_of::<ContextData>(),
+            stack_start = sym _stack_start,
+            stack_size_per_hart = const STACK_SIZE_PER_HART,
+            emergency_stacks = sym EMERGENCY_STACKS,
+            emergency_stack_shift = const EMERGENCY_STACK_SIZE.trailing_zeros(),
+            nested = sym hstrap_nested,
         );
     }
+}
+
+/// Trap vector for HS-mode.
+/// Switch to hypervisor stack and save contexts.
+///
+/// ## `fn_align`
+/// function alignment (feature `fn_align`).  
+/// See: [https://github.com/rust-lang/rust/issues/82232](https://github.com/rust-lang/rust/issues/82232).
+/// ```no_run
+/// #[repr(align(4))]
+/// pub unsafe extern "C" fn hstrap_vector() -> ! { }
+/// ```
+#[no_mangle]
+#[inline(never)]
+pub unsafe extern "C" fn hstrap_vector() -> ! {
+    save_context();
 
     hstrap_vector2();
 }
 
+// Trap vector table for vectored mode. (`stvec.MODE` = Vectored)
+//
+// Exceptions and interrupts without fast path are handled by `hstrap_vector`.
+#[cfg(feature = "vectored_trap")]
+core::arch::global_asm!(
+    ".section .text.hstrap_vector_table
+    .align 8
+    .global hstrap_vector_table
+hstrap_vector_table:
+    // each entry must be 4 bytes.
+    .option push
+    .option norvc
+    j {generic}         // 0: exception (and user software interrupt)
+    j {generic}         // 1: supervisor software interrupt
+    j {generic}         // 2: reserved
+    j {generic}         // 3: machine software interrupt
+    j {generic}         // 4: user timer interrupt
+    j {timer}           // 5: supervisor timer interrupt
+    j {generic}         // 6: reserved
+    j {generic}         // 7: machine timer interrupt
+    j {generic}         // 8: user external interrupt
+    j {external}        // 9: supervisor external interrupt
+    j {generic}         // 10: virtual supervisor external interrupt
+    j {generic}         // 11: machine external interrupt
+    j {generic}         // 12: supervisor guest external interrupt
+    j {generic}         // 13: counter overflow interrupt
+    j {generic}         // 14: reserved
+    j {generic}         // 15: reserved
+    .option pop
+    ",
+    generic = sym hstrap_vector,
+    timer = sym hstrap_timer_vector,
+    external = sym hstrap_external_vector,
+);
+
+#[cfg(feature = "vectored_trap")]
+extern "C" {
+    /// Trap vector table defined by `global_asm`.
+    pub fn hstrap_vector_table();
+}
+
+/// Fast path of supervisor timer interrupt for vectored mode.
+#[cfg(feature = "vectored_trap")]
+#[no_mangle]
+#[inline(never)]
+unsafe extern "C" fn hstrap_timer_vector() -> ! {
+    save_context();
+
+    hstrap_timer_vector2();
+}
+
+/// Separated from `hstrap_timer_vector` by stack pointer circumstance.
+#[cfg(feature = "vectored_trap")]
+#[inline(never)]
+unsafe extern "C" fn hstrap_timer_vector2() -> ! {
+    pmu_context().trap_entry();
+    trap_interrupt(Interrupt::SupervisorTimer);
+}
+
+/// Fast path of supervisor external interrupt for vectored mode.
+#[cfg(feature = "vectored_trap")]
+#[no_mangle]
+#[inline(never)]
+unsafe extern "C" fn hstrap_external_vector() -> ! {
+    save_context();
+
+    hstrap_external_vector2();
+}
+
+/// Separated from `hstrap_external_vector` by stack pointer circumstance.
+#[cfg(feature = "vectored_trap")]
+#[inline(never)]
+unsafe extern "C" fn hstrap_external_vector2() -> ! {
+    pmu_context().trap_entry();
+    trap_interrupt(Interrupt::SupervisorExternal);
+}
+
+/// Report nested HS trap and halt.
+///
+/// It runs on the emergency stack. (see `save_context`)
+/// The guest context saved by the outer trap is still on HS-mode stack top.
+#[no_mangle]
+#[inline(never)]
+unsafe extern "C" fn hstrap_nested() -> ! {
+    const { assert!(EMERGENCY_STACK_SIZE.is_power_of_two()) };
+
+    let context_addr = hs_stack_top() - size_of::<ContextData>();
+    let context = unsafe { &*(context_addr.raw() as *const ContextData) };
+    println!(
+        "sepc: {:#x}, scause: {:#x}, stval: {:#x}, htval: {:#x}, htinst: {:#x}",
+        sepc::read(),
+        scause::read().bits(),
+        stval::read(),
+        htval::read().bits(),
+        htinst::read().bits()
+    );
+    println!("guest context: {:#x?}", context);
+    panic!("nested HS trap on hart {}", current_hart_id());
+}
+
 /// Separated from `hsrap_vector` by stack pointer circumstance.
 pub unsafe extern "C" fn hstrap_vector2() -> ! {
+    pmu_context().trap_entry();
+
     match scause::read().cause() {
         Trap::Interrupt(interrupt_cause) => trap_interrupt(interrupt_cause),
         Trap::Exception(exception_cause) => trap_exception(exception_cause),
@@ -5,12 +5,9 @@ mod page_fault_handler;
 mod sbi_handler;
 
 use super::hstrap_exit;
-use crate::guest;
-use crate::h_extension::{
-    csrs::{htval, vstvec},
-    HvException,
-};
-use crate::HYPERVISOR_DATA;
+use crate::guest::{context::Context, watchdog};
+use crate::h_extension::{csrs::vstvec, HvException};
+use crate::{hart_local, stats, HartLocal};
 use sbi_handler::sbi_call;
 
 use core::arch::asm;
@@ -19,8 +16,15 @@ use riscv::register::{
     stval,
 };
 use sbi_handler::{
-    sbi_base_handler, sbi_fwft_handler, sbi_pmu_handler, sbi_rfnc_handler, sbi_time_handler,
+    is_forwarded_extension, normalize_sbi_error, sbi_base_handler, sbi_cppc_handler,
+    sbi_fwft_handler, sbi_memory_handler, sbi_pmu_handler, sbi_rfnc_handler, sbi_snapshot_handler,
+    sbi_stats_handler, sbi_susp_handler, sbi_time_handler, EID_FWFT, EID_HIKAMI_MEMORY,
+    EID_HIKAMI_SNAPSHOT, EID_HIKAMI_STATS,
 };
+use sbi_rt::SbiRet;
+
+/// Cache block size for `CBO.ZERO`. (same as `riscv,cboz-block-size` in guest dtb)
+const CBOZ_BLOCK_SIZE: usize = 64;
 
 /// Delegate exception to supervisor mode from VS-mode.
 #[no_mangle]
@@ -28,7 +32,11 @@ use sbi_handler::{
 #[allow(clippy::inline_always, clippy::module_name_repetitions)]
 pub extern "C" fn hs_forward_exception() {
     unsafe {
-        let mut context = HYPERVISOR_DATA.lock().get().unwrap().guest().context;
+        let mut hart_data = hart_local().lock();
+        let hart = hart_data.get_mut().unwrap();
+        watchdog::check_forwarded_exception(hart, scause::read().bits(), stval::read());
+        stats::record_forwarded_exception();
+        let context = hart.guest_mut().context();
         asm!(
             "csrw vsepc, {sepc}",
             "csrw vscause, {scause}",
@@ -44,10 +52,8 @@ pub extern "C" fn hs_forward_exception() {
 
 /// Handler for Ecall from VS-mode exception
 #[allow(clippy::cast_possible_truncation)]
-fn sbi_vs_mode_handler(context: &mut guest::context::Context) {
-    /// Extension ID of FWFT(Firmware Features) Extension.
-    const EID_FWFT: usize = 0x4657_4654;
-
+fn sbi_vs_mode_handler(hart: &mut HartLocal) {
+    let context = hart.guest_mut().context();
     let ext_id: usize = context.xreg(17) as usize;
     let func_id: usize = context.xreg(16) as usize;
     let arguments: &[u64; 5] = &[
@@ -58,21 +64,31 @@ fn sbi_vs_mode_handler(context: &mut guest::context::Context) {
         context.xreg(14),
     ];
 
+    stats::record_sbi_call(ext_id);
+
     let sbiret = match ext_id {
-        sbi_spec::base::EID_BASE => sbi_base_handler(func_id),
+        sbi_spec::base::EID_BASE => sbi_base_handler(func_id, arguments),
+        sbi_spec::cppc::EID_CPPC => sbi_cppc_handler(func_id, arguments),
         sbi_spec::pmu::EID_PMU => sbi_pmu_handler(func_id, arguments),
         sbi_spec::rfnc::EID_RFNC => sbi_rfnc_handler(func_id, arguments),
-        sbi_spec::time::EID_TIME => sbi_time_handler(func_id, arguments),
-        EID_FWFT => sbi_fwft_handler(func_id, arguments),
-        _ => sbi_call(ext_id, func_id, arguments),
+        sbi_spec::susp::EID_SUSP => sbi_susp_handler(func_id, arguments),
+        sbi_spec::time::EID_TIME => sbi_time_handler(hart, func_id, arguments),
+        EID_FWFT => sbi_fwft_handler(hart, func_id, arguments),
+        EID_HIKAMI_STATS => sbi_stats_handler(func_id, arguments),
+        EID_HIKAMI_MEMORY => sbi_memory_handler(hart, func_id, arguments),
+        EID_HIKAMI_SNAPSHOT => sbi_snapshot_handler(hart, func_id, arguments),
+        _ if is_forwarded_extension(ext_id) => sbi_call(ext_id, func_id, arguments),
+        _ => SbiRet::not_supported(),
     };
+    let sbiret = normalize_sbi_error(ext_id, sbiret);
 
+    let context = hart.guest_mut().context();
     context.set_xreg(10, sbiret.error as u64);
     context.set_xreg(11, sbiret.value as u64);
 }
 
 /// Update sepc by inst size (2 byte or 4 byte)
-fn update_sepc_by_inst_type(is_compressed: bool, context: &mut guest::context::Context) {
+fn update_sepc_by_inst_type(is_compressed: bool, context: &mut Context) {
     if is_compressed {
         // compressed instruction
         context.set_sepc(context.sepc() + 2);
@@ -85,22 +101,22 @@ fn update_sepc_by_inst_type(is_compressed: bool, context: &mut guest::context::C
 /// Trap handler for exception
 #[allow(clippy::cast_possible_truncation, clippy::module_name_repetitions)]
 pub unsafe fn trap_exception(exception_cause: Exception) -> ! {
+    stats::record_exception(scause::read().code());
+
     match exception_cause {
         Exception::IllegalInstruction => instruction_handler::illegal_instruction(),
         Exception::SupervisorEnvCall => panic!("SupervisorEnvCall should be handled by M-mode"),
         // Enum not found in `riscv` crate.
         Exception::Unknown => match HvException::from(scause::read().code()) {
             HvException::EcallFromVsMode => {
-                let mut context = unsafe { HYPERVISOR_DATA.lock().get().unwrap().guest().context };
-                sbi_vs_mode_handler(&mut context);
+                let mut hart_data = hart_local().lock();
+                let hart = hart_data.get_mut().unwrap();
+                sbi_vs_mode_handler(hart);
+                let context = hart.guest_mut().context();
                 context.set_sepc(context.sepc() + 4);
             }
             HvException::InstructionGuestPageFault => {
-                panic!(
-                    "Instruction guest-page fault\nfault gpa: {:#x}\nfault hpa: {:#x}",
-                    stval::read(),
-                    htval::read().bits() << 2,
-                );
+                page_fault_handler::instruction_guest_page_fault();
             }
             HvException::LoadGuestPageFault => page_fault_handler::load_guest_page_fault(),
             HvException::StoreAmoGuestPageFault => page_fault_handler::store_guest_page_fault(),
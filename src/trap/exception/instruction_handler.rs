@@ -3,35 +3,79 @@
 //! - Illegal Instruction
 //! - Virtual Instruction
 
-use super::hs_forward_exception;
-use crate::emulate_extension::zicfiss::ZICFISS_DATA;
-use crate::emulate_extension::EmulateExtension;
-use crate::HYPERVISOR_DATA;
+use super::{hs_forward_exception, CBOZ_BLOCK_SIZE};
+use crate::device::plic::ContextId;
+#[cfg(feature = "csr_log")]
+use crate::emulate_extension::csr_access_log;
+use crate::emulate_extension::zicfilp::{self, Zicfilp};
+use crate::emulate_extension::zicfiss::Zicfiss;
+use crate::emulate_extension::{dispatch_extensions, pseudo_vs_exception, EmulateExtension};
+use crate::guest::context::pmu_context;
+use crate::h_extension::csrs::{hgatp, hie, hvip, VsInterruptKind};
+use crate::h_extension::instruction::{hfence_gvma, hfence_vvma};
+use crate::memmap::page_table::{g_stage_trans_addr, vs_stage_trans_addr};
+use crate::memmap::GuestVirtualAddress;
+use crate::{hart_local, stats, DEVICES};
 
 use core::arch::asm;
-use raki::{Instruction, OpcodeKind};
-use riscv::register::{sepc, stval};
+use raki::{BaseIOpcode, COpcode, Instruction, OpcodeKind, PrivOpcode, ZicbozOpcode};
+use riscv::register::{sepc, sie, sip, stval};
 
 /// Trap `Illegal instruction` exception.
 #[inline]
 pub fn illegal_instruction() {
     let fault_inst_value = stval::read();
+
+    // an instruction other than `LPAD` after emulated indirect jump.
+    if let Err(exception) = zicfilp::check_landing_pad(fault_inst_value) {
+        exception.raise();
+    }
+
+    // extensions that `raki` does not support. (e.g. Zicond, Zba and counters without rs1)
+    if dispatch_extensions(fault_inst_value) {
+        stats::record_emulated_instruction();
+        return;
+    }
+
     let fault_inst = Instruction::try_from(fault_inst_value).unwrap_or_else(|_| {
         panic!("decoding load fault instruction failed: fault inst value: {fault_inst_value:#x} at {:#x}", sepc::read());
     });
 
     // emulate the instruction
+    // (locks taken by emulators are released before raising the exception they return)
     match fault_inst.opc {
-        OpcodeKind::Zicfiss(_) => unsafe { ZICFISS_DATA.lock() }
-            .get_mut()
-            .unwrap()
-            .instruction(&fault_inst),
+        OpcodeKind::Zicfiss(_) => {
+            if let Err(exception) = Zicfiss.instruction(&fault_inst) {
+                exception.raise();
+            }
+            stats::record_emulated_instruction();
+        }
+        // LPAD
+        OpcodeKind::BaseI(BaseIOpcode::AUIPC) if fault_inst.rd == Some(0) => {
+            if let Err(exception) = Zicfilp.instruction(&fault_inst) {
+                exception.raise();
+            }
+            stats::record_emulated_instruction();
+        }
+        // indirect jumps update sepc by themselves.
+        OpcodeKind::BaseI(BaseIOpcode::JALR) | OpcodeKind::C(COpcode::JR | COpcode::JALR) => {
+            if let Err(exception) = Zicfilp.instruction(&fault_inst) {
+                exception.raise();
+            }
+            stats::record_emulated_instruction();
+            return;
+        }
         OpcodeKind::Zicsr(_) => match fault_inst.rs2.unwrap() {
             // ssp
-            0x11 => unsafe { ZICFISS_DATA.lock() }
-                .get_mut()
-                .unwrap()
-                .csr(&fault_inst),
+            0x11 => {
+                if let Err(exception) = Zicfiss.csr(&fault_inst) {
+                    exception.raise();
+                }
+                stats::record_emulated_instruction();
+            }
+            #[cfg(feature = "csr_log")]
+            _ => csr_access_log::unsupported_csr(&fault_inst),
+            #[cfg(not(feature = "csr_log"))]
             unsupported_csr_num => {
                 unimplemented!("unsupported CSRs: {unsupported_csr_num:#x}")
             }
@@ -39,22 +83,105 @@ pub fn illegal_instruction() {
         _ => hs_forward_exception(),
     }
 
-    let mut context = unsafe { HYPERVISOR_DATA.lock().get().unwrap().guest().context };
-    context.update_sepc_by_inst(&fault_inst);
+    hart_local()
+        .lock()
+        .get_mut()
+        .unwrap()
+        .guest_mut()
+        .context()
+        .update_sepc_by_inst(&fault_inst);
+}
+
+/// Emulate `WFI` in HS-mode.
+///
+/// If no interrupt is pending to the guest, wait for an interrupt in HS-mode.
+/// It returns without waiting if the guest enables no interrupts in `hie` (`vsie`)
+/// because no interrupt could resume the guest. (`WFI` is allowed to be a nop)
+/// Pending external interrupt is reflected to the guest before resuming it.
+fn wait_for_interrupt(hart_id: usize) {
+    /// Mask of all VS-level interrupts in `hvip`.
+    const VS_INTERRUPTS: usize = VsInterruptKind::External as usize
+        | VsInterruptKind::Timer as usize
+        | VsInterruptKind::Software as usize;
+
+    // an interrupt is already pending to the guest.
+    if hvip::read().bits() & VS_INTERRUPTS != 0 {
+        return;
+    }
+
+    // blocking would never be resumed.
+    if hie::read().bits() & VS_INTERRUPTS == 0 {
+        return;
+    }
+
+    // interrupts are taken after returning to the guest because `sstatus.SIE` is 0 here.
+    if !sip::read().sext() {
+        riscv::asm::wfi();
+    }
+
+    if sip::read().sext() {
+        let context_id = ContextId::new(hart_id, true);
+
+        // read plic claim/update register and inject the claimed interrupt.
+        DEVICES
+            .lock()
+            .get_mut()
+            .unwrap()
+            .plic
+            .claim_and_inject(&context_id)
+            .expect("failed to inject external interrupt");
+
+        unsafe {
+            sie::clear_sext();
+        }
+    }
+}
+
+/// Decode `HFENCE.GVMA` and return its source registers. (rs1, rs2)
+///
+/// `raki` does not support H extension instructions, so it is decoded here.
+fn decode_hfence_gvma(inst_value: usize) -> Option<(usize, usize)> {
+    /// Opcode of `HFENCE.GVMA`. (SYSTEM)
+    const OPCODE_SYSTEM: usize = 0b111_0011;
+    /// funct7 of `HFENCE.GVMA`.
+    const FUNCT7_HFENCE_GVMA: usize = 0b011_0001;
+
+    // funct3 and rd are zero.
+    (inst_value & 0x7fff == OPCODE_SYSTEM && (inst_value >> 25) & 0x7f == FUNCT7_HFENCE_GVMA)
+        .then_some(((inst_value >> 15) & 0x1f, (inst_value >> 20) & 0x1f))
 }
 
 /// Trap `Virtual instruction` exception.
 #[inline]
+#[allow(
+    clippy::cast_possible_truncation,
+    clippy::similar_names,
+    clippy::too_many_lines
+)]
 pub fn virtual_instruction() {
+    /// Store/AMO access fault.
+    const STORE_AMO_ACCESS_FAULT: usize = 7;
+    /// Store/AMO page fault.
+    const STORE_AMO_PAGE_FAULT: usize = 15;
+
     let fault_inst_value = stval::read();
+    let mut hart_data = hart_local().lock();
+    let guest = hart_data.get_mut().unwrap().guest_mut();
+    let hart_id = guest.hart_id();
+    let context = guest.context();
+
+    // `HFENCE.GVMA` from VS-mode: re-issue it for the VMID of the guest.
+    if let Some((rs1, _rs2)) = decode_hfence_gvma(fault_inst_value) {
+        // rs1 holds guest physical address shifted right by 2 bits.
+        let gpa = (rs1 != 0).then(|| (context.xreg(rs1) as usize) << 2);
+        hfence_gvma(gpa, hgatp::read().vmid());
+        context.set_sepc(context.sepc() + 4);
+        return;
+    }
+
     let fault_inst = Instruction::try_from(fault_inst_value).unwrap_or_else(|_| {
         panic!("decoding load fault instruction failed: fault inst value: {fault_inst_value:#x} at {:#x}", sepc::read());
     });
-    let mut context = unsafe { HYPERVISOR_DATA.lock() }
-        .get()
-        .unwrap()
-        .guest()
-        .context;
 
     // emulate CSR set
     match fault_inst.opc {
@@ -69,24 +196,92 @@ pub fn virtual_instruction() {
 
                     let write_to_csr_value = context.xreg(fault_inst.rs1.unwrap());
 
-                    // update emulated CSR field.
-                    unsafe { ZICFISS_DATA.lock() }.get_mut().unwrap().csr_field(
-                        &fault_inst,
-                        write_to_csr_value,
-                        &mut read_from_csr_value,
-                    );
+                    // update emulated CSR field. (emulators lock `HART_DATA` by themselves)
+                    drop(hart_data);
+                    Zicfiss.csr_field(&fault_inst, write_to_csr_value, &mut read_from_csr_value);
+                    Zicfilp.csr_field(&fault_inst, write_to_csr_value, &mut read_from_csr_value);
 
                     // commit result
                     unsafe {
                         asm!("csrw senvcfg, {0}", in(reg) write_to_csr_value);
                     }
+                    let mut hart_data = hart_local().lock();
+                    let context = hart_data.get_mut().unwrap().guest_mut().context();
                     context.set_xreg(fault_inst.rd.unwrap(), read_from_csr_value);
+                    context.update_sepc_by_inst(&fault_inst);
+                    return;
                 }
+                // hstateen0
+                0x60c => {
+                    let mut read_from_csr_value: u64;
+                    unsafe {
+                        asm!("csrr {0}, hstateen0", out(reg) read_from_csr_value);
+                    }
+
+                    // synthesize `ENVCFG` field. (writing is ignored)
+                    context
+                        .shadow_stack_mut()
+                        .csr_field(&fault_inst, 0, &mut read_from_csr_value);
+
+                    context.set_xreg(fault_inst.rd.unwrap(), read_from_csr_value);
+                }
+                // cycle (excluding the time spent in hypervisor)
+                0xc00 => {
+                    context.set_xreg(fault_inst.rd.unwrap(), pmu_context().cycle());
+                }
+                // instret (excluding the instructions retired in hypervisor)
+                0xc02 => {
+                    context.set_xreg(fault_inst.rd.unwrap(), pmu_context().instret());
+                }
+                // time (emulated if it is not available on the platform)
+                0xc01 => {
+                    let mtime = DEVICES.lock().get().unwrap().clint.read_mtime();
+                    let htimedelta: u64;
+                    unsafe {
+                        asm!("csrr {0}, htimedelta", out(reg) htimedelta);
+                    }
+
+                    context.set_xreg(fault_inst.rd.unwrap(), mtime.wrapping_add(htimedelta));
+                }
+                #[cfg(feature = "csr_log")]
+                _ => {
+                    drop(hart_data);
+                    csr_access_log::unsupported_csr(&fault_inst)
+                }
+                #[cfg(not(feature = "csr_log"))]
                 unsupported_csr_num => {
                     unimplemented!("unsupported CSRs: {unsupported_csr_num:#x}")
                 }
             }
         }
+        OpcodeKind::Zicboz(ZicbozOpcode::CBO_ZERO) => {
+            let block_gva = GuestVirtualAddress(
+                context.xreg(fault_inst.rs1.unwrap()) as usize & !(CBOZ_BLOCK_SIZE - 1),
+            );
+            let Ok(block_gpa) = vs_stage_trans_addr(block_gva) else {
+                drop(hart_data);
+                pseudo_vs_exception(STORE_AMO_PAGE_FAULT, block_gva.raw());
+            };
+            let Ok(block_hpa) = g_stage_trans_addr(block_gpa) else {
+                drop(hart_data);
+                pseudo_vs_exception(STORE_AMO_ACCESS_FAULT, block_gva.raw());
+            };
+
+            // zero filling a cache block
+            unsafe {
+                core::ptr::write_bytes(block_hpa.raw() as *mut u8, 0, CBOZ_BLOCK_SIZE);
+            }
+        }
+        OpcodeKind::Priv(PrivOpcode::WFI) => wait_for_interrupt(hart_id),
+        // flush VS-stage TLB entries of current VMID. (trapped if `hstatus.VTVM` is set)
+        OpcodeKind::Priv(PrivOpcode::SFENCE_VMA) => {
+            let vaddr = fault_inst.rs1.filter(|&rs1| rs1 != 0);
+            let asid = fault_inst.rs2.filter(|&rs2| rs2 != 0);
+            hfence_vvma(
+                vaddr.map(|rs1| context.xreg(rs1) as usize),
+                asid.map(|rs2| context.xreg(rs2) as usize),
+            );
+        }
         _ => unreachable!(),
     }
 
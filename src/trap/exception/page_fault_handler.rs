@@ -1,25 +1,159 @@
 //! Handle page fault exceptions.
 //!
+//! - Instruction guest page fault
 //! - Load guest page fault
 //! - Store AMO guest page fault
 
-use super::{hs_forward_exception, update_sepc_by_inst_type};
-use crate::device::EmulateDevice;
+use super::{hs_forward_exception, update_sepc_by_inst_type, CBOZ_BLOCK_SIZE};
+use crate::device::{plic::ContextId, AccessWidth, DeviceEmulateError, Devices, EmulateDevice};
+use crate::emulate_extension::pseudo_vs_exception;
 use crate::h_extension::csrs::{htinst, htval};
-use crate::memmap::page_table::{g_stage_trans_addr, vs_stage_trans_addr};
+use crate::memmap::page_table::{constants::PAGE_SIZE, g_stage_trans_addr, vs_stage_trans_addr};
 use crate::memmap::{GuestPhysicalAddress, GuestVirtualAddress, HostPhysicalAddress};
-use crate::HYPERVISOR_DATA;
+use crate::stats::{record_mmio, MmioDevice};
+use crate::{current_hart_id, hart_local, DEVICES};
 
-use raki::Instruction;
-use riscv::register::sepc;
+use raki::{BaseIOpcode, COpcode, Instruction, OpcodeKind, ZicbozOpcode};
+use riscv::register::{sepc, stval};
 
 /// Fetch fault instruction
-fn fetch_fault_inst(fault_addr: HostPhysicalAddress) -> usize {
-    let inst_value = unsafe { (fault_addr.raw() as *const u32).read_unaligned() };
-    if inst_value & 0b11 == 0b11 {
-        inst_value as usize
+///
+/// The instruction is fetched by halfword because a 32-bit instruction may be placed at 2-byte aligned address.
+/// Upper halfword is translated separately since it may lie on the next page.
+///
+/// If the address cannot be translated, the fault is forwarded to the guest as an instruction fault.
+fn fetch_fault_inst(fault_gva: GuestVirtualAddress) -> usize {
+    /// Instruction access fault.
+    const INSTRUCTION_ACCESS_FAULT: usize = 1;
+    /// Instruction page fault.
+    const INSTRUCTION_PAGE_FAULT: usize = 12;
+
+    /// Read a halfword of instruction at `gva`.
+    #[allow(clippy::similar_names)]
+    fn fetch_halfword(gva: GuestVirtualAddress) -> usize {
+        let Ok(gpa) = vs_stage_trans_addr(gva) else {
+            pseudo_vs_exception(INSTRUCTION_PAGE_FAULT, gva.raw());
+        };
+        let Ok(hpa) = g_stage_trans_addr(gpa) else {
+            pseudo_vs_exception(INSTRUCTION_ACCESS_FAULT, gva.raw());
+        };
+        unsafe { (hpa.raw() as *const u16).read_unaligned() as usize }
+    }
+
+    let lower = fetch_halfword(fault_gva);
+    if lower & 0b11 == 0b11 {
+        let upper = fetch_halfword(fault_gva + 2);
+        upper << 16 | lower
     } else {
-        (inst_value & 0xffff) as usize
+        lower
+    }
+}
+
+/// Return access width of load/store instruction.
+fn access_width(inst: &Instruction) -> AccessWidth {
+    match inst.opc {
+        OpcodeKind::BaseI(BaseIOpcode::LD | BaseIOpcode::SD)
+        | OpcodeKind::C(COpcode::LD | COpcode::SD | COpcode::LDSP | COpcode::SDSP) => {
+            AccessWidth::DoubleWord
+        }
+        _ => AccessWidth::Word,
+    }
+}
+
+/// Return faulting guest physical address of guest page fault.
+fn fault_guest_physical_addr() -> GuestPhysicalAddress {
+    // htval does not hold lower 2 bits, so they are taken from stval (same page offset).
+    GuestPhysicalAddress((htval::read().bits() << 2) | (stval::read() & 0b11))
+}
+
+/// Emulate store to the guest text region that is mapped without write permission in G-stage.
+///
+/// Linux patches its text (e.g. `patch_text`) through another writable VS-stage mapping.
+/// Return false if the fault address is not in text region or the instruction is not a plain store.
+#[allow(clippy::cast_possible_truncation, clippy::similar_names)]
+fn emulate_text_store(inst: &Instruction, value: u64) -> bool {
+    let fault_gpa = fault_guest_physical_addr();
+    let store_size = match inst.opc {
+        OpcodeKind::BaseI(BaseIOpcode::SB) => 1,
+        OpcodeKind::BaseI(BaseIOpcode::SH) => 2,
+        OpcodeKind::BaseI(BaseIOpcode::SW) | OpcodeKind::C(COpcode::SW | COpcode::SWSP) => 4,
+        OpcodeKind::BaseI(BaseIOpcode::SD) | OpcodeKind::C(COpcode::SD | COpcode::SDSP) => 8,
+        _ => return false,
+    };
+
+    if !hart_local()
+        .lock()
+        .get()
+        .unwrap()
+        .guest()
+        .is_text_region(fault_gpa)
+        || fault_gpa % PAGE_SIZE + store_size > PAGE_SIZE
+    {
+        return false;
+    }
+    let Ok(fault_hpa) = g_stage_trans_addr(fault_gpa) else {
+        return false;
+    };
+
+    let dst_ptr = fault_hpa.raw() as *mut u8;
+    unsafe {
+        match store_size {
+            1 => dst_ptr.write(value as u8),
+            2 => dst_ptr.cast::<u16>().write_unaligned(value as u16),
+            4 => dst_ptr.cast::<u32>().write_unaligned(value as u32),
+            _ => dst_ptr.cast::<u64>().write_unaligned(value),
+        }
+    }
+
+    true
+}
+
+/// Make the text page writable for stores that cannot be emulated. (e.g. AMO, page crossing)
+///
+/// The faulting instruction is re-executed after that.
+/// Return false if the fault address is not in text region.
+#[allow(clippy::similar_names)]
+fn grant_text_write() -> bool {
+    let fault_gpa = fault_guest_physical_addr();
+    // the page cannot be made non-executable if the store instruction is on it.
+    let same_page = vs_stage_trans_addr(GuestVirtualAddress(sepc::read()))
+        .is_ok_and(|inst_gpa| inst_gpa.raw() / PAGE_SIZE == fault_gpa.raw() / PAGE_SIZE);
+
+    let mut hart = hart_local().lock();
+    let guest = hart.get_mut().unwrap().guest_mut();
+    guest.is_text_region(fault_gpa) && guest.grant_text_write(fault_gpa, same_page)
+}
+
+/// Trap `Instruction guest page fault` exception.
+///
+/// Text pages made writable by `grant_text_write` are write protected again and the instruction is re-executed.
+///
+/// # Panics
+/// It panics if the fault address is not such a page.
+pub fn instruction_guest_page_fault() {
+    let fault_gpa = fault_guest_physical_addr();
+    let restored = hart_local()
+        .lock()
+        .get_mut()
+        .unwrap()
+        .guest_mut()
+        .restore_text_exec(fault_gpa);
+    assert!(
+        restored,
+        "Instruction guest-page fault\nfault gva: {:#x}\nfault gpa: {:#x}",
+        stval::read(),
+        fault_gpa
+    );
+}
+
+/// Try emulation of the next device if the address is not belong to previous devices.
+fn or_next_device<T>(
+    result: Result<T, DeviceEmulateError>,
+    next: impl FnOnce() -> Result<T, DeviceEmulateError>,
+) -> Result<T, DeviceEmulateError> {
+    match result {
+        Err(DeviceEmulateError::InvalidAddress) => next(),
+        result => result,
     }
 }
 
@@ -33,12 +167,7 @@ pub fn load_guest_page_fault() {
     // thus it needed to flip bit 1.
     // ref: vol. II p.161
     let (fault_inst, is_compressed) = if htinst_value == 0 {
-        let fault_gva = GuestVirtualAddress(sepc::read());
-        let fault_gpa =
-            vs_stage_trans_addr(fault_gva).expect("failed to get a gpa of load fault instruction");
-        let fault_hpa =
-            g_stage_trans_addr(fault_gpa).expect("failed to get a hpa of load fault instruction");
-        let fault_inst_value = fetch_fault_inst(fault_hpa);
+        let fault_inst_value = fetch_fault_inst(GuestVirtualAddress(sepc::read()));
         assert_ne!(fault_inst_value, 0);
 
         (
@@ -54,46 +183,226 @@ pub fn load_guest_page_fault() {
         )
     };
 
-    let mut hypervisor_data = unsafe { HYPERVISOR_DATA.lock() };
-    if let Ok(value) = hypervisor_data
-        .get_mut()
-        .unwrap()
-        .devices()
-        .plic
-        .emulate_loading(HostPhysicalAddress(fault_addr.raw()))
-    {
-        let mut context = hypervisor_data.get().unwrap().guest().context;
-        context.set_xreg(fault_inst.rd.expect("rd is not found"), u64::from(value));
-        update_sepc_by_inst_type(is_compressed, &mut context);
-        return;
-    }
+    let fault_hpa = HostPhysicalAddress(fault_addr.raw());
+    let mut devices_lock = DEVICES.lock();
+    let devices = devices_lock.get_mut().unwrap();
 
-    if let Some(pci) = &mut hypervisor_data.get_mut().unwrap().devices().pci {
-        if let Some(sata) = &pci.pci_devices.sata {
-            if let Ok(value) = sata.emulate_loading(HostPhysicalAddress(fault_addr.raw())) {
-                let mut context = hypervisor_data.get().unwrap().guest().context;
-                context.set_xreg(fault_inst.rd.expect("rd is not found"), u64::from(value));
-                update_sepc_by_inst_type(is_compressed, &mut context);
-                return;
-            }
+    let result =
+        record_mmio(MmioDevice::Plic, devices.plic.emulate_loading(fault_hpa)).map(u64::from);
+    let result = or_next_device(result, || {
+        devices
+            .pci
+            .as_ref()
+            .and_then(|pci| pci.pci_devices.sata.as_ref())
+            .map_or(Err(DeviceEmulateError::InvalidAddress), |sata| {
+                record_mmio(
+                    MmioDevice::Sata,
+                    sata.emulate_loading(fault_hpa, access_width(&fault_inst)),
+                )
+            })
+    });
+    let result = or_next_device(result, || {
+        devices
+            .pci
+            .as_ref()
+            .map_or(Err(DeviceEmulateError::InvalidAddress), |pci| {
+                record_mmio(
+                    MmioDevice::UnknownPci,
+                    pci.pci_devices
+                        .unknown
+                        .iter()
+                        .map(|device| device.emulate_loading(fault_hpa, access_width(&fault_inst)))
+                        .find(|result| !matches!(result, Err(DeviceEmulateError::InvalidAddress)))
+                        .unwrap_or(Err(DeviceEmulateError::InvalidAddress)),
+                )
+            })
+    });
+    let result = or_next_device(result, || {
+        devices
+            .mmc
+            .as_ref()
+            .map_or(Err(DeviceEmulateError::InvalidAddress), |mmc| {
+                record_mmio(MmioDevice::Mmc, mmc.emulate_loading(fault_hpa)).map(u64::from)
+            })
+    });
+    let result = or_next_device(result, || {
+        devices
+            .rtc
+            .as_mut()
+            .map_or(Err(DeviceEmulateError::InvalidAddress), |rtc| {
+                let rtc_offset = hart_local().lock().get().unwrap().guest().rtc_offset();
+                record_mmio(MmioDevice::Rtc, rtc.emulate_loading(fault_hpa, rtc_offset))
+                    .map(u64::from)
+            })
+    });
+    let result = or_next_device(result, || {
+        record_mmio(
+            MmioDevice::VirtIo,
+            devices.virtio_list.emulate_loading(fault_hpa),
+        )
+        .map(u64::from)
+    });
+    // forwarding locks `DEVICES` again.
+    drop(devices_lock);
+
+    match result {
+        Ok(value) => {
+            let mut hart_data = hart_local().lock();
+            let context = hart_data.get_mut().unwrap().guest_mut().context();
+            context.set_xreg(fault_inst.rd.expect("rd is not found"), value);
+            update_sepc_by_inst_type(is_compressed, context);
+        }
+        #[cfg_attr(not(feature = "debug_log"), allow(unused_variables))]
+        Err(err) => {
+            crate::debugln!(
+                "load guest page fault is forwarded: {:#x} ({})",
+                fault_addr,
+                err
+            );
+            hs_forward_exception();
         }
     }
+}
 
-    if let Some(mmc) = &mut hypervisor_data.get_mut().unwrap().devices().mmc {
-        if let Ok(value) = mmc.emulate_loading(HostPhysicalAddress(fault_addr.raw())) {
-            let mut context = hypervisor_data.get().unwrap().guest().context;
-            context.set_xreg(fault_inst.rd.expect("rd is not found"), u64::from(value));
-            update_sepc_by_inst_type(is_compressed, &mut context);
-            return;
-        }
+/// Source of the value written by the store fault instruction.
+enum StoreSource {
+    /// Value of the source register. (`x0` reads as 0)
+    Reg(u64),
+    /// Zero filling a cache block. (`cbo.zero`)
+    Zero,
+    /// Cache block management. (`cbo.clean`, `cbo.flush`, `cbo.inval`)
+    CacheOp,
+}
+
+/// Is the instruction `cbo.clean`, `cbo.flush` or `cbo.inval`?
+///
+/// They are checked by encoding since raki does not decode Zicbom.
+fn is_cache_block_management(inst_value: usize) -> bool {
+    /// Opcode of MISC-MEM.
+    const MISC_MEM: usize = 0b000_1111;
+    /// funct3 of CBO instructions.
+    const FUNCT3_CBO: usize = 0b010;
+
+    inst_value & 0x7f == MISC_MEM
+        && inst_value & (0x1f << 7) == 0
+        && (inst_value >> 12) & 0x7 == FUNCT3_CBO
+        // cbo.inval (0), cbo.clean (1), cbo.flush (2)
+        && (inst_value >> 20) & 0xfff <= 2
+}
+
+/// Decode store fault instruction and return it with the source of stored value.
+///
+/// Instruction is `None` for cache block management instructions.
+fn decode_store_inst(inst_value: usize) -> (Option<Instruction>, StoreSource) {
+    if is_cache_block_management(inst_value) {
+        return (None, StoreSource::CacheOp);
     }
 
-    drop(hypervisor_data);
-    hs_forward_exception();
+    let inst = Instruction::try_from(inst_value).expect("decoding store fault instruction failed");
+    let source = match (&inst.opc, inst.rs2) {
+        (OpcodeKind::Zicboz(ZicbozOpcode::CBO_ZERO), _) => StoreSource::Zero,
+        (_, Some(rs2)) => StoreSource::Reg(
+            hart_local()
+                .lock()
+                .get_mut()
+                .unwrap()
+                .guest_mut()
+                .context()
+                .xreg(rs2),
+        ),
+        (_, None) => {
+            panic!("unsupported store fault instruction: {inst:#?} (inst_value: {inst_value:#x})")
+        }
+    };
+
+    (Some(inst), source)
+}
+
+/// Emulate storing to a device register.
+#[allow(clippy::cast_possible_truncation)]
+fn emulate_device_storing(
+    devices: &mut Devices,
+    fault_hpa: HostPhysicalAddress,
+    value: u64,
+    width: AccessWidth,
+) -> Result<(), DeviceEmulateError> {
+    let store_value = value as u32;
+    let result = record_mmio(
+        MmioDevice::Plic,
+        devices.plic.emulate_storing(fault_hpa, store_value),
+    );
+    let result = or_next_device(result, || {
+        devices
+            .pci
+            .as_mut()
+            .and_then(|pci| pci.pci_devices.sata.as_mut())
+            .map_or(Err(DeviceEmulateError::InvalidAddress), |sata| {
+                record_mmio(
+                    MmioDevice::Sata,
+                    sata.emulate_storing(fault_hpa, value, width),
+                )
+            })
+    });
+    let result = or_next_device(result, || {
+        devices
+            .pci
+            .as_ref()
+            .map_or(Err(DeviceEmulateError::InvalidAddress), |pci| {
+                record_mmio(
+                    MmioDevice::UnknownPci,
+                    pci.pci_devices
+                        .unknown
+                        .iter()
+                        .map(|device| device.emulate_storing(fault_hpa, value, width))
+                        .find(|result| !matches!(result, Err(DeviceEmulateError::InvalidAddress)))
+                        .unwrap_or(Err(DeviceEmulateError::InvalidAddress)),
+                )
+            })
+    });
+    let result = or_next_device(result, || {
+        devices
+            .mmc
+            .as_mut()
+            .map_or(Err(DeviceEmulateError::InvalidAddress), |mmc| {
+                let result =
+                    record_mmio(MmioDevice::Mmc, mmc.emulate_storing(fault_hpa, store_value));
+                mmc.inject_abort_interrupt(
+                    &mut devices.plic,
+                    &ContextId::new(current_hart_id(), true),
+                );
+                result
+            })
+    });
+    let result = or_next_device(result, || {
+        devices
+            .rtc
+            .as_mut()
+            .map_or(Err(DeviceEmulateError::InvalidAddress), |rtc| {
+                record_mmio(
+                    MmioDevice::Rtc,
+                    rtc.emulate_storing(
+                        fault_hpa,
+                        store_value,
+                        hart_local()
+                            .lock()
+                            .get_mut()
+                            .unwrap()
+                            .guest_mut()
+                            .rtc_offset_mut(),
+                    ),
+                )
+            })
+    });
+    or_next_device(result, || {
+        record_mmio(
+            MmioDevice::VirtIo,
+            devices.virtio_list.emulate_storing(fault_hpa, store_value),
+        )
+    })
 }
 
 /// Trap `Store guest page fault` exception.
-#[allow(clippy::cast_possible_truncation, clippy::similar_names)]
+#[allow(clippy::similar_names)]
 pub fn store_guest_page_fault() {
     let fault_addr = GuestPhysicalAddress(htval::read().bits() << 2);
 
@@ -101,68 +410,64 @@ pub fn store_guest_page_fault() {
     // htinst bit 1 replaced with a 0.
     // thus it needed to flip bit 1.
     // ref: vol. II p.161
-    let (fault_inst, fault_inst_value, is_compressed) = if htinst_value == 0 {
-        let fault_gva = GuestVirtualAddress(sepc::read());
-        let fault_gpa =
-            vs_stage_trans_addr(fault_gva).expect("failed to get a gpa of load fault instruction");
-        let fault_hpa =
-            g_stage_trans_addr(fault_gpa).expect("failed to get a hpa of load fault instruction");
-        let fault_inst_value = fetch_fault_inst(fault_hpa);
+    let (fault_inst_value, is_compressed) = if htinst_value == 0 {
+        let fault_inst_value = fetch_fault_inst(GuestVirtualAddress(sepc::read()));
         assert_ne!(fault_inst_value, 0);
 
-        (
-            Instruction::try_from(fault_inst_value)
-                .expect("decoding store fault instruction failed"),
-            fault_inst_value,
-            (fault_inst_value & 0b11) != 0b11,
-        )
+        (fault_inst_value, (fault_inst_value & 0b11) != 0b11)
     } else {
-        (
-            Instruction::try_from(htinst_value | 0b10)
-                .expect("decoding store fault instruction failed"),
-            htinst_value,
-            (htinst_value & 0b10) >> 1 == 0,
-        )
+        (htinst_value | 0b10, (htinst_value & 0b10) >> 1 == 0)
     };
 
-    let mut hypervisor_data = unsafe { HYPERVISOR_DATA.lock() };
-    let mut context = hypervisor_data.get().unwrap().guest().context;
-    let store_value = context.xreg(match fault_inst.rs2 {
-        Some(x) => x,
-        None => panic!("rs2 is not found: {fault_inst:#?} (inst_value: {fault_inst_value})"),
-    });
-
-    if let Ok(()) = hypervisor_data
-        .get_mut()
-        .unwrap()
-        .devices()
-        .plic
-        .emulate_storing(HostPhysicalAddress(fault_addr.raw()), store_value as u32)
-    {
-        update_sepc_by_inst_type(is_compressed, &mut context);
-        return;
-    }
+    let (fault_inst, source) = decode_store_inst(fault_inst_value);
+    let fault_hpa = HostPhysicalAddress(fault_addr.raw());
+    let mut devices_lock = DEVICES.lock();
+    let devices = devices_lock.get_mut().unwrap();
 
-    if let Some(pci) = &mut hypervisor_data.get_mut().unwrap().devices().pci {
-        if let Some(sata) = &mut pci.pci_devices.sata {
-            if let Ok(()) =
-                sata.emulate_storing(HostPhysicalAddress(fault_addr.raw()), store_value as u32)
-            {
-                update_sepc_by_inst_type(is_compressed, &mut context);
-                return;
-            }
+    let result = match (&fault_inst, &source) {
+        // emulated devices have no cache to be maintained.
+        (_, StoreSource::CacheOp) if devices.is_emulated_address(fault_hpa) => Ok(()),
+        (_, StoreSource::CacheOp) => Err(DeviceEmulateError::InvalidAddress),
+        (_, StoreSource::Zero) => {
+            let block_hpa = HostPhysicalAddress(fault_hpa.raw() & !(CBOZ_BLOCK_SIZE - 1));
+            (0..CBOZ_BLOCK_SIZE).step_by(4).try_for_each(|offset| {
+                emulate_device_storing(devices, block_hpa + offset, 0, AccessWidth::Word)
+            })
         }
-    }
+        (Some(inst), StoreSource::Reg(value)) => {
+            emulate_device_storing(devices, fault_hpa, *value, access_width(inst))
+        }
+        (None, StoreSource::Reg(_)) => unreachable!(),
+    };
+    // `grant_text_write` and forwarding lock `DEVICES` again.
+    drop(devices_lock);
 
-    if let Some(mmc) = &mut hypervisor_data.get_mut().unwrap().devices().mmc {
-        if let Ok(()) =
-            mmc.emulate_storing(HostPhysicalAddress(fault_addr.raw()), store_value as u32)
+    let update_sepc = || {
+        update_sepc_by_inst_type(
+            is_compressed,
+            hart_local().lock().get_mut().unwrap().guest_mut().context(),
+        );
+    };
+    match result {
+        Ok(()) => update_sepc(),
+        Err(DeviceEmulateError::InvalidAddress)
+            if matches!(
+                (&fault_inst, source),
+                (Some(inst), StoreSource::Reg(value)) if emulate_text_store(inst, value)
+            ) =>
         {
-            update_sepc_by_inst_type(is_compressed, &mut context);
-            return;
+            update_sepc();
+        }
+        // re-execute the instruction.
+        Err(DeviceEmulateError::InvalidAddress) if grant_text_write() => (),
+        #[cfg_attr(not(feature = "debug_log"), allow(unused_variables))]
+        Err(err) => {
+            crate::debugln!(
+                "store guest page fault is forwarded: {:#x} ({})",
+                fault_addr,
+                err
+            );
+            hs_forward_exception();
         }
     }
-
-    drop(hypervisor_data);
-    hs_forward_exception();
 }
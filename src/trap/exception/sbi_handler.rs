@@ -1,9 +1,14 @@
 //! Handle VS-mode Ecall exception  
 //! See [https://github.com/riscv-non-isa/riscv-sbi-doc/releases/download/v2.0/riscv-sbi.pdf](https://github.com/riscv-non-isa/riscv-sbi-doc/releases/download/v2.0/riscv-sbi.pdf)
 
+use crate::guest::context::pmu_context;
+use crate::guest::HotplugError;
 use crate::h_extension::csrs::{hvip, VsInterruptKind};
+use crate::hypervisor_init::{cancel_warm_boot, prepare_warm_boot};
+use crate::memmap::page_table::{constants::PAGE_SIZE, g_stage_trans_addr};
+use crate::memmap::GuestPhysicalAddress;
+use crate::HartLocal;
 
-use riscv::register::sie;
 use sbi_rt::SbiRet;
 use sbi_rt::{ConfigFlags, StartFlags, StopFlags};
 
@@ -27,12 +32,91 @@ pub fn sbi_call(ext_id: usize, func_id: usize, args: &[u64; 5]) -> SbiRet {
     SbiRet { error, value }
 }
 
+/// Convert error code of `SbiRet` to the standard SBI error code. (chapter 3.2)
+///
+/// Error codes out of the standard (e.g. internal error codes of the SBI implementation) are converted to `SBI_ERR_FAILED`.
+/// Legacy extensions (EID `0x00` - `0x0f`) are not converted since they return a value in `a0`.
+/// (e.g. a character of `CONSOLE_GETCHAR`)
+#[allow(clippy::cast_possible_wrap)]
+pub fn normalize_sbi_error(ext_id: usize, raw: SbiRet) -> SbiRet {
+    use sbi_spec::binary::RET_ERR_FAILED;
+
+    /// Last EID of legacy extensions.
+    const LEGACY_EID_END: usize = 0x0f;
+    /// The smallest error code defined by the spec. (`SBI_ERR_DENIED_LOCKED`)
+    const MIN_ERROR_CODE: isize = -14;
+
+    if ext_id <= LEGACY_EID_END {
+        return raw;
+    }
+
+    match raw.error as isize {
+        MIN_ERROR_CODE..=0 => raw,
+        _ => SbiRet {
+            error: RET_ERR_FAILED,
+            value: 0,
+        },
+    }
+}
+
+/// Extension ID of FWFT(Firmware Features) Extension.
+pub const EID_FWFT: usize = 0x4657_4654;
+
+/// Extension ID of hikami statistics extension. (vendor specific: `0x0900_0000 | 'H'`)
+pub const EID_HIKAMI_STATS: usize = 0x0900_0048;
+
+/// Extension ID of hikami memory hotplug extension. (vendor specific: `0x0900_0000 | 'M'`)
+pub const EID_HIKAMI_MEMORY: usize = 0x0900_004d;
+
+/// Extension ID of hikami snapshot extension. (vendor specific: `0x0900_0000 | 'S'`)
+pub const EID_HIKAMI_SNAPSHOT: usize = 0x0900_0053;
+
+/// Extensions that are passed through to the SBI implementation as is.
+///
+/// Other extensions that are not handled by hypervisor return `SBI_ERR_NOT_SUPPORTED`.
+const FORWARDED_EXTENSIONS: [usize; 5] = [
+    sbi_spec::legacy::LEGACY_CONSOLE_PUTCHAR,
+    sbi_spec::legacy::LEGACY_CONSOLE_GETCHAR,
+    sbi_spec::hsm::EID_HSM,
+    sbi_spec::spi::EID_SPI,
+    sbi_spec::srst::EID_SRST,
+];
+
+/// Is the extension passed through to the SBI implementation?
+pub fn is_forwarded_extension(ext_id: usize) -> bool {
+    FORWARDED_EXTENSIONS.contains(&ext_id)
+}
+
+/// Probe the extension that is visible from guest.
+///
+/// Return 0 if the extension is not available.
+fn probe_extension(ext_id: usize) -> usize {
+    use sbi_spec::base::{EID_BASE, PROBE_EXTENSION};
+
+    /// Extensions that are handled by hypervisor and backed by the SBI implementation.
+    const HANDLED_EXTENSIONS: [usize; 5] = [
+        sbi_spec::cppc::EID_CPPC,
+        sbi_spec::pmu::EID_PMU,
+        sbi_spec::rfnc::EID_RFNC,
+        sbi_spec::susp::EID_SUSP,
+        sbi_spec::time::EID_TIME,
+    ];
+
+    match ext_id {
+        EID_BASE | EID_FWFT | EID_HIKAMI_STATS | EID_HIKAMI_MEMORY | EID_HIKAMI_SNAPSHOT => 1,
+        _ if HANDLED_EXTENSIONS.contains(&ext_id) || is_forwarded_extension(ext_id) => {
+            sbi_call(EID_BASE, PROBE_EXTENSION, &[ext_id as u64, 0, 0, 0, 0]).value
+        }
+        _ => 0,
+    }
+}
+
 /// SBI ecall handler for Base Extension (EID: #0x10)
 ///
 /// All functions in the base extension must be supported by all SBI implementations,
 /// so there are no error returns defined. (p.13)
-#[allow(clippy::module_name_repetitions)]
-pub fn sbi_base_handler(func_id: usize) -> SbiRet {
+#[allow(clippy::module_name_repetitions, clippy::cast_possible_truncation)]
+pub fn sbi_base_handler(func_id: usize, args: &[u64; 5]) -> SbiRet {
     use sbi_spec::base::{
         GET_MARCHID, GET_MIMPID, GET_MVENDORID, GET_SBI_IMPL_ID, GET_SBI_IMPL_VERSION,
         GET_SBI_SPEC_VERSION, PROBE_EXTENSION,
@@ -44,11 +128,11 @@ pub fn sbi_base_handler(func_id: usize) -> SbiRet {
         }
         GET_SBI_IMPL_ID => sbi_rt::get_sbi_impl_id(),
         GET_SBI_IMPL_VERSION => sbi_rt::get_sbi_impl_version(),
-        PROBE_EXTENSION => sbi_rt::probe_extension(sbi_rt::Base).raw,
+        PROBE_EXTENSION => probe_extension(args[0] as usize),
         GET_MVENDORID => sbi_rt::get_mvendorid(),
         GET_MIMPID => sbi_rt::get_mimpid(),
         GET_MARCHID => sbi_rt::get_marchid(),
-        _ => unreachable!(),
+        _ => return SbiRet::not_supported(),
     };
 
     SbiRet {
@@ -58,20 +142,35 @@ pub fn sbi_base_handler(func_id: usize) -> SbiRet {
 }
 
 /// SBI ecall handler for TIME Extension (EID: #0x54494d45)
+///
+/// The timer is kept in guest and HS-mode timer is shared with the scheduler. (see `guest::scheduler`)
 #[allow(clippy::module_name_repetitions, clippy::cast_possible_truncation)]
-pub fn sbi_time_handler(func_id: usize, args: &[u64; 5]) -> SbiRet {
+pub fn sbi_time_handler(hart: &mut HartLocal, func_id: usize, args: &[u64; 5]) -> SbiRet {
     use sbi_spec::time::SET_TIMER;
     match func_id {
         SET_TIMER => {
-            let sbi_ret = sbi_rt::set_timer(args[0]);
-            unsafe {
-                hvip::clear(VsInterruptKind::Timer);
-                sie::set_stimer();
-            }
+            hart.guest_mut().set_timer(args[0]);
+            hvip::clear(VsInterruptKind::Timer);
 
-            sbi_ret
+            hart.program_timer()
         }
-        _ => panic!("unsupported fid: {}", func_id),
+        _ => SbiRet::not_supported(),
+    }
+}
+
+/// SBI ecall handler for CPPC Extension (EID: #0x43505043)
+///
+/// All functions take CPPC register id (and value) instead of memory address,
+/// so they are passed through to the SBI implementation without translation.
+#[allow(clippy::module_name_repetitions, clippy::cast_possible_truncation)]
+pub fn sbi_cppc_handler(func_id: usize, args: &[u64; 5]) -> SbiRet {
+    use sbi_spec::cppc::{PROBE, READ, READ_HI, WRITE};
+    match func_id {
+        PROBE => sbi_rt::cppc_probe(args[0] as u32),
+        READ => sbi_rt::cppc_read(args[0] as u32),
+        READ_HI => sbi_rt::cppc_read_hi(args[0] as u32),
+        WRITE => sbi_rt::cppc_write(args[0] as u32, args[1]),
+        _ => SbiRet::not_supported(),
     }
 }
 
@@ -102,41 +201,172 @@ impl StopFlags for PmuFlag {
     }
 }
 
+/// Is the PMU event allowed to be counted by guest?
+///
+/// Firmware events and raw hardware events may count the activity of hypervisor or firmware,
+/// so only general hardware events and cache events are allowed.
+fn is_allowed_pmu_event(event_idx: u64) -> bool {
+    use sbi_spec::pmu::event_type::{HARDWARE_CACHE, HARDWARE_GENERAL};
+
+    // event_idx[19:16] = type
+    matches!(
+        usize::try_from((event_idx >> 16) & 0xf).unwrap(),
+        HARDWARE_GENERAL | HARDWARE_CACHE
+    )
+}
+
+/// Return bitmask of counter indices from `counter_idx_base` and `counter_idx_mask`.
+///
+/// Return `None` if the counter indices exceed 64.
+fn pmu_counter_mask(counter_idx_base: u64, counter_idx_mask: u64) -> Option<u64> {
+    let base = u32::try_from(counter_idx_base).ok()?;
+    let mask = counter_idx_mask.checked_shl(base)?;
+    (mask >> base == counter_idx_mask).then_some(mask)
+}
+
 /// SBI ecall handler for PMU Extension (EID: #0x504D55)
+///
+/// Counters are virtualized per guest. (see `guest::context::PmuContext`)
+/// - The guest can start, stop and read only the counters it has configured.
+/// - Events that are not allowed by `is_allowed_pmu_event` are rejected.
 #[allow(clippy::cast_possible_truncation)]
 pub fn sbi_pmu_handler(func_id: usize, args: &[u64; 5]) -> SbiRet {
     use sbi_spec::pmu::{
         COUNTER_CONFIG_MATCHING, COUNTER_FW_READ, COUNTER_FW_READ_HI, COUNTER_GET_INFO,
-        COUNTER_START, COUNTER_STOP, EID_PMU, NUM_COUNTERS, SNAPSHOT_SET_SHMEM,
+        COUNTER_START, COUNTER_STOP, NUM_COUNTERS, SNAPSHOT_SET_SHMEM,
     };
+    /// `SBI_PMU_CFG_FLAG_AUTO_START`
+    const CFG_FLAG_AUTO_START: u64 = 1 << 2;
+    /// `SBI_PMU_STOP_FLAG_RESET`
+    const STOP_FLAG_RESET: u64 = 1 << 0;
+
+    let pmu = pmu_context();
     match func_id {
         NUM_COUNTERS => SbiRet {
             error: 0,
             value: sbi_rt::pmu_num_counters(),
         },
         COUNTER_GET_INFO => sbi_rt::pmu_counter_get_info(args[0] as usize),
-        COUNTER_CONFIG_MATCHING => sbi_rt::pmu_counter_config_matching(
-            args[0] as usize,
-            args[1] as usize,
-            PmuFlag::new(args[2]),
-            args[3] as usize,
-            args[4],
-        ),
-        COUNTER_START => sbi_rt::pmu_counter_start(
-            args[0] as usize,
-            args[1] as usize,
-            PmuFlag::new(args[2]),
-            args[3],
-        ),
+        COUNTER_CONFIG_MATCHING => {
+            if !is_allowed_pmu_event(args[3]) {
+                return SbiRet::not_supported();
+            }
+
+            let sbiret = sbi_rt::pmu_counter_config_matching(
+                args[0] as usize,
+                args[1] as usize,
+                PmuFlag::new(args[2]),
+                args[3] as usize,
+                args[4],
+            );
+            if sbiret.is_ok() && sbiret.value < u64::BITS as usize {
+                pmu.configure(sbiret.value, args[2] & CFG_FLAG_AUTO_START != 0);
+            }
+            sbiret
+        }
+        COUNTER_START => {
+            let Some(mask) = pmu_counter_mask(args[0], args[1]).filter(|&mask| pmu.owns(mask))
+            else {
+                return SbiRet::invalid_param();
+            };
+
+            let sbiret = sbi_rt::pmu_counter_start(
+                args[0] as usize,
+                args[1] as usize,
+                PmuFlag::new(args[2]),
+                args[3],
+            );
+            if sbiret.is_ok() {
+                pmu.start(mask);
+            }
+            sbiret
+        }
         COUNTER_STOP => {
-            sbi_rt::pmu_counter_stop(args[0] as usize, args[1] as usize, PmuFlag::new(args[2]))
+            let Some(mask) = pmu_counter_mask(args[0], args[1]).filter(|&mask| pmu.owns(mask))
+            else {
+                return SbiRet::invalid_param();
+            };
+
+            let sbiret =
+                sbi_rt::pmu_counter_stop(args[0] as usize, args[1] as usize, PmuFlag::new(args[2]));
+            if sbiret.is_ok() {
+                pmu.stop(mask, args[2] & STOP_FLAG_RESET != 0);
+            }
+            sbiret
         }
-        COUNTER_FW_READ => sbi_rt::pmu_counter_fw_read(args[0] as usize),
-        COUNTER_FW_READ_HI => sbi_rt::pmu_counter_fw_read_hi(args[0] as usize),
-        // `sbi_rt::pmu_snapshot_set_shmem` is unimplemented.
-        // thus it is called by ecall instruction directly.
-        SNAPSHOT_SET_SHMEM => sbi_call(EID_PMU, SNAPSHOT_SET_SHMEM, args),
-        _ => panic!("unsupported fid: {}", func_id),
+        COUNTER_FW_READ | COUNTER_FW_READ_HI => {
+            if !pmu_counter_mask(args[0], 1).is_some_and(|mask| pmu.owns(mask)) {
+                return SbiRet::invalid_param();
+            }
+
+            if func_id == COUNTER_FW_READ {
+                sbi_rt::pmu_counter_fw_read(args[0] as usize)
+            } else {
+                sbi_rt::pmu_counter_fw_read_hi(args[0] as usize)
+            }
+        }
+        SNAPSHOT_SET_SHMEM => sbi_pmu_snapshot_set_shmem(args),
+        _ => SbiRet::not_supported(),
+    }
+}
+
+/// Emulate `SNAPSHOT_SET_SHMEM` in PMU Extension (FID #7)
+///
+/// Guest passes the shared memory address as GPA, thus it is translated to HPA before passing to SBI.
+/// The snapshot area (4 KiB, page aligned) is backed by one host page,
+/// so the guest can read it from original GPA without copying back.
+///
+/// `sbi_rt::pmu_snapshot_set_shmem` is unimplemented.
+/// thus it is called by ecall instruction directly.
+#[allow(clippy::cast_possible_truncation, clippy::similar_names)]
+fn sbi_pmu_snapshot_set_shmem(args: &[u64; 5]) -> SbiRet {
+    use sbi_spec::pmu::{EID_PMU, SNAPSHOT_SET_SHMEM};
+
+    // disable the snapshot shared memory if both addresses are all-ones bitwise.
+    if args[0] == u64::MAX && args[1] == u64::MAX {
+        return sbi_call(EID_PMU, SNAPSHOT_SET_SHMEM, args);
+    }
+
+    // `shmem_phys_hi` is always zero in RV64.
+    let shmem_gpa = GuestPhysicalAddress(args[0] as usize);
+    if args[1] != 0 || shmem_gpa % PAGE_SIZE != 0 {
+        return SbiRet::invalid_param();
+    }
+
+    match g_stage_trans_addr(shmem_gpa) {
+        Ok(shmem_hpa) => sbi_call(
+            EID_PMU,
+            SNAPSHOT_SET_SHMEM,
+            &[shmem_hpa.raw() as u64, 0, args[2], args[3], args[4]],
+        ),
+        Err(_) => SbiRet::invalid_address(),
+    }
+}
+
+/// SBI ecall handler for System Suspend Extension (EID: #0x53555350)
+///
+/// The guest must resume at `resume_addr` in VS-mode but the SBI implementation resumes in HS-mode.
+/// Thus hypervisor entry is passed instead and `hstart` resumes the guest. (see `hypervisor_init::warm_boot`)
+#[allow(clippy::module_name_repetitions, clippy::cast_possible_truncation)]
+pub fn sbi_susp_handler(func_id: usize, args: &[u64; 5]) -> SbiRet {
+    use sbi_spec::susp::{EID_SUSP, SUSPEND};
+
+    match func_id {
+        SUSPEND => {
+            let resume_addr = GuestPhysicalAddress(args[1] as usize);
+            if g_stage_trans_addr(resume_addr).is_err() {
+                return SbiRet::invalid_address();
+            }
+
+            prepare_warm_boot(resume_addr, args[2]);
+            let hv_entry = crate::_start as *const fn() as u64;
+            let sbiret = sbi_call(EID_SUSP, SUSPEND, &[args[0], hv_entry, 0, 0, 0]);
+
+            // returned without suspend. (e.g. unsupported sleep type)
+            cancel_warm_boot();
+            sbiret
+        }
+        _ => SbiRet::not_supported(),
     }
 }
 
@@ -160,7 +390,7 @@ pub fn sbi_rfnc_handler(func_id: usize, args: &[u64; 5]) -> SbiRet {
             args[3] as usize,
             args[4] as usize,
         ),
-        _ => panic!("unsupported fid: {}", func_id),
+        _ => SbiRet::not_supported(),
     }
 }
 
@@ -200,8 +430,8 @@ impl TryFrom<usize> for FwftFeature {
 /// SBI ecall handler for Firmware Features Extension (EID #0x46574654)
 ///
 /// FWFT ecall will be emulated because `sbi_rt` is not supported.
-#[allow(clippy::cast_possible_truncation)]
-pub fn sbi_fwft_handler(func_id: usize, args: &[u64; 5]) -> SbiRet {
+#[allow(clippy::cast_possible_truncation, clippy::match_same_arms)]
+pub fn sbi_fwft_handler(hart: &mut HartLocal, func_id: usize, args: &[u64; 5]) -> SbiRet {
     /// Firmware Features Set (FID #0)
     const FWFT_SET: usize = 0;
     /// Firmware Features Get (FID #1)
@@ -210,20 +440,130 @@ pub fn sbi_fwft_handler(func_id: usize, args: &[u64; 5]) -> SbiRet {
     let feature = args[0] as usize;
 
     match func_id {
-        FWFT_SET => match FwftFeature::try_from(feature).unwrap() {
-            FwftFeature::ShadowStack => {
-                // hypervisor does not use shadow stack.
+        FWFT_SET => match FwftFeature::try_from(feature) {
+            // landing pads cannot be enforced. (see `emulate_extension::zicfilp`)
+            Ok(FwftFeature::LandingPad) => SbiRet::not_supported(),
+            Ok(FwftFeature::ShadowStack) => {
+                // `henvcfg.SSE` of the guest. (hypervisor does not use shadow stack)
+                let enable = args[1] & 1 == 1;
+                let guest = hart.guest_mut();
+                let shadow_stack = guest.context().shadow_stack_mut();
+                let was_enabled = shadow_stack.henv_sse;
+                shadow_stack.henv_sse = enable;
+
+                // shadow stack is not enabled on the page that cannot be used as shadow stack.
+                if enable && !was_enabled && !guest.map_next_shadow_stack_push() {
+                    guest.context().shadow_stack_mut().henv_sse = false;
+                    return SbiRet::denied();
+                }
                 SbiRet::success(0)
             }
-            feat => unimplemented!("unimplemented feature {:?}", feat),
+            _ => SbiRet::not_supported(),
         },
-        FWFT_GET => match FwftFeature::try_from(feature).unwrap() {
-            FwftFeature::ShadowStack => {
-                // hypervisor does not use shadow stack.
+        FWFT_GET => match FwftFeature::try_from(feature) {
+            // landing pads cannot be enforced. (see `emulate_extension::zicfilp`)
+            Ok(FwftFeature::LandingPad) => SbiRet::not_supported(),
+            Ok(FwftFeature::ShadowStack) => SbiRet::success(usize::from(
+                hart.guest_mut().context().shadow_stack().henv_sse,
+            )),
+            _ => SbiRet::not_supported(),
+        },
+        _ => SbiRet::not_supported(),
+    }
+}
+
+/// SBI ecall handler for hikami statistics extension (EID #0x09000048)
+///
+/// See `stats` for counter ids.
+#[allow(clippy::cast_possible_truncation)]
+pub fn sbi_stats_handler(func_id: usize, args: &[u64; 5]) -> SbiRet {
+    /// Get counter by id (FID #0)
+    /// * `args[0]`: counter id
+    /// * `args[1]`: HART id
+    const STATS_GET_COUNTER: usize = 0;
+    /// Reset all counters (FID #1)
+    const STATS_RESET: usize = 1;
+    /// Flush debug log buffered in ring buffers to console (FID #2)
+    ///
+    /// Only supported with `debug_ring` feature.
+    #[cfg(feature = "debug_ring")]
+    const STATS_FLUSH_DEBUG_LOG: usize = 2;
+
+    match func_id {
+        STATS_GET_COUNTER => crate::stats::read_counter(args[1] as usize, args[0] as usize)
+            .map_or(SbiRet::invalid_param(), |value| {
+                SbiRet::success(value as usize)
+            }),
+        STATS_RESET => {
+            crate::stats::reset();
+            SbiRet::success(0)
+        }
+        #[cfg(feature = "debug_ring")]
+        STATS_FLUSH_DEBUG_LOG => {
+            crate::debug_ring::flush();
+            SbiRet::success(0)
+        }
+        _ => SbiRet::not_supported(),
+    }
+}
+
+/// SBI ecall handler for hikami memory hotplug extension (EID #0x0900004d)
+///
+/// Memory is added to or removed from the end of guest memory region.
+/// The start of the added or removed range (GPA) is returned.
+/// Adding memory fails with `SBI_ERR_DENIED` beyond `HIKAMI_MAX_HOTPLUG_SIZE`
+/// and with `SBI_ERR_NO_SHMEM` if the hypervisor heap is exhausted.
+#[allow(clippy::cast_possible_truncation)]
+pub fn sbi_memory_handler(hart: &mut HartLocal, func_id: usize, args: &[u64; 5]) -> SbiRet {
+    /// Add memory (FID #0)
+    /// * `args[0]`: size in bytes (rounded up to page size)
+    const MEMORY_EXTEND: usize = 0;
+    /// Remove memory added by `MEMORY_EXTEND` (FID #1)
+    /// * `args[0]`: size in bytes (rounded up to page size)
+    const MEMORY_SHRINK: usize = 1;
+
+    let bytes = args[0] as usize;
+    match func_id {
+        _ if bytes == 0 => SbiRet::invalid_param(),
+        MEMORY_EXTEND => match hart.guest_mut().extend_memory(bytes) {
+            Ok(region) => SbiRet::success(region.start.raw()),
+            Err(HotplugError::ExceedLimit) => SbiRet::denied(),
+            Err(HotplugError::AlreadyMapped) => SbiRet::invalid_address(),
+            Err(HotplugError::OutOfMemory) => SbiRet::no_shmem(),
+        },
+        MEMORY_SHRINK => SbiRet::success(hart.guest_mut().shrink_memory(bytes).start.raw()),
+        _ => SbiRet::not_supported(),
+    }
+}
+
+/// SBI ecall handler for hikami snapshot extension (EID #0x09000053)
+///
+/// Register and device state of the guest is saved to or restored from a guest buffer.
+/// After restoring, the guest resumes right after the ecall that saved the snapshot with `value` 0.
+#[allow(clippy::cast_possible_truncation)]
+pub fn sbi_snapshot_handler(hart: &mut HartLocal, func_id: usize, args: &[u64; 5]) -> SbiRet {
+    /// Save snapshot (FID #0)
+    /// * `args[0]`: GPA of the buffer
+    /// * `args[1]`: size of the buffer
+    const SNAPSHOT_SAVE: usize = 0;
+    /// Restore snapshot saved by `SNAPSHOT_SAVE` (FID #1)
+    /// * `args[0]`: GPA of the buffer
+    /// * `args[1]`: size of the buffer
+    const SNAPSHOT_RESTORE: usize = 1;
+
+    let (gpa, size) = (GuestPhysicalAddress(args[0] as usize), args[1] as usize);
+    match func_id {
+        SNAPSHOT_SAVE => hart
+            .guest()
+            .save_state_to_guest(gpa, size)
+            .map_or(SbiRet::invalid_param(), SbiRet::success),
+        SNAPSHOT_RESTORE => {
+            if hart.guest_mut().restore_state_from_guest(gpa, size) {
                 SbiRet::success(0)
+            } else {
+                SbiRet::invalid_param()
             }
-            feat => unimplemented!("unimplemented feature {:?}", feat),
-        },
-        _ => unreachable!(),
+        }
+        _ => SbiRet::not_supported(),
     }
 }
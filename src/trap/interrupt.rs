@@ -1,40 +1,52 @@
 //! Trap VS-mode interrupt.
 
 use super::hstrap_exit;
-use crate::device::plic::ContextId;
+use crate::device::{plic::ContextId, IrqSource};
 use crate::h_extension::csrs::{hvip, VsInterruptKind};
-use crate::HYPERVISOR_DATA;
+use crate::{hart_local, stats, DEVICES};
 
-use riscv::register::scause::Interrupt;
+use riscv::register::scause::{self, Interrupt};
 use riscv::register::sie;
 
 /// Trap handler for Interrupt
 #[allow(clippy::module_name_repetitions)]
 pub unsafe fn trap_interrupt(interrupt_cause: Interrupt) -> ! {
+    stats::record_interrupt(scause::read().code());
+
     match interrupt_cause {
         Interrupt::SupervisorSoft => {
             hvip::set(VsInterruptKind::Software);
             sie::clear_ssoft();
         }
         Interrupt::SupervisorTimer => {
-            hvip::set(VsInterruptKind::Timer);
-            sie::clear_stimer();
+            hart_local().lock().get_mut().unwrap().timer_interrupt();
         }
         Interrupt::SupervisorExternal => {
-            let mut hypervisor_data = HYPERVISOR_DATA.lock();
-            let hart_id = hypervisor_data.get().unwrap().guest().hart_id();
+            let hart_id = hart_local().lock().get().unwrap().guest().hart_id();
             let context_id = ContextId::new(hart_id, true);
 
-            // read plic claim/update register and reflect to plic.claim_complete.
-            hypervisor_data
-                .get_mut()
-                .unwrap()
-                .devices()
-                .plic
-                .update_claim_complete(&context_id);
+            let mut devices_lock = DEVICES.lock();
+            let devices = devices_lock.get_mut().unwrap();
 
-            hvip::set(VsInterruptKind::External);
-            sie::clear_sext();
+            // read plic claim/update register and inject the claimed interrupt.
+            let irq = devices.plic.claim(&context_id);
+            match devices.irq_map.get(irq) {
+                Some(IrqSource::Mmc) => {
+                    if let Some(mmc) = &devices.mmc {
+                        mmc.inject_interrupt(&mut devices.plic, &context_id);
+                    }
+                }
+                _ if irq == 0 => {}
+                _ => devices
+                    .plic
+                    .inject_claimed(&context_id, irq)
+                    .expect("failed to inject external interrupt"),
+            }
+            // external interrupts are enabled again when the guest completes the claimed one.
+            // (nothing is claimed by spurious interrupt)
+            if irq != 0 {
+                sie::clear_sext();
+            }
         }
         Interrupt::Unknown => panic!("unknown interrupt type"),
     }